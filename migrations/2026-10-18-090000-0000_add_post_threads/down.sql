@@ -0,0 +1,8 @@
+DROP INDEX IF EXISTS idx_posts_quoted_post_id;
+DROP INDEX IF EXISTS idx_posts_reply_to_post_id;
+
+ALTER TABLE posts
+    DROP COLUMN quotes_count,
+    DROP COLUMN replies_count,
+    DROP COLUMN quoted_post_id,
+    DROP COLUMN reply_to_post_id;
@@ -0,0 +1,8 @@
+ALTER TABLE posts
+    ADD COLUMN reply_to_post_id UUID REFERENCES posts(id) ON DELETE SET NULL,
+    ADD COLUMN quoted_post_id UUID REFERENCES posts(id) ON DELETE SET NULL,
+    ADD COLUMN replies_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN quotes_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_posts_reply_to_post_id ON posts(reply_to_post_id);
+CREATE INDEX idx_posts_quoted_post_id ON posts(quoted_post_id);
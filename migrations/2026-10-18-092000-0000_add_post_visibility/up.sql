@@ -0,0 +1,5 @@
+ALTER TABLE posts
+    ADD COLUMN visibility VARCHAR NOT NULL DEFAULT 'public'
+    CHECK (visibility IN ('public', 'followers', 'mentioned', 'private'));
+
+CREATE INDEX idx_posts_visibility ON posts(visibility);
@@ -0,0 +1,3 @@
+DROP TABLE comment_hashtags;
+DROP TABLE post_hashtags;
+DROP TABLE hashtags;
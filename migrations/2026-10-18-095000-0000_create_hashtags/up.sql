@@ -0,0 +1,22 @@
+CREATE TABLE hashtags (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    name VARCHAR NOT NULL UNIQUE,
+    usage_count INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE post_hashtags (
+    post_id UUID NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    hashtag_id UUID NOT NULL REFERENCES hashtags(id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, hashtag_id)
+);
+
+CREATE INDEX idx_post_hashtags_hashtag_id ON post_hashtags(hashtag_id);
+
+CREATE TABLE comment_hashtags (
+    comment_id UUID NOT NULL REFERENCES comments(id) ON DELETE CASCADE,
+    hashtag_id UUID NOT NULL REFERENCES hashtags(id) ON DELETE CASCADE,
+    PRIMARY KEY (comment_id, hashtag_id)
+);
+
+CREATE INDEX idx_comment_hashtags_hashtag_id ON comment_hashtags(hashtag_id);
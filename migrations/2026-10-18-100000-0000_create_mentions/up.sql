@@ -0,0 +1,14 @@
+CREATE TABLE mentions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    post_id UUID NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    comment_id UUID REFERENCES comments(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    start_offset INTEGER NOT NULL,
+    length INTEGER NOT NULL
+);
+
+CREATE INDEX idx_mentions_post_id ON mentions(post_id);
+CREATE INDEX idx_mentions_comment_id ON mentions(comment_id);
+CREATE INDEX idx_mentions_user_id ON mentions(user_id);
+CREATE INDEX idx_users_username_lower ON users(LOWER(username));
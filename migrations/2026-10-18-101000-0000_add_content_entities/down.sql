@@ -0,0 +1,7 @@
+ALTER TABLE comments
+    DROP COLUMN content_html,
+    DROP COLUMN entities;
+
+ALTER TABLE posts
+    DROP COLUMN content_html,
+    DROP COLUMN entities;
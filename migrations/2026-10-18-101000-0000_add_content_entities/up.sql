@@ -0,0 +1,7 @@
+ALTER TABLE posts
+    ADD COLUMN entities JSONB NOT NULL DEFAULT '[]',
+    ADD COLUMN content_html TEXT;
+
+ALTER TABLE comments
+    ADD COLUMN entities JSONB NOT NULL DEFAULT '[]',
+    ADD COLUMN content_html TEXT;
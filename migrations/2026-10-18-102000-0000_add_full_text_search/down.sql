@@ -0,0 +1,14 @@
+DROP INDEX IF EXISTS idx_comments_search_vector;
+DROP INDEX IF EXISTS idx_posts_search_vector;
+
+DROP TRIGGER IF EXISTS set_comments_search_vector ON comments;
+DROP TRIGGER IF EXISTS set_posts_search_vector ON posts;
+DROP FUNCTION IF EXISTS set_search_vector();
+
+ALTER TABLE comments
+    DROP COLUMN search_vector,
+    DROP COLUMN language;
+
+ALTER TABLE posts
+    DROP COLUMN search_vector,
+    DROP COLUMN language;
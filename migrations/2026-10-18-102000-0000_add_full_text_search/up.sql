@@ -0,0 +1,27 @@
+ALTER TABLE posts
+    ADD COLUMN language VARCHAR NOT NULL DEFAULT 'english',
+    ADD COLUMN search_vector TSVECTOR;
+
+ALTER TABLE comments
+    ADD COLUMN language VARCHAR NOT NULL DEFAULT 'english',
+    ADD COLUMN search_vector TSVECTOR;
+
+-- Keeps `search_vector` in sync with `content`, stemmed for the row's language
+CREATE OR REPLACE FUNCTION set_search_vector() RETURNS trigger AS $$
+BEGIN
+    NEW.search_vector := to_tsvector(NEW.language::regconfig, NEW.content);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER set_posts_search_vector BEFORE INSERT OR UPDATE OF content, language ON posts
+    FOR EACH ROW EXECUTE PROCEDURE set_search_vector();
+
+CREATE TRIGGER set_comments_search_vector BEFORE INSERT OR UPDATE OF content, language ON comments
+    FOR EACH ROW EXECUTE PROCEDURE set_search_vector();
+
+UPDATE posts SET search_vector = to_tsvector(language::regconfig, content);
+UPDATE comments SET search_vector = to_tsvector(language::regconfig, content);
+
+CREATE INDEX idx_posts_search_vector ON posts USING GIN(search_vector);
+CREATE INDEX idx_comments_search_vector ON comments USING GIN(search_vector);
@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS record_notifications_stream_events ON notifications;
+DROP TRIGGER IF EXISTS record_users_stream_events ON users;
+DROP TRIGGER IF EXISTS record_posts_stream_events ON posts;
+DROP FUNCTION IF EXISTS record_notification_stream_event();
+DROP FUNCTION IF EXISTS record_user_stream_event();
+DROP FUNCTION IF EXISTS record_post_stream_event();
+
+DROP TABLE IF EXISTS stream_events;
+DROP FUNCTION IF EXISTS notify_stream_event();
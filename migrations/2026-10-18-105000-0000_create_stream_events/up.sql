@@ -0,0 +1,78 @@
+-- Log of events pushed to streaming clients, kept for a while so clients can resume
+-- from the last event id they saw. `user_id` is the post author for post events and
+-- the affected user otherwise.
+CREATE TABLE stream_events (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    kind VARCHAR NOT NULL,
+    user_id UUID NOT NULL,
+    post_id UUID,
+    payload JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX idx_stream_events_created_at ON stream_events(created_at);
+
+-- Wakes up listening server instances; delivered when the writing transaction commits
+CREATE OR REPLACE FUNCTION notify_stream_event() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('stream_events', NEW.id::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_stream_events AFTER INSERT ON stream_events
+    FOR EACH ROW EXECUTE PROCEDURE notify_stream_event();
+
+CREATE OR REPLACE FUNCTION record_post_stream_event() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO stream_events (kind, user_id, post_id)
+        VALUES ('post_created', NEW.user_id, NEW.id);
+    ELSIF (NEW.likes_count, NEW.shares_count, NEW.replies_count, NEW.quotes_count)
+        IS DISTINCT FROM (OLD.likes_count, OLD.shares_count, OLD.replies_count, OLD.quotes_count) THEN
+        INSERT INTO stream_events (kind, user_id, post_id, payload)
+        VALUES ('post_counters', NEW.user_id, NEW.id, jsonb_build_object(
+            'post_id', NEW.id,
+            'likes_count', NEW.likes_count,
+            'shares_count', NEW.shares_count,
+            'replies_count', NEW.replies_count,
+            'quotes_count', NEW.quotes_count
+        ));
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_posts_stream_events
+    AFTER INSERT OR UPDATE OF likes_count, shares_count, replies_count, quotes_count ON posts
+    FOR EACH ROW EXECUTE PROCEDURE record_post_stream_event();
+
+CREATE OR REPLACE FUNCTION record_user_stream_event() RETURNS trigger AS $$
+BEGIN
+    IF (NEW.followers_count, NEW.following_count)
+        IS DISTINCT FROM (OLD.followers_count, OLD.following_count) THEN
+        INSERT INTO stream_events (kind, user_id, payload)
+        VALUES ('user_counters', NEW.id, jsonb_build_object(
+            'user_id', NEW.id,
+            'followers_count', NEW.followers_count,
+            'following_count', NEW.following_count
+        ));
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_users_stream_events
+    AFTER UPDATE OF followers_count, following_count ON users
+    FOR EACH ROW EXECUTE PROCEDURE record_user_stream_event();
+
+CREATE OR REPLACE FUNCTION record_notification_stream_event() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO stream_events (kind, user_id, post_id, payload)
+    VALUES ('notification', NEW.user_id, NEW.post_id, to_jsonb(NEW));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_notifications_stream_events AFTER INSERT ON notifications
+    FOR EACH ROW EXECUTE PROCEDURE record_notification_stream_event();
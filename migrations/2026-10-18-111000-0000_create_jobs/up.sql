@@ -0,0 +1,24 @@
+-- Background jobs. Workers claim due jobs with FOR UPDATE SKIP LOCKED and hold them
+-- until `locked_until`; a job whose worker died is claimed again once that passes.
+-- Jobs that run out of attempts are kept with status 'dead' until retried by an admin.
+CREATE TABLE jobs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    kind VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    run_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    locked_until TIMESTAMP WITH TIME ZONE,
+    dedupe_key VARCHAR,
+    last_error TEXT,
+    finished_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_jobs_queued ON jobs(run_at) WHERE status = 'queued';
+CREATE INDEX idx_jobs_running ON jobs(locked_until) WHERE status = 'running';
+CREATE INDEX idx_jobs_status ON jobs(status, created_at DESC);
+
+-- At most one queued job per dedupe key
+CREATE UNIQUE INDEX idx_jobs_dedupe_key ON jobs(dedupe_key) WHERE status = 'queued';
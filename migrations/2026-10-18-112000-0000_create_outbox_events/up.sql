@@ -0,0 +1,20 @@
+-- Domain events, written in the same transaction as the change they describe and
+-- published to the configured sinks by the outbox relay
+CREATE TABLE outbox_events (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    event_type VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_error TEXT,
+    published_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_outbox_events_unpublished ON outbox_events(next_attempt_at)
+    WHERE published_at IS NULL;
+CREATE INDEX idx_outbox_events_published_at ON outbox_events(published_at);
+
+-- Lets an event published again after a partial failure skip webhooks it already reached
+ALTER TABLE webhook_deliveries ADD COLUMN event_id BIGINT;
+CREATE UNIQUE INDEX idx_webhook_deliveries_event ON webhook_deliveries(webhook_id, event_id);
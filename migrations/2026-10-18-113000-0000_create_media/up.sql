@@ -0,0 +1,25 @@
+-- Uploaded files. `storage_key` locates the file in the configured media store.
+CREATE TABLE media (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    storage_key VARCHAR NOT NULL UNIQUE,
+    content_type VARCHAR NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    url VARCHAR NOT NULL
+);
+
+CREATE INDEX idx_media_user_id ON media(user_id, created_at DESC);
+
+-- Media attached to posts, or to comments when comment_id is set, in display order
+CREATE TABLE media_attachments (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    post_id UUID NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    comment_id UUID REFERENCES comments(id) ON DELETE CASCADE,
+    media_id UUID NOT NULL REFERENCES media(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL
+);
+
+CREATE INDEX idx_media_attachments_post_id ON media_attachments(post_id);
+CREATE INDEX idx_media_attachments_comment_id ON media_attachments(comment_id);
+CREATE INDEX idx_media_attachments_media_id ON media_attachments(media_id);
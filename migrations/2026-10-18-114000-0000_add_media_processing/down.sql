@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS media_variants;
+
+ALTER TABLE media
+    DROP COLUMN IF EXISTS status,
+    DROP COLUMN IF EXISTS width,
+    DROP COLUMN IF EXISTS height,
+    DROP COLUMN IF EXISTS blurhash;
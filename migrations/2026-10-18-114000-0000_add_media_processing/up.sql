@@ -0,0 +1,26 @@
+-- Uploads are processed in the background: metadata is stripped, orientation is
+-- applied and resized variants are generated. Files are not served until then.
+ALTER TABLE media
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'processing',
+    ADD COLUMN width INTEGER,
+    ADD COLUMN height INTEGER,
+    ADD COLUMN blurhash VARCHAR;
+
+-- Resized and re-encoded copies of an upload
+CREATE TABLE media_variants (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    media_id UUID NOT NULL REFERENCES media(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    storage_key VARCHAR NOT NULL UNIQUE,
+    content_type VARCHAR NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    url VARCHAR NOT NULL
+);
+
+CREATE INDEX idx_media_variants_media_id ON media_variants(media_id);
+
+-- Existing uploads still carry their metadata, so they go through processing too
+INSERT INTO jobs (kind, payload, max_attempts)
+SELECT 'process_media', json_build_object('media_id', id, 'strip_original', true), 5 FROM media;
@@ -0,0 +1,5 @@
+ALTER TABLE media
+    DROP COLUMN IF EXISTS alt_text,
+    DROP COLUMN IF EXISTS caption,
+    DROP COLUMN IF EXISTS focal_x,
+    DROP COLUMN IF EXISTS focal_y;
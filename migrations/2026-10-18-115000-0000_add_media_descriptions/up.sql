@@ -0,0 +1,8 @@
+-- Accessibility and display metadata, editable by the uploader. The focal point is
+-- the part of the image crops should keep in view, from -1 to 1 on each axis with
+-- 0,0 at the center.
+ALTER TABLE media
+    ADD COLUMN alt_text VARCHAR,
+    ADD COLUMN caption VARCHAR,
+    ADD COLUMN focal_x REAL NOT NULL DEFAULT 0,
+    ADD COLUMN focal_y REAL NOT NULL DEFAULT 0;
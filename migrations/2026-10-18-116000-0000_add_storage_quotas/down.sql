@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_users_image_url;
+ALTER TABLE users DROP COLUMN IF EXISTS storage_quota_bytes;
@@ -0,0 +1,5 @@
+-- Overrides the quota of the user's role; usage is the sum of their media sizes
+ALTER TABLE users ADD COLUMN storage_quota_bytes BIGINT;
+
+-- The media collector looks for uploads still used as avatars
+CREATE INDEX idx_users_image_url ON users(image_url) WHERE image_url IS NOT NULL;
@@ -0,0 +1,4 @@
+UPDATE users SET image_url = NULL WHERE image_url = '/api/users/' || id || '/identicon';
+
+DROP INDEX IF EXISTS idx_users_banner_url;
+ALTER TABLE users DROP COLUMN IF EXISTS banner_url;
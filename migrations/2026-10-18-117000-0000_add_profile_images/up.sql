@@ -0,0 +1,6 @@
+ALTER TABLE users ADD COLUMN banner_url TEXT;
+
+CREATE INDEX idx_users_banner_url ON users(banner_url) WHERE banner_url IS NOT NULL;
+
+-- Users without an avatar get a generated identicon
+UPDATE users SET image_url = '/api/users/' || id || '/identicon' WHERE image_url IS NULL;
@@ -0,0 +1,8 @@
+DROP TABLE IF EXISTS email_change_requests;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS bio,
+    DROP COLUMN IF EXISTS location,
+    DROP COLUMN IF EXISTS website,
+    DROP COLUMN IF EXISTS pronouns,
+    DROP COLUMN IF EXISTS profile_fields;
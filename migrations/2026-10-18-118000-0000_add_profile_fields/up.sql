@@ -0,0 +1,18 @@
+-- `profile_fields` holds the user's custom `{"name", "value"}` pairs, in order
+ALTER TABLE users
+    ADD COLUMN bio TEXT,
+    ADD COLUMN location VARCHAR,
+    ADD COLUMN website VARCHAR,
+    ADD COLUMN pronouns VARCHAR,
+    ADD COLUMN profile_fields JSONB NOT NULL DEFAULT '[]';
+
+-- A pending change of address, applied once the token mailed to the new address is
+-- confirmed. Only the SHA-256 of the token is stored.
+CREATE TABLE email_change_requests (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    new_email VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
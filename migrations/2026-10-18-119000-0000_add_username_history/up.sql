@@ -0,0 +1,11 @@
+-- Usernames a user has given up, kept so the old handle can redirect to them and
+-- cannot be claimed by someone else for a while
+CREATE TABLE username_history (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    username VARCHAR NOT NULL
+);
+
+CREATE INDEX idx_username_history_username ON username_history (LOWER(username), created_at DESC);
+CREATE INDEX idx_username_history_user_id ON username_history (user_id, created_at DESC);
@@ -0,0 +1,7 @@
+ALTER TABLE posts DROP COLUMN IF EXISTS deleted_at;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS deletion_requested_at,
+    DROP COLUMN IF EXISTS deleted_at;
+
+DROP TABLE IF EXISTS data_exports;
@@ -0,0 +1,23 @@
+-- Archives of a user's data, built in the background and kept in the media store
+-- until they expire
+CREATE TABLE data_exports (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    storage_key VARCHAR,
+    size_bytes BIGINT,
+    completed_at TIMESTAMP WITH TIME ZONE,
+    expires_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_data_exports_user_id ON data_exports (user_id, created_at DESC);
+CREATE INDEX idx_data_exports_expires_at ON data_exports (expires_at);
+
+-- Deleted accounts keep their row, anonymized, so posts others replied to can stay
+-- in their threads as tombstones
+ALTER TABLE users
+    ADD COLUMN deletion_requested_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+ALTER TABLE posts ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS imported_posts;
+DROP TABLE IF EXISTS imports;
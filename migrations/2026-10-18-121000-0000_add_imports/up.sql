@@ -0,0 +1,34 @@
+-- Archives from other networks being imported into a user's account. The uploaded
+-- file is kept in the media store until the import finishes; items are processed in
+-- order and `processed_items` is how far the import has got.
+CREATE TABLE imports (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind VARCHAR NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    storage_key VARCHAR,
+    total_items INTEGER,
+    processed_items INTEGER NOT NULL DEFAULT 0,
+    imported_items INTEGER NOT NULL DEFAULT 0,
+    skipped_items INTEGER NOT NULL DEFAULT 0,
+    failed_items INTEGER NOT NULL DEFAULT 0,
+    -- `{"index", "item", "error"}` for each item that failed, up to a limit
+    failures JSONB NOT NULL DEFAULT '[]',
+    error TEXT,
+    completed_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_imports_user_id ON imports (user_id, created_at DESC);
+
+-- Where each imported post came from, so importing an archive again skips what is
+-- already there and replies can find the posts they answer
+CREATE TABLE imported_posts (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    source VARCHAR NOT NULL,
+    source_id VARCHAR NOT NULL,
+    post_id UUID NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, source, source_id)
+);
+
+CREATE INDEX idx_imported_posts_post_id ON imported_posts (post_id);
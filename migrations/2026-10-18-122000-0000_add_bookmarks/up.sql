@@ -0,0 +1,25 @@
+-- Named groups users sort their bookmarks into. Only their owner can see them.
+CREATE TABLE bookmark_collections (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL
+);
+
+CREATE UNIQUE INDEX idx_bookmark_collections_name ON bookmark_collections (user_id, lower(name));
+
+-- Posts a user saved, each in at most one collection. Deleting a collection keeps its
+-- bookmarks.
+CREATE TABLE bookmarks (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    post_id UUID NOT NULL REFERENCES posts(id) ON DELETE CASCADE,
+    collection_id UUID REFERENCES bookmark_collections(id) ON DELETE SET NULL,
+    UNIQUE (user_id, post_id)
+);
+
+-- Bookmarks are listed newest first, with (created_at, id) as the cursor
+CREATE INDEX idx_bookmarks_user_id ON bookmarks (user_id, created_at DESC, id DESC);
+CREATE INDEX idx_bookmarks_collection_id ON bookmarks (collection_id, created_at DESC, id DESC);
+CREATE INDEX idx_bookmarks_post_id ON bookmarks (post_id);
@@ -0,0 +1,5 @@
+-- When the author pinned the post to their profile; pinned posts are listed first,
+-- most recently pinned first
+ALTER TABLE posts ADD COLUMN pinned_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX idx_posts_pinned ON posts (user_id, pinned_at DESC) WHERE pinned_at IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Events the relay gave up on: their payload could not be read, or they ran out of
+-- attempts. They are kept for inspection and no longer claimed.
+ALTER TABLE outbox_events ADD COLUMN dead_at TIMESTAMP WITH TIME ZONE;
+
+DROP INDEX idx_outbox_events_unpublished;
+CREATE INDEX idx_outbox_events_unpublished ON outbox_events(next_attempt_at)
+    WHERE published_at IS NULL AND dead_at IS NULL;
@@ -0,0 +1,9 @@
+-- A pending password for an account created without one, set once the token mailed
+-- to the account's address is confirmed. Only the SHA-256 of the token is stored.
+CREATE TABLE password_setup_requests (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    user_id UUID NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
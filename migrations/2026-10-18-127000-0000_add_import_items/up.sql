@@ -0,0 +1,9 @@
+-- The items read from an import's upload, stored when the import starts so each
+-- run of the import job picks up where the last one stopped instead of reading the
+-- whole file again. Rows are removed as items are handled.
+CREATE TABLE import_items (
+    import_id UUID NOT NULL REFERENCES imports(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    item JSONB NOT NULL,
+    PRIMARY KEY (import_id, position)
+);
@@ -0,0 +1,145 @@
+use crate::content::is_username_char;
+use crate::database::DbError;
+use crate::media::MediaStore;
+use crate::models::UserData;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::env;
+use std::fs::File;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 30;
+const DEFAULT_USERNAME_CHANGE_COOLDOWN_DAYS: i64 = 30;
+const DEFAULT_USERNAME_REDIRECT_DAYS: i64 = 90;
+const DEFAULT_USERNAME_RESERVATION_DAYS: i64 = 365;
+const DEFAULT_ACCOUNT_DELETION_GRACE_DAYS: i64 = 30;
+const DEFAULT_EXPORT_RETENTION_DAYS: i64 = 7;
+const DEFAULT_MAX_PINNED_POSTS: i64 = 5;
+
+type ExportArchive = tar::Builder<GzEncoder<File>>;
+
+fn days_from_env(variable: &str, default: i64) -> chrono::Duration {
+    let days = env::var(variable)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+    chrono::Duration::days(days)
+}
+
+// Usernames are ASCII letters, digits and underscores, the characters a mention can
+// contain
+pub fn is_valid_username(username: &str) -> bool {
+    (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.len())
+        && username.chars().all(is_username_char)
+}
+
+// How long a user must wait between username changes, from
+// `USERNAME_CHANGE_COOLDOWN_DAYS`
+pub fn username_change_cooldown() -> chrono::Duration {
+    days_from_env(
+        "USERNAME_CHANGE_COOLDOWN_DAYS",
+        DEFAULT_USERNAME_CHANGE_COOLDOWN_DAYS,
+    )
+}
+
+// How long an old username redirects to its user, from `USERNAME_REDIRECT_DAYS`
+pub fn username_redirect_period() -> chrono::Duration {
+    days_from_env("USERNAME_REDIRECT_DAYS", DEFAULT_USERNAME_REDIRECT_DAYS)
+}
+
+// How long an old username is kept from other users, from
+// `USERNAME_RESERVATION_DAYS`. Never shorter than the redirect, so a handle that
+// still redirects cannot belong to someone else.
+pub fn username_reservation_period() -> chrono::Duration {
+    days_from_env(
+        "USERNAME_RESERVATION_DAYS",
+        DEFAULT_USERNAME_RESERVATION_DAYS,
+    )
+    .max(username_redirect_period())
+}
+
+// How long a user has to change their mind after asking to delete their account,
+// from `ACCOUNT_DELETION_GRACE_DAYS`
+pub fn account_deletion_grace() -> chrono::Duration {
+    days_from_env(
+        "ACCOUNT_DELETION_GRACE_DAYS",
+        DEFAULT_ACCOUNT_DELETION_GRACE_DAYS,
+    )
+}
+
+// How long finished data exports can be downloaded, from `EXPORT_RETENTION_DAYS`
+pub fn export_retention() -> chrono::Duration {
+    days_from_env("EXPORT_RETENTION_DAYS", DEFAULT_EXPORT_RETENTION_DAYS)
+}
+
+// How many posts a user can pin to their profile, from `MAX_PINNED_POSTS`
+pub fn max_pinned_posts() -> i64 {
+    env::var("MAX_PINNED_POSTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PINNED_POSTS)
+}
+
+// The username a deleted account is left with. Deleted accounts keep their row for
+// the posts others replied to, and the original username stays reserved.
+pub fn deleted_username(user_id: Uuid) -> String {
+    format!("deleted_{}", user_id.simple())
+}
+
+pub fn export_storage_key(user_id: Uuid, export_id: Uuid) -> String {
+    format!("exports/{}/{}.tar.gz", user_id, export_id)
+}
+
+// Where an upload goes in the export archive
+pub fn export_media_path(storage_key: &str) -> String {
+    let name = storage_key.rsplit('/').next().unwrap_or(storage_key);
+    format!("media/{}", name)
+}
+
+fn append_file(
+    archive: &mut ExportArchive,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    archive.append_data(&mut header, path, data)
+}
+
+// A gzipped tarball of `data.json` and the user's uploads, written to a temporary file
+// that is deleted when it is dropped. Files are fetched one at a time and compressed
+// off the async runtime.
+pub async fn build_export_archive(
+    store: &dyn MediaStore,
+    data: &UserData,
+) -> Result<NamedTempFile, DbError> {
+    let mtime = data.exported_at.timestamp().max(0) as u64;
+    let file = NamedTempFile::new()?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file.reopen()?, Compression::default()));
+    append_file(
+        &mut archive,
+        "data.json",
+        &serde_json::to_vec_pretty(data)?,
+        mtime,
+    )?;
+
+    for item in &data.media {
+        let Some(file) = store.get(&item.media.storage_key).await? else {
+            continue;
+        };
+        let path = item.file.clone();
+        archive = tokio::task::spawn_blocking(move || {
+            append_file(&mut archive, &path, &file, mtime)?;
+            Ok::<_, std::io::Error>(archive)
+        })
+        .await??;
+    }
+
+    tokio::task::spawn_blocking(move || archive.into_inner()?.finish()?.sync_all()).await??;
+    Ok(file)
+}
@@ -1,6 +1,13 @@
-use chrono::{DateTime, Duration, Utc};
+use crate::database::Database;
+use crate::models::User;
+use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::{FromRequest, HttpRequest, dev::Payload, web};
+use chrono::{Duration, Utc};
+use futures_util::future::LocalBoxFuture;
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::env;
 use uuid::Uuid;
 use validator::Validate;
@@ -30,13 +37,11 @@ pub struct RegisterRequest {
     #[validate(email(message = "Invalid email format"))]
     pub email: String,
 
-    #[validate(length(min = 3, message = "Username must be at least 3 characters"))]
+    #[validate(custom(function = "crate::models::validate_username"))]
     pub username: String,
 
     #[validate(length(min = 6, message = "Password must be at least 6 characters"))]
     pub password: String,
-
-    pub image_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +59,18 @@ pub struct AuthUser {
     pub image_url: Option<String>,
 }
 
+impl From<&User> for AuthUser {
+    fn from(user: &User) -> Self {
+        AuthUser {
+            id: user.id,
+            name: user.name.clone(),
+            email: user.email.clone(),
+            username: user.username.clone(),
+            image_url: user.image_url.clone(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthError {
     pub message: String,
@@ -136,6 +153,18 @@ pub fn validate_token(token: &str, config: &JwtConfig) -> Result<Claims, AuthErr
     Ok(token_data.claims)
 }
 
+// A random single-use token, hex encoded, for links sent by email
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Tokens are stored hashed, so a leaked table cannot be used to confirm anything
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 pub fn hash_password(password: &str) -> Result<String, AuthError> {
     Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
 }
@@ -143,3 +172,88 @@ pub fn hash_password(password: &str) -> Result<String, AuthError> {
 pub fn verify_password(password: &str, hash: &str) -> Result<bool, AuthError> {
     Ok(bcrypt::verify(password, hash)?)
 }
+
+// The caller identified by a valid `Authorization: Bearer <token>` header, as long as
+// their account has not been erased. Use `Option<AuthenticatedUser>` for endpoints that
+// also serve anonymous viewers.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: Uuid,
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_id = bearer_user_id(req);
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        Box::pin(async move {
+            Ok(AuthenticatedUser {
+                id: active_user(user_id, db).await?,
+            })
+        })
+    }
+}
+
+// Like `AuthenticatedUser`, but also accepts the token as an `access_token` query
+// parameter, since browsers cannot set headers on EventSource or WebSocket requests
+#[derive(Debug, Clone)]
+pub struct StreamUser {
+    pub id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenQuery {
+    access_token: Option<String>,
+}
+
+impl FromRequest for StreamUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_id = bearer_user_id(req).or_else(|| {
+            let config = req.app_data::<web::Data<JwtConfig>>()?;
+            let query = web::Query::<AccessTokenQuery>::from_query(req.query_string()).ok()?;
+            let claims = validate_token(query.access_token.as_deref()?, config).ok()?;
+            Some(claims.sub)
+        });
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        Box::pin(async move {
+            Ok(StreamUser {
+                id: active_user(user_id, db).await?,
+            })
+        })
+    }
+}
+
+// The user named by a valid bearer token
+fn bearer_user_id(req: &HttpRequest) -> Option<Uuid> {
+    let config = req.app_data::<web::Data<JwtConfig>>()?;
+    let token = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    let claims = validate_token(token, config).ok()?;
+    Some(claims.sub)
+}
+
+// Tokens stay valid until they expire, so erased accounts are turned away here
+async fn active_user(
+    user_id: Option<Uuid>,
+    db: Option<web::Data<Database>>,
+) -> Result<Uuid, actix_web::Error> {
+    let user_id = user_id.ok_or_else(|| ErrorUnauthorized("Missing or invalid token"))?;
+    let db = db.ok_or_else(|| ErrorInternalServerError("Database is not configured"))?;
+    match db.is_active_user(user_id).await {
+        Ok(true) => Ok(user_id),
+        Ok(false) => Err(ErrorUnauthorized("Missing or invalid token")),
+        Err(e) => Err(ErrorInternalServerError(format!(
+            "Error checking account: {}",
+            e
+        ))),
+    }
+}
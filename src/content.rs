@@ -0,0 +1,522 @@
+use linkify::{LinkFinder, LinkKind};
+use pulldown_cmark::{CowStr, Event, LinkType, Options, Parser, Tag, TagEnd, TextMergeStream};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use uuid::Uuid;
+
+// Text search configurations posts and comments can be indexed with
+pub const SEARCH_LANGUAGES: [&str; 22] = [
+    "simple",
+    "arabic",
+    "danish",
+    "dutch",
+    "english",
+    "finnish",
+    "french",
+    "german",
+    "hungarian",
+    "indonesian",
+    "irish",
+    "italian",
+    "lithuanian",
+    "nepali",
+    "norwegian",
+    "portuguese",
+    "romanian",
+    "russian",
+    "spanish",
+    "swedish",
+    "tamil",
+    "turkish",
+];
+
+pub const DEFAULT_SEARCH_LANGUAGE: &str = "english";
+
+// Longest hashtag we index, in characters
+const MAX_HASHTAG_LENGTH: usize = 100;
+
+// Tags kept by the HTML sanitizer; everything else is stripped down to its text
+const ALLOWED_HTML_TAGS: [&str; 12] = [
+    "p",
+    "br",
+    "em",
+    "strong",
+    "del",
+    "code",
+    "pre",
+    "blockquote",
+    "ul",
+    "ol",
+    "li",
+    "a",
+];
+
+// A link, hashtag or mention found in post or comment content. Offsets are given
+// both in bytes (for Rust/UTF-8 clients) and UTF-16 code units (for JS clients).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContentEntity {
+    #[serde(flatten)]
+    pub kind: EntityKind,
+    pub start: usize,
+    pub end: usize,
+    pub utf16_start: usize,
+    pub utf16_end: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntityKind {
+    Url { url: String },
+    Hashtag { tag: String },
+    Mention { user_id: Uuid, username: String },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SpanKind {
+    Url,
+    Hashtag,
+    Mention,
+}
+
+pub fn is_search_language(language: &str) -> bool {
+    SEARCH_LANGUAGES.contains(&language)
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || is_combining_mark(c)
+}
+
+pub fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Case-folds and NFKC-normalizes a tag so `#Café`, `#CAFÉ` and `#cafe\u{301}` all match
+pub fn normalize_hashtag(tag: &str) -> String {
+    tag.trim_start_matches('#')
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// Byte ranges of `#tag` occurrences in `text`, including the leading `#`.
+// A tag must start the text or follow a non-tag character, and cannot be all digits.
+fn find_hashtags(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut prev: Option<char> = None;
+
+    for (start, c) in text.char_indices() {
+        let after_boundary = !prev.is_some_and(is_tag_char);
+        prev = Some(c);
+        if c != '#' || !after_boundary {
+            continue;
+        }
+
+        let body = &text[start + 1..];
+        let len = body
+            .char_indices()
+            .find(|(_, c)| !is_tag_char(*c))
+            .map_or(body.len(), |(i, _)| i);
+        let tag = &body[..len];
+
+        if tag.is_empty()
+            || tag.chars().all(|c| c.is_ascii_digit())
+            || tag.chars().count() > MAX_HASHTAG_LENGTH
+        {
+            continue;
+        }
+        spans.push((start, start + 1 + len));
+    }
+
+    spans
+}
+
+// Byte ranges of `@username` occurrences in `text`, including the leading `@`.
+// The `@` must start the text or follow a character that cannot appear in a
+// username or email address, so `me@example.com` is not a mention.
+fn find_mentions(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut prev: Option<char> = None;
+
+    for (start, c) in text.char_indices() {
+        let after_boundary = !prev.is_some_and(|p| p.is_alphanumeric() || p == '_' || p == '@');
+        prev = Some(c);
+        if c != '@' || !after_boundary {
+            continue;
+        }
+
+        let body = &text[start + 1..];
+        let len = body
+            .char_indices()
+            .find(|(_, c)| !is_username_char(*c))
+            .map_or(body.len(), |(i, _)| i);
+        if len > 0 {
+            spans.push((start, start + 1 + len));
+        }
+    }
+
+    spans
+}
+
+// All links, hashtags and mention candidates in `text`, ordered by position.
+// Overlaps are resolved in favour of the earlier span, so `#fragment`s inside
+// URLs are not hashtags.
+fn scan(text: &str) -> Vec<(SpanKind, usize, usize)> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+
+    let mut spans: Vec<(SpanKind, usize, usize)> = finder
+        .links(text)
+        .map(|link| (SpanKind::Url, link.start(), link.end()))
+        .chain(
+            find_hashtags(text)
+                .into_iter()
+                .map(|(start, end)| (SpanKind::Hashtag, start, end)),
+        )
+        .chain(
+            find_mentions(text)
+                .into_iter()
+                .map(|(start, end)| (SpanKind::Mention, start, end)),
+        )
+        .collect();
+    spans.sort_by_key(|&(_, start, _)| start);
+
+    let mut last_end = 0;
+    spans.retain(|&(_, start, end)| {
+        let keep = start >= last_end;
+        if keep {
+            last_end = end;
+        }
+        keep
+    });
+    spans
+}
+
+// Normalized, de-duplicated hashtags in `text`, in order of first appearance
+pub fn extract_hashtags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for (kind, start, end) in scan(text) {
+        if kind != SpanKind::Hashtag {
+            continue;
+        }
+        let tag = normalize_hashtag(&text[start..end]);
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+// Byte ranges of `@username` mentions in `text`, including the leading `@`
+pub fn mention_spans(text: &str) -> Vec<(usize, usize)> {
+    scan(text)
+        .into_iter()
+        .filter(|&(kind, _, _)| kind == SpanKind::Mention)
+        .map(|(_, start, end)| (start, end))
+        .collect()
+}
+
+// Entities in `text`. `mentions` maps lowercased usernames to user ids; mentions
+// of usernames missing from it are left as plain text.
+pub fn extract_entities(text: &str, mentions: &HashMap<String, Uuid>) -> Vec<ContentEntity> {
+    // Spans are ordered and do not overlap, so UTF-16 offsets are counted in a
+    // single pass over the text
+    let mut byte_pos = 0;
+    let mut utf16_pos = 0;
+    let mut utf16_offset = |offset: usize| {
+        utf16_pos += text[byte_pos..offset].encode_utf16().count();
+        byte_pos = offset;
+        utf16_pos
+    };
+
+    scan(text)
+        .into_iter()
+        .filter_map(|(kind, start, end)| {
+            let raw = &text[start..end];
+            let kind = match kind {
+                SpanKind::Url => EntityKind::Url {
+                    url: raw.to_string(),
+                },
+                SpanKind::Hashtag => EntityKind::Hashtag {
+                    tag: normalize_hashtag(raw),
+                },
+                SpanKind::Mention => {
+                    let username = &raw[1..];
+                    EntityKind::Mention {
+                        user_id: *mentions.get(&username.to_lowercase())?,
+                        username: username.to_string(),
+                    }
+                }
+            };
+            let utf16_start = utf16_offset(start);
+            let utf16_end = utf16_offset(end);
+            Some(ContentEntity {
+                kind,
+                start,
+                end,
+                utf16_start,
+                utf16_end,
+            })
+        })
+        .collect()
+}
+
+fn entity_href(kind: &EntityKind) -> String {
+    match kind {
+        EntityKind::Url { url } => url.clone(),
+        EntityKind::Hashtag { tag } => format!("/hashtags/{}", tag),
+        EntityKind::Mention { username, .. } => format!("/users/username/{}", username),
+    }
+}
+
+// Splits a run of plain text into text and link events for its entities
+fn push_linked_text(
+    events: &mut Vec<Event<'static>>,
+    text: &str,
+    mentions: &HashMap<String, Uuid>,
+) {
+    let mut last_end = 0;
+    for entity in extract_entities(text, mentions) {
+        if entity.start > last_end {
+            events.push(Event::Text(CowStr::from(
+                text[last_end..entity.start].to_string(),
+            )));
+        }
+        events.push(Event::Start(Tag::Link {
+            link_type: LinkType::Autolink,
+            dest_url: CowStr::from(entity_href(&entity.kind)),
+            title: CowStr::Borrowed(""),
+            id: CowStr::Borrowed(""),
+        }));
+        events.push(Event::Text(CowStr::from(
+            text[entity.start..entity.end].to_string(),
+        )));
+        events.push(Event::End(TagEnd::Link));
+        last_end = entity.end;
+    }
+    if last_end < text.len() {
+        events.push(Event::Text(CowStr::from(text[last_end..].to_string())));
+    }
+}
+
+// Renders the Markdown subset we support (emphasis, strikethrough, code, lists,
+// quotes and links) to sanitized HTML, linking URLs, hashtags and resolved mentions.
+// Raw HTML in the source is escaped rather than passed through.
+pub fn render_html(text: &str, mentions: &HashMap<String, Uuid>) -> String {
+    let mut events: Vec<Event<'static>> = Vec::new();
+    let mut in_code_block = false;
+    let mut link_depth = 0;
+
+    for event in TextMergeStream::new(Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH)) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                in_code_block = true;
+                events.push(event.into_static());
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                events.push(event.into_static());
+            }
+            Event::Start(Tag::Link { .. }) => {
+                link_depth += 1;
+                events.push(event.into_static());
+            }
+            Event::End(TagEnd::Link) => {
+                link_depth -= 1;
+                events.push(event.into_static());
+            }
+            Event::Text(text) if !in_code_block && link_depth == 0 => {
+                push_linked_text(&mut events, &text, mentions);
+            }
+            Event::Html(html) | Event::InlineHtml(html) => {
+                events.push(Event::Text(CowStr::from(html.to_string())));
+            }
+            event => events.push(event.into_static()),
+        }
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+
+    ammonia::Builder::default()
+        .tags(HashSet::from(ALLOWED_HTML_TAGS))
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("nofollow noopener noreferrer"))
+        .clean(&html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_hashtag_folds_case_and_composition() {
+        assert_eq!(normalize_hashtag("#Café"), "café");
+        assert_eq!(normalize_hashtag("#CAFÉ"), "café");
+        assert_eq!(normalize_hashtag("#cafe\u{301}"), "café");
+        assert_eq!(normalize_hashtag("Rust"), "rust");
+        // NFKC folds compatibility forms such as fullwidth letters
+        assert_eq!(normalize_hashtag("#Ｒｕｓｔ"), "rust");
+    }
+
+    #[test]
+    fn extract_hashtags_dedupes_in_order_of_appearance() {
+        assert_eq!(
+            extract_hashtags("#Rust and #web, then #RUST again #Web"),
+            vec!["rust", "web"]
+        );
+    }
+
+    #[test]
+    fn extract_hashtags_needs_a_boundary_before_the_hash() {
+        assert_eq!(
+            extract_hashtags("#start mid#dle (#paren)"),
+            vec!["start", "paren"]
+        );
+        assert_eq!(extract_hashtags("##double"), vec!["double"]);
+    }
+
+    #[test]
+    fn extract_hashtags_skips_numbers_and_empty_tags() {
+        assert_eq!(
+            extract_hashtags("issue #123 and # alone"),
+            Vec::<String>::new()
+        );
+        assert_eq!(extract_hashtags("#2024goals"), vec!["2024goals"]);
+    }
+
+    #[test]
+    fn extract_hashtags_keeps_unicode_tags() {
+        assert_eq!(
+            extract_hashtags("#日本語 #naïve #cafe\u{301}"),
+            vec!["日本語", "naïve", "café"]
+        );
+    }
+
+    #[test]
+    fn extract_hashtags_ignores_url_fragments() {
+        assert_eq!(
+            extract_hashtags("see https://example.com/page#section and #real"),
+            vec!["real"]
+        );
+    }
+
+    #[test]
+    fn extract_hashtags_limits_tag_length() {
+        let longest = format!("#{}", "a".repeat(MAX_HASHTAG_LENGTH));
+        let too_long = format!("#{}", "b".repeat(MAX_HASHTAG_LENGTH + 1));
+        assert_eq!(
+            extract_hashtags(&format!("{} {}", longest, too_long)),
+            vec!["a".repeat(MAX_HASHTAG_LENGTH)]
+        );
+    }
+
+    fn users() -> HashMap<String, Uuid> {
+        HashMap::from([("alice".to_string(), Uuid::from_u128(1))])
+    }
+
+    #[test]
+    fn extract_entities_finds_links_hashtags_and_known_mentions() {
+        let text = "Hi @Alice and @bob, see https://example.com/a#b #Rust";
+        let entities = extract_entities(text, &users());
+        assert_eq!(entities.len(), 3);
+
+        assert!(matches!(
+            &entities[0].kind,
+            EntityKind::Mention { user_id, username }
+                if *user_id == Uuid::from_u128(1) && username == "Alice"
+        ));
+        assert_eq!(&text[entities[0].start..entities[0].end], "@Alice");
+        assert!(matches!(
+            &entities[1].kind,
+            EntityKind::Url { url } if url == "https://example.com/a#b"
+        ));
+        assert!(matches!(&entities[2].kind, EntityKind::Hashtag { tag } if tag == "rust"));
+        assert_eq!(&text[entities[2].start..entities[2].end], "#Rust");
+    }
+
+    #[test]
+    fn extract_entities_skips_email_addresses() {
+        assert!(extract_entities("mail alice@example.com", &users()).is_empty());
+        assert_eq!(
+            mention_spans("mail alice@example.com or @alice"),
+            vec![(26, 32)]
+        );
+    }
+
+    #[test]
+    fn extract_entities_reports_utf16_offsets() {
+        // The emoji is 4 bytes but 2 UTF-16 code units, and `é` is 2 bytes but 1 unit
+        let text = "😀 café #tag @alice https://example.com/😀 #end";
+        let entities = extract_entities(text, &users());
+        assert_eq!(entities.len(), 4);
+        for entity in &entities {
+            assert_eq!(
+                entity.utf16_start,
+                text[..entity.start].encode_utf16().count()
+            );
+            assert_eq!(entity.utf16_end, text[..entity.end].encode_utf16().count());
+        }
+        assert_eq!((entities[0].start, entities[0].utf16_start), (11, 8));
+        assert_eq!((entities[0].end, entities[0].utf16_end), (15, 12));
+    }
+
+    #[test]
+    fn extract_entities_offsets_survive_unresolved_mentions() {
+        let text = "@bob 😀 @alice";
+        let entities = extract_entities(text, &users());
+        assert_eq!(entities.len(), 1);
+        assert_eq!((entities[0].utf16_start, entities[0].utf16_end), (8, 14));
+    }
+
+    #[test]
+    fn render_html_links_entities_and_keeps_markdown() {
+        let html = render_html("**bold** #tag @alice @bob `#code`", &users());
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(
+            html.contains(r#"<a href="/hashtags/tag" rel="nofollow noopener noreferrer">#tag</a>"#)
+        );
+        assert!(html.contains(
+            r#"<a href="/users/username/alice" rel="nofollow noopener noreferrer">@alice</a>"#
+        ));
+        // Unknown users and code spans stay plain text
+        assert!(html.contains(" @bob "));
+        assert!(html.contains("<code>#code</code>"));
+    }
+
+    #[test]
+    fn render_html_escapes_script_tags() {
+        let html = render_html("<script>alert(1)</script>", &users());
+        assert!(!html.contains("<script"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    }
+
+    #[test]
+    fn render_html_escapes_inline_html_with_event_handlers() {
+        let html = render_html("hi <img src=x onerror=alert(1)> there", &users());
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;img src=x onerror=alert(1)&gt;"));
+    }
+
+    #[test]
+    fn render_html_drops_unsafe_link_schemes() {
+        let html = render_html("[click](javascript:alert(1))", &users());
+        assert!(!html.contains("javascript:"));
+        assert!(html.contains(">click</a>"));
+    }
+
+    #[test]
+    fn render_html_does_not_let_urls_or_titles_break_out_of_attributes() {
+        let html = render_html(r#"https://example.com/"onmouseover="alert(1)"#, &users());
+        assert!(
+            html.contains(r#"<a href="https://example.com/" rel="nofollow noopener noreferrer">"#)
+        );
+        assert!(!html.contains(r#"" onmouseover"#));
+
+        let html = render_html(r#"[x](https://example.com "t\" onclick=\"a")"#, &users());
+        assert!(html.contains(r#"title="t&quot; onclick=&quot;a""#));
+        assert!(!html.contains(r#" onclick=""#));
+    }
+}
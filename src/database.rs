@@ -1,295 +1,5974 @@
+use crate::accounts;
+use crate::content::{
+    DEFAULT_SEARCH_LANGUAGE, extract_entities, extract_hashtags, mention_spans, normalize_hashtag,
+    render_html,
+};
+use crate::imports::{ImportItem, ImportedPost};
 use crate::models::*;
 use crate::schema::*;
+use chrono::{DateTime, Utc};
+use diesel::dsl::{AsExprOf, case_when, count, count_star, exists, max, not, sql};
+use diesel::expression::{SqlLiteral, TypedExpressionType, UncheckedBind};
+use diesel::pg::Pg;
+use diesel::pg::upsert::excluded;
 use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Bool, Float, Float4, Float8, SqlType, Text, Varchar};
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
 pub type DbError = Box<dyn std::error::Error + Send + Sync>;
 // pub type DbResult<T> = Result<T, DbError>;
 
+define_sql_function!(fn lower(x: Varchar) -> Varchar);
+define_sql_function!(fn similarity(x: Varchar, y: Varchar) -> Float4);
+define_sql_function!(fn greatest(x: Float4, y: Float4) -> Float4);
+define_sql_function!(fn ln(x: Float8) -> Float8);
+
+// pg_trgm's similarity operator: true when two strings share enough trigrams
+diesel::infix_operator!(TrigramMatch, " % ", backend: Pg);
+
+// Upper bound on how far a thread is walked in either direction
+const MAX_THREAD_DEPTH: usize = 50;
+
+// Upper bound on the replies returned with a thread
+const MAX_THREAD_REPLIES: usize = 500;
+
+// Most recent distinct actors returned with each notification group
+const NOTIFICATION_GROUP_ACTORS: usize = 3;
+
+// Stream events read from the log per query
+const STREAM_EVENT_BATCH: i64 = 500;
+
 fn interact_error_to_db_error(e: deadpool_diesel::InteractError) -> DbError {
     format!("{}", e).into()
 }
 
-pub struct Database {
-    pub pool: deadpool_diesel::postgres::Pool,
-}
+// Erases a user's personal data and returns the storage keys of their files, which
+// the caller deletes once this commits. Posts that others replied to, quoted or
+// commented on are kept as empty, public tombstones so their threads stay whole;
+// everything else the user wrote, liked or uploaded goes, and counts on what
+// remains are adjusted. The row itself is kept, anonymized, for the tombstones.
+fn erase_account(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    username: String,
+    now: DateTime<Utc>,
+) -> QueryResult<Vec<String>> {
+    // Counts are adjusted with one statement per kind rather than one per row, since
+    // an account can have thousands of follows and likes
+    diesel::update(
+        users::table.filter(
+            users::id.eq_any(
+                follows::table
+                    .filter(follows::follower_id.eq(user_id))
+                    .select(follows::following_id),
+            ),
+        ),
+    )
+    .set(users::followers_count.eq(users::followers_count - 1))
+    .execute(conn)?;
+    diesel::update(
+        users::table.filter(
+            users::id.eq_any(
+                follows::table
+                    .filter(follows::following_id.eq(user_id))
+                    .select(follows::follower_id),
+            ),
+        ),
+    )
+    .set(users::following_count.eq(users::following_count - 1))
+    .execute(conn)?;
+    diesel::delete(
+        follows::table.filter(
+            follows::follower_id
+                .eq(user_id)
+                .or(follows::following_id.eq(user_id)),
+        ),
+    )
+    .execute(conn)?;
+
+    diesel::sql_query(
+        "UPDATE posts SET likes_count = likes_count - removed.likes, \
+             shares_count = shares_count - removed.shares \
+         FROM (SELECT post_id, \
+                 COUNT(*) FILTER (WHERE interaction_type = 'like')::INT AS likes, \
+                 COUNT(*) FILTER (WHERE interaction_type = 'share')::INT AS shares \
+             FROM interactions WHERE user_id = $1 GROUP BY post_id) AS removed \
+         WHERE posts.id = removed.post_id",
+    )
+    .bind::<diesel::sql_types::Uuid, _>(user_id)
+    .execute(conn)?;
+    diesel::delete(interactions::table.filter(interactions::user_id.eq(user_id))).execute(conn)?;
+
+    diesel::sql_query(
+        "UPDATE hashtags SET usage_count = usage_count - removed.uses \
+         FROM (SELECT hashtag_id, COUNT(*)::INT AS uses FROM comment_hashtags \
+             JOIN comments ON comments.id = comment_hashtags.comment_id \
+             WHERE comments.user_id = $1 GROUP BY hashtag_id) AS removed \
+         WHERE hashtags.id = removed.hashtag_id",
+    )
+    .bind::<diesel::sql_types::Uuid, _>(user_id)
+    .execute(conn)?;
+    diesel::delete(comments::table.filter(comments::user_id.eq(user_id))).execute(conn)?;
+
+    let post_ids: Vec<Uuid> = posts::table
+        .filter(posts::user_id.eq(user_id))
+        .select(posts::id)
+        .load(conn)?;
+    let mut kept: std::collections::HashSet<Uuid> = posts::table
+        .filter(posts::user_id.ne(user_id))
+        .filter(posts::reply_to_post_id.eq_any(&post_ids))
+        .select(posts::reply_to_post_id.assume_not_null())
+        .load::<Uuid>(conn)?
+        .into_iter()
+        .collect();
+    kept.extend(
+        posts::table
+            .filter(posts::user_id.ne(user_id))
+            .filter(posts::quoted_post_id.eq_any(&post_ids))
+            .select(posts::quoted_post_id.assume_not_null())
+            .load::<Uuid>(conn)?,
+    );
+    // The user's own comments are gone by now
+    kept.extend(
+        comments::table
+            .filter(comments::post_id.eq_any(&post_ids))
+            .select(comments::post_id)
+            .load::<Uuid>(conn)?,
+    );
+
+    diesel::sql_query(
+        "UPDATE hashtags SET usage_count = usage_count - removed.uses \
+         FROM (SELECT hashtag_id, COUNT(*)::INT AS uses FROM post_hashtags \
+             WHERE post_id = ANY($1) GROUP BY hashtag_id) AS removed \
+         WHERE hashtags.id = removed.hashtag_id",
+    )
+    .bind::<Array<diesel::sql_types::Uuid>, _>(&post_ids)
+    .execute(conn)?;
+
+    let removed: Vec<Uuid> = post_ids
+        .iter()
+        .copied()
+        .filter(|id| !kept.contains(id))
+        .collect();
+    diesel::sql_query(
+        "UPDATE posts SET replies_count = replies_count - removed.replies, \
+             quotes_count = quotes_count - removed.quotes \
+         FROM (SELECT target_id, \
+                 COUNT(*) FILTER (WHERE is_reply)::INT AS replies, \
+                 COUNT(*) FILTER (WHERE NOT is_reply)::INT AS quotes \
+             FROM (SELECT reply_to_post_id AS target_id, TRUE AS is_reply FROM posts \
+                     WHERE id = ANY($1) AND reply_to_post_id IS NOT NULL \
+                 UNION ALL \
+                 SELECT quoted_post_id, FALSE FROM posts \
+                     WHERE id = ANY($1) AND quoted_post_id IS NOT NULL) AS targets \
+             GROUP BY target_id) AS removed \
+         WHERE posts.id = removed.target_id",
+    )
+    .bind::<Array<diesel::sql_types::Uuid>, _>(&removed)
+    .execute(conn)?;
+    diesel::delete(posts::table.filter(posts::id.eq_any(&removed))).execute(conn)?;
+
+    let kept: Vec<Uuid> = kept.into_iter().collect();
+    diesel::delete(post_hashtags::table.filter(post_hashtags::post_id.eq_any(&kept)))
+        .execute(conn)?;
+    diesel::delete(mentions::table.filter(mentions::post_id.eq_any(&kept))).execute(conn)?;
+    diesel::update(posts::table.filter(posts::id.eq_any(&kept)))
+        .set((
+            posts::content.eq(""),
+            posts::images.eq(None::<Vec<String>>),
+            posts::entities.eq(serde_json::json!([])),
+            posts::content_html.eq(None::<String>),
+            posts::visibility.eq(PostVisibility::Public),
+            posts::deleted_at.eq(now),
+            posts::pinned_at.eq(None::<DateTime<Utc>>),
+        ))
+        .execute(conn)?;
+
+    diesel::delete(mentions::table.filter(mentions::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(
+        notifications::table.filter(
+            notifications::user_id
+                .eq(user_id)
+                .or(notifications::actor_id.eq(user_id)),
+        ),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        notification_preferences::table.filter(notification_preferences::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        follow_requests::table.filter(
+            follow_requests::requester_id
+                .eq(user_id)
+                .or(follow_requests::target_id.eq(user_id)),
+        ),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        blocks::table.filter(
+            blocks::blocker_id
+                .eq(user_id)
+                .or(blocks::blocked_id.eq(user_id)),
+        ),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        mutes::table.filter(mutes::muter_id.eq(user_id).or(mutes::muted_id.eq(user_id))),
+    )
+    .execute(conn)?;
+    diesel::delete(webhooks::table.filter(webhooks::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(bookmarks::table.filter(bookmarks::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(bookmark_collections::table.filter(bookmark_collections::user_id.eq(user_id)))
+        .execute(conn)?;
+    diesel::delete(email_change_requests::table.filter(email_change_requests::user_id.eq(user_id)))
+        .execute(conn)?;
+    diesel::delete(
+        password_setup_requests::table.filter(password_setup_requests::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        account_deletion_confirmations::table
+            .filter(account_deletion_confirmations::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(stream_events::table.filter(stream_events::user_id.eq(user_id)))
+        .execute(conn)?;
+
+    let mut keys: Vec<String> = media_variants::table
+        .inner_join(media::table)
+        .filter(media::user_id.eq(user_id))
+        .select(media_variants::storage_key)
+        .load(conn)?;
+    keys.extend(
+        diesel::delete(media::table.filter(media::user_id.eq(user_id)))
+            .returning(media::storage_key)
+            .get_results::<String>(conn)?,
+    );
+    keys.extend(
+        diesel::delete(data_exports::table.filter(data_exports::user_id.eq(user_id)))
+            .returning(data_exports::storage_key)
+            .get_results::<Option<String>>(conn)?
+            .into_iter()
+            .flatten(),
+    );
+    diesel::delete(imported_posts::table.filter(imported_posts::user_id.eq(user_id)))
+        .execute(conn)?;
+    keys.extend(
+        diesel::delete(imports::table.filter(imports::user_id.eq(user_id)))
+            .returning(imports::storage_key)
+            .get_results::<Option<String>>(conn)?
+            .into_iter()
+            .flatten(),
+    );
+
+    // Keeps the handle from being taken over straight away
+    diesel::insert_into(username_history::table)
+        .values((
+            username_history::user_id.eq(user_id),
+            username_history::username.eq(username),
+            username_history::created_at.eq(now),
+        ))
+        .execute(conn)?;
+    diesel::update(users::table.find(user_id))
+        .set((
+            users::name.eq("Deleted user"),
+            users::email.eq(format!("{}@deleted.invalid", user_id.simple())),
+            users::username.eq(accounts::deleted_username(user_id)),
+            users::image_url.eq(Some(crate::media::identicon_url(user_id))),
+            users::banner_url.eq(None::<String>),
+            users::bio.eq(None::<String>),
+            users::location.eq(None::<String>),
+            users::website.eq(None::<String>),
+            users::pronouns.eq(None::<String>),
+            users::profile_fields.eq(serde_json::json!([])),
+            users::password_hash.eq(None::<String>),
+            users::is_private.eq(false),
+            users::is_admin.eq(false),
+            users::storage_quota_bytes.eq(None::<i64>),
+            users::followers_count.eq(0),
+            users::following_count.eq(0),
+            users::deleted_at.eq(now),
+        ))
+        .execute(conn)?;
+
+    Ok(keys)
+}
+
+// Whether `username`, ignoring case, belongs to a user other than `user_id` or was
+// given up by one since `reserved_since`
+fn username_taken(
+    conn: &mut PgConnection,
+    username: &str,
+    user_id: Option<Uuid>,
+    reserved_since: DateTime<Utc>,
+) -> QueryResult<bool> {
+    let username = username.to_lowercase();
+    let owners: Vec<Uuid> = users::table
+        .filter(lower(users::username).eq(&username))
+        .select(users::id)
+        .load(conn)?;
+    let holders: Vec<Uuid> = username_history::table
+        .filter(lower(username_history::username).eq(&username))
+        .filter(username_history::created_at.gt(reserved_since))
+        .select(username_history::user_id)
+        .load(conn)?;
+    Ok(owners
+        .into_iter()
+        .chain(holders)
+        .any(|owner| Some(owner) != user_id))
+}
+
+// Returned when an action crosses a block between two users
+#[derive(Debug)]
+pub struct BlockedError;
+
+impl std::fmt::Display for BlockedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Action not allowed between blocked users")
+    }
+}
+
+impl std::error::Error for BlockedError {}
+
+// Returned when replying to, quoting or interacting with a post that does not exist
+// or that the user cannot see
+#[derive(Debug)]
+pub struct PostNotFoundError;
+
+impl std::fmt::Display for PostNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Post not found")
+    }
+}
+
+impl std::error::Error for PostNotFoundError {}
+
+// Returned when attaching media that does not exist or belongs to someone else
+#[derive(Debug)]
+pub struct InvalidMediaError;
+
+impl std::fmt::Display for InvalidMediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Media not found, not uploaded by the author or not a valid image"
+        )
+    }
+}
+
+impl std::error::Error for InvalidMediaError {}
+
+// Returned when media without alt text is attached, or alt text is cleared from
+// attached media, while the instance requires it
+#[derive(Debug)]
+pub struct MissingAltTextError;
+
+impl std::fmt::Display for MissingAltTextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Alt text is required for attached media")
+    }
+}
+
+impl std::error::Error for MissingAltTextError {}
+
+// Returned when an upload would take a user over their storage quota
+#[derive(Debug)]
+pub struct QuotaExceededError;
+
+impl std::fmt::Display for QuotaExceededError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Storage quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceededError {}
+
+// Returned when a username belongs to another user or is reserved for one who gave
+// it up
+#[derive(Debug)]
+pub struct UsernameTakenError;
+
+impl std::fmt::Display for UsernameTakenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Username is not available")
+    }
+}
+
+impl std::error::Error for UsernameTakenError {}
+
+// Returned when a user changed their username too recently to change it again
+#[derive(Debug)]
+pub struct RenameCooldownError {
+    pub available_at: DateTime<Utc>,
+}
+
+impl std::fmt::Display for RenameCooldownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Username can be changed again after {}",
+            self.available_at.to_rfc3339()
+        )
+    }
+}
+
+impl std::error::Error for RenameCooldownError {}
+
+// Returned when an email address already belongs to another account
+#[derive(Debug)]
+pub struct EmailTakenError;
+
+impl std::fmt::Display for EmailTakenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Email already in use")
+    }
+}
+
+impl std::error::Error for EmailTakenError {}
+
+// A bookmark collection that does not exist or belongs to someone else
+#[derive(Debug)]
+pub struct CollectionNotFoundError;
+
+impl std::fmt::Display for CollectionNotFoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Collection not found")
+    }
+}
+
+impl std::error::Error for CollectionNotFoundError {}
+
+// The user already has the most pinned posts allowed
+#[derive(Debug)]
+pub struct PinLimitError {
+    pub max: i64,
+}
+
+impl std::fmt::Display for PinLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "At most {} posts can be pinned", self.max)
+    }
+}
+
+impl std::error::Error for PinLimitError {}
+
+// Whether either user has blocked the other
+fn is_blocked_between(conn: &mut PgConnection, a: Uuid, b: Uuid) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::exists(
+        blocks::table.filter(
+            (blocks::blocker_id.eq(a).and(blocks::blocked_id.eq(b)))
+                .or(blocks::blocker_id.eq(b).and(blocks::blocked_id.eq(a))),
+        ),
+    ))
+    .get_result(conn)
+}
+
+// Fails with BlockedError when the post's author and the user have blocked each other
+fn ensure_not_blocked_by_author(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), DbError> {
+    let author_id: Option<Uuid> = posts::table
+        .filter(posts::id.eq(post_id))
+        .select(posts::user_id)
+        .first(conn)
+        .optional()?;
+    if let Some(author_id) = author_id
+        && is_blocked_between(conn, author_id, user_id)?
+    {
+        return Err(Box::new(BlockedError));
+    }
+    Ok(())
+}
+
+// Attaches the author's uploaded media to a post, or to one of its comments, in the
+// order given
+fn attach_media(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    post_id: Uuid,
+    comment_id: Option<Uuid>,
+    media_ids: &[Uuid],
+) -> Result<(), DbError> {
+    if media_ids.is_empty() {
+        return Ok(());
+    }
+    // Locking the uploads keeps the media collector from taking them meanwhile
+    let owned: Vec<Uuid> = media::table
+        .filter(media::id.eq_any(media_ids))
+        .filter(media::user_id.eq(user_id))
+        .filter(media::status.eq_any([MediaStatus::Processing, MediaStatus::Ready]))
+        .select(media::id)
+        .for_update()
+        .load(conn)?;
+    if owned.len() != media_ids.len() {
+        return Err(Box::new(InvalidMediaError));
+    }
+    if crate::media::require_alt_text() {
+        let described: i64 = media::table
+            .filter(media::id.eq_any(media_ids))
+            .filter(media::alt_text.is_not_null())
+            .count()
+            .get_result(conn)?;
+        if described != media_ids.len() as i64 {
+            return Err(Box::new(MissingAltTextError));
+        }
+    }
+
+    diesel::insert_into(media_attachments::table)
+        .values(
+            media_ids
+                .iter()
+                .enumerate()
+                .map(|(position, &media_id)| NewMediaAttachment {
+                    post_id,
+                    comment_id,
+                    media_id,
+                    position: position as i32,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    Ok(())
+}
+
+// Writes `event` to the outbox. Called inside the transaction making the change, so
+// the event is published if and only if the change commits.
+fn record_event(conn: &mut PgConnection, event: DomainEvent) -> QueryResult<()> {
+    let payload = serde_json::to_value(&event)
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    diesel::insert_into(outbox_events::table)
+        .values(&NewOutboxEvent {
+            event_type: event.event_type(),
+            payload,
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+// Creates the follow and updates both users' counters
+fn insert_follow(
+    conn: &mut PgConnection,
+    follower_id: Uuid,
+    following_id: Uuid,
+) -> QueryResult<Follow> {
+    // Create follow relationship
+    let follow = diesel::insert_into(follows::table)
+        .values(NewFollow {
+            follower_id,
+            following_id,
+        })
+        .returning(Follow::as_returning())
+        .get_result(conn)?;
+
+    // Update follower's following count
+    diesel::update(users::table.filter(users::id.eq(follower_id)))
+        .set(users::following_count.eq(users::following_count + 1))
+        .execute(conn)?;
+
+    // Update following user's followers count
+    diesel::update(users::table.filter(users::id.eq(following_id)))
+        .set(users::followers_count.eq(users::followers_count + 1))
+        .execute(conn)?;
+
+    record_event(
+        conn,
+        DomainEvent::UserFollowed {
+            follow: follow.clone(),
+        },
+    )?;
+    Ok(follow)
+}
+
+// Removes the follow (if any) and updates both users' counters
+fn remove_follow(
+    conn: &mut PgConnection,
+    follower_id: Uuid,
+    following_id: Uuid,
+) -> QueryResult<bool> {
+    let deleted = diesel::delete(
+        follows::table
+            .filter(follows::follower_id.eq(follower_id))
+            .filter(follows::following_id.eq(following_id)),
+    )
+    .execute(conn)?;
+
+    if deleted > 0 {
+        diesel::update(users::table.filter(users::id.eq(follower_id)))
+            .set(users::following_count.eq(users::following_count - 1))
+            .execute(conn)?;
+        diesel::update(users::table.filter(users::id.eq(following_id)))
+            .set(users::followers_count.eq(users::followers_count - 1))
+            .execute(conn)?;
+    }
+
+    Ok(deleted > 0)
+}
+
+// Creates any missing hashtags and returns the ids of all of them
+fn upsert_hashtags(conn: &mut PgConnection, tags: &[String]) -> QueryResult<Vec<Uuid>> {
+    if tags.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    diesel::insert_into(hashtags::table)
+        .values(
+            tags.iter()
+                .map(|tag| hashtags::name.eq(tag))
+                .collect::<Vec<_>>(),
+        )
+        .on_conflict(hashtags::name)
+        .do_nothing()
+        .execute(conn)?;
+
+    hashtags::table
+        .filter(hashtags::name.eq_any(tags))
+        .select(hashtags::id)
+        .load(conn)
+}
+
+fn adjust_hashtag_usage(conn: &mut PgConnection, ids: &[Uuid], delta: i32) -> QueryResult<()> {
+    if !ids.is_empty() {
+        diesel::update(hashtags::table.filter(hashtags::id.eq_any(ids)))
+            .set(hashtags::usage_count.eq(hashtags::usage_count + delta))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+// Replaces the post's hashtag links with the tags found in its content
+fn sync_post_hashtags(conn: &mut PgConnection, post_id: Uuid, content: &str) -> QueryResult<()> {
+    let old_ids: Vec<Uuid> =
+        diesel::delete(post_hashtags::table.filter(post_hashtags::post_id.eq(post_id)))
+            .returning(post_hashtags::hashtag_id)
+            .get_results(conn)?;
+    adjust_hashtag_usage(conn, &old_ids, -1)?;
+
+    let new_ids = upsert_hashtags(conn, &extract_hashtags(content))?;
+    diesel::insert_into(post_hashtags::table)
+        .values(
+            new_ids
+                .iter()
+                .map(|id| {
+                    (
+                        post_hashtags::post_id.eq(post_id),
+                        post_hashtags::hashtag_id.eq(id),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    adjust_hashtag_usage(conn, &new_ids, 1)
+}
+
+// Replaces the comment's hashtag links with the tags found in its content
+fn sync_comment_hashtags(
+    conn: &mut PgConnection,
+    comment_id: Uuid,
+    content: &str,
+) -> QueryResult<()> {
+    let old_ids: Vec<Uuid> =
+        diesel::delete(comment_hashtags::table.filter(comment_hashtags::comment_id.eq(comment_id)))
+            .returning(comment_hashtags::hashtag_id)
+            .get_results(conn)?;
+    adjust_hashtag_usage(conn, &old_ids, -1)?;
+
+    let new_ids = upsert_hashtags(conn, &extract_hashtags(content))?;
+    diesel::insert_into(comment_hashtags::table)
+        .values(
+            new_ids
+                .iter()
+                .map(|id| {
+                    (
+                        comment_hashtags::comment_id.eq(comment_id),
+                        comment_hashtags::hashtag_id.eq(id),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    adjust_hashtag_usage(conn, &new_ids, 1)
+}
+
+type TsQuerySql<ST> = SqlLiteral<
+    ST,
+    UncheckedBind<
+        SqlLiteral<ST, UncheckedBind<SqlLiteral<ST>, AsExprOf<String, Varchar>>>,
+        AsExprOf<String, Text>,
+    >,
+>;
+
+// `<before>websearch_to_tsquery(language, query)<after>` with both values bound.
+// Web search syntax supports quoted phrases, `or` and `-excluded` terms.
+fn with_tsquery<ST>(before: &str, language: &str, query: &str, after: &str) -> TsQuerySql<ST>
+where
+    ST: SqlType + TypedExpressionType,
+{
+    sql::<ST>(&format!("{}websearch_to_tsquery(", before))
+        .bind::<Varchar, _>(language.to_string())
+        .sql("::regconfig, ")
+        .bind::<Text, _>(query.to_string())
+        .sql(&format!("){}", after))
+}
+
+// Highlighted fragments of `table.content`, escaped for HTML first so only our
+// `<mark>`s are markup
+fn headline_sql(table: &str, language: &str, query: &str) -> TsQuerySql<Text> {
+    with_tsquery(
+        &format!(
+            "ts_headline({table}.language::regconfig, \
+             replace(replace(replace({table}.content, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), "
+        ),
+        language,
+        query,
+        ", 'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=30, MinWords=10') AS highlight",
+    )
+}
+
+type PostFilter = Box<dyn BoxableExpression<posts::table, Pg, SqlType = Bool>>;
+type CommentFilter = Box<dyn BoxableExpression<comments::table, Pg, SqlType = Bool>>;
+
+// Maps the lowercased `@username`s in `content` to the users they resolve to.
+// Users on either side of a block with the author are not resolved.
+fn resolve_mentions(
+    conn: &mut PgConnection,
+    author_id: Uuid,
+    content: &str,
+) -> QueryResult<HashMap<String, Uuid>> {
+    let names: Vec<String> = mention_spans(content)
+        .into_iter()
+        .map(|(start, end)| content[start + 1..end].to_lowercase())
+        .collect();
+    if names.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(users::table
+        .filter(lower(users::username).eq_any(&names))
+        .filter(
+            users::id.ne_all(
+                blocks::table
+                    .filter(blocks::blocker_id.eq(author_id))
+                    .select(blocks::blocked_id),
+            ),
+        )
+        .filter(
+            users::id.ne_all(
+                blocks::table
+                    .filter(blocks::blocked_id.eq(author_id))
+                    .select(blocks::blocker_id),
+            ),
+        )
+        .select((users::username, users::id))
+        .load::<(String, Uuid)>(conn)?
+        .into_iter()
+        .map(|(username, id)| (username.to_lowercase(), id))
+        .collect())
+}
+
+// Replaces the mentions stored for a post (or, with `comment_id`, one of its comments)
+// with the resolved `@username`s in `content`
+fn store_mentions(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    comment_id: Option<Uuid>,
+    content: &str,
+    resolved: &HashMap<String, Uuid>,
+) -> QueryResult<Vec<Uuid>> {
+    let previous: Vec<Uuid> = match comment_id {
+        Some(comment_id) => {
+            diesel::delete(mentions::table.filter(mentions::comment_id.eq(comment_id)))
+                .returning(mentions::user_id)
+                .get_results(conn)?
+        }
+        None => diesel::delete(
+            mentions::table
+                .filter(mentions::post_id.eq(post_id))
+                .filter(mentions::comment_id.is_null()),
+        )
+        .returning(mentions::user_id)
+        .get_results(conn)?,
+    };
+
+    let new_mentions: Vec<NewMention> = mention_spans(content)
+        .into_iter()
+        .filter_map(|(start, end)| {
+            resolved
+                .get(&content[start + 1..end].to_lowercase())
+                .map(|&user_id| NewMention {
+                    post_id,
+                    comment_id,
+                    user_id,
+                    start_offset: start as i32,
+                    length: (end - start) as i32,
+                })
+        })
+        .collect();
+    diesel::insert_into(mentions::table)
+        .values(&new_mentions)
+        .execute(conn)?;
+
+    let mut added: Vec<Uuid> = Vec::new();
+    for mention in &new_mentions {
+        if !previous.contains(&mention.user_id) && !added.contains(&mention.user_id) {
+            added.push(mention.user_id);
+        }
+    }
+    Ok(added)
+}
+
+// Notifications sharing a key are grouped: reactions by post, follows by day,
+// and each mention on its own
+fn notification_group_key(
+    kind: NotificationKind,
+    post_id: Option<Uuid>,
+    comment_id: Option<Uuid>,
+) -> String {
+    match kind {
+        NotificationKind::Like | NotificationKind::Share | NotificationKind::Comment => {
+            format!("{}:{}", kind.as_str(), post_id.unwrap_or_default())
+        }
+        NotificationKind::Follow | NotificationKind::FollowRequest => {
+            format!("{}:{}", kind.as_str(), Utc::now().date_naive())
+        }
+        NotificationKind::Mention => {
+            format!("mention:{}", comment_id.or(post_id).unwrap_or_default())
+        }
+    }
+}
+
+// Records a notification for `user_id`, unless they are the actor, have turned
+// this kind off, have muted the actor, or the post is hidden from either of them
+fn notify(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    actor_id: Uuid,
+    kind: NotificationKind,
+    post_id: Option<Uuid>,
+    comment_id: Option<Uuid>,
+) -> QueryResult<()> {
+    if user_id == actor_id {
+        return Ok(());
+    }
+
+    let enabled: Option<bool> = notification_preferences::table
+        .filter(notification_preferences::user_id.eq(user_id))
+        .filter(notification_preferences::kind.eq(kind))
+        .select(notification_preferences::enabled)
+        .first(conn)
+        .optional()?;
+    let muted = diesel::select(diesel::dsl::exists(
+        mutes::table
+            .filter(mutes::muter_id.eq(user_id))
+            .filter(mutes::muted_id.eq(actor_id)),
+    ))
+    .get_result::<bool>(conn)?;
+    if enabled == Some(false) || muted {
+        return Ok(());
+    }
+    if let Some(post_id) = post_id {
+        for viewer_id in [actor_id, user_id] {
+            if find_visible_post(conn, post_id, Some(viewer_id))?.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    diesel::insert_into(notifications::table)
+        .values(NewNotification {
+            user_id,
+            actor_id,
+            kind,
+            post_id,
+            comment_id,
+            group_key: notification_group_key(kind, post_id, comment_id),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+// Group key, latest timestamp, distinct actors, and total and read notifications
+type NotificationGroupRow = (String, Option<DateTime<Utc>>, i64, i64, i64);
+
+// Every notification kind with the user's setting, enabled unless turned off
+fn load_notification_preferences(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> QueryResult<NotificationPreferences> {
+    let mut preferences: NotificationPreferences = NotificationKind::ALL
+        .into_iter()
+        .map(|kind| (kind, true))
+        .collect();
+    let overrides: Vec<NotificationPreference> = notification_preferences::table
+        .filter(notification_preferences::user_id.eq(user_id))
+        .select(NotificationPreference::as_select())
+        .load(conn)?;
+    for preference in overrides {
+        preferences.insert(preference.kind, preference.enabled);
+    }
+    Ok(preferences)
+}
+
+// "alice liked your post", "alice and bob liked your post",
+// "alice and 12 others liked your post"
+fn notification_summary(kind: NotificationKind, actors: &[User], actors_count: i64) -> String {
+    let action = kind.action();
+    match actors {
+        [] => format!("Someone {}", action),
+        [only] if actors_count <= 1 => format!("{} {}", only.username, action),
+        [first, second, ..] if actors_count == 2 => {
+            format!("{} and {} {}", first.username, second.username, action)
+        }
+        [first, ..] => {
+            let others = actors_count - 1;
+            let noun = if others == 1 { "other" } else { "others" };
+            format!("{} and {} {} {}", first.username, others, noun, action)
+        }
+    }
+}
+
+// Notifies newly mentioned users who can see the post the mention is in
+fn notify_mentions(
+    conn: &mut PgConnection,
+    author_id: Uuid,
+    post_id: Uuid,
+    comment_id: Option<Uuid>,
+    mentioned: &[Uuid],
+) -> QueryResult<()> {
+    for &user_id in mentioned {
+        if find_visible_post(conn, post_id, Some(user_id))?.is_some() {
+            notify(
+                conn,
+                user_id,
+                author_id,
+                NotificationKind::Mention,
+                Some(post_id),
+                comment_id,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Output of the content pipeline, stored alongside the raw text
+struct ProcessedContent {
+    entities: serde_json::Value,
+    html: String,
+    mentions: HashMap<String, Uuid>,
+}
+
+fn process_content(
+    conn: &mut PgConnection,
+    author_id: Uuid,
+    content: &str,
+) -> QueryResult<ProcessedContent> {
+    let mentions = resolve_mentions(conn, author_id, content)?;
+    Ok(ProcessedContent {
+        entities: serde_json::json!(extract_entities(content, &mentions)),
+        html: render_html(content, &mentions),
+        mentions,
+    })
+}
+
+// Imported posts mention people on other networks, so their mentions are left
+// unresolved rather than matched to local users who share a name
+fn process_imported_content(content: &str) -> ProcessedContent {
+    let mentions = HashMap::new();
+    ProcessedContent {
+        entities: serde_json::json!(extract_entities(content, &mentions)),
+        html: render_html(content, &mentions),
+        mentions,
+    }
+}
+
+// Inserts a post with its media, hashtags and mentions and updates the counts of
+// the posts it replies to or quotes. Returns the post and the users it mentions.
+fn insert_post(
+    conn: &mut PgConnection,
+    new_post: &NewPost,
+    processed: ProcessedContent,
+) -> Result<(Post, Vec<Uuid>), DbError> {
+    // Create post
+    let post = diesel::insert_into(posts::table)
+        .values((
+            new_post,
+            posts::entities.eq(&processed.entities),
+            posts::content_html.eq(&processed.html),
+        ))
+        .returning(Post::as_returning())
+        .get_result(conn)?;
+
+    // Update parent's replies count
+    if let Some(parent_id) = post.reply_to_post_id {
+        diesel::update(posts::table.filter(posts::id.eq(parent_id)))
+            .set(posts::replies_count.eq(posts::replies_count + 1))
+            .execute(conn)?;
+    }
+
+    // Update quoted post's quotes count
+    if let Some(quoted_id) = post.quoted_post_id {
+        diesel::update(posts::table.filter(posts::id.eq(quoted_id)))
+            .set(posts::quotes_count.eq(posts::quotes_count + 1))
+            .execute(conn)?;
+    }
+
+    attach_media(conn, post.user_id, post.id, None, &new_post.media_ids)?;
+    sync_post_hashtags(conn, post.id, &post.content)?;
+    let mentioned = store_mentions(conn, post.id, None, &post.content, &processed.mentions)?;
+    Ok((post, mentioned))
+}
+
+// Posts the viewer is allowed to see. Anonymous viewers only see public posts
+// from public accounts; posts from private accounts require following the author.
+// Mentioned-only posts are visible to their author and the users they mention.
+// Posts are never shown across a block in either direction.
+fn visible_to(viewer_id: Option<Uuid>) -> PostFilter {
+    let public = posts::visibility.eq(PostVisibility::Public).and(
+        posts::user_id.ne_all(
+            users::table
+                .filter(users::is_private.eq(true))
+                .select(users::id),
+        ),
+    );
+    match viewer_id {
+        None => Box::new(public),
+        Some(viewer_id) => Box::new(
+            posts::user_id
+                .ne_all(
+                    blocks::table
+                        .filter(blocks::blocker_id.eq(viewer_id))
+                        .select(blocks::blocked_id),
+                )
+                .and(
+                    posts::user_id.ne_all(
+                        blocks::table
+                            .filter(blocks::blocked_id.eq(viewer_id))
+                            .select(blocks::blocker_id),
+                    ),
+                )
+                .and(
+                    public
+                        .or(posts::user_id.eq(viewer_id))
+                        .or(posts::visibility
+                            .eq_any([PostVisibility::Public, PostVisibility::Followers])
+                            .and(
+                                posts::user_id.eq_any(
+                                    follows::table
+                                        .filter(follows::follower_id.eq(viewer_id))
+                                        .select(follows::following_id),
+                                ),
+                            ))
+                        .or(posts::visibility.eq(PostVisibility::Mentioned).and(
+                            posts::id.eq_any(
+                                mentions::table
+                                    .filter(mentions::user_id.eq(viewer_id))
+                                    .filter(mentions::comment_id.is_null())
+                                    .select(mentions::post_id),
+                            ),
+                        )),
+                ),
+        ),
+    }
+}
+
+fn find_visible_post(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    viewer_id: Option<Uuid>,
+) -> QueryResult<Option<Post>> {
+    posts::table
+        .filter(posts::id.eq(post_id))
+        .filter(visible_to(viewer_id))
+        .select(Post::as_select())
+        .first(conn)
+        .optional()
+}
+
+// Fails with PostNotFoundError unless the user can see the post they are acting on
+fn ensure_visible_target(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), DbError> {
+    if find_visible_post(conn, post_id, Some(user_id))?.is_none() {
+        return Err(Box::new(PostNotFoundError));
+    }
+    Ok(())
+}
+
+// The posts of a profile page. The first page starts with the user's pinned posts,
+// most recently pinned first and at most `max_pinned` of them, followed by up to
+// `limit` other posts, newest first; so it can hold `limit + max_pinned` posts.
+// Later pages leave out the pinned posts the first page shows, and `offset` counts
+// only the others. Pins over `max_pinned`, left from when the limit was higher, fall
+// back into date order.
+fn load_user_posts(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    viewer_id: Option<Uuid>,
+    limit: i64,
+    offset: i64,
+    max_pinned: i64,
+) -> QueryResult<Vec<Post>> {
+    let pinned: Vec<Post> = posts::table
+        .filter(posts::user_id.eq(user_id))
+        .filter(visible_to(viewer_id))
+        .filter(posts::deleted_at.is_null())
+        .filter(posts::pinned_at.is_not_null())
+        .order(posts::pinned_at.desc())
+        .limit(max_pinned)
+        .select(Post::as_select())
+        .load(conn)?;
+    let pinned_ids: Vec<Uuid> = pinned.iter().map(|post| post.id).collect();
+    let mut posts = match offset {
+        0 => pinned,
+        _ => Vec::new(),
+    };
+    posts.extend(
+        posts::table
+            .filter(posts::user_id.eq(user_id))
+            .filter(visible_to(viewer_id))
+            .filter(posts::deleted_at.is_null())
+            .filter(posts::id.ne_all(pinned_ids))
+            .order(posts::created_at.desc())
+            .limit(limit)
+            .offset(offset)
+            .select(Post::as_select())
+            .load(conn)?,
+    );
+    Ok(posts)
+}
+
+// Pins the post for `Database::pin_post`, inside its transaction
+fn pin_own_post(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    user_id: Uuid,
+    max_pinned: i64,
+) -> Result<Option<Post>, DbError> {
+    // Serializes pins by the same user, so the limit holds
+    users::table
+        .find(user_id)
+        .select(users::id)
+        .for_update()
+        .first::<Uuid>(conn)?;
+    let Some(post) = posts::table
+        .find(post_id)
+        .filter(posts::user_id.eq(user_id))
+        .filter(posts::deleted_at.is_null())
+        .select(Post::as_select())
+        .first(conn)
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    if post.pinned_at.is_some() {
+        return Ok(Some(post));
+    }
+
+    let pinned: i64 = posts::table
+        .filter(posts::user_id.eq(user_id))
+        .filter(posts::pinned_at.is_not_null())
+        .count()
+        .get_result(conn)?;
+    if pinned >= max_pinned {
+        return Err(Box::new(PinLimitError { max: max_pinned }));
+    }
+    let post = diesel::update(posts::table.find(post_id))
+        .set(posts::pinned_at.eq(Utc::now()))
+        .returning(Post::as_returning())
+        .get_result(conn)?;
+    Ok(Some(post))
+}
+
+// Unpins the post for `Database::unpin_post`; None when it is someone else's
+fn unpin_own_post(
+    conn: &mut PgConnection,
+    post_id: Uuid,
+    user_id: Uuid,
+) -> QueryResult<Option<Post>> {
+    diesel::update(
+        posts::table
+            .find(post_id)
+            .filter(posts::user_id.eq(user_id)),
+    )
+    .set(posts::pinned_at.eq(None::<DateTime<Utc>>))
+    .returning(Post::as_returning())
+    .get_result(conn)
+    .optional()
+}
+
+// Attaches the quoted post (if any, and visible to the viewer), the attached media
+// and whether the viewer bookmarked it to each post. Mentions are in the entities.
+fn load_post_views(
+    conn: &mut PgConnection,
+    posts: Vec<Post>,
+    viewer_id: Option<Uuid>,
+) -> QueryResult<Vec<PostView>> {
+    let post_ids: Vec<Uuid> = posts.iter().map(|p| p.id).collect();
+    let attached = media_attachments::table
+        .inner_join(media::table)
+        .filter(media_attachments::post_id.eq_any(&post_ids))
+        .filter(media_attachments::comment_id.is_null())
+        .order(media_attachments::position.asc())
+        .select((media_attachments::post_id, Media::as_select()))
+        .load::<(Uuid, Media)>(conn)?;
+    let mut media_by_post = load_media_views(conn, attached)?;
+
+    let quoted_ids: Vec<Uuid> = posts.iter().filter_map(|p| p.quoted_post_id).collect();
+    let quoted: HashMap<Uuid, Post> = if quoted_ids.is_empty() {
+        HashMap::new()
+    } else {
+        posts::table
+            .filter(posts::id.eq_any(&quoted_ids))
+            .filter(visible_to(viewer_id))
+            .select(Post::as_select())
+            .load(conn)?
+            .into_iter()
+            .map(|p| (p.id, p))
+            .collect()
+    };
+
+    let bookmarked: HashSet<Uuid> = match viewer_id {
+        Some(viewer_id) => bookmarks::table
+            .filter(bookmarks::user_id.eq(viewer_id))
+            .filter(bookmarks::post_id.eq_any(&post_ids))
+            .select(bookmarks::post_id)
+            .load::<Uuid>(conn)?
+            .into_iter()
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    Ok(posts
+        .into_iter()
+        .map(|post| {
+            let quoted_post = post.quoted_post_id.and_then(|id| quoted.get(&id).cloned());
+            let media = media_by_post.remove(&post.id).unwrap_or_default();
+            let bookmarked_by_viewer = bookmarked.contains(&post.id);
+            PostView {
+                post,
+                quoted_post,
+                media,
+                bookmarked_by_viewer,
+            }
+        })
+        .collect())
+}
+
+// Groups attached media by what it is attached to, keeping their order, with the
+// variants of each
+fn load_media_views(
+    conn: &mut PgConnection,
+    attached: Vec<(Uuid, Media)>,
+) -> QueryResult<HashMap<Uuid, Vec<MediaView>>> {
+    let media_ids: Vec<Uuid> = attached.iter().map(|(_, item)| item.id).collect();
+    let mut variants_by_media: HashMap<Uuid, Vec<MediaVariant>> = HashMap::new();
+    for variant in media_variants::table
+        .filter(media_variants::media_id.eq_any(&media_ids))
+        .order((media_variants::media_id, media_variants::width.asc()))
+        .select(MediaVariant::as_select())
+        .load(conn)?
+    {
+        variants_by_media
+            .entry(variant.media_id)
+            .or_default()
+            .push(variant);
+    }
+
+    let mut grouped: HashMap<Uuid, Vec<MediaView>> = HashMap::new();
+    for (owner_id, media) in attached {
+        let variants = variants_by_media
+            .get(&media.id)
+            .cloned()
+            .unwrap_or_default();
+        grouped
+            .entry(owner_id)
+            .or_default()
+            .push(MediaView { media, variants });
+    }
+    Ok(grouped)
+}
+
+// Attaches the media to each comment
+fn load_comment_views(
+    conn: &mut PgConnection,
+    comments: Vec<Comment>,
+) -> QueryResult<Vec<CommentView>> {
+    let comment_ids: Vec<Uuid> = comments.iter().map(|c| c.id).collect();
+    let attached = media_attachments::table
+        .inner_join(media::table)
+        .filter(
+            media_attachments::comment_id
+                .assume_not_null()
+                .eq_any(&comment_ids),
+        )
+        .order(media_attachments::position.asc())
+        .select((
+            media_attachments::comment_id.assume_not_null(),
+            Media::as_select(),
+        ))
+        .load::<(Uuid, Media)>(conn)?;
+    let mut media_by_comment = load_media_views(conn, attached)?;
+
+    Ok(comments
+        .into_iter()
+        .map(|comment| {
+            let media = media_by_comment.remove(&comment.id).unwrap_or_default();
+            CommentView { comment, media }
+        })
+        .collect())
+}
+
+// A page of bookmarks for `Database::get_bookmarks`. Bookmarks of posts the user can
+// no longer see are left out by the query, so pages are only short at the end.
+fn load_bookmarks(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    collection_id: Option<Uuid>,
+    before: Option<BookmarkCursor>,
+    limit: i64,
+) -> QueryResult<Option<(Vec<BookmarkView>, Option<BookmarkCursor>)>> {
+    let visible_posts = posts::table
+        .filter(visible_to(Some(user_id)))
+        .filter(posts::deleted_at.is_null())
+        .select(posts::id)
+        .into_boxed();
+    let mut query = bookmarks::table
+        .filter(bookmarks::user_id.eq(user_id))
+        .filter(bookmarks::post_id.eq_any(visible_posts))
+        .into_boxed();
+    if let Some(collection_id) = collection_id {
+        let owned = diesel::select(exists(
+            bookmark_collections::table
+                .find(collection_id)
+                .filter(bookmark_collections::user_id.eq(user_id)),
+        ))
+        .get_result::<bool>(conn)?;
+        if !owned {
+            return Ok(None);
+        }
+        query = query.filter(bookmarks::collection_id.eq(collection_id));
+    }
+    if let Some(before) = before {
+        query = query.filter(
+            bookmarks::created_at
+                .lt(before.created_at)
+                .or(bookmarks::created_at
+                    .eq(before.created_at)
+                    .and(bookmarks::id.lt(before.id))),
+        );
+    }
+    let mut bookmarks: Vec<Bookmark> = query
+        .order((bookmarks::created_at.desc(), bookmarks::id.desc()))
+        .limit(limit + 1)
+        .select(Bookmark::as_select())
+        .load(conn)?;
+    let has_more = bookmarks.len() as i64 > limit;
+    bookmarks.truncate(limit as usize);
+    let next = bookmarks
+        .last()
+        .filter(|_| has_more)
+        .map(|last| BookmarkCursor {
+            created_at: last.created_at,
+            id: last.id,
+        });
+
+    let post_ids: Vec<Uuid> = bookmarks.iter().map(|b| b.post_id).collect();
+    let posts = posts::table
+        .filter(posts::id.eq_any(&post_ids))
+        .select(Post::as_select())
+        .load(conn)?;
+    let mut views: HashMap<Uuid, PostView> = load_post_views(conn, posts, Some(user_id))?
+        .into_iter()
+        .map(|view| (view.post.id, view))
+        .collect();
+    let bookmarks = bookmarks
+        .into_iter()
+        .filter_map(|bookmark| {
+            let post = views.remove(&bookmark.post_id)?;
+            Some(BookmarkView { bookmark, post })
+        })
+        .collect();
+    Ok(Some((bookmarks, next)))
+}
+
+// Which of the connected `candidates` should receive `event`, with the data each
+// gets. New posts and post counters go to the author and to followers who can see
+// the post and have not muted the author; other events go to the affected user.
+fn route_stream_event(
+    conn: &mut PgConnection,
+    event: &StreamEvent,
+    candidates: &[Uuid],
+) -> QueryResult<Vec<(Uuid, StreamMessage)>> {
+    let message = |data| StreamMessage {
+        id: event.id,
+        event: event.kind,
+        data,
+    };
+
+    let post_id = match (event.kind, event.post_id) {
+        (StreamEventKind::PostCreated | StreamEventKind::PostCounters, Some(post_id)) => post_id,
+        _ if candidates.contains(&event.user_id) => {
+            return Ok(vec![(event.user_id, message(event.payload.clone()))]);
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut audience: Vec<Uuid> = follows::table
+        .filter(follows::following_id.eq(event.user_id))
+        .filter(follows::follower_id.eq_any(candidates))
+        .filter(
+            follows::follower_id.ne_all(
+                mutes::table
+                    .filter(mutes::muted_id.eq(event.user_id))
+                    .select(mutes::muter_id),
+            ),
+        )
+        .select(follows::follower_id)
+        .load(conn)?;
+    if candidates.contains(&event.user_id) {
+        audience.push(event.user_id);
+    }
+
+    let mut routed = Vec::new();
+    for recipient in audience {
+        let Some(post) = find_visible_post(conn, post_id, Some(recipient))? else {
+            continue;
+        };
+        let data = match event.kind {
+            StreamEventKind::PostCreated => {
+                serde_json::to_value(load_post_views(conn, vec![post], Some(recipient))?.remove(0))
+                    .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?
+            }
+            _ => event.payload.clone(),
+        };
+        routed.push((recipient, message(data)));
+    }
+    Ok(routed)
+}
+
+// Enqueues a job on `conn`, so it can share a transaction with the change that
+// caused it. Returns None when a queued job already has the same dedupe key.
+fn enqueue_job(
+    conn: &mut PgConnection,
+    job: &NewBackgroundJob,
+) -> QueryResult<Option<BackgroundJob>> {
+    diesel::insert_into(jobs::table)
+        .values(job)
+        .on_conflict_do_nothing()
+        .returning(BackgroundJob::as_returning())
+        .get_result(conn)
+        .optional()
+}
+
+// The bytes used by the user's uploads and their variants, and the quota of their
+// role, unless they have their own
+fn storage_usage(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<StorageUsage> {
+    let (is_admin, quota_bytes): (bool, Option<i64>) = users::table
+        .find(user_id)
+        .select((users::is_admin, users::storage_quota_bytes))
+        .first(conn)?;
+    let used_bytes = media::table
+        .filter(media::user_id.eq(user_id))
+        .select(sql::<BigInt>("COALESCE(SUM(size_bytes), 0)::BIGINT"))
+        .first::<i64>(conn)?;
+    let variant_bytes = media_variants::table
+        .inner_join(media::table)
+        .filter(media::user_id.eq(user_id))
+        .select(sql::<BigInt>(
+            "COALESCE(SUM(media_variants.size_bytes), 0)::BIGINT",
+        ))
+        .first::<i64>(conn)?;
+    Ok(StorageUsage {
+        used_bytes: used_bytes + variant_bytes,
+        quota_bytes: quota_bytes.or_else(|| crate::media::role_quota_bytes(is_admin)),
+    })
+}
+
+// Saves an upload, unless it would take the uploader over their quota
+fn insert_media(conn: &mut PgConnection, new_media: &NewMedia) -> Result<Media, DbError> {
+    // Locking the uploader keeps concurrent uploads from both fitting
+    users::table
+        .find(new_media.user_id)
+        .select(users::id)
+        .for_update()
+        .first::<Uuid>(conn)?;
+    if !storage_usage(conn, new_media.user_id)?.allows(new_media.size_bytes) {
+        return Err(Box::new(QuotaExceededError));
+    }
+    Ok(diesel::insert_into(media::table)
+        .values(new_media)
+        .returning(Media::as_returning())
+        .get_result(conn)?)
+}
+
+// The webhook if `user_id` may manage it: their own, or an instance-wide one
+// when they are an admin
+fn find_manageable_webhook(
+    conn: &mut PgConnection,
+    webhook_id: Uuid,
+    user_id: Uuid,
+) -> QueryResult<Option<Webhook>> {
+    let is_admin: bool = users::table
+        .find(user_id)
+        .select(users::is_admin)
+        .first(conn)
+        .optional()?
+        .unwrap_or(false);
+    let webhook: Option<Webhook> = webhooks::table
+        .find(webhook_id)
+        .select(Webhook::as_select())
+        .first(conn)
+        .optional()?;
+    Ok(webhook.filter(|w| match w.user_id {
+        Some(owner_id) => owner_id == user_id,
+        None => is_admin,
+    }))
+}
+
+pub struct Database {
+    pub pool: deadpool_diesel::postgres::Pool,
+}
+
+impl Clone for Database {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl Database {
+    pub fn new(pool: deadpool_diesel::postgres::Pool) -> Self {
+        Self { pool }
+    }
+
+    // User operations
+    pub async fn create_user(&self, new_user: NewUser) -> Result<User, DbError> {
+        let conn = self.pool.get().await?;
+        let id = Uuid::new_v4();
+        let reserved_since = Utc::now() - accounts::username_reservation_period();
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    if username_taken(conn, &new_user.username, None, reserved_since)? {
+                        return Err(Box::new(UsernameTakenError));
+                    }
+                    Ok(diesel::insert_into(users::table)
+                        .values((
+                            users::id.eq(id),
+                            &new_user,
+                            users::image_url.eq(crate::media::identicon_url(id)),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn)?)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(user)
+    }
+
+    pub async fn create_user_with_password(
+        &self,
+        new_user: NewUser,
+        password_hash: String,
+    ) -> Result<User, DbError> {
+        let conn = self.pool.get().await?;
+        let id = Uuid::new_v4();
+        let reserved_since = Utc::now() - accounts::username_reservation_period();
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    if username_taken(conn, &new_user.username, None, reserved_since)? {
+                        return Err(Box::new(UsernameTakenError));
+                    }
+                    Ok(diesel::insert_into(users::table)
+                        .values((
+                            users::id.eq(id),
+                            &new_user,
+                            users::image_url.eq(crate::media::identicon_url(id)),
+                            users::password_hash.eq(password_hash),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn)?)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(user)
+    }
+
+    // Gives the user a new username and keeps the old one in their history. Their
+    // own old usernames can be taken back at any time. Returns None when the user
+    // does not exist.
+    pub async fn rename_user(
+        &self,
+        user_id: Uuid,
+        username: String,
+    ) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let now = Utc::now();
+        let cooldown = accounts::username_change_cooldown();
+        let reserved_since = now - accounts::username_reservation_period();
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let Some(current) = users::table
+                        .find(user_id)
+                        .select(User::as_select())
+                        .for_update()
+                        .first(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    if current.username == username {
+                        return Ok(Some(current));
+                    }
+
+                    let last_change: Option<DateTime<Utc>> = username_history::table
+                        .filter(username_history::user_id.eq(user_id))
+                        .select(max(username_history::created_at))
+                        .first(conn)?;
+                    if let Some(last_change) = last_change
+                        && last_change + cooldown > now
+                    {
+                        return Err(Box::new(RenameCooldownError {
+                            available_at: last_change + cooldown,
+                        }));
+                    }
+                    if username_taken(conn, &username, Some(user_id), reserved_since)? {
+                        return Err(Box::new(UsernameTakenError));
+                    }
+
+                    diesel::delete(
+                        username_history::table
+                            .filter(username_history::user_id.eq(user_id))
+                            .filter(lower(username_history::username).eq(username.to_lowercase())),
+                    )
+                    .execute(conn)?;
+                    diesel::insert_into(username_history::table)
+                        .values((
+                            username_history::user_id.eq(user_id),
+                            username_history::username.eq(&current.username),
+                            username_history::created_at.eq(now),
+                        ))
+                        .execute(conn)?;
+                    let user = diesel::update(users::table.find(user_id))
+                        .set((users::username.eq(username), users::updated_at.eq(now)))
+                        .returning(User::as_returning())
+                        .get_result(conn)?;
+                    Ok(Some(user))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(user)
+    }
+
+    // Returns None when the user does not exist
+    pub async fn update_profile(
+        &self,
+        user_id: Uuid,
+        changes: UpdateProfileRequest,
+    ) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let Some(current) = users::table
+                        .find(user_id)
+                        .select(User::as_select())
+                        .for_update()
+                        .first(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    // Text arrives trimmed, see `UpdateProfileRequest::trimmed`
+                    let cleared = |text: Option<String>, current: Option<String>| match text {
+                        Some(text) if text.is_empty() => None,
+                        Some(text) => Some(text),
+                        None => current,
+                    };
+                    let profile_fields = match changes.profile_fields {
+                        Some(fields) => serde_json::to_value(fields)
+                            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?,
+                        None => current.profile_fields,
+                    };
+
+                    diesel::update(users::table.find(user_id))
+                        .set((
+                            users::name.eq(changes.name.unwrap_or(current.name)),
+                            users::bio.eq(cleared(changes.bio, current.bio)),
+                            users::location.eq(cleared(changes.location, current.location)),
+                            users::website.eq(cleared(changes.website, current.website)),
+                            users::pronouns.eq(cleared(changes.pronouns, current.pronouns)),
+                            users::profile_fields.eq(profile_fields),
+                            users::updated_at.eq(Utc::now()),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn)
+                        .map(Some)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    pub async fn get_password_hash(&self, user_id: Uuid) -> Result<Option<String>, DbError> {
+        let conn = self.pool.get().await?;
+        let hash = conn
+            .interact(move |conn| {
+                users::table
+                    .find(user_id)
+                    .select(users::password_hash)
+                    .first::<Option<String>>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(hash.flatten())
+    }
+
+    // Replaces any pending change of address for the user and queues the emails
+    // about it
+    pub async fn request_email_change(
+        &self,
+        change: NewEmailChange,
+        emails: Vec<NewBackgroundJob>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction::<_, DbError, _>(|conn| {
+                let taken: i64 = users::table
+                    .filter(lower(users::email).eq(change.new_email.to_lowercase()))
+                    .count()
+                    .get_result(conn)?;
+                if taken > 0 {
+                    return Err(Box::new(EmailTakenError));
+                }
+
+                diesel::insert_into(email_change_requests::table)
+                    .values(&change)
+                    .on_conflict(email_change_requests::user_id)
+                    .do_update()
+                    .set((
+                        email_change_requests::created_at.eq(Utc::now()),
+                        email_change_requests::new_email
+                            .eq(excluded(email_change_requests::new_email)),
+                        email_change_requests::token_hash
+                            .eq(excluded(email_change_requests::token_hash)),
+                        email_change_requests::expires_at
+                            .eq(excluded(email_change_requests::expires_at)),
+                    ))
+                    .execute(conn)?;
+                for email in &emails {
+                    enqueue_job(conn, email)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)??;
+        Ok(())
+    }
+
+    // Applies the change of address the token was sent for. None when the token is
+    // unknown or has expired.
+    pub async fn confirm_email_change(&self, token_hash: String) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let Some((request_id, user_id, new_email)) = email_change_requests::table
+                        .filter(email_change_requests::token_hash.eq(token_hash))
+                        .filter(email_change_requests::expires_at.gt(Utc::now()))
+                        .select((
+                            email_change_requests::id,
+                            email_change_requests::user_id,
+                            email_change_requests::new_email,
+                        ))
+                        .for_update()
+                        .first::<(Uuid, Uuid, String)>(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    // Someone may have registered the address since it was requested
+                    let taken: i64 = users::table
+                        .filter(lower(users::email).eq(new_email.to_lowercase()))
+                        .filter(users::id.ne(user_id))
+                        .count()
+                        .get_result(conn)?;
+                    if taken > 0 {
+                        return Err(Box::new(EmailTakenError));
+                    }
+
+                    diesel::delete(email_change_requests::table.find(request_id)).execute(conn)?;
+                    let user = diesel::update(users::table.find(user_id))
+                        .set((users::email.eq(new_email), users::updated_at.eq(Utc::now())))
+                        .returning(User::as_returning())
+                        .get_result(conn)?;
+                    Ok(Some(user))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(user)
+    }
+
+    // Saves a password setup for an account without a password, replacing any earlier
+    // one, and queues the email carrying its token
+    pub async fn request_password_setup(
+        &self,
+        setup: NewPasswordSetup,
+        email: NewBackgroundJob,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction::<_, DbError, _>(|conn| {
+                diesel::insert_into(password_setup_requests::table)
+                    .values(&setup)
+                    .on_conflict(password_setup_requests::user_id)
+                    .do_update()
+                    .set((
+                        password_setup_requests::created_at.eq(Utc::now()),
+                        password_setup_requests::token_hash
+                            .eq(excluded(password_setup_requests::token_hash)),
+                        password_setup_requests::expires_at
+                            .eq(excluded(password_setup_requests::expires_at)),
+                    ))
+                    .execute(conn)?;
+                enqueue_job(conn, &email)?;
+                Ok(())
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)??;
+        Ok(())
+    }
+
+    // Sets the password of the setup with `token_hash`. None when the token is unknown or
+    // expired, or the account has a password or was deleted since.
+    pub async fn confirm_password_setup(
+        &self,
+        token_hash: String,
+        password_hash: String,
+    ) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let Some((request_id, user_id)) = password_setup_requests::table
+                        .filter(password_setup_requests::token_hash.eq(token_hash))
+                        .filter(password_setup_requests::expires_at.gt(Utc::now()))
+                        .select((
+                            password_setup_requests::id,
+                            password_setup_requests::user_id,
+                        ))
+                        .for_update()
+                        .first::<(Uuid, Uuid)>(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+
+                    diesel::delete(password_setup_requests::table.find(request_id))
+                        .execute(conn)?;
+                    diesel::update(
+                        users::table
+                            .find(user_id)
+                            .filter(users::password_hash.is_null())
+                            .filter(users::deleted_at.is_null()),
+                    )
+                    .set((
+                        users::password_hash.eq(password_hash),
+                        users::updated_at.eq(Utc::now()),
+                    ))
+                    .returning(User::as_returning())
+                    .get_result(conn)
+                    .optional()
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    // The user's current export, or a new one queued with `job` when they have
+    // none that is pending or ready. The flag is true for a new export.
+    pub async fn start_data_export(
+        &self,
+        user_id: Uuid,
+        export_id: Uuid,
+        job: NewBackgroundJob,
+    ) -> Result<(DataExport, bool), DbError> {
+        let conn = self.pool.get().await?;
+        let export = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    // Serializes concurrent requests from the same user
+                    users::table
+                        .find(user_id)
+                        .select(users::id)
+                        .for_update()
+                        .first::<Uuid>(conn)?;
+                    let current = data_exports::table
+                        .filter(data_exports::user_id.eq(user_id))
+                        .filter(data_exports::status.ne(ExportStatus::Failed))
+                        .filter(
+                            data_exports::expires_at
+                                .is_null()
+                                .or(data_exports::expires_at.gt(Utc::now())),
+                        )
+                        .order(data_exports::created_at.desc())
+                        .select(DataExport::as_select())
+                        .first(conn)
+                        .optional()?;
+                    if let Some(current) = current {
+                        return Ok((current, false));
+                    }
+
+                    let export = diesel::insert_into(data_exports::table)
+                        .values((
+                            data_exports::id.eq(export_id),
+                            data_exports::user_id.eq(user_id),
+                        ))
+                        .returning(DataExport::as_returning())
+                        .get_result(conn)?;
+                    enqueue_job(conn, &job)?;
+                    Ok((export, true))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(export)
+    }
+
+    pub async fn get_data_export(&self, export_id: Uuid) -> Result<Option<DataExport>, DbError> {
+        let conn = self.pool.get().await?;
+        let export = conn
+            .interact(move |conn| {
+                data_exports::table
+                    .find(export_id)
+                    .select(DataExport::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(export)
+    }
+
+    // The user's latest export that can still be downloaded
+    pub async fn get_ready_data_export(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<DataExport>, DbError> {
+        let conn = self.pool.get().await?;
+        let export = conn
+            .interact(move |conn| {
+                data_exports::table
+                    .filter(data_exports::user_id.eq(user_id))
+                    .filter(data_exports::status.eq(ExportStatus::Ready))
+                    .filter(data_exports::expires_at.gt(Utc::now()))
+                    .order(data_exports::created_at.desc())
+                    .select(DataExport::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(export)
+    }
+
+    pub async fn complete_data_export(
+        &self,
+        export_id: Uuid,
+        storage_key: String,
+        size_bytes: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(data_exports::table.find(export_id))
+                .set((
+                    data_exports::status.eq(ExportStatus::Ready),
+                    data_exports::storage_key.eq(storage_key),
+                    data_exports::size_bytes.eq(size_bytes),
+                    data_exports::completed_at.eq(Utc::now()),
+                    data_exports::expires_at.eq(expires_at),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Failed exports are purged like finished ones
+    pub async fn fail_data_export(
+        &self,
+        export_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(data_exports::table.find(export_id))
+                .set((
+                    data_exports::status.eq(ExportStatus::Failed),
+                    data_exports::completed_at.eq(Utc::now()),
+                    data_exports::expires_at.eq(expires_at),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Deletes expired exports and returns the storage keys of their archives
+    pub async fn take_expired_data_exports(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, DbError> {
+        let conn = self.pool.get().await?;
+        let keys = conn
+            .interact(move |conn| {
+                diesel::delete(data_exports::table.filter(data_exports::expires_at.le(now)))
+                    .returning(data_exports::storage_key)
+                    .get_results::<Option<String>>(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(keys.into_iter().flatten().collect())
+    }
+
+    // Everything a data export holds about the user, or None if they do not exist
+    pub async fn get_user_data(&self, user_id: Uuid) -> Result<Option<UserData>, DbError> {
+        let conn = self.pool.get().await?;
+        let data = conn
+            .interact(move |conn| {
+                let Some(profile) = users::table
+                    .find(user_id)
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+                let posts = posts::table
+                    .filter(posts::user_id.eq(user_id))
+                    .order(posts::created_at.asc())
+                    .select(Post::as_select())
+                    .load(conn)?;
+                let comments = comments::table
+                    .filter(comments::user_id.eq(user_id))
+                    .order(comments::created_at.asc())
+                    .select(Comment::as_select())
+                    .load(conn)?;
+                let interactions = interactions::table
+                    .filter(interactions::user_id.eq(user_id))
+                    .order(interactions::created_at.asc())
+                    .select(Interaction::as_select())
+                    .load(conn)?;
+                let following = follows::table
+                    .inner_join(users::table.on(follows::following_id.eq(users::id)))
+                    .filter(follows::follower_id.eq(user_id))
+                    .order(follows::created_at.asc())
+                    .select((users::id, users::username, follows::created_at))
+                    .load::<FollowedUser>(conn)?;
+                let followers = follows::table
+                    .inner_join(users::table.on(follows::follower_id.eq(users::id)))
+                    .filter(follows::following_id.eq(user_id))
+                    .order(follows::created_at.asc())
+                    .select((users::id, users::username, follows::created_at))
+                    .load::<FollowedUser>(conn)?;
+                let media = media::table
+                    .filter(media::user_id.eq(user_id))
+                    .order(media::created_at.asc())
+                    .select(Media::as_select())
+                    .load(conn)?
+                    .into_iter()
+                    .map(|media| ExportedMedia {
+                        file: accounts::export_media_path(&media.storage_key),
+                        media,
+                    })
+                    .collect();
+                let bookmarks = bookmarks::table
+                    .filter(bookmarks::user_id.eq(user_id))
+                    .order(bookmarks::created_at.asc())
+                    .select(Bookmark::as_select())
+                    .load(conn)?;
+                let bookmark_collections = bookmark_collections::table
+                    .filter(bookmark_collections::user_id.eq(user_id))
+                    .order(bookmark_collections::created_at.asc())
+                    .select(BookmarkCollection::as_select())
+                    .load(conn)?;
+
+                Ok(Some(UserData {
+                    exported_at: Utc::now(),
+                    profile,
+                    posts,
+                    comments,
+                    interactions,
+                    following,
+                    followers,
+                    media,
+                    bookmarks,
+                    bookmark_collections,
+                }))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(data)
+    }
+
+    // Marks the account for deletion at `requested_at` and queues `job` to erase it
+    // once the grace period is over. A request already pending is kept, with its
+    // original time. None when the user does not exist.
+    pub async fn request_account_deletion(
+        &self,
+        user_id: Uuid,
+        requested_at: DateTime<Utc>,
+        job: NewBackgroundJob,
+    ) -> Result<Option<DateTime<Utc>>, DbError> {
+        let conn = self.pool.get().await?;
+        let requested_at = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let Some(pending) = users::table
+                        .find(user_id)
+                        .filter(users::deleted_at.is_null())
+                        .select(users::deletion_requested_at)
+                        .for_update()
+                        .first::<Option<DateTime<Utc>>>(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    if let Some(pending) = pending {
+                        return Ok(Some(pending));
+                    }
+
+                    diesel::update(users::table.find(user_id))
+                        .set(users::deletion_requested_at.eq(requested_at))
+                        .execute(conn)?;
+                    enqueue_job(conn, &job)?;
+                    Ok(Some(requested_at))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(requested_at)
+    }
+
+    // Saves a deletion confirmation for an account without a password, replacing any
+    // earlier one, and queues the email carrying its token
+    pub async fn request_deletion_confirmation(
+        &self,
+        confirmation: NewDeletionConfirmation,
+        email: NewBackgroundJob,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction::<_, DbError, _>(|conn| {
+                diesel::insert_into(account_deletion_confirmations::table)
+                    .values(&confirmation)
+                    .on_conflict(account_deletion_confirmations::user_id)
+                    .do_update()
+                    .set((
+                        account_deletion_confirmations::created_at.eq(Utc::now()),
+                        account_deletion_confirmations::token_hash
+                            .eq(excluded(account_deletion_confirmations::token_hash)),
+                        account_deletion_confirmations::expires_at
+                            .eq(excluded(account_deletion_confirmations::expires_at)),
+                    ))
+                    .execute(conn)?;
+                enqueue_job(conn, &email)?;
+                Ok(())
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)??;
+        Ok(())
+    }
+
+    // Uses up the deletion confirmation with `token_hash` and returns its user. None
+    // when the token is unknown or expired, or the account has a password since.
+    pub async fn take_deletion_confirmation(
+        &self,
+        token_hash: String,
+    ) -> Result<Option<Uuid>, DbError> {
+        let conn = self.pool.get().await?;
+        let user_id = conn
+            .interact(move |conn| {
+                let Some(user_id) = diesel::delete(
+                    account_deletion_confirmations::table
+                        .filter(account_deletion_confirmations::token_hash.eq(token_hash))
+                        .filter(account_deletion_confirmations::expires_at.gt(Utc::now())),
+                )
+                .returning(account_deletion_confirmations::user_id)
+                .get_result::<Uuid>(conn)
+                .optional()?
+                else {
+                    return Ok(None);
+                };
+                users::table
+                    .find(user_id)
+                    .filter(users::password_hash.is_null())
+                    .select(users::id)
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user_id)
+    }
+
+    // The queued deletion job finds nothing to do once the request is cleared
+    pub async fn cancel_account_deletion(&self, user_id: Uuid) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                diesel::update(
+                    users::table
+                        .find(user_id)
+                        .filter(users::deleted_at.is_null()),
+                )
+                .set(users::deletion_requested_at.eq(None::<DateTime<Utc>>))
+                .returning(User::as_returning())
+                .get_result(conn)
+                .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    // Erases the account if its deletion was requested before `requested_before` and
+    // has not been cancelled, returning the storage keys of the files to delete.
+    // None when there is nothing to erase.
+    pub async fn delete_account(
+        &self,
+        user_id: Uuid,
+        requested_before: DateTime<Utc>,
+    ) -> Result<Option<Vec<String>>, DbError> {
+        let conn = self.pool.get().await?;
+        let keys = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let Some(username) = users::table
+                        .find(user_id)
+                        .filter(users::deleted_at.is_null())
+                        .filter(users::deletion_requested_at.le(requested_before))
+                        .select(users::username)
+                        .for_update()
+                        .first::<String>(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    erase_account(conn, user_id, username, Utc::now()).map(Some)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(keys)
+    }
+
+    pub async fn create_import(
+        &self,
+        new_import: NewImport,
+        job: NewBackgroundJob,
+    ) -> Result<Import, DbError> {
+        let conn = self.pool.get().await?;
+        let import = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let import = diesel::insert_into(imports::table)
+                        .values(&new_import)
+                        .returning(Import::as_returning())
+                        .get_result(conn)?;
+                    enqueue_job(conn, &job)?;
+                    Ok(import)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(import)
+    }
+
+    pub async fn get_import(&self, import_id: Uuid) -> Result<Option<Import>, DbError> {
+        let conn = self.pool.get().await?;
+        let import = conn
+            .interact(move |conn| {
+                imports::table
+                    .find(import_id)
+                    .select(Import::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(import)
+    }
+
+    // The user's imports, newest first
+    pub async fn get_imports(
+        &self,
+        user_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Import>, DbError> {
+        let conn = self.pool.get().await?;
+        let imports = conn
+            .interact(move |conn| {
+                imports::table
+                    .filter(imports::user_id.eq(user_id))
+                    .order(imports::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(Import::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(imports)
+    }
+
+    // Stores the items read from the import's file and marks it as running, so
+    // later runs resume from the stored items
+    pub async fn start_import(
+        &self,
+        import_id: Uuid,
+        items: Vec<ImportItem>,
+    ) -> Result<(), DbError> {
+        let total_items = items.len() as i32;
+        let rows = items
+            .iter()
+            .enumerate()
+            .map(|(position, item)| {
+                Ok((
+                    import_items::import_id.eq(import_id),
+                    import_items::position.eq(position as i32),
+                    import_items::item.eq(serde_json::to_value(item)?),
+                ))
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction(|conn| {
+                // Left over from a run that failed part way
+                diesel::delete(import_items::table.filter(import_items::import_id.eq(import_id)))
+                    .execute(conn)?;
+                // Three binds a row, well under Postgres' limit per statement
+                for chunk in rows.chunks(1000) {
+                    diesel::insert_into(import_items::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                diesel::update(imports::table.find(import_id))
+                    .set((
+                        imports::status.eq(ImportStatus::Running),
+                        imports::total_items.eq(total_items),
+                    ))
+                    .execute(conn)
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // The import's next items still to be handled, with their positions
+    pub async fn get_import_items(
+        &self,
+        import_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<(i32, ImportItem)>, DbError> {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .interact(move |conn| {
+                import_items::table
+                    .filter(import_items::import_id.eq(import_id))
+                    .order(import_items::position.asc())
+                    .limit(limit)
+                    .select((import_items::position, import_items::item))
+                    .load::<(i32, serde_json::Value)>(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        let items = rows
+            .into_iter()
+            .map(|(position, item)| Ok((position, serde_json::from_value(item)?)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        Ok(items)
+    }
+
+    // Creates an imported post with its original time, unless it was imported
+    // before. Mentions are not notified and no events are published, so followers
+    // are not flooded with old posts. Returns whether the post was created.
+    pub async fn import_post(
+        &self,
+        user_id: Uuid,
+        kind: ImportKind,
+        imported: ImportedPost,
+    ) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let created = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let source_posts = imported_posts::table
+                        .filter(imported_posts::user_id.eq(user_id))
+                        .filter(imported_posts::source.eq(kind));
+                    let already_imported = diesel::select(exists(
+                        source_posts.filter(imported_posts::source_id.eq(&imported.source_id)),
+                    ))
+                    .get_result::<bool>(conn)?;
+                    if already_imported {
+                        return Ok(false);
+                    }
+
+                    // Replies stay threaded when the post they answer was imported too
+                    let reply_to_post_id = match &imported.reply_to {
+                        Some(parent) => source_posts
+                            .filter(imported_posts::source_id.eq(parent))
+                            .select(imported_posts::post_id)
+                            .first::<Uuid>(conn)
+                            .optional()?,
+                        None => None,
+                    };
+                    let new_post = NewPost {
+                        user_id,
+                        content: imported.content,
+                        media_ids: Vec::new(),
+                        reply_to_post_id,
+                        quoted_post_id: None,
+                        visibility: imported.visibility,
+                        language: imported.language,
+                        created_at: Some(imported.created_at),
+                    };
+                    let processed = process_imported_content(&new_post.content);
+                    let (post, _) = insert_post(conn, &new_post, processed)?;
+
+                    diesel::insert_into(imported_posts::table)
+                        .values((
+                            imported_posts::user_id.eq(user_id),
+                            imported_posts::source.eq(kind),
+                            imported_posts::source_id.eq(imported.source_id),
+                            imported_posts::post_id.eq(post.id),
+                        ))
+                        .execute(conn)?;
+                    Ok(true)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(created)
+    }
+
+    // Records what became of the item at `index` and removes it from the stored
+    // items. Only the first MAX_REPORTED_FAILURES failures are listed. Items already
+    // recorded, as when a run is retried, are left alone.
+    pub async fn record_import_progress(
+        &self,
+        import_id: Uuid,
+        index: i32,
+        label: String,
+        outcome: ImportOutcome,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction(|conn| {
+                let Some(failed_items) = imports::table
+                    .find(import_id)
+                    .filter(imports::processed_items.eq(index))
+                    .select(imports::failed_items)
+                    .for_update()
+                    .first::<i32>(conn)
+                    .optional()?
+                else {
+                    return Ok(());
+                };
+                diesel::delete(import_items::table.find((import_id, index))).execute(conn)?;
+                let import = imports::table.find(import_id);
+                let processed = imports::processed_items.eq(imports::processed_items + 1);
+                match outcome {
+                    ImportOutcome::Imported => diesel::update(import)
+                        .set((
+                            processed,
+                            imports::imported_items.eq(imports::imported_items + 1),
+                        ))
+                        .execute(conn)?,
+                    ImportOutcome::Skipped => diesel::update(import)
+                        .set((
+                            processed,
+                            imports::skipped_items.eq(imports::skipped_items + 1),
+                        ))
+                        .execute(conn)?,
+                    ImportOutcome::Failed(error) => {
+                        let failure = match failed_items < crate::imports::MAX_REPORTED_FAILURES {
+                            true => serde_json::json!([{
+                                "index": index,
+                                "item": label,
+                                "error": error,
+                            }]),
+                            false => serde_json::json!([]),
+                        };
+                        diesel::update(import)
+                            .set((
+                                processed,
+                                imports::failed_items.eq(imports::failed_items + 1),
+                                imports::failures.eq(imports::failures.concat(failure)),
+                            ))
+                            .execute(conn)?
+                    }
+                };
+                Ok(())
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Finishes the import, failed when there is an error, and drops any items still
+    // stored. The uploaded file is deleted by the caller.
+    pub async fn finish_import(
+        &self,
+        import_id: Uuid,
+        error: Option<String>,
+    ) -> Result<(), DbError> {
+        let status = match error {
+            Some(_) => ImportStatus::Failed,
+            None => ImportStatus::Completed,
+        };
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction(|conn| {
+                diesel::delete(import_items::table.filter(import_items::import_id.eq(import_id)))
+                    .execute(conn)?;
+                diesel::update(imports::table.find(import_id))
+                    .set((
+                        imports::status.eq(status),
+                        imports::error.eq(error),
+                        imports::storage_key.eq(None::<String>),
+                        imports::completed_at.eq(Utc::now()),
+                    ))
+                    .execute(conn)
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // The user with this name, ignoring case, unless their account was deleted
+    pub async fn find_user_id_by_username(
+        &self,
+        username_str: &str,
+    ) -> Result<Option<Uuid>, DbError> {
+        let username = username_str.to_lowercase();
+        let conn = self.pool.get().await?;
+        let user_id = conn
+            .interact(move |conn| {
+                users::table
+                    .filter(lower(users::username).eq(username))
+                    .filter(users::deleted_at.is_null())
+                    .select(users::id)
+                    .first::<Uuid>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user_id)
+    }
+
+    // Returns the user together with their password hash, if they have one
+    pub async fn get_user_credentials(
+        &self,
+        email_str: &str,
+    ) -> Result<Option<(User, Option<String>)>, DbError> {
+        let email = email_str.to_string();
+        let conn = self.pool.get().await?;
+        let credentials = conn
+            .interact(move |conn| {
+                users::table
+                    .filter(users::email.eq(email))
+                    .select((User::as_select(), users::password_hash))
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(credentials)
+    }
+
+    // Whether the user exists and has not been erased
+    pub async fn is_active_user(&self, user_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let active = conn
+            .interact(move |conn| {
+                diesel::select(exists(
+                    users::table
+                        .find(user_id)
+                        .filter(users::deleted_at.is_null()),
+                ))
+                .get_result(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(active)
+    }
+
+    pub async fn get_user(&self, user_id: Uuid) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                users::table
+                    .filter(users::id.eq(user_id))
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    pub async fn get_user_by_username(&self, username_str: &str) -> Result<Option<User>, DbError> {
+        let username = username_str.to_string();
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                users::table
+                    .filter(lower(users::username).eq(username.to_lowercase()))
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    // The user who most recently gave up `username`, while it still redirects to them
+    pub async fn get_renamed_user(&self, username_str: &str) -> Result<Option<User>, DbError> {
+        let username = username_str.to_string();
+        let since = Utc::now() - accounts::username_redirect_period();
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                username_history::table
+                    .inner_join(users::table)
+                    .filter(lower(username_history::username).eq(username.to_lowercase()))
+                    .filter(username_history::created_at.gt(since))
+                    .filter(users::deleted_at.is_null())
+                    .order(username_history::created_at.desc())
+                    .select(User::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    // Users whose username or name resembles `query`, or starts with it when
+    // `prefix` is set. Popular accounts and accounts the viewer follows rank higher.
+    pub async fn search_users(
+        &self,
+        query: UserSearchQuery,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserSearchResult>, DbError> {
+        let conn = self.pool.get().await?;
+        let results = conn
+            .interact(move |conn| {
+                let q = query.q.trim().trim_start_matches('@').to_string();
+                let pattern = format!(
+                    "{}%",
+                    q.replace('\\', "\\\\")
+                        .replace('%', "\\%")
+                        .replace('_', "\\_")
+                );
+
+                let mut matches = users::table
+                    .filter(users::deleted_at.is_null())
+                    .into_boxed();
+                matches = if query.prefix {
+                    matches.filter(
+                        users::username
+                            .ilike(pattern.clone())
+                            .or(users::name.ilike(pattern)),
+                    )
+                } else {
+                    matches.filter(
+                        TrigramMatch::new(users::username, q.clone().into_sql::<Varchar>())
+                            .or(TrigramMatch::new(
+                                users::name,
+                                q.clone().into_sql::<Varchar>(),
+                            ))
+                            .or(users::username.ilike(pattern)),
+                    )
+                };
+
+                let followed = users::id.eq_any(
+                    follows::table
+                        .filter(follows::follower_id.nullable().eq(viewer_id))
+                        .select(follows::following_id),
+                );
+                let score = greatest(
+                    similarity(users::username, q.clone()),
+                    similarity(users::name, q),
+                )
+                .cast::<Float8>()
+                    + ln((users::followers_count + 1).cast::<Float8>()) * 0.05
+                    + case_when(followed, 0.5.into_sql::<Float8>()).otherwise(0.0);
+
+                let rows: Vec<(User, bool, f64)> = matches
+                    .filter(
+                        users::id.ne_all(
+                            blocks::table
+                                .filter(blocks::blocker_id.nullable().eq(viewer_id))
+                                .select(blocks::blocked_id),
+                        ),
+                    )
+                    .filter(
+                        users::id.ne_all(
+                            blocks::table
+                                .filter(blocks::blocked_id.nullable().eq(viewer_id))
+                                .select(blocks::blocker_id),
+                        ),
+                    )
+                    .select((User::as_select(), followed, score.clone()))
+                    .order((score.desc(), users::followers_count.desc()))
+                    .limit(limit)
+                    .offset(offset)
+                    .load(conn)?;
+
+                Ok(rows
+                    .into_iter()
+                    .map(|(user, followed_by_viewer, score)| UserSearchResult {
+                        user,
+                        followed_by_viewer,
+                        score,
+                    })
+                    .collect())
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(results)
+    }
+
+    // Post operations
+    // `user_id` is the authenticated author, who must own any attached media
+    pub async fn create_post(
+        &self,
+        user_id: Uuid,
+        mut new_post: NewPost,
+    ) -> Result<PostView, DbError> {
+        new_post.user_id = user_id;
+        let conn = self.pool.get().await?;
+        let post = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    for target in [new_post.reply_to_post_id, new_post.quoted_post_id]
+                        .into_iter()
+                        .flatten()
+                    {
+                        ensure_not_blocked_by_author(conn, target, new_post.user_id)?;
+                        ensure_visible_target(conn, target, new_post.user_id)?;
+                    }
+                    let processed = process_content(conn, new_post.user_id, &new_post.content)?;
+                    let (post, mentioned) = insert_post(conn, &new_post, processed)?;
+                    notify_mentions(conn, post.user_id, post.id, None, &mentioned)?;
+                    record_event(conn, DomainEvent::PostCreated { post: post.clone() })?;
+
+                    let author_id = post.user_id;
+                    Ok(load_post_views(conn, vec![post], Some(author_id))?.remove(0))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(post)
+    }
+
+    // Returns None when the post does not exist or belongs to someone else
+    pub async fn update_post(
+        &self,
+        post_id: Uuid,
+        user_id: Uuid,
+        content: String,
+    ) -> Result<Option<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let post = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let processed = process_content(conn, user_id, &content)?;
+
+                    let post = diesel::update(
+                        posts::table
+                            .filter(posts::id.eq(post_id))
+                            .filter(posts::user_id.eq(user_id)),
+                    )
+                    .set((
+                        posts::content.eq(&content),
+                        posts::entities.eq(&processed.entities),
+                        posts::content_html.eq(&processed.html),
+                    ))
+                    .returning(Post::as_returning())
+                    .get_result(conn)
+                    .optional()?;
+
+                    let Some(post) = post else {
+                        return Ok(None);
+                    };
+                    sync_post_hashtags(conn, post.id, &post.content)?;
+                    let mentioned =
+                        store_mentions(conn, post.id, None, &post.content, &processed.mentions)?;
+                    notify_mentions(conn, post.user_id, post.id, None, &mentioned)?;
+
+                    Ok(load_post_views(conn, vec![post], Some(user_id))?.pop())
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(post)
+    }
+
+    pub async fn get_post(
+        &self,
+        post_id: Uuid,
+        viewer_id: Option<Uuid>,
+    ) -> Result<Option<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let post = conn
+            .interact(
+                move |conn| match find_visible_post(conn, post_id, viewer_id)? {
+                    Some(post) => Ok(load_post_views(conn, vec![post], viewer_id)?.pop()),
+                    None => Ok(None),
+                },
+            )
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(post)
+    }
+
+    pub async fn get_post_thread(
+        &self,
+        post_id: Uuid,
+        viewer_id: Option<Uuid>,
+    ) -> Result<Option<PostThread>, DbError> {
+        let conn = self.pool.get().await?;
+        let thread = conn
+            .interact(move |conn| {
+                let Some(post) = find_visible_post(conn, post_id, viewer_id)? else {
+                    return Ok(None);
+                };
+
+                // Walk up the reply chain, root first, stopping at the first hidden post
+                let mut ancestors = Vec::new();
+                let mut parent_id = post.reply_to_post_id;
+                while let Some(id) = parent_id {
+                    if ancestors.len() >= MAX_THREAD_DEPTH {
+                        break;
+                    }
+                    let Some(parent) = find_visible_post(conn, id, viewer_id)? else {
+                        break;
+                    };
+                    parent_id = parent.reply_to_post_id;
+                    ancestors.push(parent);
+                }
+                ancestors.reverse();
+
+                // Walk down the replies one level at a time, skipping hidden branches,
+                // until MAX_THREAD_REPLIES have been collected
+                let mut descendants = Vec::new();
+                let mut frontier = vec![post.id];
+                for _ in 0..MAX_THREAD_DEPTH {
+                    let remaining = MAX_THREAD_REPLIES - descendants.len();
+                    let replies: Vec<Post> = posts::table
+                        .filter(posts::reply_to_post_id.eq_any(&frontier))
+                        .filter(visible_to(viewer_id))
+                        .order(posts::created_at.asc())
+                        .limit(remaining as i64)
+                        .select(Post::as_select())
+                        .load(conn)?;
+                    if replies.is_empty() {
+                        break;
+                    }
+                    frontier = replies.iter().map(|p| p.id).collect();
+                    descendants.extend(replies);
+                    if descendants.len() >= MAX_THREAD_REPLIES {
+                        break;
+                    }
+                }
+                descendants.sort_by_key(|p| p.created_at);
+
+                let post = load_post_views(conn, vec![post], viewer_id)?.remove(0);
+                Ok(Some(PostThread {
+                    ancestors: load_post_views(conn, ancestors, viewer_id)?,
+                    post,
+                    descendants: load_post_views(conn, descendants, viewer_id)?,
+                }))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(thread)
+    }
+
+    pub async fn get_posts(
+        &self,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let posts = conn
+            .interact(move |conn| {
+                let posts = posts::table
+                    .filter(visible_to(viewer_id))
+                    .filter(posts::deleted_at.is_null())
+                    .order(posts::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(Post::as_select())
+                    .load(conn)?;
+                load_post_views(conn, posts, viewer_id)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(posts)
+    }
+
+    // A page of the user's posts, newest first, for `get_user_posts`
+    pub async fn get_user_posts(
+        &self,
+        user_id: Uuid,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+        max_pinned: i64,
+    ) -> Result<Vec<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let posts = conn
+            .interact(move |conn| {
+                let posts = load_user_posts(conn, user_id, viewer_id, limit, offset, max_pinned)?;
+                load_post_views(conn, posts, viewer_id)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(posts)
+    }
+
+    // Bookmark operations
+    pub async fn get_bookmark_collections(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Vec<BookmarkCollection>, DbError> {
+        let conn = self.pool.get().await?;
+        let collections = conn
+            .interact(move |conn| {
+                bookmark_collections::table
+                    .filter(bookmark_collections::user_id.eq(user_id))
+                    .order(bookmark_collections::name.asc())
+                    .select(BookmarkCollection::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(collections)
+    }
+
+    pub async fn create_bookmark_collection(
+        &self,
+        user_id: Uuid,
+        name: String,
+    ) -> Result<BookmarkCollection, DbError> {
+        let conn = self.pool.get().await?;
+        let collection = conn
+            .interact(move |conn| {
+                diesel::insert_into(bookmark_collections::table)
+                    .values((
+                        bookmark_collections::user_id.eq(user_id),
+                        bookmark_collections::name.eq(name),
+                    ))
+                    .returning(BookmarkCollection::as_returning())
+                    .get_result(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(collection)
+    }
+
+    // Returns None when the collection does not exist or belongs to someone else
+    pub async fn rename_bookmark_collection(
+        &self,
+        collection_id: Uuid,
+        user_id: Uuid,
+        name: String,
+    ) -> Result<Option<BookmarkCollection>, DbError> {
+        let conn = self.pool.get().await?;
+        let collection = conn
+            .interact(move |conn| {
+                diesel::update(
+                    bookmark_collections::table
+                        .find(collection_id)
+                        .filter(bookmark_collections::user_id.eq(user_id)),
+                )
+                .set(bookmark_collections::name.eq(name))
+                .returning(BookmarkCollection::as_returning())
+                .get_result(conn)
+                .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(collection)
+    }
+
+    // Its bookmarks are kept, outside any collection. Returns false when the
+    // collection does not exist or belongs to someone else.
+    pub async fn delete_bookmark_collection(
+        &self,
+        collection_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    bookmark_collections::table
+                        .find(collection_id)
+                        .filter(bookmark_collections::user_id.eq(user_id)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted > 0)
+    }
+
+    // Bookmarks the post, or moves an existing bookmark to `collection_id`. The flag
+    // is true for a new bookmark. None when the post is not visible to the user.
+    pub async fn bookmark_post(
+        &self,
+        user_id: Uuid,
+        post_id: Uuid,
+        collection_id: Option<Uuid>,
+    ) -> Result<Option<(Bookmark, bool)>, DbError> {
+        let conn = self.pool.get().await?;
+        let bookmark = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    if find_visible_post(conn, post_id, Some(user_id))?.is_none() {
+                        return Ok(None);
+                    }
+                    if let Some(collection_id) = collection_id {
+                        let owned = diesel::select(exists(
+                            bookmark_collections::table
+                                .find(collection_id)
+                                .filter(bookmark_collections::user_id.eq(user_id)),
+                        ))
+                        .get_result::<bool>(conn)?;
+                        if !owned {
+                            return Err(Box::new(CollectionNotFoundError));
+                        }
+                    }
+
+                    let existing = diesel::update(
+                        bookmarks::table
+                            .filter(bookmarks::user_id.eq(user_id))
+                            .filter(bookmarks::post_id.eq(post_id)),
+                    )
+                    .set(bookmarks::collection_id.eq(collection_id))
+                    .returning(Bookmark::as_returning())
+                    .get_result(conn)
+                    .optional()?;
+                    if let Some(bookmark) = existing {
+                        return Ok(Some((bookmark, false)));
+                    }
+                    let bookmark = diesel::insert_into(bookmarks::table)
+                        .values((
+                            bookmarks::user_id.eq(user_id),
+                            bookmarks::post_id.eq(post_id),
+                            bookmarks::collection_id.eq(collection_id),
+                        ))
+                        .returning(Bookmark::as_returning())
+                        .get_result(conn)?;
+                    Ok(Some((bookmark, true)))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(bookmark)
+    }
+
+    // Returns false when the post was not bookmarked
+    pub async fn unbookmark_post(&self, user_id: Uuid, post_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    bookmarks::table
+                        .filter(bookmarks::user_id.eq(user_id))
+                        .filter(bookmarks::post_id.eq(post_id)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted > 0)
+    }
+
+    // A page of the user's bookmarks, newest first, starting after `before`, with the
+    // cursor of the next page while there is one. None when the collection does not
+    // exist or belongs to someone else.
+    pub async fn get_bookmarks(
+        &self,
+        user_id: Uuid,
+        collection_id: Option<Uuid>,
+        before: Option<BookmarkCursor>,
+        limit: i64,
+    ) -> Result<Option<(Vec<BookmarkView>, Option<BookmarkCursor>)>, DbError> {
+        let conn = self.pool.get().await?;
+        let page = conn
+            .interact(move |conn| load_bookmarks(conn, user_id, collection_id, before, limit))
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(page)
+    }
+
+    // Pins the user's post to their profile; pinning it again keeps the original
+    // pin. Returns None when the post does not exist or belongs to someone else.
+    pub async fn pin_post(
+        &self,
+        post_id: Uuid,
+        user_id: Uuid,
+        max_pinned: i64,
+    ) -> Result<Option<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let post = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    match pin_own_post(conn, post_id, user_id, max_pinned)? {
+                        Some(post) => Ok(load_post_views(conn, vec![post], Some(user_id))?.pop()),
+                        None => Ok(None),
+                    }
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(post)
+    }
+
+    // Returns None when the post does not exist or belongs to someone else
+    pub async fn unpin_post(
+        &self,
+        post_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let post = conn
+            .interact(move |conn| match unpin_own_post(conn, post_id, user_id)? {
+                Some(post) => Ok(load_post_views(conn, vec![post], Some(user_id))?.pop()),
+                None => Ok(None),
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(post)
+    }
+
+    // Hashtag operations
+    pub async fn get_hashtag(&self, name: String) -> Result<Option<Hashtag>, DbError> {
+        let conn = self.pool.get().await?;
+        let hashtag = conn
+            .interact(move |conn| {
+                hashtags::table
+                    .filter(hashtags::name.eq(name))
+                    .select(Hashtag::as_select())
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(hashtag)
+    }
+
+    pub async fn get_hashtag_posts(
+        &self,
+        name: String,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let posts = conn
+            .interact(move |conn| {
+                let posts = posts::table
+                    .filter(
+                        posts::id.eq_any(
+                            post_hashtags::table
+                                .inner_join(hashtags::table)
+                                .filter(hashtags::name.eq(name))
+                                .select(post_hashtags::post_id),
+                        ),
+                    )
+                    .filter(visible_to(viewer_id))
+                    .order(posts::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(Post::as_select())
+                    .load(conn)?;
+                load_post_views(conn, posts, viewer_id)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(posts)
+    }
+
+    // Posts that mention the user in their content
+    pub async fn get_user_mentions(
+        &self,
+        user_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let posts = conn
+            .interact(move |conn| {
+                let posts = posts::table
+                    .filter(
+                        posts::id.eq_any(
+                            mentions::table
+                                .filter(mentions::user_id.eq(user_id))
+                                .filter(mentions::comment_id.is_null())
+                                .select(mentions::post_id),
+                        ),
+                    )
+                    .filter(visible_to(Some(user_id)))
+                    .order(posts::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(Post::as_select())
+                    .load(conn)?;
+                load_post_views(conn, posts, Some(user_id))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(posts)
+    }
+
+    // Search operations
+    pub async fn search_posts(
+        &self,
+        filters: SearchFilters,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PostSearchResult>, DbError> {
+        let conn = self.pool.get().await?;
+        let results = conn
+            .interact(move |conn| {
+                let language = filters
+                    .lang
+                    .unwrap_or_else(|| DEFAULT_SEARCH_LANGUAGE.to_string());
+
+                let mut query = posts::table
+                    .filter(with_tsquery::<Bool>(
+                        "posts.search_vector @@ ",
+                        &language,
+                        &filters.q,
+                        "",
+                    ))
+                    .filter(visible_to(viewer_id))
+                    .into_boxed();
+                if let Some(author_id) = filters.author {
+                    query = query.filter(posts::user_id.eq(author_id));
+                }
+                if let Some(since) = filters.since {
+                    query = query.filter(posts::created_at.ge(since));
+                }
+                if let Some(until) = filters.until {
+                    query = query.filter(posts::created_at.le(until));
+                }
+                if let Some(hashtag) = filters.hashtag {
+                    query = query.filter(
+                        posts::id.eq_any(
+                            post_hashtags::table
+                                .inner_join(hashtags::table)
+                                .filter(hashtags::name.eq(normalize_hashtag(&hashtag)))
+                                .select(post_hashtags::post_id),
+                        ),
+                    );
+                }
+
+                let rows: Vec<(Post, f32, String)> = query
+                    .select((
+                        Post::as_select(),
+                        with_tsquery::<Float>(
+                            "ts_rank(posts.search_vector, ",
+                            &language,
+                            &filters.q,
+                            ") AS rank",
+                        ),
+                        headline_sql("posts", &language, &filters.q),
+                    ))
+                    .order((sql::<Float>("rank").desc(), posts::created_at.desc()))
+                    .limit(limit)
+                    .offset(offset)
+                    .load(conn)?;
+
+                let (posts, scores): (Vec<Post>, Vec<(f32, String)>) = rows
+                    .into_iter()
+                    .map(|(post, rank, highlight)| (post, (rank, highlight)))
+                    .unzip();
+                Ok(load_post_views(conn, posts, viewer_id)?
+                    .into_iter()
+                    .zip(scores)
+                    .map(|(post, (rank, highlight))| PostSearchResult {
+                        post,
+                        rank,
+                        highlight,
+                    })
+                    .collect())
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(results)
+    }
+
+    // Comments on posts the viewer can see, excluding comments across a block
+    pub async fn search_comments(
+        &self,
+        filters: SearchFilters,
+        viewer_id: Option<Uuid>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<CommentSearchResult>, DbError> {
+        let conn = self.pool.get().await?;
+        let results = conn
+            .interact(move |conn| {
+                let language = filters
+                    .lang
+                    .unwrap_or_else(|| DEFAULT_SEARCH_LANGUAGE.to_string());
+
+                // Only comments on posts the viewer can see, checked in the same query so
+                // the limit and offset apply to visible comments
+                let mut predicate: CommentFilter = Box::new(
+                    comments::post_id.eq_any(
+                        posts::table
+                            .filter(visible_to(viewer_id))
+                            .select(posts::id)
+                            .into_boxed(),
+                    ),
+                );
+                if let Some(viewer_id) = viewer_id {
+                    predicate = Box::new(
+                        predicate
+                            .and(
+                                comments::user_id.ne_all(
+                                    blocks::table
+                                        .filter(blocks::blocker_id.eq(viewer_id))
+                                        .select(blocks::blocked_id),
+                                ),
+                            )
+                            .and(
+                                comments::user_id.ne_all(
+                                    blocks::table
+                                        .filter(blocks::blocked_id.eq(viewer_id))
+                                        .select(blocks::blocker_id),
+                                ),
+                            ),
+                    );
+                }
+                if let Some(author_id) = filters.author {
+                    predicate = Box::new(predicate.and(comments::user_id.eq(author_id)));
+                }
+                if let Some(since) = filters.since {
+                    predicate = Box::new(predicate.and(comments::created_at.ge(since)));
+                }
+                if let Some(until) = filters.until {
+                    predicate = Box::new(predicate.and(comments::created_at.le(until)));
+                }
+                if let Some(hashtag) = filters.hashtag {
+                    predicate = Box::new(
+                        predicate.and(
+                            comments::id.eq_any(
+                                comment_hashtags::table
+                                    .inner_join(hashtags::table)
+                                    .filter(hashtags::name.eq(normalize_hashtag(&hashtag)))
+                                    .select(comment_hashtags::comment_id),
+                            ),
+                        ),
+                    );
+                }
+
+                let rows: Vec<(Comment, f32, String)> = comments::table
+                    .filter(with_tsquery::<Bool>(
+                        "comments.search_vector @@ ",
+                        &language,
+                        &filters.q,
+                        "",
+                    ))
+                    .filter(predicate)
+                    .select((
+                        Comment::as_select(),
+                        with_tsquery::<Float>(
+                            "ts_rank(comments.search_vector, ",
+                            &language,
+                            &filters.q,
+                            ") AS rank",
+                        ),
+                        headline_sql("comments", &language, &filters.q),
+                    ))
+                    .order((sql::<Float>("rank").desc(), comments::created_at.desc()))
+                    .limit(limit)
+                    .offset(offset)
+                    .load(conn)?;
+
+                let (comments, scores): (Vec<Comment>, Vec<(f32, String)>) = rows
+                    .into_iter()
+                    .map(|(comment, rank, highlight)| (comment, (rank, highlight)))
+                    .unzip();
+                Ok(load_comment_views(conn, comments)?
+                    .into_iter()
+                    .zip(scores)
+                    .map(|(comment, (rank, highlight))| CommentSearchResult {
+                        comment,
+                        rank,
+                        highlight,
+                    })
+                    .collect())
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(results)
+    }
+
+    // Posts from the viewer and the accounts they follow, minus muted accounts
+    pub async fn get_feed(
+        &self,
+        viewer_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PostView>, DbError> {
+        let conn = self.pool.get().await?;
+        let posts = conn
+            .interact(move |conn| {
+                let posts = posts::table
+                    .filter(
+                        posts::user_id.eq(viewer_id).or(posts::user_id.eq_any(
+                            follows::table
+                                .filter(follows::follower_id.eq(viewer_id))
+                                .select(follows::following_id),
+                        )),
+                    )
+                    .filter(
+                        posts::user_id.ne_all(
+                            mutes::table
+                                .filter(mutes::muter_id.eq(viewer_id))
+                                .select(mutes::muted_id),
+                        ),
+                    )
+                    .filter(visible_to(Some(viewer_id)))
+                    .order(posts::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(Post::as_select())
+                    .load(conn)?;
+                load_post_views(conn, posts, Some(viewer_id))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(posts)
+    }
+
+    // Comment operations
+    // `user_id` is the authenticated author, who must own any attached media
+    pub async fn create_comment(
+        &self,
+        user_id: Uuid,
+        mut new_comment: NewComment,
+    ) -> Result<CommentView, DbError> {
+        new_comment.user_id = user_id;
+        let conn = self.pool.get().await?;
+        let comment = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    ensure_not_blocked_by_author(conn, new_comment.post_id, new_comment.user_id)?;
+                    ensure_visible_target(conn, new_comment.post_id, new_comment.user_id)?;
+                    let processed =
+                        process_content(conn, new_comment.user_id, &new_comment.content)?;
+
+                    let comment = diesel::insert_into(comments::table)
+                        .values((
+                            &new_comment,
+                            comments::entities.eq(&processed.entities),
+                            comments::content_html.eq(&processed.html),
+                        ))
+                        .returning(Comment::as_returning())
+                        .get_result(conn)?;
+
+                    attach_media(
+                        conn,
+                        comment.user_id,
+                        comment.post_id,
+                        Some(comment.id),
+                        &new_comment.media_ids,
+                    )?;
+                    sync_comment_hashtags(conn, comment.id, &comment.content)?;
+                    let mentioned = store_mentions(
+                        conn,
+                        comment.post_id,
+                        Some(comment.id),
+                        &comment.content,
+                        &processed.mentions,
+                    )?;
+                    notify_mentions(
+                        conn,
+                        comment.user_id,
+                        comment.post_id,
+                        Some(comment.id),
+                        &mentioned,
+                    )?;
+
+                    let post_author_id = posts::table
+                        .find(comment.post_id)
+                        .select(posts::user_id)
+                        .first(conn)?;
+                    notify(
+                        conn,
+                        post_author_id,
+                        comment.user_id,
+                        NotificationKind::Comment,
+                        Some(comment.post_id),
+                        Some(comment.id),
+                    )?;
+                    record_event(
+                        conn,
+                        DomainEvent::CommentCreated {
+                            comment: comment.clone(),
+                            post_author_id,
+                        },
+                    )?;
+
+                    Ok(load_comment_views(conn, vec![comment])?.remove(0))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(comment)
+    }
+
+    // Returns None when the comment does not exist or belongs to someone else
+    pub async fn update_comment(
+        &self,
+        comment_id: Uuid,
+        user_id: Uuid,
+        content: String,
+    ) -> Result<Option<CommentView>, DbError> {
+        let conn = self.pool.get().await?;
+        let comment = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let processed = process_content(conn, user_id, &content)?;
+
+                    let comment = diesel::update(
+                        comments::table
+                            .filter(comments::id.eq(comment_id))
+                            .filter(comments::user_id.eq(user_id)),
+                    )
+                    .set((
+                        comments::content.eq(&content),
+                        comments::entities.eq(&processed.entities),
+                        comments::content_html.eq(&processed.html),
+                    ))
+                    .returning(Comment::as_returning())
+                    .get_result(conn)
+                    .optional()?;
+
+                    let Some(comment) = comment else {
+                        return Ok(None);
+                    };
+                    sync_comment_hashtags(conn, comment.id, &comment.content)?;
+                    let mentioned = store_mentions(
+                        conn,
+                        comment.post_id,
+                        Some(comment.id),
+                        &comment.content,
+                        &processed.mentions,
+                    )?;
+                    notify_mentions(
+                        conn,
+                        comment.user_id,
+                        comment.post_id,
+                        Some(comment.id),
+                        &mentioned,
+                    )?;
+
+                    Ok(load_comment_views(conn, vec![comment])?.pop())
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(comment)
+    }
+
+    // Returns None when the post does not exist or is hidden from the viewer
+    pub async fn get_post_comments(
+        &self,
+        post_id: Uuid,
+        viewer_id: Option<Uuid>,
+    ) -> Result<Option<Vec<CommentView>>, DbError> {
+        let conn = self.pool.get().await?;
+        let comments = conn
+            .interact(move |conn| {
+                if find_visible_post(conn, post_id, viewer_id)?.is_none() {
+                    return Ok(None);
+                }
+                let mut query = comments::table
+                    .filter(comments::post_id.eq(post_id))
+                    .into_boxed();
+                if let Some(viewer_id) = viewer_id {
+                    query = query
+                        .filter(
+                            comments::user_id.ne_all(
+                                blocks::table
+                                    .filter(blocks::blocker_id.eq(viewer_id))
+                                    .select(blocks::blocked_id),
+                            ),
+                        )
+                        .filter(
+                            comments::user_id.ne_all(
+                                blocks::table
+                                    .filter(blocks::blocked_id.eq(viewer_id))
+                                    .select(blocks::blocker_id),
+                            ),
+                        );
+                }
+                let comments = query
+                    .order(comments::created_at.asc())
+                    .select(Comment::as_select())
+                    .load(conn)?;
+                load_comment_views(conn, comments).map(Some)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(comments)
+    }
+
+    // Interaction operations
+    pub async fn like_post(&self, post_id: Uuid, user_id: Uuid) -> Result<Interaction, DbError> {
+        let conn = self.pool.get().await?;
+        let interaction = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    ensure_not_blocked_by_author(conn, post_id, user_id)?;
+                    ensure_visible_target(conn, post_id, user_id)?;
+
+                    // Create interaction
+                    let interaction = diesel::insert_into(interactions::table)
+                        .values(NewInteraction {
+                            post_id,
+                            user_id,
+                            interaction_type: "like".to_string(),
+                        })
+                        .returning(Interaction::as_returning())
+                        .get_result(conn)?;
+
+                    // Update likes count
+                    let author_id = diesel::update(posts::table.filter(posts::id.eq(post_id)))
+                        .set(posts::likes_count.eq(posts::likes_count + 1))
+                        .returning(posts::user_id)
+                        .get_result(conn)?;
+
+                    notify(
+                        conn,
+                        author_id,
+                        user_id,
+                        NotificationKind::Like,
+                        Some(post_id),
+                        None,
+                    )?;
+                    record_event(
+                        conn,
+                        DomainEvent::PostLiked {
+                            interaction: interaction.clone(),
+                            post_author_id: author_id,
+                        },
+                    )?;
+
+                    Ok(interaction)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(interaction)
+    }
+
+    pub async fn share_post(&self, post_id: Uuid, user_id: Uuid) -> Result<Interaction, DbError> {
+        let conn = self.pool.get().await?;
+        let interaction = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    ensure_not_blocked_by_author(conn, post_id, user_id)?;
+                    ensure_visible_target(conn, post_id, user_id)?;
+
+                    // Create interaction
+                    let interaction = diesel::insert_into(interactions::table)
+                        .values(NewInteraction {
+                            post_id,
+                            user_id,
+                            interaction_type: "share".to_string(),
+                        })
+                        .returning(Interaction::as_returning())
+                        .get_result(conn)?;
+
+                    // Update shares count
+                    let author_id = diesel::update(posts::table.filter(posts::id.eq(post_id)))
+                        .set(posts::shares_count.eq(posts::shares_count + 1))
+                        .returning(posts::user_id)
+                        .get_result(conn)?;
+
+                    notify(
+                        conn,
+                        author_id,
+                        user_id,
+                        NotificationKind::Share,
+                        Some(post_id),
+                        None,
+                    )?;
+                    record_event(
+                        conn,
+                        DomainEvent::PostShared {
+                            interaction: interaction.clone(),
+                            post_author_id: author_id,
+                        },
+                    )?;
+
+                    Ok(interaction)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(interaction)
+    }
+
+    // Follow operations
+    pub async fn follow_user(
+        &self,
+        follower_id: Uuid,
+        following_id: Uuid,
+    ) -> Result<FollowOutcome, DbError> {
+        let conn = self.pool.get().await?;
+        let outcome = conn
+            .interact(move |conn| {
+                conn.transaction::<_, DbError, _>(|conn| {
+                    if is_blocked_between(conn, follower_id, following_id)? {
+                        return Err(Box::new(BlockedError));
+                    }
+
+                    let is_private: bool = users::table
+                        .filter(users::id.eq(following_id))
+                        .select(users::is_private)
+                        .first(conn)?;
+                    let already_following = diesel::select(diesel::dsl::exists(
+                        follows::table
+                            .filter(follows::follower_id.eq(follower_id))
+                            .filter(follows::following_id.eq(following_id)),
+                    ))
+                    .get_result::<bool>(conn)?;
+
+                    // Private accounts have to approve new followers
+                    if is_private && !already_following {
+                        let request = diesel::insert_into(follow_requests::table)
+                            .values(NewFollowRequest {
+                                requester_id: follower_id,
+                                target_id: following_id,
+                            })
+                            .returning(FollowRequest::as_returning())
+                            .get_result(conn)?;
+                        notify(
+                            conn,
+                            following_id,
+                            follower_id,
+                            NotificationKind::FollowRequest,
+                            None,
+                            None,
+                        )?;
+                        return Ok(FollowOutcome::Requested(request));
+                    }
+
+                    let follow = insert_follow(conn, follower_id, following_id)?;
+                    notify(
+                        conn,
+                        following_id,
+                        follower_id,
+                        NotificationKind::Follow,
+                        None,
+                        None,
+                    )?;
+                    Ok(FollowOutcome::Followed(follow))
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(outcome)
+    }
+
+    pub async fn set_account_private(
+        &self,
+        user_id: Uuid,
+        is_private: bool,
+    ) -> Result<Option<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let user = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let user: Option<User> =
+                        diesel::update(users::table.filter(users::id.eq(user_id)))
+                            .set(users::is_private.eq(is_private))
+                            .returning(User::as_returning())
+                            .get_result(conn)
+                            .optional()?;
+                    if user.is_none() || is_private {
+                        return Ok(user);
+                    }
+
+                    // A public account has nothing left to approve, so pending requests
+                    // become follows
+                    let requester_ids: Vec<Uuid> = diesel::delete(
+                        follow_requests::table.filter(follow_requests::target_id.eq(user_id)),
+                    )
+                    .returning(follow_requests::requester_id)
+                    .get_results(conn)?;
+                    for requester_id in requester_ids {
+                        insert_follow(conn, requester_id, user_id)?;
+                    }
+
+                    // Re-read for the updated follower count
+                    users::table
+                        .filter(users::id.eq(user_id))
+                        .select(User::as_select())
+                        .first(conn)
+                        .optional()
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(user)
+    }
+
+    // Users waiting for the target to approve their follow request
+    pub async fn get_follow_requests(&self, target_id: Uuid) -> Result<Vec<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let requesters = conn
+            .interact(move |conn| {
+                follow_requests::table
+                    .inner_join(users::table.on(follow_requests::requester_id.eq(users::id)))
+                    .filter(follow_requests::target_id.eq(target_id))
+                    .order(follow_requests::created_at.asc())
+                    .select(User::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(requesters)
+    }
+
+    // Returns None when there is no pending request from the requester
+    pub async fn approve_follow_request(
+        &self,
+        target_id: Uuid,
+        requester_id: Uuid,
+    ) -> Result<Option<Follow>, DbError> {
+        let conn = self.pool.get().await?;
+        let follow = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let deleted = diesel::delete(
+                        follow_requests::table
+                            .filter(follow_requests::requester_id.eq(requester_id))
+                            .filter(follow_requests::target_id.eq(target_id)),
+                    )
+                    .execute(conn)?;
+                    if deleted == 0 {
+                        return Ok(None);
+                    }
+
+                    insert_follow(conn, requester_id, target_id).map(Some)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(follow)
+    }
+
+    // Returns false when there is no pending request from the requester
+    pub async fn reject_follow_request(
+        &self,
+        target_id: Uuid,
+        requester_id: Uuid,
+    ) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    follow_requests::table
+                        .filter(follow_requests::requester_id.eq(requester_id))
+                        .filter(follow_requests::target_id.eq(target_id)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn get_user_followers(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let followers = conn
+            .interact(move |conn| {
+                follows::table
+                    .inner_join(users::table.on(follows::follower_id.eq(users::id)))
+                    .filter(follows::following_id.eq(user_id))
+                    .select(User::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(followers)
+    }
+
+    pub async fn get_user_following(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let following = conn
+            .interact(move |conn| {
+                follows::table
+                    .inner_join(users::table.on(follows::following_id.eq(users::id)))
+                    .filter(follows::follower_id.eq(user_id))
+                    .select(User::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(following)
+    }
+
+    // Block operations
+    pub async fn block_user(&self, blocker_id: Uuid, blocked_id: Uuid) -> Result<Block, DbError> {
+        let conn = self.pool.get().await?;
+        let block = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    // Create block
+                    let block = diesel::insert_into(blocks::table)
+                        .values(NewBlock {
+                            blocker_id,
+                            blocked_id,
+                        })
+                        .returning(Block::as_returning())
+                        .get_result(conn)?;
+
+                    // Drop follows in both directions
+                    remove_follow(conn, blocker_id, blocked_id)?;
+                    remove_follow(conn, blocked_id, blocker_id)?;
+
+                    // Drop pending follow requests in both directions
+                    diesel::delete(
+                        follow_requests::table.filter(
+                            (follow_requests::requester_id.eq(blocker_id))
+                                .and(follow_requests::target_id.eq(blocked_id))
+                                .or(follow_requests::requester_id
+                                    .eq(blocked_id)
+                                    .and(follow_requests::target_id.eq(blocker_id))),
+                        ),
+                    )
+                    .execute(conn)?;
+
+                    Ok(block)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(block)
+    }
+
+    pub async fn unblock_user(&self, blocker_id: Uuid, blocked_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    blocks::table
+                        .filter(blocks::blocker_id.eq(blocker_id))
+                        .filter(blocks::blocked_id.eq(blocked_id)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn get_blocked_users(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let blocked = conn
+            .interact(move |conn| {
+                blocks::table
+                    .inner_join(users::table.on(blocks::blocked_id.eq(users::id)))
+                    .filter(blocks::blocker_id.eq(user_id))
+                    .select(User::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(blocked)
+    }
+
+    // Mute operations
+    pub async fn mute_user(&self, muter_id: Uuid, muted_id: Uuid) -> Result<Mute, DbError> {
+        let conn = self.pool.get().await?;
+        let mute = conn
+            .interact(move |conn| {
+                diesel::insert_into(mutes::table)
+                    .values(NewMute { muter_id, muted_id })
+                    .returning(Mute::as_returning())
+                    .get_result(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(mute)
+    }
+
+    pub async fn unmute_user(&self, muter_id: Uuid, muted_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    mutes::table
+                        .filter(mutes::muter_id.eq(muter_id))
+                        .filter(mutes::muted_id.eq(muted_id)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn get_muted_users(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
+        let conn = self.pool.get().await?;
+        let muted = conn
+            .interact(move |conn| {
+                mutes::table
+                    .inner_join(users::table.on(mutes::muted_id.eq(users::id)))
+                    .filter(mutes::muter_id.eq(user_id))
+                    .select(User::as_select())
+                    .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(muted)
+    }
+
+    // Notification operations
+    pub async fn get_notifications(
+        &self,
+        user_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<NotificationsPage, DbError> {
+        let conn = self.pool.get().await?;
+        let page = conn
+            .interact(move |conn| {
+                let unread_count = notifications::table
+                    .filter(notifications::user_id.eq(user_id))
+                    .filter(notifications::read_at.is_null())
+                    .count()
+                    .get_result(conn)?;
+
+                let summaries: Vec<NotificationGroupRow> = notifications::table
+                    .filter(notifications::user_id.eq(user_id))
+                    .group_by(notifications::group_key)
+                    .select((
+                        notifications::group_key,
+                        max(notifications::created_at),
+                        count(notifications::actor_id).aggregate_distinct(),
+                        count_star(),
+                        count(notifications::read_at),
+                    ))
+                    .order(max(notifications::created_at).desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .load(conn)?;
+
+                let mut groups = Vec::with_capacity(summaries.len());
+                for (group_key, latest_at, actors_count, total, read) in summaries {
+                    let latest: Notification = notifications::table
+                        .filter(notifications::user_id.eq(user_id))
+                        .filter(notifications::group_key.eq(&group_key))
+                        .order(notifications::created_at.desc())
+                        .select(Notification::as_select())
+                        .first(conn)?;
+
+                    let mut actors: Vec<User> = Vec::new();
+                    let recent: Vec<User> = notifications::table
+                        .inner_join(users::table.on(notifications::actor_id.eq(users::id)))
+                        .filter(notifications::user_id.eq(user_id))
+                        .filter(notifications::group_key.eq(&group_key))
+                        .order(notifications::created_at.desc())
+                        .select(User::as_select())
+                        .limit(NOTIFICATION_GROUP_ACTORS as i64 * 4)
+                        .load(conn)?;
+                    for actor in recent {
+                        if actors.len() < NOTIFICATION_GROUP_ACTORS
+                            && !actors.iter().any(|a| a.id == actor.id)
+                        {
+                            actors.push(actor);
+                        }
+                    }
+
+                    groups.push(NotificationGroup {
+                        summary: notification_summary(latest.kind, &actors, actors_count),
+                        group_key,
+                        kind: latest.kind,
+                        post_id: latest.post_id,
+                        comment_id: latest.comment_id,
+                        actors,
+                        actors_count,
+                        unread_count: total - read,
+                        latest_at: latest_at.unwrap_or(latest.created_at),
+                    });
+                }
+
+                Ok(NotificationsPage {
+                    unread_count,
+                    groups,
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(page)
+    }
+
+    pub async fn get_unread_notification_count(&self, user_id: Uuid) -> Result<i64, DbError> {
+        let conn = self.pool.get().await?;
+        let count = conn
+            .interact(move |conn| {
+                notifications::table
+                    .filter(notifications::user_id.eq(user_id))
+                    .filter(notifications::read_at.is_null())
+                    .count()
+                    .get_result(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(count)
+    }
+
+    // Marks the user's unread notifications as read, limited to one group when
+    // `group_key` is given
+    pub async fn mark_notifications_read(
+        &self,
+        user_id: Uuid,
+        group_key: Option<String>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            let unread = notifications::table
+                .filter(notifications::user_id.eq(user_id))
+                .filter(notifications::read_at.is_null());
+            match group_key {
+                Some(group_key) => {
+                    diesel::update(unread.filter(notifications::group_key.eq(group_key)))
+                        .set(notifications::read_at.eq(Utc::now()))
+                        .execute(conn)
+                }
+                None => diesel::update(unread)
+                    .set(notifications::read_at.eq(Utc::now()))
+                    .execute(conn),
+            }
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    pub async fn get_notification_preferences(
+        &self,
+        user_id: Uuid,
+    ) -> Result<NotificationPreferences, DbError> {
+        let conn = self.pool.get().await?;
+        let preferences = conn
+            .interact(move |conn| load_notification_preferences(conn, user_id))
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(preferences)
+    }
+
+    // Applies the given switches and returns the full set of preferences
+    pub async fn update_notification_preferences(
+        &self,
+        user_id: Uuid,
+        changes: NotificationPreferences,
+    ) -> Result<NotificationPreferences, DbError> {
+        let conn = self.pool.get().await?;
+        let preferences = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let rows: Vec<NotificationPreference> = changes
+                        .into_iter()
+                        .map(|(kind, enabled)| NotificationPreference {
+                            user_id,
+                            kind,
+                            enabled,
+                        })
+                        .collect();
+                    diesel::insert_into(notification_preferences::table)
+                        .values(&rows)
+                        .on_conflict((
+                            notification_preferences::user_id,
+                            notification_preferences::kind,
+                        ))
+                        .do_update()
+                        .set(
+                            notification_preferences::enabled
+                                .eq(excluded(notification_preferences::enabled)),
+                        )
+                        .execute(conn)?;
+
+                    load_notification_preferences(conn, user_id)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(preferences)
+    }
+
+    // Stream operations
+    pub async fn latest_stream_event_id(&self) -> Result<i64, DbError> {
+        let conn = self.pool.get().await?;
+        let id = conn
+            .interact(move |conn| {
+                stream_events::table
+                    .select(max(stream_events::id))
+                    .first::<Option<i64>>(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(id.unwrap_or(0))
+    }
+
+    // Routes every event logged after `after_id` to the connected `recipients`.
+    // Returns the messages and the id of the last event read.
+    pub async fn route_stream_events(
+        &self,
+        after_id: i64,
+        recipients: Vec<Uuid>,
+    ) -> Result<(Vec<(Uuid, StreamMessage)>, i64), DbError> {
+        let conn = self.pool.get().await?;
+        let routed = conn
+            .interact(move |conn| {
+                let mut routed = Vec::new();
+                let mut last_id = after_id;
+                loop {
+                    let events: Vec<StreamEvent> = stream_events::table
+                        .filter(stream_events::id.gt(last_id))
+                        .order(stream_events::id.asc())
+                        .limit(STREAM_EVENT_BATCH)
+                        .select(StreamEvent::as_select())
+                        .load(conn)?;
+                    let Some(last) = events.last() else {
+                        break;
+                    };
+                    last_id = last.id;
+                    for event in &events {
+                        routed.extend(route_stream_event(conn, event, &recipients)?);
+                    }
+                    if (events.len() as i64) < STREAM_EVENT_BATCH {
+                        break;
+                    }
+                }
+                Ok((routed, last_id))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(routed)
+    }
+
+    // Events after `after_id` for a client resuming its stream, oldest first, read
+    // up to the latest event at the time of the call. Returns the messages and the
+    // id of the last event read, whether or not it was for this user.
+    pub async fn get_stream_backlog(
+        &self,
+        user_id: Uuid,
+        after_id: i64,
+    ) -> Result<(Vec<StreamMessage>, i64), DbError> {
+        let conn = self.pool.get().await?;
+        let backlog = conn
+            .interact(move |conn| {
+                let latest_id: i64 = stream_events::table
+                    .select(max(stream_events::id))
+                    .first::<Option<i64>>(conn)?
+                    .unwrap_or(0);
+
+                let mut backlog = Vec::new();
+                let mut last_id = after_id;
+                while last_id < latest_id {
+                    let events: Vec<StreamEvent> = stream_events::table
+                        .filter(stream_events::id.gt(last_id))
+                        .filter(stream_events::id.le(latest_id))
+                        .order(stream_events::id.asc())
+                        .limit(STREAM_EVENT_BATCH)
+                        .select(StreamEvent::as_select())
+                        .load(conn)?;
+                    let Some(last) = events.last() else {
+                        break;
+                    };
+                    last_id = last.id;
+                    for event in &events {
+                        backlog.extend(
+                            route_stream_event(conn, event, &[user_id])?
+                                .into_iter()
+                                .map(|(_, message)| message),
+                        );
+                    }
+                }
+                Ok((backlog, last_id))
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(backlog)
+    }
+
+    pub async fn prune_stream_events(&self, before: DateTime<Utc>) -> Result<usize, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(stream_events::table.filter(stream_events::created_at.lt(before)))
+                    .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted)
+    }
+
+    // Webhook operations
+    pub async fn is_admin(&self, user_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let is_admin = conn
+            .interact(move |conn| {
+                users::table
+                    .find(user_id)
+                    .select(users::is_admin)
+                    .first(conn)
+                    .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(is_admin.unwrap_or(false))
+    }
+
+    pub async fn create_webhook(&self, new_webhook: NewWebhook) -> Result<Webhook, DbError> {
+        let conn = self.pool.get().await?;
+        let webhook = conn
+            .interact(move |conn| {
+                diesel::insert_into(webhooks::table)
+                    .values(&new_webhook)
+                    .returning(Webhook::as_returning())
+                    .get_result(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(webhook)
+    }
+
+    // Webhooks owned by `owner_id`, or the instance-wide ones when it is None
+    pub async fn get_webhooks(&self, owner_id: Option<Uuid>) -> Result<Vec<Webhook>, DbError> {
+        let conn = self.pool.get().await?;
+        let webhooks = conn
+            .interact(move |conn| {
+                let query = webhooks::table
+                    .order(webhooks::created_at.asc())
+                    .select(Webhook::as_select())
+                    .into_boxed();
+                match owner_id {
+                    Some(owner_id) => query.filter(webhooks::user_id.eq(owner_id)),
+                    None => query.filter(webhooks::user_id.is_null()),
+                }
+                .load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(webhooks)
+    }
+
+    // Returns None when the webhook does not exist or the user may not manage it
+    pub async fn get_webhook(
+        &self,
+        webhook_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Webhook>, DbError> {
+        let conn = self.pool.get().await?;
+        let webhook = conn
+            .interact(move |conn| find_manageable_webhook(conn, webhook_id, user_id))
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(webhook)
+    }
+
+    // Returns None when the webhook does not exist or the user may not manage it
+    pub async fn update_webhook(
+        &self,
+        webhook_id: Uuid,
+        user_id: Uuid,
+        changes: UpdateWebhookRequest,
+    ) -> Result<Option<Webhook>, DbError> {
+        let conn = self.pool.get().await?;
+        let webhook = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let Some(webhook) = find_manageable_webhook(conn, webhook_id, user_id)? else {
+                        return Ok(None);
+                    };
+                    let is_active = changes.is_active.unwrap_or(webhook.is_active);
+                    // Re-enabling starts the failure count over
+                    let (consecutive_failures, disabled_at) = if is_active {
+                        (0, None)
+                    } else {
+                        (
+                            webhook.consecutive_failures,
+                            webhook.disabled_at.or(Some(Utc::now())),
+                        )
+                    };
+
+                    diesel::update(webhooks::table.find(webhook_id))
+                        .set((
+                            webhooks::url.eq(changes.url.unwrap_or(webhook.url)),
+                            webhooks::event_types
+                                .eq(changes.event_types.unwrap_or(webhook.event_types)),
+                            webhooks::is_active.eq(is_active),
+                            webhooks::consecutive_failures.eq(consecutive_failures),
+                            webhooks::disabled_at.eq(disabled_at),
+                            webhooks::updated_at.eq(Utc::now()),
+                        ))
+                        .returning(Webhook::as_returning())
+                        .get_result(conn)
+                        .map(Some)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(webhook)
+    }
+
+    // Returns false when the webhook does not exist or the user may not manage it
+    pub async fn delete_webhook(&self, webhook_id: Uuid, user_id: Uuid) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                if find_manageable_webhook(conn, webhook_id, user_id)?.is_none() {
+                    return Ok(false);
+                }
+                diesel::delete(webhooks::table.find(webhook_id))
+                    .execute(conn)
+                    .map(|deleted| deleted > 0)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(deleted)
+    }
+
+    // Delivery log, newest first. Returns None when the webhook does not exist or
+    // the user may not manage it.
+    pub async fn get_webhook_deliveries(
+        &self,
+        webhook_id: Uuid,
+        user_id: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Option<Vec<WebhookDelivery>>, DbError> {
+        let conn = self.pool.get().await?;
+        let deliveries = conn
+            .interact(move |conn| {
+                if find_manageable_webhook(conn, webhook_id, user_id)?.is_none() {
+                    return Ok(None);
+                }
+                webhook_deliveries::table
+                    .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+                    .order(webhook_deliveries::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(WebhookDelivery::as_select())
+                    .load(conn)
+                    .map(Some)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(deliveries)
+    }
+
+    // Claims due deliveries for active webhooks. Claimed deliveries are pushed back
+    // by `lease`, so they are retried if this worker dies before recording a result.
+    pub async fn claim_webhook_deliveries(
+        &self,
+        limit: i64,
+        lease: chrono::Duration,
+    ) -> Result<Vec<(WebhookDelivery, Webhook)>, DbError> {
+        let conn = self.pool.get().await?;
+        let claimed = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let now = Utc::now();
+                    let ids: Vec<Uuid> = webhook_deliveries::table
+                        .filter(webhook_deliveries::status.eq(DeliveryStatus::Pending))
+                        .filter(webhook_deliveries::next_attempt_at.le(now))
+                        .filter(
+                            webhook_deliveries::webhook_id.eq_any(
+                                webhooks::table
+                                    .filter(webhooks::is_active.eq(true))
+                                    .select(webhooks::id),
+                            ),
+                        )
+                        .order(webhook_deliveries::next_attempt_at.asc())
+                        .limit(limit)
+                        .select(webhook_deliveries::id)
+                        .for_update()
+                        .skip_locked()
+                        .load(conn)?;
+
+                    diesel::update(
+                        webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)),
+                    )
+                    .set((
+                        webhook_deliveries::attempts.eq(webhook_deliveries::attempts + 1),
+                        webhook_deliveries::last_attempt_at.eq(now),
+                        webhook_deliveries::next_attempt_at.eq(now + lease),
+                    ))
+                    .execute(conn)?;
+
+                    webhook_deliveries::table
+                        .inner_join(webhooks::table)
+                        .filter(webhook_deliveries::id.eq_any(&ids))
+                        .select((WebhookDelivery::as_select(), Webhook::as_select()))
+                        .load(conn)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(claimed)
+    }
+
+    // Records a delivery attempt. Failures count against the webhook, which is
+    // disabled once `disable_after` attempts in a row have failed.
+    pub async fn record_webhook_attempt(
+        &self,
+        delivery_id: Uuid,
+        webhook_id: Uuid,
+        attempt: WebhookAttempt,
+        disable_after: i32,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.transaction(|conn| {
+                let now = Utc::now();
+                if attempt.succeeded() {
+                    diesel::update(webhook_deliveries::table.find(delivery_id))
+                        .set((
+                            webhook_deliveries::status.eq(DeliveryStatus::Succeeded),
+                            webhook_deliveries::response_status.eq(attempt.response_status),
+                            webhook_deliveries::last_error.eq(None::<String>),
+                            webhook_deliveries::delivered_at.eq(now),
+                        ))
+                        .execute(conn)?;
+                    diesel::update(webhooks::table.find(webhook_id))
+                        .set(webhooks::consecutive_failures.eq(0))
+                        .execute(conn)?;
+                    return Ok(());
+                }
+
+                let status = match attempt.retry_at {
+                    Some(_) => DeliveryStatus::Pending,
+                    None => DeliveryStatus::Failed,
+                };
+                diesel::update(webhook_deliveries::table.find(delivery_id))
+                    .set((
+                        webhook_deliveries::status.eq(status),
+                        webhook_deliveries::response_status.eq(attempt.response_status),
+                        webhook_deliveries::last_error.eq(&attempt.error),
+                        webhook_deliveries::next_attempt_at.eq(attempt.retry_at.unwrap_or(now)),
+                    ))
+                    .execute(conn)?;
+
+                let failures: i32 = diesel::update(webhooks::table.find(webhook_id))
+                    .set(webhooks::consecutive_failures.eq(webhooks::consecutive_failures + 1))
+                    .returning(webhooks::consecutive_failures)
+                    .get_result(conn)?;
+                if failures >= disable_after {
+                    diesel::update(
+                        webhooks::table
+                            .find(webhook_id)
+                            .filter(webhooks::is_active.eq(true)),
+                    )
+                    .set((webhooks::is_active.eq(false), webhooks::disabled_at.eq(now)))
+                    .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Job queue operations
+    pub async fn enqueue_job(
+        &self,
+        job: NewBackgroundJob,
+    ) -> Result<Option<BackgroundJob>, DbError> {
+        let conn = self.pool.get().await?;
+        let job = conn
+            .interact(move |conn| enqueue_job(conn, &job))
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(job)
+    }
+
+    // Claims up to `limit` due jobs, including running ones whose lease has
+    // expired because their worker died. Each claim counts as an attempt.
+    pub async fn claim_jobs(
+        &self,
+        limit: i64,
+        lease: chrono::Duration,
+    ) -> Result<Vec<BackgroundJob>, DbError> {
+        let conn = self.pool.get().await?;
+        let claimed = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let now = Utc::now();
+
+                    // A job whose lease ran out during its last attempt is not run again
+                    let exhausted: Vec<Uuid> = jobs::table
+                        .filter(jobs::status.eq(JobStatus::Running))
+                        .filter(jobs::locked_until.lt(now))
+                        .filter(jobs::attempts.ge(jobs::max_attempts))
+                        .select(jobs::id)
+                        .for_update()
+                        .skip_locked()
+                        .load(conn)?;
+                    if !exhausted.is_empty() {
+                        diesel::update(jobs::table.filter(jobs::id.eq_any(&exhausted)))
+                            .set((
+                                jobs::status.eq(JobStatus::Dead),
+                                jobs::locked_until.eq(None::<DateTime<Utc>>),
+                                jobs::last_error
+                                    .eq("Lease expired before the last attempt finished"),
+                                jobs::finished_at.eq(now),
+                            ))
+                            .execute(conn)?;
+                    }
+
+                    let ids: Vec<Uuid> = jobs::table
+                        .filter(
+                            jobs::status
+                                .eq(JobStatus::Queued)
+                                .and(jobs::run_at.le(now))
+                                .or(jobs::status
+                                    .eq(JobStatus::Running)
+                                    .and(jobs::locked_until.lt(now))
+                                    .and(jobs::attempts.lt(jobs::max_attempts))),
+                        )
+                        .order(jobs::run_at.asc())
+                        .limit(limit)
+                        .select(jobs::id)
+                        .for_update()
+                        .skip_locked()
+                        .load(conn)?;
+
+                    diesel::update(jobs::table.filter(jobs::id.eq_any(&ids)))
+                        .set((
+                            jobs::status.eq(JobStatus::Running),
+                            jobs::attempts.eq(jobs::attempts + 1),
+                            jobs::locked_until.eq(now + lease),
+                        ))
+                        .returning(BackgroundJob::as_returning())
+                        .get_results(conn)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(claimed)
+    }
+
+    // A claim is identified by the job's `attempts`, which every claim increments, so
+    // a worker whose lease expired and was taken over cannot record a result. Returns
+    // false when the claim was lost.
+    pub async fn complete_job(&self, job_id: Uuid, attempts: i32) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let updated = conn
+            .interact(move |conn| {
+                diesel::update(
+                    jobs::table
+                        .find(job_id)
+                        .filter(jobs::status.eq(JobStatus::Running))
+                        .filter(jobs::attempts.eq(attempts)),
+                )
+                .set((
+                    jobs::status.eq(JobStatus::Completed),
+                    jobs::locked_until.eq(None::<DateTime<Utc>>),
+                    jobs::last_error.eq(None::<String>),
+                    jobs::finished_at.eq(Utc::now()),
+                ))
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(updated > 0)
+    }
+
+    // Requeues a failed job to run at `retry_at`, or moves it to the dead letters
+    // when that is None. Like `complete_job`, only applies to the claim made at
+    // `attempts` and returns false when it was lost.
+    pub async fn fail_job(
+        &self,
+        job_id: Uuid,
+        attempts: i32,
+        error: String,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, DbError> {
+        let conn = self.pool.get().await?;
+        let updated = conn
+            .interact(move |conn| {
+                let target = jobs::table
+                    .find(job_id)
+                    .filter(jobs::status.eq(JobStatus::Running))
+                    .filter(jobs::attempts.eq(attempts));
+                match retry_at {
+                    Some(retry_at) => diesel::update(target)
+                        .set((
+                            jobs::status.eq(JobStatus::Queued),
+                            jobs::run_at.eq(retry_at),
+                            jobs::locked_until.eq(None::<DateTime<Utc>>),
+                            jobs::last_error.eq(error),
+                        ))
+                        .execute(conn),
+                    None => diesel::update(target)
+                        .set((
+                            jobs::status.eq(JobStatus::Dead),
+                            jobs::locked_until.eq(None::<DateTime<Utc>>),
+                            jobs::last_error.eq(error),
+                            jobs::finished_at.eq(Utc::now()),
+                        ))
+                        .execute(conn),
+                }
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(updated > 0)
+    }
+
+    pub async fn get_jobs(
+        &self,
+        status: Option<JobStatus>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<BackgroundJob>, DbError> {
+        let conn = self.pool.get().await?;
+        let jobs = conn
+            .interact(move |conn| {
+                let mut query = jobs::table
+                    .order(jobs::created_at.desc())
+                    .limit(limit)
+                    .offset(offset)
+                    .select(BackgroundJob::as_select())
+                    .into_boxed();
+                if let Some(status) = status {
+                    query = query.filter(jobs::status.eq(status));
+                }
+                query.load(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(jobs)
+    }
+
+    // Requeues a dead job with a fresh set of attempts. Returns None unless the job
+    // exists and is dead. The dedupe key is dropped so the retry cannot clash with
+    // a job queued since.
+    pub async fn retry_job(&self, job_id: Uuid) -> Result<Option<BackgroundJob>, DbError> {
+        let conn = self.pool.get().await?;
+        let job = conn
+            .interact(move |conn| {
+                diesel::update(
+                    jobs::table
+                        .find(job_id)
+                        .filter(jobs::status.eq(JobStatus::Dead)),
+                )
+                .set((
+                    jobs::status.eq(JobStatus::Queued),
+                    jobs::attempts.eq(0),
+                    jobs::run_at.eq(Utc::now()),
+                    jobs::dedupe_key.eq(None::<String>),
+                    jobs::finished_at.eq(None::<DateTime<Utc>>),
+                ))
+                .returning(BackgroundJob::as_returning())
+                .get_result(conn)
+                .optional()
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(job)
+    }
+
+    // Deletes completed jobs that finished before `before`; dead jobs are kept
+    pub async fn prune_completed_jobs(&self, before: DateTime<Utc>) -> Result<usize, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
+            .interact(move |conn| {
+                diesel::delete(
+                    jobs::table
+                        .filter(jobs::status.eq(JobStatus::Completed))
+                        .filter(jobs::finished_at.lt(before)),
+                )
+                .execute(conn)
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted)
+    }
+
+    // Outbox operations
+
+    // Claims unpublished events that are due, oldest first. Claimed events are pushed
+    // back by `lease`, so they are published again if this relay dies first, unless
+    // that was their last attempt: those are moved to the dead letters instead.
+    pub async fn claim_outbox_events(
+        &self,
+        limit: i64,
+        lease: chrono::Duration,
+        max_attempts: i32,
+    ) -> Result<Vec<OutboxEvent>, DbError> {
+        let conn = self.pool.get().await?;
+        let claimed = conn
+            .interact(move |conn| {
+                conn.transaction(|conn| {
+                    let now = Utc::now();
+                    diesel::update(
+                        outbox_events::table
+                            .filter(outbox_events::published_at.is_null())
+                            .filter(outbox_events::dead_at.is_null())
+                            .filter(outbox_events::next_attempt_at.le(now))
+                            .filter(outbox_events::attempts.ge(max_attempts)),
+                    )
+                    .set((
+                        outbox_events::dead_at.eq(now),
+                        outbox_events::last_error
+                            .eq("Lease expired before the last attempt finished"),
+                    ))
+                    .execute(conn)?;
+
+                    let ids: Vec<i64> = outbox_events::table
+                        .filter(outbox_events::published_at.is_null())
+                        .filter(outbox_events::dead_at.is_null())
+                        .filter(outbox_events::next_attempt_at.le(now))
+                        .order(outbox_events::id.asc())
+                        .limit(limit)
+                        .select(outbox_events::id)
+                        .for_update()
+                        .skip_locked()
+                        .load(conn)?;
+
+                    diesel::update(outbox_events::table.filter(outbox_events::id.eq_any(&ids)))
+                        .set((
+                            outbox_events::attempts.eq(outbox_events::attempts + 1),
+                            outbox_events::next_attempt_at.eq(now + lease),
+                        ))
+                        .returning(OutboxEvent::as_returning())
+                        .get_results::<OutboxEvent>(conn)
+                        .map(|mut events| {
+                            events.sort_by_key(|e| e.id);
+                            events
+                        })
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)?
+            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(claimed)
+    }
+
+    pub async fn mark_outbox_event_published(&self, event_id: i64) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(outbox_events::table.find(event_id))
+                .set((
+                    outbox_events::published_at.eq(Utc::now()),
+                    outbox_events::last_error.eq(None::<String>),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
 
-impl Clone for Database {
-    fn clone(&self) -> Self {
-        Self {
-            pool: self.pool.clone(),
-        }
+    pub async fn retry_outbox_event(
+        &self,
+        event_id: i64,
+        error: String,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(outbox_events::table.find(event_id))
+                .set((
+                    outbox_events::next_attempt_at.eq(retry_at),
+                    outbox_events::last_error.eq(error),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
     }
-}
 
-impl Database {
-    pub fn new(pool: deadpool_diesel::postgres::Pool) -> Self {
-        Self { pool }
+    // Requeues an event that was claimed but not attempted because an earlier event
+    // in its batch failed, giving back the attempt its claim counted
+    pub async fn hold_back_outbox_event(
+        &self,
+        event_id: i64,
+        error: String,
+        retry_at: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(outbox_events::table.find(event_id))
+                .set((
+                    outbox_events::attempts.eq(outbox_events::attempts - 1),
+                    outbox_events::next_attempt_at.eq(retry_at),
+                    outbox_events::last_error.eq(error),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
     }
 
-    // User operations
-    pub async fn create_user(&self, new_user: NewUser) -> Result<User, DbError> {
+    // Gives up on an event, keeping it with its error for inspection
+    pub async fn dead_letter_outbox_event(
+        &self,
+        event_id: i64,
+        error: String,
+    ) -> Result<(), DbError> {
         let conn = self.pool.get().await?;
-        let user = conn
+        conn.interact(move |conn| {
+            diesel::update(outbox_events::table.find(event_id))
+                .set((
+                    outbox_events::dead_at.eq(Utc::now()),
+                    outbox_events::last_error.eq(error),
+                ))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Deletes events published before `before`; unpublished events are kept
+    pub async fn prune_published_outbox_events(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<usize, DbError> {
+        let conn = self.pool.get().await?;
+        let deleted = conn
             .interact(move |conn| {
-                diesel::insert_into(users::table)
-                    .values(&new_user)
-                    .returning(User::as_returning())
-                    .get_result(conn)
+                diesel::delete(outbox_events::table.filter(outbox_events::published_at.lt(before)))
+                    .execute(conn)
             })
             .await
             .map_err(interact_error_to_db_error)?
-            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(user)
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted)
     }
 
-    pub async fn get_user(&self, user_id: Uuid) -> Result<Option<User>, DbError> {
+    // Deletes expired email changes, password setups and deletion confirmations
+    pub async fn prune_expired_confirmations(&self, now: DateTime<Utc>) -> Result<usize, DbError> {
         let conn = self.pool.get().await?;
-        let user = conn
+        let deleted = conn
             .interact(move |conn| {
-                users::table
-                    .filter(users::id.eq(user_id))
-                    .select(User::as_select())
-                    .first(conn)
-                    .optional()
+                let changes = diesel::delete(
+                    email_change_requests::table.filter(email_change_requests::expires_at.le(now)),
+                )
+                .execute(conn)?;
+                let setups = diesel::delete(
+                    password_setup_requests::table
+                        .filter(password_setup_requests::expires_at.le(now)),
+                )
+                .execute(conn)?;
+                let deletions = diesel::delete(
+                    account_deletion_confirmations::table
+                        .filter(account_deletion_confirmations::expires_at.le(now)),
+                )
+                .execute(conn)?;
+                Ok::<_, diesel::result::Error>(changes + setups + deletions)
             })
             .await
             .map_err(interact_error_to_db_error)?
-            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(user)
+            .map_err(|e| Box::new(e) as DbError)?;
+        Ok(deleted)
     }
 
-    pub async fn get_user_by_username(&self, username_str: &str) -> Result<Option<User>, DbError> {
-        let username = username_str.to_string();
+    // Queues `event` for every active webhook subscribed to its type that is either
+    // instance-wide or owned by one of the users involved. Webhooks that already
+    // have a delivery for the event are skipped.
+    pub async fn enqueue_webhook_deliveries(&self, event: &PublishedEvent) -> Result<(), DbError> {
+        let event_type = event.event.event_type();
+        let owners: Vec<Option<Uuid>> =
+            event.event.involved_users().into_iter().map(Some).collect();
+        let event_id = event.id;
+        let payload = serde_json::to_value(event)?;
+
         let conn = self.pool.get().await?;
-        let user = conn
+        conn.interact(move |conn| {
+            let webhook_ids: Vec<Uuid> = webhooks::table
+                .filter(webhooks::is_active.eq(true))
+                .filter(webhooks::event_types.contains(vec![event_type]))
+                .filter(
+                    webhooks::user_id
+                        .is_null()
+                        .or(webhooks::user_id.eq_any(owners)),
+                )
+                .select(webhooks::id)
+                .load(conn)?;
+
+            diesel::insert_into(webhook_deliveries::table)
+                .values(
+                    webhook_ids
+                        .into_iter()
+                        .map(|webhook_id| NewWebhookDelivery {
+                            webhook_id,
+                            event_type,
+                            payload: payload.clone(),
+                            event_id: Some(event_id),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .on_conflict_do_nothing()
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+
+    // Media operations
+    // Saves an upload along with the job that processes it
+    pub async fn create_media(
+        &self,
+        new_media: NewMedia,
+        processing: NewBackgroundJob,
+    ) -> Result<MediaView, DbError> {
+        let conn = self.pool.get().await?;
+        let media = conn
             .interact(move |conn| {
-                users::table
-                    .filter(users::username.eq(username))
-                    .select(User::as_select())
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let media = insert_media(conn, &new_media)?;
+                    enqueue_job(conn, &processing)?;
+                    Ok(media)
+                })
+            })
+            .await
+            .map_err(interact_error_to_db_error)??;
+        Ok(MediaView {
+            media,
+            variants: Vec::new(),
+        })
+    }
+
+    pub async fn get_media(&self, media_id: Uuid) -> Result<Option<MediaView>, DbError> {
+        let conn = self.pool.get().await?;
+        let media = conn
+            .interact(move |conn| {
+                let Some(media) = media::table
+                    .find(media_id)
+                    .select(Media::as_select())
                     .first(conn)
-                    .optional()
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+                let variants = media_variants::table
+                    .filter(media_variants::media_id.eq(media_id))
+                    .order(media_variants::width.asc())
+                    .select(MediaVariant::as_select())
+                    .load(conn)?;
+                Ok(Some(MediaView { media, variants }))
             })
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(user)
+        Ok(media)
     }
 
-    // Post operations
-    pub async fn create_post(&self, new_post: NewPost) -> Result<Post, DbError> {
+    // Returns None when the media does not exist or was uploaded by someone else
+    pub async fn update_media(
+        &self,
+        media_id: Uuid,
+        user_id: Uuid,
+        changes: UpdateMediaRequest,
+    ) -> Result<Option<MediaView>, DbError> {
         let conn = self.pool.get().await?;
-        let post = conn
+        let updated = conn
             .interact(move |conn| {
-                diesel::insert_into(posts::table)
-                    .values(&new_post)
-                    .returning(Post::as_returning())
-                    .get_result(conn)
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let Some(current) = media::table
+                        .find(media_id)
+                        .filter(media::user_id.eq(user_id))
+                        .select(Media::as_select())
+                        .for_update()
+                        .first(conn)
+                        .optional()?
+                    else {
+                        return Ok(None);
+                    };
+                    let cleared = |text: Option<String>, current: Option<String>| match text {
+                        Some(text) if text.trim().is_empty() => None,
+                        Some(text) => Some(text),
+                        None => current,
+                    };
+                    let alt_text = cleared(changes.alt_text, current.alt_text);
+                    if alt_text.is_none() && crate::media::require_alt_text() {
+                        let attached = diesel::select(diesel::dsl::exists(
+                            media_attachments::table
+                                .filter(media_attachments::media_id.eq(media_id)),
+                        ))
+                        .get_result(conn)?;
+                        if attached {
+                            return Err(Box::new(MissingAltTextError));
+                        }
+                    }
+
+                    let media = diesel::update(media::table.find(media_id))
+                        .set((
+                            media::alt_text.eq(alt_text),
+                            media::caption.eq(cleared(changes.caption, current.caption)),
+                            media::focal_x.eq(changes.focal_x.unwrap_or(current.focal_x)),
+                            media::focal_y.eq(changes.focal_y.unwrap_or(current.focal_y)),
+                        ))
+                        .returning(Media::as_returning())
+                        .get_result(conn)?;
+                    let variants = media_variants::table
+                        .filter(media_variants::media_id.eq(media_id))
+                        .order(media_variants::width.asc())
+                        .select(MediaVariant::as_select())
+                        .load(conn)?;
+                    Ok(Some(MediaView { media, variants }))
+                })
             })
             .await
-            .map_err(interact_error_to_db_error)?
-            .map_err(|e| Box::new(e) as DbError)?;
-        Ok(post)
+            .map_err(interact_error_to_db_error)??;
+        Ok(updated)
     }
 
-    pub async fn get_posts(&self, limit: i64, offset: i64) -> Result<Vec<Post>, DbError> {
+    // Saves an already processed avatar or banner and makes it the user's current one.
+    // The one it replaces is left for the media collector.
+    pub async fn set_profile_image(
+        &self,
+        kind: ProfileImageKind,
+        new_media: NewMedia,
+        variants: Vec<NewMediaVariant>,
+    ) -> Result<(User, MediaView), DbError> {
         let conn = self.pool.get().await?;
-        let posts = conn
+        let user = conn
             .interact(move |conn| {
-                posts::table
-                    .order(posts::created_at.desc())
-                    .limit(limit)
-                    .offset(offset)
-                    .select(Post::as_select())
-                    .load(conn)
+                conn.transaction::<_, DbError, _>(|conn| {
+                    let media = insert_media(conn, &new_media)?;
+                    let variants = diesel::insert_into(media_variants::table)
+                        .values(&variants)
+                        .returning(MediaVariant::as_returning())
+                        .get_results(conn)?;
+                    let user = diesel::update(users::table.find(media.user_id));
+                    let user = match kind {
+                        ProfileImageKind::Avatar => user
+                            .set((
+                                users::image_url.eq(&media.url),
+                                users::updated_at.eq(Utc::now()),
+                            ))
+                            .returning(User::as_returning())
+                            .get_result(conn)?,
+                        ProfileImageKind::Banner => user
+                            .set((
+                                users::banner_url.eq(&media.url),
+                                users::updated_at.eq(Utc::now()),
+                            ))
+                            .returning(User::as_returning())
+                            .get_result(conn)?,
+                    };
+                    Ok((user, MediaView { media, variants }))
+                })
             })
             .await
-            .map_err(interact_error_to_db_error)?
-            .map_err(|e| Box::new(e) as DbError)?;
-        Ok(posts)
+            .map_err(interact_error_to_db_error)??;
+        Ok(user)
     }
 
-    pub async fn get_user_posts(
+    // Puts back the generated avatar, or removes the banner
+    pub async fn remove_profile_image(
         &self,
         user_id: Uuid,
-        limit: i64,
-        offset: i64,
-    ) -> Result<Vec<Post>, DbError> {
+        kind: ProfileImageKind,
+    ) -> Result<User, DbError> {
         let conn = self.pool.get().await?;
-        let posts = conn
+        let user = conn
             .interact(move |conn| {
-                posts::table
-                    .filter(posts::user_id.eq(user_id))
-                    .order(posts::created_at.desc())
-                    .limit(limit)
-                    .offset(offset)
-                    .select(Post::as_select())
-                    .load(conn)
+                let user = diesel::update(users::table.find(user_id));
+                match kind {
+                    ProfileImageKind::Avatar => user
+                        .set((
+                            users::image_url.eq(crate::media::identicon_url(user_id)),
+                            users::updated_at.eq(Utc::now()),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn),
+                    ProfileImageKind::Banner => user
+                        .set((
+                            users::banner_url.eq(None::<String>),
+                            users::updated_at.eq(Utc::now()),
+                        ))
+                        .returning(User::as_returning())
+                        .get_result(conn),
+                }
             })
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e| Box::new(e) as DbError)?;
-        Ok(posts)
+        Ok(user)
     }
 
-    // Comment operations
-    pub async fn create_comment(&self, new_comment: NewComment) -> Result<Comment, DbError> {
+    pub async fn get_storage_usage(&self, user_id: Uuid) -> Result<StorageUsage, DbError> {
         let conn = self.pool.get().await?;
-        let comment = conn
-            .interact(move |conn| {
-                diesel::insert_into(comments::table)
-                    .values(&new_comment)
-                    .returning(Comment::as_returning())
-                    .get_result(conn)
-            })
+        let usage = conn
+            .interact(move |conn| storage_usage(conn, user_id))
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e| Box::new(e) as DbError)?;
-        Ok(comment)
+        Ok(usage)
     }
 
-    pub async fn get_post_comments(&self, post_id: Uuid) -> Result<Vec<Comment>, DbError> {
+    // Returns None when the user does not exist
+    pub async fn set_storage_quota(
+        &self,
+        user_id: Uuid,
+        quota_bytes: Option<i64>,
+    ) -> Result<Option<StorageUsage>, DbError> {
         let conn = self.pool.get().await?;
-        let comments = conn
+        let usage = conn
             .interact(move |conn| {
-                comments::table
-                    .filter(comments::post_id.eq(post_id))
-                    .order(comments::created_at.asc())
-                    .select(Comment::as_select())
-                    .load(conn)
+                let updated = diesel::update(users::table.find(user_id))
+                    .set(users::storage_quota_bytes.eq(quota_bytes))
+                    .execute(conn)?;
+                if updated == 0 {
+                    return Ok(None);
+                }
+                storage_usage(conn, user_id).map(Some)
             })
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e| Box::new(e) as DbError)?;
-        Ok(comments)
+        Ok(usage)
     }
 
-    // Interaction operations
-    pub async fn like_post(&self, post_id: Uuid, user_id: Uuid) -> Result<Interaction, DbError> {
+    // Marks up to `limit` uploads created before `created_before` that no post, comment,
+    // avatar or banner uses as deleting, returning each with the storage keys of its
+    // file and variants. Uploads whose files could not be deleted are returned again.
+    pub async fn collect_unused_media(
+        &self,
+        created_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, Vec<String>)>, DbError> {
         let conn = self.pool.get().await?;
-        let interaction = conn
+        let keys = conn
             .interact(move |conn| {
                 conn.transaction(|conn| {
-                    // Create interaction
-                    let interaction = diesel::insert_into(interactions::table)
-                        .values(NewInteraction {
-                            post_id,
-                            user_id,
-                            interaction_type: "like".to_string(),
-                        })
-                        .returning(Interaction::as_returning())
-                        .get_result(conn)?;
-
-                    // Update likes count
-                    diesel::update(posts::table.filter(posts::id.eq(post_id)))
-                        .set(posts::likes_count.eq(posts::likes_count + 1))
+                    let avatar_urls = || {
+                        users::table
+                            .filter(users::image_url.is_not_null())
+                            .select(users::image_url.assume_not_null())
+                    };
+                    let banner_urls = || {
+                        users::table
+                            .filter(users::banner_url.is_not_null())
+                            .select(users::banner_url.assume_not_null())
+                    };
+                    let unused: Vec<(Uuid, String)> = media::table
+                        .filter(media::created_at.lt(created_before))
+                        // Processing writes variant files, so it has to finish first
+                        .filter(media::status.ne(MediaStatus::Processing))
+                        .filter(not(exists(
+                            media_attachments::table
+                                .filter(media_attachments::media_id.eq(media::id)),
+                        )))
+                        .filter(not(media::url.eq_any(avatar_urls())))
+                        .filter(not(media::url.eq_any(banner_urls())))
+                        .filter(not(exists(
+                            media_variants::table
+                                .filter(media_variants::media_id.eq(media::id))
+                                .filter(
+                                    media_variants::url
+                                        .eq_any(avatar_urls())
+                                        .or(media_variants::url.eq_any(banner_urls())),
+                                ),
+                        )))
+                        .select((media::id, media::storage_key))
+                        .limit(limit)
+                        .for_update()
+                        .skip_locked()
+                        .load(conn)?;
+                    let ids: Vec<Uuid> = unused.iter().map(|(id, _)| *id).collect();
+                    diesel::update(media::table.filter(media::id.eq_any(&ids)))
+                        .set(media::status.eq(MediaStatus::Deleting))
                         .execute(conn)?;
 
-                    Ok(interaction)
+                    let variant_keys: Vec<(Uuid, String)> = media_variants::table
+                        .filter(media_variants::media_id.eq_any(&ids))
+                        .select((media_variants::media_id, media_variants::storage_key))
+                        .load(conn)?;
+                    Ok(unused
+                        .into_iter()
+                        .map(|(id, key)| {
+                            let mut keys: Vec<String> = variant_keys
+                                .iter()
+                                .filter(|(media_id, _)| *media_id == id)
+                                .map(|(_, key)| key.clone())
+                                .collect();
+                            keys.push(key);
+                            (id, keys)
+                        })
+                        .collect())
                 })
             })
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(interaction)
+        Ok(keys)
     }
 
-    pub async fn share_post(&self, post_id: Uuid, user_id: Uuid) -> Result<Interaction, DbError> {
+    // Deletes uploads collected by `collect_unused_media` once their files are gone
+    pub async fn delete_collected_media(&self, media_ids: Vec<Uuid>) -> Result<(), DbError> {
         let conn = self.pool.get().await?;
-        let interaction = conn
-            .interact(move |conn| {
-                conn.transaction(|conn| {
-                    // Create interaction
-                    let interaction = diesel::insert_into(interactions::table)
-                        .values(NewInteraction {
-                            post_id,
-                            user_id,
-                            interaction_type: "share".to_string(),
-                        })
-                        .returning(Interaction::as_returning())
-                        .get_result(conn)?;
-
-                    // Update shares count
-                    diesel::update(posts::table.filter(posts::id.eq(post_id)))
-                        .set(posts::shares_count.eq(posts::shares_count + 1))
-                        .execute(conn)?;
+        conn.interact(move |conn| {
+            diesel::delete(
+                media::table
+                    .filter(media::id.eq_any(media_ids))
+                    .filter(media::status.eq(MediaStatus::Deleting)),
+            )
+            .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
 
-                    Ok(interaction)
-                })
+    // The content type of the file stored under `storage_key`, either an upload or
+    // one of its variants. None until the upload has been processed.
+    pub async fn get_media_file(&self, storage_key: String) -> Result<Option<String>, DbError> {
+        let conn = self.pool.get().await?;
+        let content_type = conn
+            .interact(move |conn| {
+                let original = media::table
+                    .filter(media::storage_key.eq(&storage_key))
+                    .filter(media::status.eq(MediaStatus::Ready))
+                    .select(media::content_type)
+                    .first::<String>(conn)
+                    .optional()?;
+                if original.is_some() {
+                    return Ok(original);
+                }
+                media_variants::table
+                    .inner_join(media::table)
+                    .filter(media_variants::storage_key.eq(&storage_key))
+                    .filter(media::status.eq(MediaStatus::Ready))
+                    .select(media_variants::content_type)
+                    .first::<String>(conn)
+                    .optional()
             })
             .await
             .map_err(interact_error_to_db_error)?
             .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(interaction)
+        Ok(content_type)
     }
 
-    // Follow operations
-    pub async fn follow_user(
+    // Records the stripped upload and its variants, replacing those of an earlier
+    // attempt, and makes the files available
+    pub async fn complete_media_processing(
         &self,
-        follower_id: Uuid,
-        following_id: Uuid,
-    ) -> Result<Follow, DbError> {
+        media_id: Uuid,
+        processed: ProcessedMedia,
+    ) -> Result<(), DbError> {
         let conn = self.pool.get().await?;
-        let follow = conn
-            .interact(move |conn| {
-                conn.transaction(|conn| {
-                    // Create follow relationship
-                    let follow = diesel::insert_into(follows::table)
-                        .values(NewFollow {
-                            follower_id,
-                            following_id,
-                        })
-                        .returning(Follow::as_returning())
-                        .get_result(conn)?;
+        conn.interact(move |conn| {
+            conn.transaction(|conn| {
+                diesel::delete(media_variants::table.filter(media_variants::media_id.eq(media_id)))
+                    .execute(conn)?;
+                diesel::insert_into(media_variants::table)
+                    .values(&processed.variants)
+                    .execute(conn)?;
+                diesel::update(media::table.find(media_id))
+                    .set((
+                        media::status.eq(MediaStatus::Ready),
+                        media::size_bytes.eq(processed.size_bytes),
+                        media::width.eq(processed.width),
+                        media::height.eq(processed.height),
+                        media::blurhash.eq(processed.blurhash),
+                    ))
+                    .execute(conn)
+            })
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
+        Ok(())
+    }
 
-                    // Update follower's following count
-                    diesel::update(users::table.filter(users::id.eq(follower_id)))
-                        .set(users::following_count.eq(users::following_count + 1))
-                        .execute(conn)?;
+    pub async fn fail_media_processing(&self, media_id: Uuid) -> Result<(), DbError> {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            diesel::update(media::table.find(media_id))
+                .set(media::status.eq(MediaStatus::Failed))
+                .execute(conn)
+        })
+        .await
+        .map_err(interact_error_to_db_error)?
+        .map_err(|e| Box::new(e) as DbError)?;
+        Ok(())
+    }
+}
 
-                    // Update following user's followers count
-                    diesel::update(users::table.filter(users::id.eq(following_id)))
-                        .set(users::followers_count.eq(users::followers_count + 1))
-                        .execute(conn)?;
+// These run against `DATABASE_URL` inside a transaction that is rolled back, and are
+// skipped when it is not set
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    Ok(follow)
-                })
-            })
-            .await
-            .map_err(interact_error_to_db_error)?
-            .map_err(|e: diesel::result::Error| Box::new(e) as DbError)?;
-        Ok(follow)
+    fn test_connection() -> Option<PgConnection> {
+        dotenvy::dotenv().ok();
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return None;
+        };
+        Some(PgConnection::establish(&url).expect("Failed to connect to DATABASE_URL"))
     }
 
-    pub async fn get_user_followers(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
-        let conn = self.pool.get().await?;
-        let followers = conn
-            .interact(move |conn| {
-                follows::table
-                    .inner_join(users::table.on(follows::follower_id.eq(users::id)))
-                    .filter(follows::following_id.eq(user_id))
-                    .select(User::as_select())
-                    .load(conn)
+    fn insert_user(conn: &mut PgConnection, name: &str) -> Uuid {
+        let suffix = Uuid::new_v4().simple().to_string();
+        diesel::insert_into(users::table)
+            .values(NewUser {
+                name: name.to_string(),
+                email: format!("{}_{}@example.com", name, suffix),
+                username: format!("{}_{}", name, &suffix[..8]),
+                is_private: false,
             })
-            .await
-            .map_err(interact_error_to_db_error)?
-            .map_err(|e| Box::new(e) as DbError)?;
-        Ok(followers)
+            .returning(users::id)
+            .get_result(conn)
+            .unwrap()
     }
 
-    pub async fn get_user_following(&self, user_id: Uuid) -> Result<Vec<User>, DbError> {
-        let conn = self.pool.get().await?;
-        let following = conn
-            .interact(move |conn| {
-                follows::table
-                    .inner_join(users::table.on(follows::following_id.eq(users::id)))
-                    .filter(follows::follower_id.eq(user_id))
-                    .select(User::as_select())
-                    .load(conn)
+    fn post(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        content: &str,
+        reply_to_post_id: Option<Uuid>,
+        quoted_post_id: Option<Uuid>,
+    ) -> Uuid {
+        let new_post = NewPost {
+            user_id,
+            content: content.to_string(),
+            media_ids: vec![],
+            reply_to_post_id,
+            quoted_post_id,
+            visibility: PostVisibility::Public,
+            language: None,
+            created_at: None,
+        };
+        let processed = process_content(conn, user_id, content).unwrap();
+        insert_post(conn, &new_post, processed).unwrap().0.id
+    }
+
+    fn interact(conn: &mut PgConnection, user_id: Uuid, post_id: Uuid, kind: &str) {
+        diesel::insert_into(interactions::table)
+            .values(NewInteraction {
+                post_id,
+                user_id,
+                interaction_type: kind.to_string(),
             })
-            .await
-            .map_err(interact_error_to_db_error)?
-            .map_err(|e| Box::new(e) as DbError)?;
-        Ok(following)
+            .execute(conn)
+            .unwrap();
+        let post = posts::table.find(post_id);
+        match kind {
+            "like" => diesel::update(post)
+                .set(posts::likes_count.eq(posts::likes_count + 1))
+                .execute(conn),
+            _ => diesel::update(post)
+                .set(posts::shares_count.eq(posts::shares_count + 1))
+                .execute(conn),
+        }
+        .unwrap();
+    }
+
+    fn comment(conn: &mut PgConnection, user_id: Uuid, post_id: Uuid, content: &str) {
+        let comment_id = diesel::insert_into(comments::table)
+            .values((
+                comments::post_id.eq(post_id),
+                comments::user_id.eq(user_id),
+                comments::content.eq(content),
+            ))
+            .returning(comments::id)
+            .get_result(conn)
+            .unwrap();
+        sync_comment_hashtags(conn, comment_id, content).unwrap();
+    }
+
+    fn counts(conn: &mut PgConnection, post_id: Uuid) -> (i32, i32, i32, i32) {
+        posts::table
+            .find(post_id)
+            .select((
+                posts::likes_count,
+                posts::shares_count,
+                posts::replies_count,
+                posts::quotes_count,
+            ))
+            .first(conn)
+            .unwrap()
+    }
+
+    fn hashtag_usage(conn: &mut PgConnection, name: &str) -> i32 {
+        hashtags::table
+            .filter(hashtags::name.eq(name))
+            .select(hashtags::usage_count)
+            .first(conn)
+            .unwrap()
+    }
+
+    fn bookmark(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        post_id: Uuid,
+        collection_id: Option<Uuid>,
+        created_at: DateTime<Utc>,
+    ) -> Uuid {
+        diesel::insert_into(bookmarks::table)
+            .values((
+                bookmarks::user_id.eq(user_id),
+                bookmarks::post_id.eq(post_id),
+                bookmarks::collection_id.eq(collection_id),
+                bookmarks::created_at.eq(created_at),
+            ))
+            .returning(bookmarks::id)
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn bookmarked_posts(page: &[BookmarkView]) -> Vec<Uuid> {
+        page.iter().map(|view| view.post.post.id).collect()
+    }
+
+    #[test]
+    fn bookmarks_page_newest_first_without_hidden_posts() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let reader = insert_user(conn, "reader");
+            let author = insert_user(conn, "author");
+            let now = Utc::now();
+            let posts: Vec<Uuid> = (0..5)
+                .map(|i| post(conn, author, &format!("post {}", i), None, None))
+                .collect();
+            let hidden = post(conn, author, "followers only", None, None);
+            diesel::update(posts::table.find(hidden))
+                .set(posts::visibility.eq(PostVisibility::Followers))
+                .execute(conn)?;
+            let deleted = post(conn, author, "deleted", None, None);
+            diesel::update(posts::table.find(deleted))
+                .set(posts::deleted_at.eq(Some(now)))
+                .execute(conn)?;
+
+            // Two bookmarks share a time and are ordered by ID; the hidden and deleted
+            // posts sit between the others
+            let at = |minutes| now - chrono::Duration::minutes(minutes);
+            bookmark(conn, reader, posts[0], None, at(10));
+            bookmark(conn, reader, hidden, None, at(9));
+            let tied = [
+                bookmark(conn, reader, posts[1], None, at(8)),
+                bookmark(conn, reader, posts[2], None, at(8)),
+            ];
+            bookmark(conn, reader, deleted, None, at(7));
+            bookmark(conn, reader, posts[3], None, at(6));
+            bookmark(conn, reader, posts[4], None, at(5));
+            let (first_tied, second_tied) = match tied[0] > tied[1] {
+                true => (posts[1], posts[2]),
+                false => (posts[2], posts[1]),
+            };
+
+            let (page, next) = load_bookmarks(conn, reader, None, None, 2)?.unwrap();
+            assert_eq!(bookmarked_posts(&page), vec![posts[4], posts[3]]);
+            let next = next.expect("a second page");
+            let (page, next) = load_bookmarks(conn, reader, None, Some(next), 2)?.unwrap();
+            assert_eq!(bookmarked_posts(&page), vec![first_tied, second_tied]);
+            // The cursor survives the trip through the client
+            let next = next.unwrap().to_string().parse().unwrap();
+            let (page, next) = load_bookmarks(conn, reader, None, Some(next), 2)?.unwrap();
+            assert_eq!(bookmarked_posts(&page), vec![posts[0]]);
+            assert!(next.is_none());
+
+            // Exactly a page left gives no cursor
+            let (page, next) = load_bookmarks(conn, reader, None, None, 5)?.unwrap();
+            assert_eq!(page.len(), 5);
+            assert!(next.is_none());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn bookmarks_filter_by_the_users_own_collection() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let reader = insert_user(conn, "reader");
+            let other = insert_user(conn, "other");
+            let collection: Uuid = diesel::insert_into(bookmark_collections::table)
+                .values((
+                    bookmark_collections::user_id.eq(reader),
+                    bookmark_collections::name.eq("Later"),
+                ))
+                .returning(bookmark_collections::id)
+                .get_result(conn)?;
+            let filed = post(conn, other, "filed", None, None);
+            let loose = post(conn, other, "loose", None, None);
+            bookmark(conn, reader, filed, Some(collection), Utc::now());
+            bookmark(conn, reader, loose, None, Utc::now());
+
+            let (page, _) = load_bookmarks(conn, reader, Some(collection), None, 10)?.unwrap();
+            assert_eq!(bookmarked_posts(&page), vec![filed]);
+            let (page, _) = load_bookmarks(conn, reader, None, None, 10)?.unwrap();
+            assert_eq!(page.len(), 2);
+            assert!(load_bookmarks(conn, other, Some(collection), None, 10)?.is_none());
+            Ok(())
+        });
+    }
+
+    fn post_ids(posts: &[Post]) -> Vec<Uuid> {
+        posts.iter().map(|post| post.id).collect()
+    }
+
+    #[test]
+    fn only_the_author_pins_and_unpins() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let author = insert_user(conn, "author");
+            let other = insert_user(conn, "other");
+            let own = post(conn, author, "mine", None, None);
+            let theirs = post(conn, other, "theirs", None, None);
+            let deleted = post(conn, author, "deleted", None, None);
+            diesel::update(posts::table.find(deleted))
+                .set(posts::deleted_at.eq(Some(Utc::now())))
+                .execute(conn)?;
+
+            assert!(pin_own_post(conn, theirs, author, 3)?.is_none());
+            assert!(pin_own_post(conn, deleted, author, 3)?.is_none());
+            let pinned_at = pin_own_post(conn, own, author, 3)?.unwrap().pinned_at;
+            assert!(pinned_at.is_some());
+            // Pinning again keeps the original time
+            let again = pin_own_post(conn, own, author, 3)?.unwrap();
+            assert_eq!(again.pinned_at, pinned_at);
+
+            assert!(unpin_own_post(conn, own, other)?.is_none());
+            let still: Option<DateTime<Utc>> = posts::table
+                .find(own)
+                .select(posts::pinned_at)
+                .first(conn)?;
+            assert_eq!(still, pinned_at);
+            assert!(
+                unpin_own_post(conn, own, author)?
+                    .unwrap()
+                    .pinned_at
+                    .is_none()
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn pins_stop_at_the_limit() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let author = insert_user(conn, "author");
+            let first = post(conn, author, "first", None, None);
+            let second = post(conn, author, "second", None, None);
+            pin_own_post(conn, first, author, 1)?;
+            let Err(e) = pin_own_post(conn, second, author, 1) else {
+                panic!("pinned past the limit");
+            };
+            assert!(e.is::<PinLimitError>());
+            // The pinned post itself is still fine
+            assert!(pin_own_post(conn, first, author, 1)?.is_some());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn profile_pages_lead_with_pinned_posts() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let author = insert_user(conn, "author");
+            let now = Utc::now();
+            let posts: Vec<Uuid> = (0..6)
+                .map(|i| {
+                    let id = post(conn, author, &format!("post {}", i), None, None);
+                    diesel::update(posts::table.find(id))
+                        .set(posts::created_at.eq(now - chrono::Duration::minutes(10 - i)))
+                        .execute(conn)
+                        .unwrap();
+                    id
+                })
+                .collect();
+            // Pinned out of date order, posts[2] first and posts[4] last
+            for (post_id, minutes) in [(posts[2], 3), (posts[0], 2), (posts[4], 1)] {
+                diesel::update(posts::table.find(post_id))
+                    .set(posts::pinned_at.eq(Some(now - chrono::Duration::minutes(minutes))))
+                    .execute(conn)?;
+            }
+
+            let first = load_user_posts(conn, author, None, 2, 0, 3)?;
+            assert_eq!(
+                post_ids(&first),
+                vec![posts[4], posts[0], posts[2], posts[5], posts[3]]
+            );
+            let second = load_user_posts(conn, author, None, 2, 2, 3)?;
+            assert_eq!(post_ids(&second), vec![posts[1]]);
+
+            // The oldest pin is over the lowered limit and goes back into date order
+            let capped = load_user_posts(conn, author, None, 2, 0, 2)?;
+            assert_eq!(
+                post_ids(&capped),
+                vec![posts[4], posts[0], posts[5], posts[3]]
+            );
+            let capped = load_user_posts(conn, author, None, 2, 2, 2)?;
+            assert_eq!(post_ids(&capped), vec![posts[2], posts[1]]);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn erasure_adjusts_counts_on_what_remains() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let erased = insert_user(conn, "erased");
+            let other = insert_user(conn, "other");
+            let third = insert_user(conn, "third");
+            insert_follow(conn, erased, other)?;
+            insert_follow(conn, other, erased)?;
+            insert_follow(conn, third, other)?;
+
+            let tag = format!("erasure{}", &Uuid::new_v4().simple().to_string()[..8]);
+            let target = post(conn, other, "target", None, None);
+            let second = post(conn, third, "second", None, None);
+            post(
+                conn,
+                erased,
+                &format!("a reply #{}", tag),
+                Some(target),
+                None,
+            );
+            post(conn, erased, "another reply", Some(target), None);
+            post(conn, erased, "a quote", None, Some(target));
+            post(conn, erased, "a reply elsewhere", Some(second), None);
+            interact(conn, erased, target, "like");
+            interact(conn, erased, target, "share");
+            interact(conn, erased, second, "like");
+            interact(conn, third, target, "like");
+            comment(conn, erased, target, &format!("a comment #{}", tag));
+            post(conn, third, &format!("stays #{}", tag), None, None);
+            assert_eq!(counts(conn, target), (2, 1, 2, 1));
+            assert_eq!(hashtag_usage(conn, &tag), 3);
+
+            erase_account(conn, erased, "erased".to_string(), Utc::now())?;
+
+            assert_eq!(counts(conn, target), (1, 0, 0, 0));
+            assert_eq!(counts(conn, second), (0, 0, 0, 0));
+            assert_eq!(hashtag_usage(conn, &tag), 1);
+            let followers: (i32, i32) = users::table
+                .find(other)
+                .select((users::followers_count, users::following_count))
+                .first(conn)?;
+            assert_eq!(followers, (1, 0));
+            let erased_counts: (i32, i32, bool) = users::table
+                .find(erased)
+                .select((
+                    users::followers_count,
+                    users::following_count,
+                    users::deleted_at.is_not_null(),
+                ))
+                .first(conn)?;
+            assert_eq!(erased_counts, (0, 0, true));
+            let remaining: i64 = posts::table
+                .filter(posts::user_id.eq(erased))
+                .count()
+                .get_result(conn)?;
+            assert_eq!(remaining, 0);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn erasure_keeps_posts_others_replied_to_as_tombstones() {
+        let Some(mut conn) = test_connection() else {
+            return;
+        };
+        conn.test_transaction::<_, DbError, _>(|conn| {
+            let erased = insert_user(conn, "erased");
+            let other = insert_user(conn, "other");
+            let replied = post(conn, erased, "replied to", None, None);
+            let commented = post(conn, erased, "commented on", None, None);
+            let alone = post(conn, erased, "alone", None, None);
+            let reply = post(conn, other, "a reply", Some(replied), None);
+            comment(conn, other, commented, "a comment");
+
+            erase_account(conn, erased, "erased".to_string(), Utc::now())?;
+
+            let kept: Vec<(Uuid, String, bool)> = posts::table
+                .filter(posts::user_id.eq(erased))
+                .order(posts::created_at.asc())
+                .select((posts::id, posts::content, posts::deleted_at.is_not_null()))
+                .load(conn)?;
+            let mut kept_ids: Vec<Uuid> = kept.iter().map(|(id, _, _)| *id).collect();
+            kept_ids.sort();
+            let mut expected = vec![replied, commented];
+            expected.sort();
+            assert_eq!(kept_ids, expected);
+            assert!(
+                kept.iter()
+                    .all(|(_, content, deleted)| content.is_empty() && *deleted)
+            );
+            let alone_exists: bool =
+                diesel::select(exists(posts::table.find(alone))).get_result(conn)?;
+            assert!(!alone_exists);
+            assert_eq!(counts(conn, replied).2, 1);
+            let reply_exists: bool =
+                diesel::select(exists(posts::table.find(reply))).get_result(conn)?;
+            assert!(reply_exists);
+            Ok(())
+        });
     }
 }
@@ -1,12 +1,106 @@
-use crate::database::Database;
+use crate::accounts;
+use crate::auth::*;
+use crate::content::{is_search_language, normalize_hashtag};
+use crate::database::{
+    BlockedError, CollectionNotFoundError, Database, EmailTakenError, InvalidMediaError,
+    MissingAltTextError, PinLimitError, PostNotFoundError, QuotaExceededError, RenameCooldownError,
+    UsernameTakenError,
+};
+use crate::imports;
+use crate::jobs::{self, BuildDataExport, DeleteAccount, ProcessMedia, RunImport, SendEmail};
+use crate::mailer::{self, EmailMessage};
+use crate::media::{self, MediaStore};
 use crate::models::*;
-use actix_web::{HttpResponse, Responder, web};
+use crate::stream::{self, StreamHub};
+use crate::webhooks;
+use actix_multipart::Multipart;
+use actix_web::{HttpRequest, HttpResponse, Responder, http::header, web};
+use chrono::Utc;
+use futures_util::StreamExt;
 use serde::Deserialize;
 use uuid::Uuid;
+use validator::Validate;
+
+pub async fn register(
+    db: web::Data<Database>,
+    jwt_config: web::Data<JwtConfig>,
+    request: web::Json<RegisterRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let password_hash = match hash_password(&request.password) {
+        Ok(hash) => hash,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let new_user = NewUser {
+        name: request.name,
+        email: request.email,
+        username: request.username,
+        is_private: false,
+    };
+
+    match db.create_user_with_password(new_user, password_hash).await {
+        Ok(user) => match create_token(user.id, &user.username, &jwt_config) {
+            Ok(token) => HttpResponse::Created().json(AuthResponse {
+                token,
+                user: AuthUser::from(&user),
+            }),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        Err(e) if e.is::<UsernameTakenError>() => HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => {
+            if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("Username or email already exists")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error creating user: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn login(
+    db: web::Data<Database>,
+    jwt_config: web::Data<JwtConfig>,
+    request: web::Json<LoginRequest>,
+) -> impl Responder {
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let user = match db.get_user_credentials(&request.email).await {
+        Ok(Some((user, Some(hash))))
+            if verify_password(&request.password, &hash).unwrap_or(false) =>
+        {
+            user
+        }
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid email or password"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Error logging in: {}", e));
+        }
+    };
+
+    match create_token(user.id, &user.username, &jwt_config) {
+        Ok(token) => HttpResponse::Ok().json(AuthResponse {
+            token,
+            user: AuthUser::from(&user),
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
 
 pub async fn create_user(db: web::Data<Database>, new_user: web::Json<NewUser>) -> impl Responder {
-    match db.create_user(new_user.into_inner()).await {
+    let new_user = new_user.into_inner();
+    if let Err(e) = new_user.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    match db.create_user(new_user).await {
         Ok(user) => HttpResponse::Created().json(user),
+        Err(e) if e.is::<UsernameTakenError>() => HttpResponse::Conflict().body(e.to_string()),
         Err(e) => {
             if e.to_string().contains("unique constraint") {
                 HttpResponse::Conflict().body("Username or email already exists")
@@ -30,143 +124,2008 @@ pub async fn get_user_by_username(
     username: web::Path<String>,
 ) -> impl Responder {
     match db.get_user_by_username(&username).await {
-        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(Some(user)) => return HttpResponse::Ok().json(user),
+        Ok(None) => {}
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Error fetching user: {}", e));
+        }
+    }
+
+    // A recently changed username points at the user's current one. The redirect is
+    // temporary, since the old name is released eventually.
+    match db.get_renamed_user(&username).await {
+        Ok(Some(user)) => HttpResponse::TemporaryRedirect()
+            .insert_header((
+                header::LOCATION,
+                format!("/api/users/username/{}", user.username),
+            ))
+            .json(user),
         Ok(None) => HttpResponse::NotFound().body("User not found"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching user: {}", e)),
     }
 }
 
-pub async fn create_post(db: web::Data<Database>, new_post: web::Json<NewPost>) -> impl Responder {
-    match db.create_post(new_post.into_inner()).await {
-        Ok(post) => HttpResponse::Created().json(post),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Error creating post: {}", e)),
+// Tokens carry the username, so the response includes a new one
+pub async fn change_username(
+    db: web::Data<Database>,
+    jwt_config: web::Data<JwtConfig>,
+    user: AuthenticatedUser,
+    request: web::Json<UsernameChangeRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let user = match db.rename_user(user.id, request.username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(e) if e.is::<UsernameTakenError>() || e.to_string().contains("unique constraint") => {
+            return HttpResponse::Conflict().body(UsernameTakenError.to_string());
+        }
+        Err(e) => {
+            return match e.downcast_ref::<RenameCooldownError>() {
+                Some(cooldown) => {
+                    let wait = (cooldown.available_at - Utc::now()).num_seconds().max(1);
+                    HttpResponse::TooManyRequests()
+                        .insert_header((header::RETRY_AFTER, wait.to_string()))
+                        .body(e.to_string())
+                }
+                None => HttpResponse::InternalServerError()
+                    .body(format!("Error changing username: {}", e)),
+            };
+        }
+    };
+
+    match create_token(user.id, &user.username, &jwt_config) {
+        Ok(token) => HttpResponse::Ok().json(AuthResponse {
+            token,
+            user: AuthUser::from(&user),
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
-pub async fn get_posts(
+pub async fn update_profile(
     db: web::Data<Database>,
-    query: web::Query<PaginatedQuery>,
+    user: AuthenticatedUser,
+    changes: web::Json<UpdateProfileRequest>,
 ) -> impl Responder {
-    let limit = query.limit.unwrap_or(20).min(100);
-    let offset = query.offset.unwrap_or(0);
+    let changes = changes.into_inner().trimmed();
+    if let Err(e) = changes.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
 
-    match db.get_posts(limit, offset).await {
-        Ok(posts) => HttpResponse::Ok().json(posts),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching posts: {}", e)),
+    match db.update_profile(user.id, changes).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating profile: {}", e))
+        }
     }
 }
 
-pub async fn get_user_posts(
+// Email change tokens are valid for a day
+const EMAIL_CHANGE_TTL_HOURS: i64 = 24;
+
+// Sends a confirmation link to the new address. The email only changes once the
+// link is followed; the old address is told about the request.
+pub async fn request_email_change(
     db: web::Data<Database>,
-    user_id: web::Path<Uuid>,
-    query: web::Query<PaginatedQuery>,
+    user: AuthenticatedUser,
+    request: web::Json<EmailChangeRequest>,
 ) -> impl Responder {
-    let limit = query.limit.unwrap_or(20).min(100);
-    let offset = query.offset.unwrap_or(0);
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
 
-    match db.get_user_posts(*user_id, limit, offset).await {
-        Ok(posts) => HttpResponse::Ok().json(posts),
+    let current = match db.get_user(user.id).await {
+        Ok(Some(current)) => current,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error fetching user posts: {}", e))
+            return HttpResponse::InternalServerError()
+                .body(format!("Error changing email: {}", e));
+        }
+    };
+    match db.get_password_hash(user.id).await {
+        Ok(Some(hash)) if verify_password(&request.password, &hash).unwrap_or(false) => {}
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid password"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error changing email: {}", e));
+        }
+    }
+    if current.email.eq_ignore_ascii_case(&request.email) {
+        return HttpResponse::BadRequest().body("That is already your email");
+    }
+
+    let token = generate_token();
+    let link = format!("{}/confirm-email?token={}", mailer::app_url(), token);
+    let messages = [
+        EmailMessage {
+            to: request.email.clone(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Follow this link within {} hours to use this address for @{}:\n\n{}\n\nOr confirm with this code: {}",
+                EMAIL_CHANGE_TTL_HOURS, current.username, link, token
+            ),
+        },
+        EmailMessage {
+            to: current.email.clone(),
+            subject: "Your email address is being changed".to_string(),
+            body: format!(
+                "Someone asked to change the email address for @{} to {}. If this wasn't you, change your password.",
+                current.username, request.email
+            ),
+        },
+    ];
+    let now = Utc::now();
+    let jobs = match messages
+        .into_iter()
+        .map(|message| jobs::new_job(&SendEmail { message }, now))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error changing email: {}", e));
         }
+    };
+
+    let change = NewEmailChange {
+        user_id: user.id,
+        new_email: request.email,
+        token_hash: hash_token(&token),
+        expires_at: now + chrono::Duration::hours(EMAIL_CHANGE_TTL_HOURS),
+    };
+    match db.request_email_change(change, jobs).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) if e.is::<EmailTakenError>() => HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error changing email: {}", e)),
     }
 }
 
-pub async fn create_comment(
+pub async fn confirm_email_change(
     db: web::Data<Database>,
-    new_comment: web::Json<NewComment>,
+    request: web::Json<ConfirmEmailRequest>,
 ) -> impl Responder {
-    match db.create_comment(new_comment.into_inner()).await {
-        Ok(comment) => HttpResponse::Created().json(comment),
+    match db.confirm_email_change(hash_token(&request.token)).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::BadRequest().body("Invalid or expired token"),
+        Err(e) if e.is::<EmailTakenError>() => HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error changing email: {}", e)),
+    }
+}
+
+// Password setup tokens are valid for a day
+const PASSWORD_SETUP_TTL_HOURS: i64 = 24;
+
+// Mails a link for setting a password to accounts created without one, such as through
+// `POST /users`. Always accepted, so it does not reveal which addresses have accounts.
+pub async fn request_password_setup(
+    db: web::Data<Database>,
+    request: web::Json<PasswordSetupRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let user = match db.get_user_credentials(&request.email).await {
+        Ok(Some((user, None))) if user.deleted_at.is_none() => user,
+        Ok(_) => return HttpResponse::Accepted().finish(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error setting up password: {}", e));
+        }
+    };
+
+    let token = generate_token();
+    let link = format!("{}/set-password?token={}", mailer::app_url(), token);
+    let message = EmailMessage {
+        to: user.email.clone(),
+        subject: "Set your password".to_string(),
+        body: format!(
+            "Follow this link within {} hours to set a password for @{}:\n\n{}\n\nOr use this code: {}",
+            PASSWORD_SETUP_TTL_HOURS, user.username, link, token
+        ),
+    };
+    let now = Utc::now();
+    let job = match jobs::new_job(&SendEmail { message }, now) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error setting up password: {}", e));
+        }
+    };
+
+    let setup = NewPasswordSetup {
+        user_id: user.id,
+        token_hash: hash_token(&token),
+        expires_at: now + chrono::Duration::hours(PASSWORD_SETUP_TTL_HOURS),
+    };
+    match db.request_password_setup(setup, job).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error creating comment: {}", e))
+            HttpResponse::InternalServerError().body(format!("Error setting up password: {}", e))
         }
     }
 }
 
-pub async fn get_post_comments(
+// Sets the password from a setup token and signs the user in
+pub async fn confirm_password_setup(
     db: web::Data<Database>,
-    post_id: web::Path<Uuid>,
+    jwt_config: web::Data<JwtConfig>,
+    request: web::Json<ConfirmPasswordSetupRequest>,
 ) -> impl Responder {
-    match db.get_post_comments(*post_id).await {
-        Ok(comments) => HttpResponse::Ok().json(comments),
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let password_hash = match hash_password(&request.password) {
+        Ok(hash) => hash,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let user = match db
+        .confirm_password_setup(hash_token(&request.token), password_hash)
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::BadRequest().body("Invalid or expired token"),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error fetching comments: {}", e))
+            return HttpResponse::InternalServerError()
+                .body(format!("Error setting up password: {}", e));
+        }
+    };
+    match create_token(user.id, &user.username, &jwt_config) {
+        Ok(token) => HttpResponse::Ok().json(AuthResponse {
+            token,
+            user: AuthUser::from(&user),
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+// The caller's data export, starting one if they have none pending or ready. The
+// archive can be downloaded once the export is ready.
+pub async fn get_data_export(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    let export_id = Uuid::new_v4();
+    let job = match jobs::new_job(&BuildDataExport { export_id }, Utc::now()) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error starting export: {}", e));
         }
+    };
+
+    match db.start_data_export(user.id, export_id, job).await {
+        Ok((export, _)) if export.status == ExportStatus::Ready => HttpResponse::Ok().json(export),
+        Ok((export, _)) => HttpResponse::Accepted().json(export),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error starting export: {}", e)),
     }
 }
 
-pub async fn like_post(db: web::Data<Database>, path: web::Path<(Uuid, Uuid)>) -> impl Responder {
-    let (post_id, user_id) = path.into_inner();
+pub async fn download_data_export(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    let key = match db.get_ready_data_export(user.id).await {
+        Ok(Some(DataExport {
+            storage_key: Some(key),
+            ..
+        })) => key,
+        Ok(_) => return HttpResponse::NotFound().body("No export is ready"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error fetching export: {}", e));
+        }
+    };
+
+    match store.get(&key).await {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .content_type("application/gzip")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"export-{}.tar.gz\"",
+                    Utc::now().format("%Y-%m-%d")
+                ),
+            ))
+            .insert_header(("Cache-Control", "private, no-store"))
+            .body(data),
+        Ok(None) => HttpResponse::NotFound().body("No export is ready"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching export: {}", e)),
+    }
+}
 
-    match db.like_post(post_id, user_id).await {
-        Ok(interaction) => HttpResponse::Created().json(interaction),
+// Schedules the caller's account for deletion after the grace period. Until then the
+// account works as before and the deletion can be cancelled.
+pub async fn delete_account(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    request: web::Json<DeleteAccountRequest>,
+) -> impl Responder {
+    match db.get_password_hash(user.id).await {
+        Ok(Some(hash)) if verify_password(&request.password, &hash).unwrap_or(false) => {}
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid password"),
         Err(e) => {
-            if e.to_string().contains("unique constraint") {
-                HttpResponse::Conflict().body("Post already liked by user")
-            } else {
-                HttpResponse::InternalServerError().body(format!("Error liking post: {}", e))
-            }
+            return HttpResponse::InternalServerError()
+                .body(format!("Error deleting account: {}", e));
         }
     }
+    schedule_account_deletion(&db, user.id).await
 }
 
-pub async fn share_post(db: web::Data<Database>, path: web::Path<(Uuid, Uuid)>) -> impl Responder {
-    let (post_id, user_id) = path.into_inner();
+// Schedules the account's erasure after the grace period
+async fn schedule_account_deletion(db: &Database, user_id: Uuid) -> HttpResponse {
+    let now = Utc::now();
+    let grace = accounts::account_deletion_grace();
+    let job = match jobs::new_job(&DeleteAccount { user_id }, now + grace) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error deleting account: {}", e));
+        }
+    };
 
-    match db.share_post(post_id, user_id).await {
-        Ok(interaction) => HttpResponse::Created().json(interaction),
+    match db.request_account_deletion(user_id, now, job).await {
+        Ok(Some(requested_at)) => HttpResponse::Accepted().json(AccountDeletion {
+            requested_at,
+            deletes_at: requested_at + grace,
+        }),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
         Err(e) => {
-            if e.to_string().contains("unique constraint") {
-                HttpResponse::Conflict().body("Post already shared by user")
-            } else {
-                HttpResponse::InternalServerError().body(format!("Error sharing post: {}", e))
+            HttpResponse::InternalServerError().body(format!("Error deleting account: {}", e))
+        }
+    }
+}
+
+pub async fn cancel_account_deletion(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.cancel_account_deletion(user.id).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error cancelling account deletion: {}", e)),
+    }
+}
+
+// Deletion confirmation tokens are valid for a day
+const DELETION_CONFIRMATION_TTL_HOURS: i64 = 24;
+
+// Mails a link for deleting an account created without a password, which cannot sign
+// in to confirm with one. Always accepted, so it does not reveal which addresses have
+// accounts.
+pub async fn request_deletion_confirmation(
+    db: web::Data<Database>,
+    request: web::Json<DeletionConfirmationRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let user = match db.get_user_credentials(&request.email).await {
+        Ok(Some((user, None))) if user.deleted_at.is_none() => user,
+        Ok(_) => return HttpResponse::Accepted().finish(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error deleting account: {}", e));
+        }
+    };
+
+    let token = generate_token();
+    let link = format!("{}/delete-account?token={}", mailer::app_url(), token);
+    let message = EmailMessage {
+        to: user.email.clone(),
+        subject: "Confirm deleting your account".to_string(),
+        body: format!(
+            "Follow this link within {} hours to delete @{}:\n\n{}\n\nOr confirm with this code: {}",
+            DELETION_CONFIRMATION_TTL_HOURS, user.username, link, token
+        ),
+    };
+    let now = Utc::now();
+    let job = match jobs::new_job(&SendEmail { message }, now) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error deleting account: {}", e));
+        }
+    };
+
+    let confirmation = NewDeletionConfirmation {
+        user_id: user.id,
+        token_hash: hash_token(&token),
+        expires_at: now + chrono::Duration::hours(DELETION_CONFIRMATION_TTL_HOURS),
+    };
+    match db.request_deletion_confirmation(confirmation, job).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error deleting account: {}", e))
+        }
+    }
+}
+
+// Schedules the deletion of an account without a password from a confirmation token
+pub async fn confirm_account_deletion(
+    db: web::Data<Database>,
+    request: web::Json<ConfirmDeletionRequest>,
+) -> impl Responder {
+    match db
+        .take_deletion_confirmation(hash_token(&request.token))
+        .await
+    {
+        Ok(Some(user_id)) => schedule_account_deletion(&db, user_id).await,
+        Ok(None) => HttpResponse::BadRequest().body("Invalid or expired token"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error deleting account: {}", e))
+        }
+    }
+}
+
+// Starts an import from the `file` field of a multipart form. The optional `kind`
+// field says what the file is; otherwise it is guessed from the content.
+pub async fn create_import(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user: AuthenticatedUser,
+    mut payload: Multipart,
+) -> impl Responder {
+    let fields = match read_form(&mut payload, imports::max_upload_bytes()).await {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let mut upload = None;
+    let mut kind = None;
+    for (name, data) in fields {
+        match name.as_str() {
+            "file" => upload = Some(data),
+            "kind" => {
+                let value = String::from_utf8_lossy(&data).trim().to_string();
+                match serde_json::from_value::<ImportKind>(serde_json::Value::String(value)) {
+                    Ok(value) => kind = Some(value),
+                    Err(_) => {
+                        return HttpResponse::BadRequest()
+                            .body("Kind must be twitter, mastodon or follows");
+                    }
+                }
             }
+            _ => {}
+        }
+    }
+    let Some(data) = upload else {
+        return HttpResponse::BadRequest().body("Missing file field");
+    };
+    let Some(kind) = kind.or_else(|| imports::detect_kind(&data)) else {
+        return HttpResponse::BadRequest().body("Unrecognized file; set kind to say what it is");
+    };
+
+    let id = Uuid::new_v4();
+    let key = format!("imports/{}/{}", user.id, id);
+    if let Err(e) = store.put(&key, "application/octet-stream", data).await {
+        return HttpResponse::InternalServerError().body(format!("Error saving file: {}", e));
+    }
+    let new_import = NewImport {
+        id,
+        user_id: user.id,
+        kind,
+        storage_key: key.clone(),
+    };
+    let job = match jobs::new_job(&RunImport { import_id: id }, Utc::now()) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error starting import: {}", e));
+        }
+    };
+    match db.create_import(new_import, job).await {
+        Ok(import) => HttpResponse::Accepted().json(import),
+        Err(e) => {
+            let _ = store.delete(&key).await;
+            HttpResponse::InternalServerError().body(format!("Error starting import: {}", e))
         }
     }
 }
 
-pub async fn follow_user(db: web::Data<Database>, path: web::Path<(Uuid, Uuid)>) -> impl Responder {
-    let (follower_id, following_id) = path.into_inner();
+pub async fn get_imports(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
 
-    if follower_id == following_id {
-        return HttpResponse::BadRequest().body("Cannot follow yourself");
+    match db.get_imports(user.id, limit, offset).await {
+        Ok(imports) => HttpResponse::Ok().json(imports),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching imports: {}", e))
+        }
+    }
+}
+
+pub async fn get_import(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    import_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_import(*import_id).await {
+        Ok(Some(import)) if import.user_id == user.id => HttpResponse::Ok().json(import),
+        Ok(_) => HttpResponse::NotFound().body("Import not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching import: {}", e)),
+    }
+}
+
+// Why a list of media IDs cannot be attached, if it cannot
+fn invalid_attachments(media_ids: &[Uuid]) -> Option<String> {
+    if media_ids.len() > media::MAX_ATTACHMENTS {
+        return Some(format!(
+            "At most {} media items can be attached",
+            media::MAX_ATTACHMENTS
+        ));
+    }
+    let unique: std::collections::HashSet<&Uuid> = media_ids.iter().collect();
+    (unique.len() != media_ids.len()).then(|| "Media items can only be attached once".to_string())
+}
+
+pub async fn create_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    new_post: web::Json<NewPost>,
+) -> impl Responder {
+    if let Some(language) = &new_post.language
+        && !is_search_language(language)
+    {
+        return HttpResponse::BadRequest().body(format!("Unsupported language: {}", language));
+    }
+    if let Some(message) = invalid_attachments(&new_post.media_ids) {
+        return HttpResponse::BadRequest().body(message);
     }
 
-    match db.follow_user(follower_id, following_id).await {
-        Ok(follow) => HttpResponse::Created().json(follow),
+    match db.create_post(user.id, new_post.into_inner()).await {
+        Ok(post) => HttpResponse::Created().json(post),
         Err(e) => {
-            if e.to_string().contains("unique constraint") {
-                HttpResponse::Conflict().body("Already following user")
+            if e.is::<BlockedError>() {
+                HttpResponse::Forbidden().body(e.to_string())
+            } else if e.is::<PostNotFoundError>() {
+                HttpResponse::BadRequest().body("Replied-to or quoted post does not exist")
+            } else if e.is::<InvalidMediaError>() {
+                HttpResponse::BadRequest().body(e.to_string())
+            } else if e.is::<MissingAltTextError>() {
+                HttpResponse::UnprocessableEntity().body(e.to_string())
+            } else if e.to_string().contains("foreign key constraint") {
+                HttpResponse::BadRequest().body("Replied-to or quoted post does not exist")
             } else {
-                HttpResponse::InternalServerError().body(format!("Error following user: {}", e))
+                HttpResponse::InternalServerError().body(format!("Error creating post: {}", e))
             }
         }
     }
 }
 
-pub async fn get_user_followers(
+pub async fn update_post(
     db: web::Data<Database>,
-    user_id: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+    update: web::Json<UpdateContent>,
 ) -> impl Responder {
-    match db.get_user_followers(*user_id).await {
-        Ok(followers) => HttpResponse::Ok().json(followers),
+    match db
+        .update_post(*post_id, user.id, update.into_inner().content)
+        .await
+    {
+        Ok(Some(post)) => HttpResponse::Ok().json(post),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating post: {}", e)),
+    }
+}
+
+pub async fn get_post(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_post(*post_id, viewer.map(|v| v.id)).await {
+        Ok(Some(post)) => HttpResponse::Ok().json(post),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching post: {}", e)),
+    }
+}
+
+pub async fn get_post_thread(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_post_thread(*post_id, viewer.map(|v| v.id)).await {
+        Ok(Some(thread)) => HttpResponse::Ok().json(thread),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching thread: {}", e)),
+    }
+}
+
+pub async fn get_posts(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db.get_posts(viewer.map(|v| v.id), limit, offset).await {
+        Ok(posts) => HttpResponse::Ok().json(posts),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching posts: {}", e)),
+    }
+}
+
+pub async fn get_feed(
+    db: web::Data<Database>,
+    viewer: AuthenticatedUser,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db.get_feed(viewer.id, limit, offset).await {
+        Ok(posts) => HttpResponse::Ok().json(posts),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching feed: {}", e)),
+    }
+}
+
+pub async fn get_hashtag(db: web::Data<Database>, tag: web::Path<String>) -> impl Responder {
+    match db.get_hashtag(normalize_hashtag(&tag)).await {
+        Ok(Some(hashtag)) => HttpResponse::Ok().json(hashtag),
+        Ok(None) => HttpResponse::NotFound().body("Hashtag not found"),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error fetching followers: {}", e))
+            HttpResponse::InternalServerError().body(format!("Error fetching hashtag: {}", e))
         }
     }
 }
 
-pub async fn get_user_following(
+pub async fn get_hashtag_posts(
     db: web::Data<Database>,
-    user_id: web::Path<Uuid>,
+    viewer: Option<AuthenticatedUser>,
+    tag: web::Path<String>,
+    query: web::Query<PaginatedQuery>,
 ) -> impl Responder {
-    match db.get_user_following(*user_id).await {
-        Ok(following) => HttpResponse::Ok().json(following),
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .get_hashtag_posts(normalize_hashtag(&tag), viewer.map(|v| v.id), limit, offset)
+        .await
+    {
+        Ok(posts) => HttpResponse::Ok().json(posts),
         Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error fetching following: {}", e))
+            HttpResponse::InternalServerError().body(format!("Error fetching hashtag posts: {}", e))
+        }
+    }
+}
+
+// Why a search request would be rejected, if it would be
+fn invalid_search(filters: &SearchFilters) -> Option<String> {
+    if filters.q.trim().is_empty() {
+        return Some("Search query must not be empty".to_string());
+    }
+    match &filters.lang {
+        Some(language) if !is_search_language(language) => {
+            Some(format!("Unsupported language: {}", language))
         }
+        _ => None,
+    }
+}
+
+pub async fn search_posts(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    filters: web::Query<SearchFilters>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    if let Some(reason) = invalid_search(&filters) {
+        return HttpResponse::BadRequest().body(reason);
     }
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .search_posts(filters.into_inner(), viewer.map(|v| v.id), limit, offset)
+        .await
+    {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error searching posts: {}", e)),
+    }
+}
+
+pub async fn search_comments(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    filters: web::Query<SearchFilters>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    if let Some(reason) = invalid_search(&filters) {
+        return HttpResponse::BadRequest().body(reason);
+    }
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .search_comments(filters.into_inner(), viewer.map(|v| v.id), limit, offset)
+        .await
+    {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error searching comments: {}", e))
+        }
+    }
+}
+
+pub async fn search_users(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    search: web::Query<UserSearchQuery>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    if search.q.trim().trim_start_matches('@').is_empty() {
+        return HttpResponse::BadRequest().body("Search query must not be empty");
+    }
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .search_users(search.into_inner(), viewer.map(|v| v.id), limit, offset)
+        .await
+    {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error searching users: {}", e)),
+    }
+}
+
+pub async fn get_user_mentions(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db.get_user_mentions(user.id, limit, offset).await {
+        Ok(posts) => HttpResponse::Ok().json(posts),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching mentions: {}", e))
+        }
+    }
+}
+
+pub async fn get_user_posts(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    user_id: web::Path<Uuid>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .get_user_posts(
+            *user_id,
+            viewer.map(|v| v.id),
+            limit,
+            offset,
+            accounts::max_pinned_posts(),
+        )
+        .await
+    {
+        Ok(posts) => HttpResponse::Ok().json(posts),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching user posts: {}", e))
+        }
+    }
+}
+
+pub async fn create_comment(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    new_comment: web::Json<NewComment>,
+) -> impl Responder {
+    if let Some(language) = &new_comment.language
+        && !is_search_language(language)
+    {
+        return HttpResponse::BadRequest().body(format!("Unsupported language: {}", language));
+    }
+    if let Some(message) = invalid_attachments(&new_comment.media_ids) {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    match db.create_comment(user.id, new_comment.into_inner()).await {
+        Ok(comment) => HttpResponse::Created().json(comment),
+        Err(e) => {
+            if e.is::<BlockedError>() {
+                HttpResponse::Forbidden().body(e.to_string())
+            } else if e.is::<PostNotFoundError>() {
+                HttpResponse::NotFound().body(e.to_string())
+            } else if e.is::<InvalidMediaError>() {
+                HttpResponse::BadRequest().body(e.to_string())
+            } else if e.is::<MissingAltTextError>() {
+                HttpResponse::UnprocessableEntity().body(e.to_string())
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error creating comment: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn update_comment(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    comment_id: web::Path<Uuid>,
+    update: web::Json<UpdateContent>,
+) -> impl Responder {
+    match db
+        .update_comment(*comment_id, user.id, update.into_inner().content)
+        .await
+    {
+        Ok(Some(comment)) => HttpResponse::Ok().json(comment),
+        Ok(None) => HttpResponse::NotFound().body("Comment not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating comment: {}", e))
+        }
+    }
+}
+
+pub async fn get_post_comments(
+    db: web::Data<Database>,
+    viewer: Option<AuthenticatedUser>,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_post_comments(*post_id, viewer.map(|v| v.id)).await {
+        Ok(Some(comments)) => HttpResponse::Ok().json(comments),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching comments: {}", e))
+        }
+    }
+}
+
+pub async fn like_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.like_post(*post_id, user.id).await {
+        Ok(interaction) => HttpResponse::Created().json(interaction),
+        Err(e) => {
+            if e.is::<BlockedError>() {
+                HttpResponse::Forbidden().body(e.to_string())
+            } else if e.is::<PostNotFoundError>() {
+                HttpResponse::NotFound().body(e.to_string())
+            } else if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("Post already liked by user")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error liking post: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn share_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.share_post(*post_id, user.id).await {
+        Ok(interaction) => HttpResponse::Created().json(interaction),
+        Err(e) => {
+            if e.is::<BlockedError>() {
+                HttpResponse::Forbidden().body(e.to_string())
+            } else if e.is::<PostNotFoundError>() {
+                HttpResponse::NotFound().body(e.to_string())
+            } else if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("Post already shared by user")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error sharing post: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn pin_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db
+        .pin_post(*post_id, user.id, accounts::max_pinned_posts())
+        .await
+    {
+        Ok(Some(post)) => HttpResponse::Ok().json(post),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) if e.is::<PinLimitError>() => HttpResponse::Conflict().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error pinning post: {}", e)),
+    }
+}
+
+pub async fn unpin_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.unpin_post(*post_id, user.id).await {
+        Ok(Some(post)) => HttpResponse::Ok().json(post),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error unpinning post: {}", e)),
+    }
+}
+
+// Bookmarks the post, or moves the bookmark to another collection if it already is.
+// The body is optional, but one that is sent has to be a valid request: a typo must
+// not move the bookmark out of its collection.
+pub async fn bookmark_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+    body: web::Bytes,
+) -> impl Responder {
+    let request = if body.trim_ascii().is_empty() {
+        BookmarkRequest::default()
+    } else {
+        match serde_json::from_slice::<BookmarkRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid request: {}", e)),
+        }
+    };
+
+    match db
+        .bookmark_post(user.id, *post_id, request.collection_id)
+        .await
+    {
+        Ok(Some((bookmark, true))) => HttpResponse::Created().json(bookmark),
+        Ok(Some((bookmark, false))) => HttpResponse::Ok().json(bookmark),
+        Ok(None) => HttpResponse::NotFound().body("Post not found"),
+        Err(e) if e.is::<CollectionNotFoundError>() => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error bookmarking post: {}", e))
+        }
+    }
+}
+
+pub async fn unbookmark_post(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    post_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.unbookmark_post(user.id, *post_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Bookmark not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error removing bookmark: {}", e))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookmarksQuery {
+    pub collection_id: Option<Uuid>,
+    // `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+pub async fn get_bookmarks(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    query: web::Query<BookmarksQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let cursor = match query.cursor.as_deref().map(str::parse::<BookmarkCursor>) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(())) => return HttpResponse::BadRequest().body("Invalid cursor"),
+        None => None,
+    };
+
+    match db
+        .get_bookmarks(user.id, query.collection_id, cursor, limit)
+        .await
+    {
+        Ok(Some((bookmarks, next))) => HttpResponse::Ok().json(BookmarksPage {
+            bookmarks,
+            next_cursor: next.map(|cursor| cursor.to_string()),
+        }),
+        Ok(None) => HttpResponse::NotFound().body(CollectionNotFoundError.to_string()),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching bookmarks: {}", e))
+        }
+    }
+}
+
+pub async fn get_bookmark_collections(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.get_bookmark_collections(user.id).await {
+        Ok(collections) => HttpResponse::Ok().json(collections),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching collections: {}", e))
+        }
+    }
+}
+
+// Names are trimmed and unique per user, ignoring case
+fn collection_name(request: BookmarkCollectionRequest) -> Result<String, String> {
+    let request = BookmarkCollectionRequest {
+        name: request.name.trim().to_string(),
+    };
+    request.validate().map_err(|e| e.to_string())?;
+    Ok(request.name)
+}
+
+pub async fn create_bookmark_collection(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    request: web::Json<BookmarkCollectionRequest>,
+) -> impl Responder {
+    let name = match collection_name(request.into_inner()) {
+        Ok(name) => name,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match db.create_bookmark_collection(user.id, name).await {
+        Ok(collection) => HttpResponse::Created().json(collection),
+        Err(e) if e.to_string().contains("unique constraint") => {
+            HttpResponse::Conflict().body("A collection with this name already exists")
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error creating collection: {}", e))
+        }
+    }
+}
+
+pub async fn rename_bookmark_collection(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    collection_id: web::Path<Uuid>,
+    request: web::Json<BookmarkCollectionRequest>,
+) -> impl Responder {
+    let name = match collection_name(request.into_inner()) {
+        Ok(name) => name,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match db
+        .rename_bookmark_collection(*collection_id, user.id, name)
+        .await
+    {
+        Ok(Some(collection)) => HttpResponse::Ok().json(collection),
+        Ok(None) => HttpResponse::NotFound().body(CollectionNotFoundError.to_string()),
+        Err(e) if e.to_string().contains("unique constraint") => {
+            HttpResponse::Conflict().body("A collection with this name already exists")
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error renaming collection: {}", e))
+        }
+    }
+}
+
+pub async fn delete_bookmark_collection(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    collection_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.delete_bookmark_collection(*collection_id, user.id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(CollectionNotFoundError.to_string()),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error deleting collection: {}", e))
+        }
+    }
+}
+
+pub async fn follow_user(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    following_id: web::Path<Uuid>,
+) -> impl Responder {
+    if user.id == *following_id {
+        return HttpResponse::BadRequest().body("Cannot follow yourself");
+    }
+
+    match db.follow_user(user.id, *following_id).await {
+        Ok(outcome @ FollowOutcome::Followed(_)) => HttpResponse::Created().json(outcome),
+        Ok(outcome @ FollowOutcome::Requested(_)) => HttpResponse::Accepted().json(outcome),
+        Err(e) => {
+            if e.is::<BlockedError>() {
+                HttpResponse::Forbidden().body(e.to_string())
+            } else if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("Already following or requested to follow user")
+            } else if e.to_string().contains("not found") {
+                HttpResponse::NotFound().body("User not found")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error following user: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn set_account_private(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    request: web::Json<PrivacyRequest>,
+) -> impl Responder {
+    match db.set_account_private(user.id, request.is_private).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating user: {}", e)),
+    }
+}
+
+pub async fn get_follow_requests(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.get_follow_requests(user.id).await {
+        Ok(requesters) => HttpResponse::Ok().json(requesters),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error fetching follow requests: {}", e)),
+    }
+}
+
+pub async fn approve_follow_request(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    requester_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.approve_follow_request(user.id, *requester_id).await {
+        Ok(Some(follow)) => HttpResponse::Created().json(follow),
+        Ok(None) => HttpResponse::NotFound().body("Follow request not found"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error approving follow request: {}", e)),
+    }
+}
+
+pub async fn reject_follow_request(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    requester_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.reject_follow_request(user.id, *requester_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Follow request not found"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error rejecting follow request: {}", e)),
+    }
+}
+
+pub async fn get_user_followers(
+    db: web::Data<Database>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_user_followers(*user_id).await {
+        Ok(followers) => HttpResponse::Ok().json(followers),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching followers: {}", e))
+        }
+    }
+}
+
+pub async fn get_user_following(
+    db: web::Data<Database>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_user_following(*user_id).await {
+        Ok(following) => HttpResponse::Ok().json(following),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching following: {}", e))
+        }
+    }
+}
+
+pub async fn block_user(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    blocked_id: web::Path<Uuid>,
+) -> impl Responder {
+    if user.id == *blocked_id {
+        return HttpResponse::BadRequest().body("Cannot block yourself");
+    }
+
+    match db.block_user(user.id, *blocked_id).await {
+        Ok(block) => HttpResponse::Created().json(block),
+        Err(e) => {
+            if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("User already blocked")
+            } else if e.to_string().contains("foreign key constraint") {
+                HttpResponse::NotFound().body("User not found")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error blocking user: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn unblock_user(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    blocked_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.unblock_user(user.id, *blocked_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("User not blocked"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error unblocking user: {}", e)),
+    }
+}
+
+pub async fn get_blocked_users(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    match db.get_blocked_users(user.id).await {
+        Ok(blocked) => HttpResponse::Ok().json(blocked),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching blocked users: {}", e))
+        }
+    }
+}
+
+pub async fn mute_user(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    muted_id: web::Path<Uuid>,
+) -> impl Responder {
+    if user.id == *muted_id {
+        return HttpResponse::BadRequest().body("Cannot mute yourself");
+    }
+
+    match db.mute_user(user.id, *muted_id).await {
+        Ok(mute) => HttpResponse::Created().json(mute),
+        Err(e) => {
+            if e.to_string().contains("unique constraint") {
+                HttpResponse::Conflict().body("User already muted")
+            } else if e.to_string().contains("foreign key constraint") {
+                HttpResponse::NotFound().body("User not found")
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error muting user: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn unmute_user(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    muted_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.unmute_user(user.id, *muted_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("User not muted"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error unmuting user: {}", e)),
+    }
+}
+
+pub async fn get_muted_users(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    match db.get_muted_users(user.id).await {
+        Ok(muted) => HttpResponse::Ok().json(muted),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching muted users: {}", e))
+        }
+    }
+}
+
+pub async fn get_notifications(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db.get_notifications(user.id, limit, offset).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching notifications: {}", e))
+        }
+    }
+}
+
+pub async fn get_unread_notification_count(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.get_unread_notification_count(user.id).await {
+        Ok(unread_count) => HttpResponse::Ok().json(UnreadCount { unread_count }),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error fetching unread notification count: {}", e)),
+    }
+}
+
+pub async fn mark_all_notifications_read(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.mark_notifications_read(user.id, None).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error marking notifications as read: {}", e)),
+    }
+}
+
+pub async fn mark_notification_group_read(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    group_key: web::Path<String>,
+) -> impl Responder {
+    match db
+        .mark_notifications_read(user.id, Some(group_key.into_inner()))
+        .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error marking notifications as read: {}", e)),
+    }
+}
+
+pub async fn get_notification_preferences(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.get_notification_preferences(user.id).await {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error fetching notification preferences: {}", e)),
+    }
+}
+
+pub async fn update_notification_preferences(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    changes: web::Json<NotificationPreferences>,
+) -> impl Responder {
+    match db
+        .update_notification_preferences(user.id, changes.into_inner())
+        .await
+    {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error updating notification preferences: {}", e)),
+    }
+}
+
+// Where a streaming client resumes from. SSE clients may send `Last-Event-ID` instead.
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    pub last_event_id: Option<i64>,
+}
+
+pub async fn stream_events(
+    req: HttpRequest,
+    hub: web::Data<StreamHub>,
+    user: StreamUser,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .or(query.last_event_id);
+
+    match hub.subscribe(user.id, last_event_id).await {
+        Ok(subscription) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(stream::sse_stream(subscription)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error opening stream: {}", e)),
+    }
+}
+
+pub async fn stream_websocket(
+    req: HttpRequest,
+    body: web::Payload,
+    hub: web::Data<StreamHub>,
+    user: StreamUser,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    let subscription = match hub.subscribe(user.id, query.last_event_id).await {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error opening stream: {}", e));
+        }
+    };
+
+    match actix_ws::handle(&req, body) {
+        Ok((response, session, incoming)) => {
+            actix_web::rt::spawn(stream::run_websocket(subscription, session, incoming));
+            response
+        }
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn register_webhook(
+    db: &Database,
+    owner_id: Option<Uuid>,
+    request: WebhookRequest,
+) -> HttpResponse {
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    let secret = webhooks::generate_secret();
+    let new_webhook = NewWebhook {
+        user_id: owner_id,
+        url: request.url,
+        secret: secret.clone(),
+        event_types: request.event_types,
+    };
+    match db.create_webhook(new_webhook).await {
+        Ok(webhook) => HttpResponse::Created().json(CreatedWebhook { webhook, secret }),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error creating webhook: {}", e))
+        }
+    }
+}
+
+async fn list_webhooks(db: &Database, owner_id: Option<Uuid>) -> HttpResponse {
+    match db.get_webhooks(owner_id).await {
+        Ok(webhooks) => HttpResponse::Ok().json(webhooks),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching webhooks: {}", e))
+        }
+    }
+}
+
+pub async fn create_webhook(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    request: web::Json<WebhookRequest>,
+) -> impl Responder {
+    register_webhook(&db, Some(user.id), request.into_inner()).await
+}
+
+pub async fn get_webhooks(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    list_webhooks(&db, Some(user.id)).await
+}
+
+// Instance-wide webhooks receive every event and can only be managed by admins
+pub async fn create_instance_webhook(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    request: web::Json<WebhookRequest>,
+) -> impl Responder {
+    match db.is_admin(user.id).await {
+        Ok(true) => register_webhook(&db, None, request.into_inner()).await,
+        Ok(false) => HttpResponse::Forbidden().body("Admin access required"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error creating webhook: {}", e))
+        }
+    }
+}
+
+pub async fn get_instance_webhooks(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+) -> impl Responder {
+    match db.is_admin(user.id).await {
+        Ok(true) => list_webhooks(&db, None).await,
+        Ok(false) => HttpResponse::Forbidden().body("Admin access required"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching webhooks: {}", e))
+        }
+    }
+}
+
+pub async fn get_webhook(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.get_webhook(*webhook_id, user.id).await {
+        Ok(Some(webhook)) => HttpResponse::Ok().json(webhook),
+        Ok(None) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching webhook: {}", e))
+        }
+    }
+}
+
+pub async fn update_webhook(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<Uuid>,
+    changes: web::Json<UpdateWebhookRequest>,
+) -> impl Responder {
+    if let Err(e) = changes.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    match db
+        .update_webhook(*webhook_id, user.id, changes.into_inner())
+        .await
+    {
+        Ok(Some(webhook)) => HttpResponse::Ok().json(webhook),
+        Ok(None) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating webhook: {}", e))
+        }
+    }
+}
+
+pub async fn delete_webhook(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.delete_webhook(*webhook_id, user.id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error deleting webhook: {}", e))
+        }
+    }
+}
+
+pub async fn get_webhook_deliveries(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<Uuid>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db
+        .get_webhook_deliveries(*webhook_id, user.id, limit, offset)
+        .await
+    {
+        Ok(Some(deliveries)) => HttpResponse::Ok().json(deliveries),
+        Ok(None) => HttpResponse::NotFound().body("Webhook not found"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error fetching webhook deliveries: {}", e)),
+    }
+}
+
+// The fields of a multipart form. Only the `file` field may be as large as
+// `max_bytes`.
+async fn read_form(
+    payload: &mut Multipart,
+    max_bytes: usize,
+) -> Result<Vec<(String, web::Bytes)>, HttpResponse> {
+    let mut fields = Vec::new();
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+        let name = field.name().unwrap_or_default().to_string();
+        let limit = if name == "file" {
+            max_bytes
+        } else {
+            media::MAX_TEXT_FIELD_BYTES
+        };
+
+        let mut data = web::BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+            if data.len() + chunk.len() > limit {
+                return Err(HttpResponse::PayloadTooLarge()
+                    .body(format!("The {} field is limited to {} bytes", name, limit)));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        fields.push((name, data.freeze()));
+    }
+    Ok(fields)
+}
+
+// Checked again when an upload is saved; this avoids storing files that won't fit
+async fn check_storage_quota(
+    db: &Database,
+    user_id: Uuid,
+    size_bytes: i64,
+) -> Option<HttpResponse> {
+    match db.get_storage_usage(user_id).await {
+        Ok(usage) if !usage.allows(size_bytes) => {
+            Some(HttpResponse::PayloadTooLarge().body(QuotaExceededError.to_string()))
+        }
+        Ok(_) => None,
+        Err(e) => Some(
+            HttpResponse::InternalServerError()
+                .body(format!("Error checking storage quota: {}", e)),
+        ),
+    }
+}
+
+// Accepts a multipart upload with the file in a `file` field. The type is sniffed from
+// the content; the client's declared type and file name are ignored.
+pub async fn upload_media(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user: AuthenticatedUser,
+    mut payload: Multipart,
+) -> impl Responder {
+    let fields = match read_form(&mut payload, media::max_upload_bytes()).await {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let mut upload = None;
+    let mut description = UpdateMediaRequest::default();
+    for (name, data) in fields {
+        let text = || String::from_utf8_lossy(&data).into_owned();
+        // Range checks let NaN through, so only finite values are accepted
+        let focal = |value: String| value.trim().parse::<f32>().ok().filter(|v| v.is_finite());
+        match name.as_str() {
+            "file" => upload = Some(data),
+            "alt_text" => description.alt_text = Some(text()),
+            "caption" => description.caption = Some(text()),
+            "focal_x" => match focal(text()) {
+                Some(x) => description.focal_x = Some(x),
+                None => return HttpResponse::BadRequest().body("Invalid focal_x"),
+            },
+            "focal_y" => match focal(text()) {
+                Some(y) => description.focal_y = Some(y),
+                None => return HttpResponse::BadRequest().body("Invalid focal_y"),
+            },
+            _ => {}
+        }
+    }
+
+    let Some(data) = upload else {
+        return HttpResponse::BadRequest().body("Missing file field");
+    };
+    if let Err(e) = description.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let non_empty = |text: Option<String>| text.filter(|text| !text.trim().is_empty());
+    let Some((content_type, extension)) = media::sniff_content_type(&data) else {
+        return HttpResponse::UnsupportedMediaType().body("Unsupported file type");
+    };
+
+    // Decoding is CPU-bound, so it runs off the async workers
+    let image = match web::block(move || media::strip_metadata(&data, content_type)).await {
+        Ok(Ok(image)) => image,
+        Ok(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid image: {}", e)),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error processing media: {}", e));
+        }
+    };
+
+    let size_bytes = image.data.len() as i64;
+    if let Some(response) = check_storage_quota(&db, user.id, size_bytes).await {
+        return response;
+    }
+
+    let id = Uuid::new_v4();
+    let key = format!("{}/{}.{}", user.id, id, extension);
+    if let Err(e) = store.put(&key, content_type, image.data.into()).await {
+        return HttpResponse::InternalServerError().body(format!("Error storing media: {}", e));
+    }
+
+    let new_media = NewMedia {
+        id,
+        user_id: user.id,
+        storage_key: key.clone(),
+        content_type: content_type.to_string(),
+        size_bytes,
+        url: store.url(&key),
+        width: image.width as i32,
+        height: image.height as i32,
+        alt_text: non_empty(description.alt_text),
+        caption: non_empty(description.caption),
+        focal_x: description.focal_x.unwrap_or_default(),
+        focal_y: description.focal_y.unwrap_or_default(),
+        status: MediaStatus::Processing,
+        blurhash: None,
+    };
+    let processing = match jobs::new_job(
+        &ProcessMedia {
+            media_id: id,
+            strip_original: false,
+        },
+        Utc::now(),
+    ) {
+        Ok(job) => job,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error processing media: {}", e));
+        }
+    };
+    match db.create_media(new_media, processing).await {
+        Ok(media) => HttpResponse::Created().json(media),
+        Err(e) => {
+            // Best effort: the file is unreachable without its row anyway
+            let _ = store.delete(&key).await;
+            if e.is::<QuotaExceededError>() {
+                HttpResponse::PayloadTooLarge().body(e.to_string())
+            } else {
+                HttpResponse::InternalServerError().body(format!("Error saving media: {}", e))
+            }
+        }
+    }
+}
+
+// Crops the `file` field of a multipart form into a new avatar or banner, stored
+// ready to serve with its sizes
+async fn upload_profile_image(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user_id: Uuid,
+    mut payload: Multipart,
+    kind: ProfileImageKind,
+) -> HttpResponse {
+    let fields = match read_form(&mut payload, media::max_upload_bytes()).await {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let Some((_, data)) = fields.into_iter().find(|(name, _)| name == "file") else {
+        return HttpResponse::BadRequest().body("Missing file field");
+    };
+    if media::sniff_content_type(&data).is_none() {
+        return HttpResponse::UnsupportedMediaType().body("Unsupported file type");
+    }
+
+    let (image, generated) = match web::block(move || media::crop_profile_image(&data, kind)).await
+    {
+        Ok(Ok(cropped)) => cropped,
+        Ok(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid image: {}", e)),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error processing media: {}", e));
+        }
+    };
+    let size_bytes = image.data.len() as i64;
+    if let Some(response) = check_storage_quota(&db, user_id, size_bytes).await {
+        return response;
+    }
+
+    let id = Uuid::new_v4();
+    let key = format!("{}/{}.{}", user_id, id, image.extension);
+    let (width, height, content_type) = (image.width, image.height, image.content_type);
+    let mut keys = vec![key.clone()];
+    let stored = match store.put(&key, content_type, image.data.into()).await {
+        Ok(()) => media::store_variants(store.as_ref(), id, &key, generated.variants).await,
+        Err(e) => Err(e),
+    };
+    let saved = match stored {
+        Ok(variants) => {
+            keys.extend(variants.iter().map(|v| v.storage_key.clone()));
+            let new_media = NewMedia {
+                id,
+                user_id,
+                storage_key: key.clone(),
+                content_type: content_type.to_string(),
+                size_bytes,
+                url: store.url(&key),
+                width: width as i32,
+                height: height as i32,
+                alt_text: None,
+                caption: None,
+                focal_x: 0.0,
+                focal_y: 0.0,
+                status: MediaStatus::Ready,
+                blurhash: Some(generated.blurhash),
+            };
+            db.set_profile_image(kind, new_media, variants).await
+        }
+        Err(e) => Err(e),
+    };
+
+    match saved {
+        Ok((user, media)) => HttpResponse::Ok().json(UpdatedProfileImage { user, media }),
+        Err(e) => {
+            // Best effort, as for uploads
+            for key in &keys {
+                let _ = store.delete(key).await;
+            }
+            if e.is::<QuotaExceededError>() {
+                HttpResponse::PayloadTooLarge().body(e.to_string())
+            } else {
+                HttpResponse::InternalServerError()
+                    .body(format!("Error saving profile image: {}", e))
+            }
+        }
+    }
+}
+
+pub async fn set_avatar(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user: AuthenticatedUser,
+    payload: Multipart,
+) -> impl Responder {
+    upload_profile_image(db, store, user.id, payload, ProfileImageKind::Avatar).await
+}
+
+pub async fn set_banner(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    user: AuthenticatedUser,
+    payload: Multipart,
+) -> impl Responder {
+    upload_profile_image(db, store, user.id, payload, ProfileImageKind::Banner).await
+}
+
+async fn remove_profile_image(
+    db: &Database,
+    user_id: Uuid,
+    kind: ProfileImageKind,
+) -> HttpResponse {
+    match db.remove_profile_image(user_id, kind).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error removing profile image: {}", e))
+        }
+    }
+}
+
+// Goes back to the generated identicon
+pub async fn remove_avatar(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    remove_profile_image(&db, user.id, ProfileImageKind::Avatar).await
+}
+
+pub async fn remove_banner(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    remove_profile_image(&db, user.id, ProfileImageKind::Banner).await
+}
+
+#[derive(Deserialize)]
+pub struct IdenticonQuery {
+    pub size: Option<u32>,
+}
+
+// The default avatar for a user, `size` pixels square
+pub async fn get_identicon(
+    user_id: web::Path<Uuid>,
+    query: web::Query<IdenticonQuery>,
+) -> impl Responder {
+    let size = query.size.unwrap_or(200).clamp(16, 512);
+    match media::identicon(user_id.as_bytes(), size) {
+        Ok(data) => HttpResponse::Ok()
+            .content_type("image/png")
+            // The same user always gets the same image
+            .insert_header(("Cache-Control", "public, max-age=31536000, immutable"))
+            .body(data),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error generating identicon: {}", e))
+        }
+    }
+}
+
+pub async fn get_media(db: web::Data<Database>, media_id: web::Path<Uuid>) -> impl Responder {
+    match db.get_media(*media_id).await {
+        Ok(Some(media)) => HttpResponse::Ok().json(media),
+        Ok(None) => HttpResponse::NotFound().body("Media not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching media: {}", e)),
+    }
+}
+
+// Edits the alt text, caption or focal point of the caller's upload
+pub async fn update_media(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    media_id: web::Path<Uuid>,
+    changes: web::Json<UpdateMediaRequest>,
+) -> impl Responder {
+    let changes = changes.into_inner();
+    if let Err(e) = changes.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    match db.update_media(*media_id, user.id, changes).await {
+        Ok(Some(media)) => HttpResponse::Ok().json(media),
+        Ok(None) => HttpResponse::NotFound().body("Media not found"),
+        Err(e) if e.is::<MissingAltTextError>() => {
+            HttpResponse::UnprocessableEntity().body(e.to_string())
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating media: {}", e)),
+    }
+}
+
+// Serves stored files when no public media URL is configured
+pub async fn get_media_file(
+    db: web::Data<Database>,
+    store: web::Data<dyn MediaStore>,
+    key: web::Path<String>,
+) -> impl Responder {
+    let key = key.into_inner();
+    let content_type = match db.get_media_file(key.clone()).await {
+        Ok(Some(content_type)) => content_type,
+        Ok(None) => return HttpResponse::NotFound().body("Media not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error fetching media: {}", e));
+        }
+    };
+
+    match store.get(&key).await {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .content_type(content_type)
+            // Keys are never reused, so files can be cached for good
+            .insert_header(("Cache-Control", "public, max-age=31536000, immutable"))
+            .body(data),
+        Ok(None) => HttpResponse::NotFound().body("Media not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching media: {}", e)),
+    }
+}
+
+pub async fn get_storage_usage(db: web::Data<Database>, user: AuthenticatedUser) -> impl Responder {
+    match db.get_storage_usage(user.id).await {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error fetching storage usage: {}", e))
+        }
+    }
+}
+
+// Gives a user their own storage quota, or returns them to their role's
+pub async fn set_storage_quota(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    user_id: web::Path<Uuid>,
+    request: web::Json<StorageQuotaRequest>,
+) -> impl Responder {
+    if let Err(e) = request.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    match db.is_admin(user.id).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Forbidden().body("Admin access required"),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error updating storage quota: {}", e));
+        }
+    }
+
+    match db.set_storage_quota(*user_id, request.quota_bytes).await {
+        Ok(Some(usage)) => HttpResponse::Ok().json(usage),
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating storage quota: {}", e))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct JobsQuery {
+    pub status: Option<JobStatus>,
+}
+
+// Lists background jobs, e.g. `?status=dead` for the dead letters
+pub async fn get_jobs(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    filter: web::Query<JobsQuery>,
+    query: web::Query<PaginatedQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(20).min(100);
+    let offset = query.offset.unwrap_or(0);
+
+    match db.is_admin(user.id).await {
+        Ok(true) => match db.get_jobs(filter.status, limit, offset).await {
+            Ok(jobs) => HttpResponse::Ok().json(jobs),
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Error fetching jobs: {}", e))
+            }
+        },
+        Ok(false) => HttpResponse::Forbidden().body("Admin access required"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching jobs: {}", e)),
+    }
+}
+
+pub async fn retry_job(
+    db: web::Data<Database>,
+    user: AuthenticatedUser,
+    job_id: web::Path<Uuid>,
+) -> impl Responder {
+    match db.is_admin(user.id).await {
+        Ok(true) => match db.retry_job(*job_id).await {
+            Ok(Some(job)) => HttpResponse::Ok().json(job),
+            Ok(None) => HttpResponse::NotFound().body("Dead job not found"),
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Error retrying job: {}", e))
+            }
+        },
+        Ok(false) => HttpResponse::Forbidden().body("Admin access required"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error retrying job: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PrivacyRequest {
+    pub is_private: bool,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,703 @@
+use crate::database::DbError;
+use crate::models::{ImportKind, PostVisibility};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::Read;
+
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+// Items handled per run of the import job, so each run stays well inside the job
+// timeout
+pub const BATCH_SIZE: usize = 200;
+
+// Failed items listed on an import; all of them are counted
+pub const MAX_REPORTED_FAILURES: i32 = 500;
+
+// Outboxes are read into memory, so archives cannot expand past this
+const MAX_OUTBOX_BYTES: u64 = 512 * 1024 * 1024;
+
+const TWITTER_PREFIX: &str = "window.YTD.tweets.part";
+const TWITTER_DATE_FORMAT: &str = "%a %b %d %H:%M:%S %z %Y";
+const AS_PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const FOLLOWS_HEADER: &str = "Account address";
+
+// Largest archive that can be uploaded, from `IMPORT_MAX_UPLOAD_BYTES`
+pub fn max_upload_bytes() -> usize {
+    env::var("IMPORT_MAX_UPLOAD_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportedPost {
+    // The post's ID on the other network
+    pub source_id: String,
+    pub created_at: DateTime<Utc>,
+    pub content: String,
+    // Source ID of the post this replies to; only kept if that post was imported
+    pub reply_to: Option<String>,
+    pub visibility: PostVisibility,
+    pub language: Option<String>,
+}
+
+// Items are stored between runs of the import job
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImportItem {
+    Post(ImportedPost),
+    // `domain` is the server the account is on, when the handle names one
+    Follow {
+        account: String,
+        username: String,
+        domain: Option<String>,
+    },
+    // An entry that could not be read, reported as a failure
+    Invalid {
+        label: String,
+        error: String,
+    },
+}
+
+impl ImportItem {
+    // How the item is named in the import's failures
+    pub fn label(&self) -> &str {
+        match self {
+            ImportItem::Post(post) => &post.source_id,
+            ImportItem::Follow { account, .. } => account,
+            ImportItem::Invalid { label, .. } => label,
+        }
+    }
+
+    fn created_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            ImportItem::Post(post) => Some(post.created_at),
+            _ => None,
+        }
+    }
+}
+
+// Guesses what kind of file was uploaded: gzip is a Mastodon archive, tweets.js
+// starts with its JavaScript assignment and an outbox is a JSON object. Anything
+// else that reads as a list of accounts is taken to be follows.
+pub fn detect_kind(data: &[u8]) -> Option<ImportKind> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return Some(ImportKind::Mastodon);
+    }
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    if head.starts_with(TWITTER_PREFIX) {
+        return Some(ImportKind::Twitter);
+    }
+    if head.starts_with('{') {
+        return Some(ImportKind::Mastodon);
+    }
+    let first = head.lines().next()?.split(',').next()?.trim();
+    let is_account = !first.is_empty()
+        && first
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@_.-".contains(c));
+    (first == FOLLOWS_HEADER || is_account).then_some(ImportKind::Follows)
+}
+
+// Reads every item of an upload. Posts come oldest first, so replies are imported
+// after the posts they answer.
+pub fn parse(kind: ImportKind, data: &[u8]) -> Result<Vec<ImportItem>, DbError> {
+    let mut items = match kind {
+        ImportKind::Twitter => parse_tweets(data)?,
+        ImportKind::Mastodon => parse_outbox(&read_outbox(data)?)?,
+        ImportKind::Follows => parse_follows(data),
+    };
+    items.sort_by_key(ImportItem::created_at);
+    Ok(items)
+}
+
+#[derive(Deserialize)]
+struct TweetEntry {
+    tweet: Tweet,
+}
+
+#[derive(Deserialize)]
+struct Tweet {
+    id_str: String,
+    full_text: String,
+    created_at: String,
+    in_reply_to_status_id_str: Option<String>,
+    lang: Option<String>,
+    #[serde(default)]
+    entities: TweetEntities,
+}
+
+#[derive(Deserialize, Default)]
+struct TweetEntities {
+    #[serde(default)]
+    urls: Vec<TweetUrl>,
+    #[serde(default)]
+    media: Vec<TweetUrl>,
+}
+
+#[derive(Deserialize)]
+struct TweetUrl {
+    url: String,
+    expanded_url: String,
+}
+
+// `tweets.js` is a JSON array assigned to `window.YTD.tweets.part0`
+fn parse_tweets(data: &[u8]) -> Result<Vec<ImportItem>, DbError> {
+    let text = std::str::from_utf8(data)?;
+    let start = text.find('[').ok_or("Not a tweets.js file")?;
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(text[start..].trim_end().trim_end_matches(';'))?;
+
+    let mut items = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let label = entry
+            .pointer("/tweet/id_str")
+            .and_then(|id| id.as_str())
+            .map_or_else(|| format!("#{}", index + 1), str::to_string);
+        let tweet = match serde_json::from_value::<TweetEntry>(entry) {
+            Ok(entry) => entry.tweet,
+            Err(e) => {
+                items.push(ImportItem::Invalid {
+                    label,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        // Retweets are someone else's post
+        if tweet.full_text.starts_with("RT @") {
+            continue;
+        }
+        let created_at = match DateTime::parse_from_str(&tweet.created_at, TWITTER_DATE_FORMAT) {
+            Ok(created_at) => created_at.with_timezone(&Utc),
+            Err(e) => {
+                items.push(ImportItem::Invalid {
+                    label,
+                    error: format!("Invalid date {}: {}", tweet.created_at, e),
+                });
+                continue;
+            }
+        };
+
+        // Links are shortened in the text; media links point back at the tweet
+        let mut content = tweet.full_text;
+        for url in &tweet.entities.urls {
+            content = content.replace(&url.url, &url.expanded_url);
+        }
+        for media in &tweet.entities.media {
+            content = content.replace(&media.url, "");
+        }
+        items.push(ImportItem::Post(ImportedPost {
+            source_id: tweet.id_str,
+            created_at,
+            content: decode_entities(content.trim()),
+            reply_to: tweet.in_reply_to_status_id_str,
+            visibility: PostVisibility::Public,
+            language: tweet.lang.as_deref().and_then(search_language),
+        }));
+    }
+    Ok(items)
+}
+
+// The outbox from a Mastodon archive, or the upload itself if it is not one
+fn read_outbox(data: &[u8]) -> Result<Vec<u8>, DbError> {
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Ok(data.to_vec());
+    }
+    let mut archive = tar::Archive::new(GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()?.file_name() != Some("outbox.json".as_ref()) {
+            continue;
+        }
+        let mut outbox = Vec::new();
+        entry.take(MAX_OUTBOX_BYTES).read_to_end(&mut outbox)?;
+        return Ok(outbox);
+    }
+    Err("The archive has no outbox.json".into())
+}
+
+#[derive(Deserialize)]
+struct Outbox {
+    #[serde(rename = "orderedItems", default)]
+    ordered_items: Vec<Activity>,
+}
+
+#[derive(Deserialize)]
+struct Activity {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    object: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Note {
+    id: String,
+    published: DateTime<Utc>,
+    #[serde(default)]
+    content: String,
+    // A content warning
+    summary: Option<String>,
+    in_reply_to: Option<String>,
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    #[serde(default)]
+    content_map: HashMap<String, String>,
+}
+
+fn parse_outbox(data: &[u8]) -> Result<Vec<ImportItem>, DbError> {
+    let outbox: Outbox = serde_json::from_slice(data)?;
+
+    let mut items = Vec::new();
+    for (index, activity) in outbox.ordered_items.into_iter().enumerate() {
+        // Boosts are someone else's post
+        if activity.kind != "Create" {
+            continue;
+        }
+        let label = activity
+            .object
+            .get("id")
+            .and_then(|id| id.as_str())
+            .map_or_else(|| format!("#{}", index + 1), str::to_string);
+        let note = match serde_json::from_value::<Note>(activity.object) {
+            Ok(note) => note,
+            Err(e) => {
+                items.push(ImportItem::Invalid {
+                    label,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        // Direct messages are left out: their recipients are not on this server
+        let visibility = if note.to.iter().chain(&note.cc).any(|to| to == AS_PUBLIC) {
+            PostVisibility::Public
+        } else if note.to.iter().any(|to| to.ends_with("/followers")) {
+            PostVisibility::Followers
+        } else {
+            continue;
+        };
+        let text = html_to_text(&note.content);
+        let content = match note.summary.filter(|summary| !summary.trim().is_empty()) {
+            Some(summary) => format!("CW: {}\n\n{}", summary.trim(), text),
+            None => text,
+        };
+        items.push(ImportItem::Post(ImportedPost {
+            source_id: note.id,
+            created_at: note.published,
+            content,
+            reply_to: note.in_reply_to,
+            visibility,
+            language: note
+                .content_map
+                .keys()
+                .find_map(|lang| search_language(lang)),
+        }));
+    }
+    Ok(items)
+}
+
+// The search language for an ISO 639-1 code, as archives give them
+fn search_language(code: &str) -> Option<String> {
+    let language = match code.split(['-', '_']).next()? {
+        "ar" => "arabic",
+        "da" => "danish",
+        "nl" => "dutch",
+        "en" => "english",
+        "fi" => "finnish",
+        "fr" => "french",
+        "de" => "german",
+        "hu" => "hungarian",
+        "id" | "in" => "indonesian",
+        "ga" => "irish",
+        "it" => "italian",
+        "lt" => "lithuanian",
+        "ne" => "nepali",
+        "no" | "nb" | "nn" => "norwegian",
+        "pt" => "portuguese",
+        "ro" => "romanian",
+        "ru" => "russian",
+        "es" => "spanish",
+        "sv" => "swedish",
+        "ta" => "tamil",
+        "tr" => "turkish",
+        _ => return None,
+    };
+    Some(language.to_string())
+}
+
+// One account per line, in the first column: `@name`, `name@instance` or `name`
+fn parse_follows(data: &[u8]) -> Vec<ImportItem> {
+    let text = String::from_utf8_lossy(data);
+    let mut seen = HashSet::new();
+    text.lines()
+        .filter_map(|line| {
+            let account = line.split(',').next()?.trim();
+            if account.is_empty() || account == FOLLOWS_HEADER {
+                return None;
+            }
+            let (username, domain) = match account.trim_start_matches('@').split_once('@') {
+                Some((username, domain)) => (username, Some(domain.to_lowercase())),
+                None => (account.trim_start_matches('@'), None),
+            };
+            seen.insert(account.trim_start_matches('@').to_lowercase())
+                .then(|| ImportItem::Follow {
+                    account: account.to_string(),
+                    username: username.to_string(),
+                    domain,
+                })
+        })
+        .collect()
+}
+
+// Whether a followed account can be matched to a local user by name: bare names
+// and handles on this server, as given by the host of `APP_URL`. Someone on another
+// server is not the local user who happens to share their name.
+pub fn is_local_account(domain: Option<&str>) -> bool {
+    let Some(domain) = domain else {
+        return true;
+    };
+    reqwest::Url::parse(&crate::mailer::app_url())
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.eq_ignore_ascii_case(domain)))
+        .unwrap_or(false)
+}
+
+// Plain text from post HTML, keeping line and paragraph breaks
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        text.push_str(&rest[..start]);
+        let tag = rest[start + 1..start + len].trim().to_ascii_lowercase();
+        if tag == "br" || tag.starts_with("br ") || tag.starts_with("br/") {
+            text.push('\n');
+        } else if tag == "/p" {
+            text.push_str("\n\n");
+        }
+        rest = &rest[start + len + 1..];
+    }
+    text.push_str(rest);
+    decode_entities(text.trim())
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| (&rest[1..end + 1], end + 2));
+        let character = entity.and_then(|(name, len)| {
+            let character = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match name.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                        .ok()
+                        .and_then(char::from_u32),
+                    Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            character.map(|c| (c, len))
+        });
+        match character {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    const TWEETS: &str = r#"window.YTD.tweets.part0 = [
+  {
+    "tweet": {
+      "id_str": "2",
+      "full_text": "Replying &amp; reading https://t.co/abc https://t.co/pic",
+      "created_at": "Thu Oct 11 09:00:00 +0000 2018",
+      "in_reply_to_status_id_str": "1",
+      "lang": "en",
+      "entities": {
+        "urls": [{ "url": "https://t.co/abc", "expanded_url": "https://example.com/a" }],
+        "media": [{ "url": "https://t.co/pic", "expanded_url": "https://x.com/pic" }]
+      }
+    }
+  },
+  {
+    "tweet": {
+      "id_str": "1",
+      "full_text": "First",
+      "created_at": "Wed Oct 10 20:19:24 +0000 2018",
+      "lang": "und"
+    }
+  },
+  {
+    "tweet": {
+      "id_str": "3",
+      "full_text": "RT @someone: not mine",
+      "created_at": "Fri Oct 12 09:00:00 +0000 2018"
+    }
+  },
+  { "tweet": { "id_str": "4", "full_text": "Bad date", "created_at": "yesterday" } },
+  { "tweet": { "id_str": "5" } }
+];
+"#;
+
+    const OUTBOX: &str = r#"{
+  "orderedItems": [
+    {
+      "type": "Create",
+      "object": {
+        "id": "https://example.social/statuses/1",
+        "published": "2024-01-02T10:00:00Z",
+        "content": "<p>Hello<br>world</p><p>a &amp; b</p>",
+        "summary": " spoilers ",
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "contentMap": { "de": "" }
+      }
+    },
+    {
+      "type": "Create",
+      "object": {
+        "id": "https://example.social/statuses/2",
+        "published": "2024-01-01T10:00:00Z",
+        "content": "<p>Followers only</p>",
+        "inReplyTo": "https://example.social/statuses/0",
+        "to": ["https://example.social/users/me/followers"]
+      }
+    },
+    {
+      "type": "Create",
+      "object": {
+        "id": "https://example.social/statuses/3",
+        "published": "2024-01-03T10:00:00Z",
+        "content": "<p>A direct message</p>",
+        "to": ["https://other.social/users/friend"]
+      }
+    },
+    { "type": "Announce", "object": "https://other.social/statuses/9" },
+    { "type": "Create", "object": { "id": "https://example.social/statuses/4" } }
+  ]
+}"#;
+
+    fn post(item: &ImportItem) -> &ImportedPost {
+        match item {
+            ImportItem::Post(post) => post,
+            _ => panic!("expected a post, got {}", item.label()),
+        }
+    }
+
+    fn invalid_labels(items: &[ImportItem]) -> Vec<&str> {
+        items
+            .iter()
+            .filter(|item| matches!(item, ImportItem::Invalid { .. }))
+            .map(ImportItem::label)
+            .collect()
+    }
+
+    #[test]
+    fn detect_kind_recognises_each_format() {
+        assert_eq!(detect_kind(&[0x1f, 0x8b, 0x08]), Some(ImportKind::Mastodon));
+        assert_eq!(
+            detect_kind("\u{feff}window.YTD.tweets.part0 = []".as_bytes()),
+            Some(ImportKind::Twitter)
+        );
+        assert_eq!(
+            detect_kind(b"  {\"orderedItems\": []}"),
+            Some(ImportKind::Mastodon)
+        );
+        assert_eq!(
+            detect_kind(b"Account address,Show boosts\nalice@example.social,true\n"),
+            Some(ImportKind::Follows)
+        );
+        assert_eq!(detect_kind(b"@alice\nbob\n"), Some(ImportKind::Follows));
+        assert_eq!(detect_kind(b"just some text"), None);
+        assert_eq!(detect_kind(b""), None);
+    }
+
+    #[test]
+    fn parse_tweets_reads_posts_and_reports_bad_entries() {
+        let items = parse(ImportKind::Twitter, TWEETS.as_bytes()).unwrap();
+        assert_eq!(invalid_labels(&items), vec!["4", "5"]);
+
+        let posts: Vec<_> = items
+            .iter()
+            .filter(|item| matches!(item, ImportItem::Post(_)))
+            .map(post)
+            .collect();
+        // Oldest first, with the retweet left out
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].source_id, "1");
+        assert_eq!(posts[0].content, "First");
+        assert_eq!(posts[0].language, None);
+        assert_eq!(posts[1].source_id, "2");
+        assert_eq!(posts[1].content, "Replying & reading https://example.com/a");
+        assert_eq!(posts[1].reply_to.as_deref(), Some("1"));
+        assert_eq!(posts[1].language.as_deref(), Some("english"));
+        assert_eq!(
+            posts[1].created_at.to_rfc3339(),
+            "2018-10-11T09:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn parse_tweets_rejects_other_files() {
+        assert!(parse_tweets(b"window.YTD.tweets.part0 = {}").is_err());
+        assert!(parse_tweets(b"window.YTD.tweets.part0 = [{]").is_err());
+    }
+
+    #[test]
+    fn parse_outbox_keeps_public_and_followers_posts() {
+        let items = parse(ImportKind::Mastodon, OUTBOX.as_bytes()).unwrap();
+        assert_eq!(
+            invalid_labels(&items),
+            vec!["https://example.social/statuses/4"]
+        );
+
+        let posts: Vec<_> = items
+            .iter()
+            .filter(|item| matches!(item, ImportItem::Post(_)))
+            .map(post)
+            .collect();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].source_id, "https://example.social/statuses/2");
+        assert_eq!(posts[0].visibility, PostVisibility::Followers);
+        assert_eq!(
+            posts[0].reply_to.as_deref(),
+            Some("https://example.social/statuses/0")
+        );
+        assert_eq!(posts[1].visibility, PostVisibility::Public);
+        assert_eq!(posts[1].content, "CW: spoilers\n\nHello\nworld\n\na & b");
+        assert_eq!(posts[1].language.as_deref(), Some("german"));
+    }
+
+    #[test]
+    fn parse_outbox_reads_it_from_an_archive() {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, data) in [("media/a.txt", "not it"), ("outbox.json", OUTBOX)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        let data = archive.into_inner().unwrap().finish().unwrap();
+        assert_eq!(detect_kind(&data), Some(ImportKind::Mastodon));
+        let items = parse(ImportKind::Mastodon, &data).unwrap();
+        assert_eq!(items.len(), 3);
+
+        let mut empty = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_cksum();
+        empty
+            .append_data(&mut header, "actor.json", std::io::empty())
+            .unwrap();
+        let data = empty.into_inner().unwrap().finish().unwrap();
+        assert!(parse(ImportKind::Mastodon, &data).is_err());
+    }
+
+    #[test]
+    fn parse_follows_keeps_the_domain() {
+        let items = parse_follows(
+            b"Account address,Show boosts\n@alice,true\nbob@Example.Social,true\n\nALICE\nbob@example.social\n",
+        );
+        let follows: Vec<_> = items
+            .iter()
+            .map(|item| match item {
+                ImportItem::Follow {
+                    username, domain, ..
+                } => (username.as_str(), domain.as_deref()),
+                _ => panic!("expected a follow"),
+            })
+            .collect();
+        assert_eq!(
+            follows,
+            vec![("alice", None), ("bob", Some("example.social"))]
+        );
+    }
+
+    #[test]
+    fn only_bare_names_are_local_by_default() {
+        assert!(is_local_account(None));
+        assert!(!is_local_account(Some("mastodon.social")));
+    }
+
+    #[test]
+    fn items_survive_being_stored() {
+        let items = parse(ImportKind::Twitter, TWEETS.as_bytes()).unwrap();
+        let stored = serde_json::to_value(&items).unwrap();
+        let loaded: Vec<ImportItem> = serde_json::from_value(stored).unwrap();
+        let labels: Vec<_> = loaded.iter().map(ImportItem::label).collect();
+        assert_eq!(labels, vec!["4", "5", "1", "2"]);
+        assert_eq!(post(&loaded[3]).content, post(&items[3]).content);
+        assert_eq!(post(&loaded[3]).created_at, post(&items[3]).created_at);
+    }
+
+    #[test]
+    fn html_to_text_keeps_breaks_and_drops_tags() {
+        assert_eq!(
+            html_to_text(
+                "<p>Hi <a href=\"https://x.test\"><span>@bob</span></a></p><p>one<br/>two<BR >three</p>"
+            ),
+            "Hi @bob\n\none\ntwo\nthree"
+        );
+        assert_eq!(html_to_text("<p>1 &lt; 2</p>"), "1 < 2");
+        // An unclosed tag is kept as text
+        assert_eq!(html_to_text("a <b"), "a <b");
+    }
+
+    #[test]
+    fn decode_entities_handles_named_and_numeric_references() {
+        assert_eq!(
+            decode_entities("&amp;&lt;&gt;&quot;&apos;&nbsp;"),
+            "&<>\"' "
+        );
+        assert_eq!(decode_entities("&#233;&#xE9;&#X1F600;"), "éé😀");
+        assert_eq!(
+            decode_entities("&bogus; &#xZZ; & alone"),
+            "&bogus; &#xZZ; & alone"
+        );
+        // Too long to be an entity
+        assert_eq!(
+            decode_entities("&averyverylongname;"),
+            "&averyverylongname;"
+        );
+        assert_eq!(decode_entities("trailing &"), "trailing &");
+    }
+}
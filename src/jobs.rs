@@ -0,0 +1,644 @@
+use crate::accounts;
+use crate::database::{BlockedError, Database, DbError};
+use crate::imports::{self, ImportItem};
+use crate::mailer::{EmailMessage, Mailer};
+use crate::media::{self, MediaStore};
+use crate::models::{
+    BackgroundJob, ExportStatus, Import, ImportOutcome, ImportStatus, MediaStatus, MediaView,
+    NewBackgroundJob, ProcessedMedia,
+};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+// Failed jobs back off exponentially from this delay, up to MAX_RETRY_DELAY
+const BASE_RETRY_DELAY_SECS: i64 = 10;
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+
+// A job still running after this long counts as a failed attempt
+const JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Claimed jobs are picked up again if their worker has not finished them by then.
+// Longer than JOB_TIMEOUT so a live worker always records its result first.
+const CLAIM_LEASE_SECS: i64 = 6 * 60;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long events stay in the stream log for resuming clients
+const STREAM_EVENT_RETENTION_HOURS: i64 = 24;
+
+// How long completed jobs are kept; dead jobs are kept until retried
+const COMPLETED_JOB_RETENTION_DAYS: i64 = 7;
+
+// How long published outbox events are kept
+const PUBLISHED_EVENT_RETENTION_DAYS: i64 = 7;
+
+const PURGE_INTERVAL_MINUTES: i64 = 60;
+
+const MEDIA_GC_INTERVAL_MINUTES: i64 = 60;
+const MEDIA_GC_BATCH: i64 = 100;
+
+// What jobs have access to while they run
+#[derive(Clone)]
+pub struct JobContext {
+    pub db: Database,
+    pub media_store: Arc<dyn MediaStore>,
+    pub mailer: Arc<dyn Mailer>,
+}
+
+// A background job. The struct is the payload, stored as JSON, and `KIND` finds its
+// handler when a worker claims it, so it must not change while jobs are queued.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    const KIND: &'static str;
+    // Attempts before the job is moved to the dead letters
+    const MAX_ATTEMPTS: i32 = 5;
+
+    fn run(self, ctx: JobContext) -> impl Future<Output = Result<(), DbError>> + Send;
+}
+
+// A queue row for `job`, due at `run_at`
+pub fn new_job<J: Job>(job: &J, run_at: DateTime<Utc>) -> Result<NewBackgroundJob, DbError> {
+    Ok(NewBackgroundJob {
+        kind: J::KIND.to_string(),
+        payload: serde_json::to_value(job)?,
+        max_attempts: J::MAX_ATTEMPTS,
+        run_at,
+        dedupe_key: None,
+    })
+}
+
+type JobHandler = Box<
+    dyn Fn(serde_json::Value, JobContext) -> BoxFuture<'static, Result<(), DbError>> + Send + Sync,
+>;
+
+#[derive(Default)]
+pub struct JobRegistry {
+    handlers: HashMap<&'static str, JobHandler>,
+}
+
+impl JobRegistry {
+    pub fn register<J: Job>(&mut self) {
+        self.handlers.insert(
+            J::KIND,
+            Box::new(|payload, ctx| {
+                Box::pin(async move {
+                    let job: J = serde_json::from_value(payload)?;
+                    job.run(ctx).await
+                })
+            }),
+        );
+    }
+
+    async fn run(&self, job: &BackgroundJob, ctx: JobContext) -> Result<(), String> {
+        // Unknown kinds are retried like any failure, since a newer worker may know them
+        let Some(handler) = self.handlers.get(job.kind.as_str()) else {
+            return Err(format!("No handler registered for job kind {}", job.kind));
+        };
+        match tokio::time::timeout(JOB_TIMEOUT, handler(job.payload.clone(), ctx)).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(format!("Timed out after {} seconds", JOB_TIMEOUT.as_secs())),
+        }
+    }
+}
+
+// Every job type the worker can run
+pub fn registry() -> JobRegistry {
+    let mut registry = JobRegistry::default();
+    registry.register::<PurgeExpiredRecords>();
+    registry.register::<ProcessMedia>();
+    registry.register::<CollectUnusedMedia>();
+    registry.register::<SendEmail>();
+    registry.register::<BuildDataExport>();
+    registry.register::<DeleteAccount>();
+    registry.register::<RunImport>();
+    registry
+}
+
+// Deletes expired stream events, old completed jobs and published outbox events,
+// then schedules its next run
+#[derive(Deserialize, Serialize)]
+pub struct PurgeExpiredRecords;
+
+impl PurgeExpiredRecords {
+    const DEDUPE_KEY: &'static str = "purge_expired_records";
+
+    async fn schedule(db: &Database, run_at: DateTime<Utc>) -> Result<(), DbError> {
+        let job = NewBackgroundJob {
+            dedupe_key: Some(Self::DEDUPE_KEY.to_string()),
+            ..new_job(&PurgeExpiredRecords, run_at)?
+        };
+        db.enqueue_job(job).await?;
+        Ok(())
+    }
+}
+
+impl Job for PurgeExpiredRecords {
+    const KIND: &'static str = "purge_expired_records";
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let db = ctx.db;
+        let now = Utc::now();
+        db.prune_stream_events(now - chrono::Duration::hours(STREAM_EVENT_RETENTION_HOURS))
+            .await?;
+        db.prune_completed_jobs(now - chrono::Duration::days(COMPLETED_JOB_RETENTION_DAYS))
+            .await?;
+        db.prune_published_outbox_events(
+            now - chrono::Duration::days(PUBLISHED_EVENT_RETENTION_DAYS),
+        )
+        .await?;
+        db.prune_expired_confirmations(now).await?;
+        for key in db.take_expired_data_exports(now).await? {
+            if let Err(e) = ctx.media_store.delete(&key).await {
+                eprintln!("Error deleting export {}: {}", key, e);
+            }
+        }
+        Self::schedule(&db, now + chrono::Duration::minutes(PURGE_INTERVAL_MINUTES)).await
+    }
+}
+
+// Generates the resized variants and blurhash of an upload. Uploads are stripped of
+// metadata when they are received; `strip_original` also does that here, for files
+// stored before uploads were processed.
+#[derive(Deserialize, Serialize)]
+pub struct ProcessMedia {
+    pub media_id: Uuid,
+    #[serde(default)]
+    pub strip_original: bool,
+}
+
+impl Job for ProcessMedia {
+    const KIND: &'static str = "process_media";
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let Some(MediaView { media, .. }) = ctx.db.get_media(self.media_id).await? else {
+            return Ok(());
+        };
+        if media.status != MediaStatus::Processing {
+            return Ok(());
+        }
+        let data = ctx
+            .media_store
+            .get(&media.storage_key)
+            .await?
+            .ok_or("Uploaded file is missing")?;
+
+        let strip_original = self.strip_original;
+        let processed = tokio::task::spawn_blocking(move || {
+            let original = match strip_original {
+                true => {
+                    let content_type = media::sniff_content_type(&data)
+                        .ok_or("Unsupported file type")?
+                        .0;
+                    Some(media::strip_metadata(&data, content_type)?)
+                }
+                false => None,
+            };
+            let source = original.as_ref().map_or(&data[..], |image| &image.data[..]);
+            Ok::<_, DbError>((media::generate_variants(source)?, original))
+        })
+        .await?;
+        // Files that cannot be decoded will not decode on a retry either
+        let (generated, original) = match processed {
+            Ok(processed) => processed,
+            Err(e) => {
+                eprintln!("Error processing media {}: {}", media.id, e);
+                return ctx.db.fail_media_processing(media.id).await;
+            }
+        };
+
+        let variants = media::store_variants(
+            ctx.media_store.as_ref(),
+            media.id,
+            &media.storage_key,
+            generated.variants,
+        )
+        .await?;
+
+        let (size_bytes, width, height) = match original {
+            Some(image) => {
+                let dimensions = (image.data.len() as i64, image.width, image.height);
+                ctx.media_store
+                    .put(&media.storage_key, image.content_type, image.data.into())
+                    .await?;
+                dimensions
+            }
+            None => {
+                let (width, height) = media.width.zip(media.height).ok_or("Missing dimensions")?;
+                (media.size_bytes, width as u32, height as u32)
+            }
+        };
+        ctx.db
+            .complete_media_processing(
+                media.id,
+                ProcessedMedia {
+                    size_bytes,
+                    width: width as i32,
+                    height: height as i32,
+                    blurhash: generated.blurhash,
+                    variants,
+                },
+            )
+            .await
+    }
+}
+
+// Deletes uploads that no post, comment or avatar uses once they are older than the
+// grace period, then schedules its next run
+#[derive(Deserialize, Serialize)]
+pub struct CollectUnusedMedia;
+
+impl CollectUnusedMedia {
+    const DEDUPE_KEY: &'static str = "collect_unused_media";
+
+    async fn schedule(db: &Database, run_at: DateTime<Utc>) -> Result<(), DbError> {
+        let job = NewBackgroundJob {
+            dedupe_key: Some(Self::DEDUPE_KEY.to_string()),
+            ..new_job(&CollectUnusedMedia, run_at)?
+        };
+        db.enqueue_job(job).await?;
+        Ok(())
+    }
+}
+
+impl Job for CollectUnusedMedia {
+    const KIND: &'static str = "collect_unused_media";
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let now = Utc::now();
+        loop {
+            // Uploads are marked first so nothing can attach them, and their rows only go
+            // once every file is deleted. The rest are retried on the next run.
+            let unused = ctx
+                .db
+                .collect_unused_media(now - media::gc_grace(), MEDIA_GC_BATCH)
+                .await?;
+            let mut deleted = Vec::new();
+            for (media_id, keys) in unused {
+                let mut failed = false;
+                for key in &keys {
+                    if let Err(e) = ctx.media_store.delete(key).await {
+                        eprintln!("Error deleting media file {}: {}", key, e);
+                        failed = true;
+                    }
+                }
+                if !failed {
+                    deleted.push(media_id);
+                }
+            }
+            if deleted.is_empty() {
+                break;
+            }
+            ctx.db.delete_collected_media(deleted).await?;
+        }
+        Self::schedule(
+            &ctx.db,
+            now + chrono::Duration::minutes(MEDIA_GC_INTERVAL_MINUTES),
+        )
+        .await
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SendEmail {
+    pub message: EmailMessage,
+}
+
+impl Job for SendEmail {
+    const KIND: &'static str = "send_email";
+    const MAX_ATTEMPTS: i32 = 8;
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        ctx.mailer.send(&self.message).await
+    }
+}
+
+// Builds a user's data export and stores the archive next to their media
+#[derive(Deserialize, Serialize)]
+pub struct BuildDataExport {
+    pub export_id: Uuid,
+}
+
+impl BuildDataExport {
+    async fn build(&self, ctx: &JobContext, user_id: Uuid) -> Result<(String, i64), DbError> {
+        let data = ctx
+            .db
+            .get_user_data(user_id)
+            .await?
+            .ok_or("User not found")?;
+        let archive = accounts::build_export_archive(ctx.media_store.as_ref(), &data).await?;
+        let size_bytes = archive.as_file().metadata()?.len() as i64;
+        let key = accounts::export_storage_key(user_id, self.export_id);
+        ctx.media_store
+            .put_file(&key, "application/gzip", archive.path())
+            .await?;
+        Ok((key, size_bytes))
+    }
+}
+
+impl Job for BuildDataExport {
+    const KIND: &'static str = "build_data_export";
+
+    // Failures are recorded on the export instead of retried; asking for the export
+    // again starts a new one
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let Some(export) = ctx.db.get_data_export(self.export_id).await? else {
+            return Ok(());
+        };
+        if export.status != ExportStatus::Pending {
+            return Ok(());
+        }
+        let expires_at = Utc::now() + accounts::export_retention();
+        match self.build(&ctx, export.user_id).await {
+            Ok((key, size_bytes)) => {
+                ctx.db
+                    .complete_data_export(export.id, key, size_bytes, expires_at)
+                    .await
+            }
+            Err(e) => {
+                eprintln!("Error building data export {}: {}", export.id, e);
+                ctx.db.fail_data_export(export.id, expires_at).await
+            }
+        }
+    }
+}
+
+// Erases an account once its deletion grace period is over, unless the user changed
+// their mind
+#[derive(Deserialize, Serialize)]
+pub struct DeleteAccount {
+    pub user_id: Uuid,
+}
+
+impl Job for DeleteAccount {
+    const KIND: &'static str = "delete_account";
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let requested_before = Utc::now() - accounts::account_deletion_grace();
+        let Some(keys) = ctx
+            .db
+            .delete_account(self.user_id, requested_before)
+            .await?
+        else {
+            return Ok(());
+        };
+        for key in &keys {
+            if let Err(e) = ctx.media_store.delete(key).await {
+                eprintln!("Error deleting file {}: {}", key, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Imports an uploaded archive a batch of items at a time, queueing itself again
+// until every item has been handled. The file is read on the first run and its
+// items stored; later runs take the next batch of stored items.
+#[derive(Deserialize, Serialize)]
+pub struct RunImport {
+    pub import_id: Uuid,
+}
+
+impl RunImport {
+    async fn import_item(
+        ctx: &JobContext,
+        import: &Import,
+        item: ImportItem,
+    ) -> Result<ImportOutcome, DbError> {
+        match item {
+            // Media is not imported, so posts with nothing else are left out
+            ImportItem::Post(post) if post.content.trim().is_empty() => Ok(ImportOutcome::Skipped),
+            ImportItem::Post(post) => {
+                match ctx
+                    .db
+                    .import_post(import.user_id, import.kind, post)
+                    .await?
+                {
+                    true => Ok(ImportOutcome::Imported),
+                    false => Ok(ImportOutcome::Skipped),
+                }
+            }
+            ImportItem::Follow {
+                account,
+                username,
+                domain,
+            } => {
+                if !imports::is_local_account(domain.as_deref()) {
+                    return Ok(ImportOutcome::Failed(format!(
+                        "{} is on another server",
+                        account
+                    )));
+                }
+                let Some(following_id) = ctx.db.find_user_id_by_username(&username).await? else {
+                    return Ok(ImportOutcome::Failed(format!("No user named {}", username)));
+                };
+                if following_id == import.user_id {
+                    return Ok(ImportOutcome::Skipped);
+                }
+                match ctx.db.follow_user(import.user_id, following_id).await {
+                    Ok(_) => Ok(ImportOutcome::Imported),
+                    Err(e) if e.is::<BlockedError>() => Ok(ImportOutcome::Failed(e.to_string())),
+                    // Already following or requested to follow
+                    Err(e) if e.to_string().contains("unique constraint") => {
+                        Ok(ImportOutcome::Skipped)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            ImportItem::Invalid { error, .. } => Ok(ImportOutcome::Failed(error)),
+        }
+    }
+
+    async fn finish(
+        ctx: &JobContext,
+        import: &Import,
+        error: Option<String>,
+    ) -> Result<(), DbError> {
+        ctx.db.finish_import(import.id, error).await?;
+        if let Some(key) = &import.storage_key
+            && let Err(e) = ctx.media_store.delete(key).await
+        {
+            eprintln!("Error deleting file {}: {}", key, e);
+        }
+        Ok(())
+    }
+}
+
+impl Job for RunImport {
+    const KIND: &'static str = "run_import";
+
+    async fn run(self, ctx: JobContext) -> Result<(), DbError> {
+        let Some(import) = ctx.db.get_import(self.import_id).await? else {
+            return Ok(());
+        };
+        if matches!(
+            import.status,
+            ImportStatus::Completed | ImportStatus::Failed
+        ) {
+            return Ok(());
+        }
+        if import.total_items.is_none() {
+            let key = import.storage_key.as_deref().ok_or("Import has no file")?;
+            let data = ctx
+                .media_store
+                .get(key)
+                .await?
+                .ok_or("Uploaded file is missing")?;
+
+            // Files that cannot be read will not read on a retry either
+            let kind = import.kind;
+            let items =
+                match tokio::task::spawn_blocking(move || imports::parse(kind, &data)).await? {
+                    Ok(items) => items,
+                    Err(e) => return Self::finish(&ctx, &import, Some(e.to_string())).await,
+                };
+            ctx.db.start_import(import.id, items).await?;
+        }
+
+        let items = ctx
+            .db
+            .get_import_items(import.id, imports::BATCH_SIZE as i64)
+            .await?;
+        // A short batch was the last one
+        let more = items.len() == imports::BATCH_SIZE;
+        for (index, item) in items {
+            let label = item.label().to_string();
+            let outcome = Self::import_item(&ctx, &import, item).await?;
+            ctx.db
+                .record_import_progress(import.id, index, label, outcome)
+                .await?;
+        }
+
+        let Some(import) = ctx.db.get_import(self.import_id).await? else {
+            return Ok(());
+        };
+        if more
+            && import
+                .total_items
+                .is_some_and(|total| import.processed_items < total)
+        {
+            let job = new_job(
+                &RunImport {
+                    import_id: import.id,
+                },
+                Utc::now(),
+            )?;
+            ctx.db.enqueue_job(job).await?;
+            return Ok(());
+        }
+        Self::finish(&ctx, &import, None).await
+    }
+}
+
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    let secs = BASE_RETRY_DELAY_SECS
+        .saturating_mul(2i64.pow(exponent))
+        .min(MAX_RETRY_DELAY_SECS);
+    // Jitter keeps jobs that failed together from retrying together
+    let jitter = rand::thread_rng().gen_range(0..=secs / 10);
+    chrono::Duration::seconds(secs + jitter)
+}
+
+// Runs queued jobs, up to `concurrency` at a time. Jobs are claimed with SKIP
+// LOCKED, so any number of workers can share the queue.
+pub struct JobWorker {
+    ctx: JobContext,
+    registry: Arc<JobRegistry>,
+    slots: Arc<Semaphore>,
+}
+
+impl JobWorker {
+    pub fn new(ctx: JobContext, registry: JobRegistry, concurrency: usize) -> Self {
+        Self {
+            ctx,
+            registry: Arc::new(registry),
+            slots: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    async fn execute(ctx: JobContext, registry: Arc<JobRegistry>, job: BackgroundJob) {
+        let db = ctx.db.clone();
+        let recorded = match registry.run(&job, ctx).await {
+            Ok(()) => db.complete_job(job.id, job.attempts).await,
+            Err(error) => {
+                // `attempts` was already incremented when the job was claimed
+                let retry_at = (job.attempts < job.max_attempts)
+                    .then(|| Utc::now() + retry_delay(job.attempts));
+                db.fail_job(job.id, job.attempts, error, retry_at).await
+            }
+        };
+        match recorded {
+            Ok(true) => {}
+            Ok(false) => eprintln!("Job {} lost its lease before finishing", job.id),
+            Err(e) => eprintln!("Error recording result of job {}: {}", job.id, e),
+        }
+    }
+
+    // Claims and runs jobs until the process exits
+    pub async fn run(self) {
+        let now = Utc::now();
+        if let Err(e) = PurgeExpiredRecords::schedule(&self.ctx.db, now).await {
+            eprintln!("Error scheduling maintenance jobs: {}", e);
+        }
+        if let Err(e) = CollectUnusedMedia::schedule(&self.ctx.db, now).await {
+            eprintln!("Error scheduling maintenance jobs: {}", e);
+        }
+
+        loop {
+            // Wait for a free slot, then claim as many jobs as there are free slots
+            let first = match self.slots.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(e) => {
+                    eprintln!("Job worker stopped: {}", e);
+                    return;
+                }
+            };
+            let free = self.slots.available_permits() + 1;
+            let jobs = match self
+                .ctx
+                .db
+                .claim_jobs(free as i64, chrono::Duration::seconds(CLAIM_LEASE_SECS))
+                .await
+            {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    eprintln!("Error claiming jobs: {}", e);
+                    Vec::new()
+                }
+            };
+            if jobs.is_empty() {
+                drop(first);
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+
+            let mut permit = Some(first);
+            for job in jobs {
+                let permit = match permit.take() {
+                    Some(permit) => Ok(permit),
+                    None => self.slots.clone().acquire_owned().await,
+                };
+                // Jobs claimed but not started are picked up again once their lease
+                // expires
+                let Ok(permit) = permit else {
+                    eprintln!("Job worker stopped with claimed jobs left unstarted");
+                    return;
+                };
+                let ctx = self.ctx.clone();
+                let registry = self.registry.clone();
+                tokio::spawn(async move {
+                    Self::execute(ctx, registry, job).await;
+                    drop(permit);
+                });
+            }
+        }
+    }
+}
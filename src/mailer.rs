@@ -0,0 +1,95 @@
+use crate::database::DbError;
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_FROM: &str = "no-reply@localhost";
+const DEFAULT_APP_URL: &str = "http://localhost:3000";
+const LOG_MAILER_URL: &str = "log://";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Sends email on behalf of the `SendEmail` job, which retries failures
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, message: &'a EmailMessage) -> BoxFuture<'a, Result<(), DbError>>;
+}
+
+// Prints messages instead of sending them, for development with `MAIL_API_URL=log://`
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send<'a>(&'a self, message: &'a EmailMessage) -> BoxFuture<'a, Result<(), DbError>> {
+        println!(
+            "Email to {}: {}\n{}",
+            message.to, message.subject, message.body
+        );
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Posts each message as JSON, with a `from` address, to a transactional email API or
+// a relay in front of an SMTP server
+pub struct HttpMailer {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    from: String,
+}
+
+#[derive(Serialize)]
+struct OutgoingEmail<'a> {
+    from: &'a str,
+    #[serde(flatten)]
+    message: &'a EmailMessage,
+}
+
+impl Mailer for HttpMailer {
+    fn send<'a>(&'a self, message: &'a EmailMessage) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let mut request = self.client.post(&self.url).json(&OutgoingEmail {
+                from: &self.from,
+                message,
+            });
+            if let Some(api_key) = &self.api_key {
+                request = request.bearer_auth(api_key);
+            }
+            request.send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+// Sends through `MAIL_API_URL`, authenticated with `MAIL_API_KEY` and from `MAIL_FROM`,
+// or only logs messages when it is `log://`. Unset is an error, so a deployment cannot
+// drop its email by accident.
+pub fn mailer_from_env() -> Result<Box<dyn Mailer>, DbError> {
+    let url = env::var("MAIL_API_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .ok_or("MAIL_API_URL must be set")?;
+    if url == LOG_MAILER_URL {
+        return Ok(Box::new(LogMailer));
+    }
+    Ok(Box::new(HttpMailer {
+        client: reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?,
+        url,
+        api_key: env::var("MAIL_API_KEY").ok(),
+        from: env::var("MAIL_FROM").unwrap_or_else(|_| DEFAULT_FROM.to_string()),
+    }))
+}
+
+// Where links in emails point: the web client, `APP_URL`
+pub fn app_url() -> String {
+    env::var("APP_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| DEFAULT_APP_URL.to_string())
+}
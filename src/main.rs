@@ -1,13 +1,62 @@
+mod accounts;
+mod auth;
+mod content;
 mod database;
 mod handlers;
+mod imports;
+mod jobs;
+mod mailer;
+mod media;
 // mod lib;
 mod models;
+mod outbox;
 mod schema;
+mod stream;
+mod webhooks;
 
 use actix_web::{App, HttpServer, web};
 use deadpool_diesel::postgres::{Manager, Pool};
 use dotenvy::dotenv;
 use std::env;
+use std::sync::Arc;
+
+// Connects the outbox sinks, so a misconfigured broker stops the process at startup
+async fn event_sinks(
+    database: &database::Database,
+) -> std::io::Result<Vec<Box<dyn outbox::EventSink>>> {
+    outbox::sinks(database.clone())
+        .await
+        .map_err(|e| std::io::Error::other(format!("Failed to connect to the event broker: {}", e)))
+}
+
+// Configures email for the jobs that send it, failing at startup when it is not set up
+fn job_mailer() -> std::io::Result<Arc<dyn mailer::Mailer>> {
+    mailer::mailer_from_env()
+        .map(Arc::from)
+        .map_err(|e| std::io::Error::other(format!("Failed to configure email: {}", e)))
+}
+
+// Relays outbox events, delivers webhooks and runs queued jobs until the process exits
+async fn run_background(
+    database: database::Database,
+    media_store: Arc<dyn media::MediaStore>,
+    sinks: Vec<Box<dyn outbox::EventSink>>,
+    mailer: Arc<dyn mailer::Mailer>,
+) {
+    let concurrency = env::var("JOB_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(4);
+    let relay = outbox::OutboxRelay::new(database.clone(), sinks);
+    let dispatcher = webhooks::WebhookDispatcher::new(database.clone());
+    let ctx = jobs::JobContext {
+        db: database,
+        media_store,
+        mailer,
+    };
+    let worker = jobs::JobWorker::new(ctx, jobs::registry(), concurrency);
+    tokio::join!(relay.run(), dispatcher.run(), worker.run());
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -16,22 +65,177 @@ async fn main() -> std::io::Result<()> {
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
-    let manager = Manager::new(database_url, deadpool_diesel::Runtime::Tokio1);
+    let manager = Manager::new(database_url.clone(), deadpool_diesel::Runtime::Tokio1);
     let pool = Pool::builder(manager)
         .build()
         .expect("Failed to create pool");
 
     let database = database::Database::new(pool);
+    let media_store: Arc<dyn media::MediaStore> =
+        Arc::from(media::store_from_env().expect("Failed to configure media storage"));
+
+    // `posts worker` runs only the background work, for deployments that scale it
+    // separately from the API
+    if env::args().nth(1).as_deref() == Some("worker") {
+        println!("Starting background worker");
+        let mailer = job_mailer()?;
+        let sinks = event_sinks(&database).await?;
+        run_background(database, media_store, sinks, mailer).await;
+        return Ok(());
+    }
+    let jwt_config = web::Data::new(auth::JwtConfig::new());
+    let stream_hub = stream::StreamHub::new(database.clone());
+    actix_web::rt::spawn(stream_hub.clone().run(database_url));
+    let stream_hub = web::Data::new(stream_hub);
+
+    if env::var("IN_PROCESS_WORKER").map_or(true, |value| value != "false") {
+        let mailer = job_mailer()?;
+        let sinks = event_sinks(&database).await?;
+        actix_web::rt::spawn(run_background(
+            database.clone(),
+            media_store.clone(),
+            sinks,
+            mailer,
+        ));
+    }
+
+    let media_store = web::Data::from(media_store);
 
     println!("Starting server at http://127.0.0.1:8080");
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(database.clone()))
+            .app_data(jwt_config.clone())
+            .app_data(stream_hub.clone())
+            .app_data(media_store.clone())
             .service(
                 web::scope("/api")
+                    .route("/auth/register", web::post().to(handlers::register))
+                    .route("/auth/login", web::post().to(handlers::login))
+                    .route(
+                        "/auth/confirm-email",
+                        web::post().to(handlers::confirm_email_change),
+                    )
+                    .route(
+                        "/auth/set-password",
+                        web::post().to(handlers::request_password_setup),
+                    )
+                    .route(
+                        "/auth/set-password/confirm",
+                        web::post().to(handlers::confirm_password_setup),
+                    )
+                    .route(
+                        "/auth/delete-account",
+                        web::post().to(handlers::request_deletion_confirmation),
+                    )
+                    .route(
+                        "/auth/delete-account/confirm",
+                        web::post().to(handlers::confirm_account_deletion),
+                    )
                     .route("/users", web::post().to(handlers::create_user))
+                    .route("/users/me", web::patch().to(handlers::update_profile))
+                    .route("/users/me", web::delete().to(handlers::delete_account))
+                    .route(
+                        "/users/me/restore",
+                        web::post().to(handlers::cancel_account_deletion),
+                    )
+                    .route("/users/me/export", web::get().to(handlers::get_data_export))
+                    .route(
+                        "/users/me/export/download",
+                        web::get().to(handlers::download_data_export),
+                    )
+                    .route("/imports", web::post().to(handlers::create_import))
+                    .route("/imports", web::get().to(handlers::get_imports))
+                    .route("/imports/{import_id}", web::get().to(handlers::get_import))
+                    .route(
+                        "/users/me/username",
+                        web::put().to(handlers::change_username),
+                    )
+                    .route(
+                        "/users/me/email",
+                        web::post().to(handlers::request_email_change),
+                    )
+                    .route(
+                        "/users/me/privacy",
+                        web::put().to(handlers::set_account_private),
+                    )
+                    .route(
+                        "/users/me/follow-requests",
+                        web::get().to(handlers::get_follow_requests),
+                    )
+                    .route(
+                        "/users/me/follow-requests/{requester_id}/approve",
+                        web::post().to(handlers::approve_follow_request),
+                    )
+                    .route(
+                        "/users/me/follow-requests/{requester_id}/reject",
+                        web::post().to(handlers::reject_follow_request),
+                    )
+                    .route(
+                        "/users/me/mentions",
+                        web::get().to(handlers::get_user_mentions),
+                    )
+                    .route(
+                        "/users/me/blocks",
+                        web::get().to(handlers::get_blocked_users),
+                    )
+                    .route(
+                        "/users/me/blocks/{user_id}",
+                        web::post().to(handlers::block_user),
+                    )
+                    .route(
+                        "/users/me/blocks/{user_id}",
+                        web::delete().to(handlers::unblock_user),
+                    )
+                    .route(
+                        "/users/me/bookmarks",
+                        web::get().to(handlers::get_bookmarks),
+                    )
+                    .route(
+                        "/users/me/bookmarks/collections",
+                        web::get().to(handlers::get_bookmark_collections),
+                    )
+                    .route(
+                        "/users/me/bookmarks/collections",
+                        web::post().to(handlers::create_bookmark_collection),
+                    )
+                    .route(
+                        "/users/me/bookmarks/collections/{collection_id}",
+                        web::patch().to(handlers::rename_bookmark_collection),
+                    )
+                    .route(
+                        "/users/me/bookmarks/collections/{collection_id}",
+                        web::delete().to(handlers::delete_bookmark_collection),
+                    )
+                    .route("/users/me/mutes", web::get().to(handlers::get_muted_users))
+                    .route(
+                        "/users/me/storage",
+                        web::get().to(handlers::get_storage_usage),
+                    )
+                    .route("/users/me/avatar", web::put().to(handlers::set_avatar))
+                    .route(
+                        "/users/me/avatar",
+                        web::delete().to(handlers::remove_avatar),
+                    )
+                    .route("/users/me/banner", web::put().to(handlers::set_banner))
+                    .route(
+                        "/users/me/banner",
+                        web::delete().to(handlers::remove_banner),
+                    )
+                    .route(
+                        "/users/me/mutes/{user_id}",
+                        web::post().to(handlers::mute_user),
+                    )
+                    .route(
+                        "/users/me/mutes/{user_id}",
+                        web::delete().to(handlers::unmute_user),
+                    )
                     .route("/users/{id}", web::get().to(handlers::get_user))
+                    .route(
+                        "/users/{user_id}/identicon",
+                        web::get().to(handlers::get_identicon),
+                    )
                     .route(
                         "/users/username/{username}",
                         web::get().to(handlers::get_user_by_username),
@@ -50,21 +254,115 @@ async fn main() -> std::io::Result<()> {
                     )
                     .route("/posts", web::post().to(handlers::create_post))
                     .route("/posts", web::get().to(handlers::get_posts))
+                    .route("/feed", web::get().to(handlers::get_feed))
+                    .route("/posts/{post_id}", web::get().to(handlers::get_post))
+                    .route("/posts/{post_id}", web::put().to(handlers::update_post))
+                    .route(
+                        "/posts/{post_id}/thread",
+                        web::get().to(handlers::get_post_thread),
+                    )
+                    .route("/posts/{post_id}/pin", web::post().to(handlers::pin_post))
+                    .route(
+                        "/posts/{post_id}/pin",
+                        web::delete().to(handlers::unpin_post),
+                    )
+                    .route(
+                        "/posts/{post_id}/bookmark",
+                        web::post().to(handlers::bookmark_post),
+                    )
+                    .route(
+                        "/posts/{post_id}/bookmark",
+                        web::delete().to(handlers::unbookmark_post),
+                    )
                     .route(
                         "/posts/{post_id}/comments",
                         web::get().to(handlers::get_post_comments),
                     )
                     .route("/comments", web::post().to(handlers::create_comment))
+                    .route("/media", web::post().to(handlers::upload_media))
+                    .route(
+                        "/media/files/{key:.*}",
+                        web::get().to(handlers::get_media_file),
+                    )
+                    .route("/media/{media_id}", web::get().to(handlers::get_media))
+                    .route("/media/{media_id}", web::patch().to(handlers::update_media))
+                    .route(
+                        "/comments/{comment_id}",
+                        web::put().to(handlers::update_comment),
+                    )
+                    .route("/hashtags/{tag}", web::get().to(handlers::get_hashtag))
+                    .route(
+                        "/hashtags/{tag}/posts",
+                        web::get().to(handlers::get_hashtag_posts),
+                    )
+                    .route("/search/posts", web::get().to(handlers::search_posts))
+                    .route("/search/comments", web::get().to(handlers::search_comments))
+                    .route("/search/users", web::get().to(handlers::search_users))
+                    .route("/notifications", web::get().to(handlers::get_notifications))
+                    .route("/stream", web::get().to(handlers::stream_events))
+                    .route("/stream/ws", web::get().to(handlers::stream_websocket))
+                    .route("/webhooks", web::get().to(handlers::get_webhooks))
+                    .route("/webhooks", web::post().to(handlers::create_webhook))
+                    .route(
+                        "/webhooks/{webhook_id}",
+                        web::get().to(handlers::get_webhook),
+                    )
+                    .route(
+                        "/webhooks/{webhook_id}",
+                        web::put().to(handlers::update_webhook),
+                    )
+                    .route(
+                        "/webhooks/{webhook_id}",
+                        web::delete().to(handlers::delete_webhook),
+                    )
+                    .route(
+                        "/webhooks/{webhook_id}/deliveries",
+                        web::get().to(handlers::get_webhook_deliveries),
+                    )
+                    .route(
+                        "/admin/webhooks",
+                        web::get().to(handlers::get_instance_webhooks),
+                    )
+                    .route(
+                        "/admin/webhooks",
+                        web::post().to(handlers::create_instance_webhook),
+                    )
+                    .route("/admin/jobs", web::get().to(handlers::get_jobs))
+                    .route(
+                        "/admin/users/{user_id}/storage-quota",
+                        web::put().to(handlers::set_storage_quota),
+                    )
+                    .route(
+                        "/admin/jobs/{job_id}/retry",
+                        web::post().to(handlers::retry_job),
+                    )
+                    .route(
+                        "/notifications/unread-count",
+                        web::get().to(handlers::get_unread_notification_count),
+                    )
+                    .route(
+                        "/notifications/read",
+                        web::post().to(handlers::mark_all_notifications_read),
+                    )
+                    .route(
+                        "/notifications/preferences",
+                        web::get().to(handlers::get_notification_preferences),
+                    )
+                    .route(
+                        "/notifications/preferences",
+                        web::put().to(handlers::update_notification_preferences),
+                    )
                     .route(
-                        "/posts/{post_id}/like/{user_id}",
-                        web::post().to(handlers::like_post),
+                        "/notifications/{group_key}/read",
+                        web::post().to(handlers::mark_notification_group_read),
                     )
+                    .route("/posts/{post_id}/like", web::post().to(handlers::like_post))
                     .route(
-                        "/posts/{post_id}/share/{user_id}",
+                        "/posts/{post_id}/share",
                         web::post().to(handlers::share_post),
                     )
                     .route(
-                        "/users/{follower_id}/follow/{following_id}",
+                        "/users/{user_id}/follow",
                         web::post().to(handlers::follow_user),
                     ),
             )
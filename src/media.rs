@@ -0,0 +1,741 @@
+use crate::database::DbError;
+use crate::models::{NewMediaVariant, ProfileImageKind};
+use actix_web::web::Bytes;
+use futures_util::future::BoxFuture;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutMultipartOptions, PutPayload, WriteMultipart};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_MEDIA_ROOT: &str = "./media";
+
+// How much of a file is read at a time, and how many parts are sent at once, when it
+// is uploaded to S3 in parts
+const UPLOAD_CHUNK_BYTES: usize = 1024 * 1024;
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+// Where files are served from when no public URL is configured
+pub const MEDIA_FILES_PATH: &str = "/api/media/files";
+
+// Content types accepted for upload, with the extension stored files get
+const ALLOWED_TYPES: [(&str, &str); 4] = [
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+// Most media items a post or comment can carry
+pub const MAX_ATTACHMENTS: usize = 4;
+
+// Limit for the text fields sent alongside an upload, such as its alt text
+pub const MAX_TEXT_FIELD_BYTES: usize = 8 * 1024;
+
+// The content type and file extension of an upload, sniffed from its first bytes
+// rather than trusted from the client. None when the type is not accepted.
+pub fn sniff_content_type(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let mime = infer::get(data)?.mime_type();
+    ALLOWED_TYPES
+        .iter()
+        .find(|(allowed, _)| *allowed == mime)
+        .copied()
+}
+
+// Larger images are rejected before their pixels are decoded
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+const MAX_IMAGE_PIXELS: u64 = 40_000_000;
+
+// Most memory a decoder may allocate for one image
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+// Resized variants by name and width. Images narrower than a width get a variant at
+// their own width instead, and none for the larger names.
+const VARIANT_WIDTHS: [(&str, u32); 3] = [("small", 320), ("medium", 640), ("large", 1280)];
+
+const JPEG_QUALITY: u8 = 85;
+
+// Avatars are cropped square and banners 3:1, at most this size, then scaled down to
+// the variant widths
+const AVATAR_SIZE: (u32, u32) = (400, 400);
+const AVATAR_VARIANT_WIDTHS: [(&str, u32); 3] = [("small", 48), ("medium", 96), ("large", 200)];
+const BANNER_SIZE: (u32, u32) = (1500, 500);
+const BANNER_VARIANT_WIDTHS: [(&str, u32); 2] = [("small", 600), ("medium", 1000)];
+
+// Where generated default avatars are served from
+const IDENTICON_PATH: &str = "/api/users";
+const IDENTICON_GRID: u32 = 5;
+
+// Blurhash detail, and the size images are scaled down to before it is computed
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const BLURHASH_SAMPLE_SIZE: u32 = 64;
+
+const DEFAULT_STORAGE_QUOTA_BYTES: i64 = 1024 * 1024 * 1024;
+
+// Unattached uploads are kept this long before they are collected
+const DEFAULT_GC_GRACE_HOURS: i64 = 24;
+
+// The storage quota of a role: `MEDIA_QUOTA_BYTES` for users (default 1 GiB) and
+// `MEDIA_ADMIN_QUOTA_BYTES` for admins. None is unlimited, which admins are unless
+// their variable is set.
+pub fn role_quota_bytes(is_admin: bool) -> Option<i64> {
+    let (variable, default) = match is_admin {
+        true => ("MEDIA_ADMIN_QUOTA_BYTES", None),
+        false => ("MEDIA_QUOTA_BYTES", Some(DEFAULT_STORAGE_QUOTA_BYTES)),
+    };
+    env::var(variable)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .or(default)
+}
+
+// How long uploads may go unreferenced before they are deleted, from
+// `MEDIA_GC_GRACE_HOURS`
+pub fn gc_grace() -> chrono::Duration {
+    let hours = env::var("MEDIA_GC_GRACE_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_GC_GRACE_HOURS);
+    chrono::Duration::hours(hours)
+}
+
+// Whether media must have alt text before it can be attached to a post or comment,
+// set with `MEDIA_REQUIRE_ALT_TEXT=true`
+pub fn require_alt_text() -> bool {
+    env::var("MEDIA_REQUIRE_ALT_TEXT").is_ok_and(|value| value == "true")
+}
+
+pub fn max_upload_bytes() -> usize {
+    env::var("MEDIA_MAX_UPLOAD_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+// An encoded image file with its pixel dimensions
+pub struct EncodedImage {
+    pub content_type: &'static str,
+    pub extension: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+// A processed upload's placeholder and resized variants, each as WebP and as JPEG
+// (PNG for images with transparency)
+pub struct ImageVariants {
+    pub blurhash: String,
+    pub variants: Vec<(&'static str, EncodedImage)>,
+}
+
+fn reader(data: &[u8]) -> Result<ImageReader<Cursor<&[u8]>>, DbError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    Ok(reader)
+}
+
+// Decodes an image with its EXIF orientation applied to the pixels
+pub fn decode(data: &[u8]) -> Result<DynamicImage, DbError> {
+    let mut decoder = reader(data)?.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    check_pixel_count(width, height)?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn check_pixel_count(width: u32, height: u32) -> Result<(), DbError> {
+    if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+        return Err("Image is too large".into());
+    }
+    Ok(())
+}
+
+// Encodes `image` as `content_type`. Encoders only write pixels, so nothing from the
+// source file's metadata carries over.
+fn encode(image: &DynamicImage, content_type: &'static str) -> Result<EncodedImage, DbError> {
+    let (_, extension) = ALLOWED_TYPES
+        .iter()
+        .find(|(allowed, _)| *allowed == content_type)
+        .copied()
+        .ok_or("Unsupported content type")?;
+    let pixels = if image.color().has_alpha() && content_type != "image/jpeg" {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    let mut data = Vec::new();
+    match content_type {
+        "image/jpeg" => {
+            pixels.write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY))?
+        }
+        "image/png" => pixels.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?,
+        "image/webp" => pixels.write_to(&mut Cursor::new(&mut data), ImageFormat::WebP)?,
+        _ => return Err("Unsupported content type".into()),
+    }
+    Ok(EncodedImage {
+        content_type,
+        extension,
+        width: image.width(),
+        height: image.height(),
+        data,
+    })
+}
+
+// Re-encodes an upload without its metadata, such as the EXIF GPS position, and with
+// its orientation applied. GIFs are not re-encoded so animations survive; their
+// comment and application extensions are dropped instead.
+pub fn strip_metadata(data: &[u8], content_type: &'static str) -> Result<EncodedImage, DbError> {
+    if content_type == "image/gif" {
+        let (width, height) = reader(data)?.into_dimensions()?;
+        check_pixel_count(width, height)?;
+        return Ok(EncodedImage {
+            content_type,
+            extension: "gif",
+            width,
+            height,
+            data: strip_gif_extensions(data)?,
+        });
+    }
+    encode(&decode(data)?, content_type)
+}
+
+// Application extensions that only control how animations loop
+const GIF_LOOP_EXTENSIONS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+// Copies a GIF block by block, leaving out comment extensions and application
+// extensions other than the loop count, which is where XMP and other metadata live
+fn strip_gif_extensions(data: &[u8]) -> Result<Vec<u8>, DbError> {
+    const INVALID: &str = "Invalid GIF";
+    let take = |pos: usize, len: usize| data.get(pos..pos + len).ok_or(INVALID);
+    // The end of the data sub-blocks starting at `pos`, after their terminator
+    let sub_blocks_end = |mut pos: usize| -> Result<usize, &str> {
+        loop {
+            let size = *data.get(pos).ok_or(INVALID)? as usize;
+            pos += 1 + size;
+            if size == 0 {
+                return Ok(pos);
+            }
+        }
+    };
+    let color_table_len = |flags: u8| match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1),
+    };
+
+    // Header, logical screen descriptor and global color table
+    let screen_flags = take(10, 1)?[0];
+    let mut pos = 13 + color_table_len(screen_flags);
+    let mut stripped = take(0, pos)?.to_vec();
+    loop {
+        let start = pos;
+        match *data.get(pos).ok_or(INVALID)? {
+            0x3B => {
+                stripped.push(0x3B);
+                return Ok(stripped);
+            }
+            0x2C => {
+                let image_flags = take(pos + 9, 1)?[0];
+                // Descriptor, local color table and LZW code size, then the pixels
+                pos = sub_blocks_end(pos + 10 + color_table_len(image_flags) + 1)?;
+            }
+            0x21 => {
+                let label = take(pos + 1, 1)?[0];
+                pos = sub_blocks_end(pos + 2)?;
+                let keep = match label {
+                    0xFE => false,
+                    0xFF => {
+                        let size = take(start + 2, 1)?[0] as usize;
+                        let identifier = take(start + 3, size)?;
+                        GIF_LOOP_EXTENSIONS.contains(&identifier)
+                    }
+                    _ => true,
+                };
+                if !keep {
+                    continue;
+                }
+            }
+            _ => return Err(INVALID.into()),
+        }
+        stripped.extend_from_slice(take(start, pos - start)?);
+    }
+}
+
+fn blurhash(image: &DynamicImage) -> Result<String, DbError> {
+    let sample = image
+        .thumbnail(BLURHASH_SAMPLE_SIZE, BLURHASH_SAMPLE_SIZE)
+        .to_rgba8();
+    let (components_x, components_y) = BLURHASH_COMPONENTS;
+    Ok(blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )?)
+}
+
+// JPEG, or PNG to keep transparency
+fn fallback_type(image: &DynamicImage) -> &'static str {
+    if image.color().has_alpha() {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+// Generates the resized variants and blurhash of a stored upload
+pub fn generate_variants(data: &[u8]) -> Result<ImageVariants, DbError> {
+    let image = decode(data)?;
+    let blurhash = blurhash(&image)?;
+    let fallback_type = fallback_type(&image);
+    let mut variants = Vec::new();
+    for (name, width) in VARIANT_WIDTHS {
+        let resized = if width < image.width() {
+            image.resize(width, MAX_IMAGE_DIMENSION, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        variants.push((name, encode(&resized, "image/webp")?));
+        variants.push((name, encode(&resized, fallback_type)?));
+        if width >= image.width() {
+            break;
+        }
+    }
+    Ok(ImageVariants { blurhash, variants })
+}
+
+// Crops an avatar or banner upload to its aspect ratio around the center, returning
+// the cropped image and its smaller variants, plus a WebP version named `full`
+pub fn crop_profile_image(
+    data: &[u8],
+    kind: ProfileImageKind,
+) -> Result<(EncodedImage, ImageVariants), DbError> {
+    let image = decode(data)?;
+    let ((max_width, max_height), widths) = match kind {
+        ProfileImageKind::Avatar => (AVATAR_SIZE, &AVATAR_VARIANT_WIDTHS[..]),
+        ProfileImageKind::Banner => (BANNER_SIZE, &BANNER_VARIANT_WIDTHS[..]),
+    };
+    // The largest crop that fits the image, without scaling it up
+    let scale = f64::min(
+        1.0,
+        f64::min(
+            image.width() as f64 / max_width as f64,
+            image.height() as f64 / max_height as f64,
+        ),
+    );
+    let width = ((max_width as f64 * scale).round() as u32).max(1);
+    let height = ((max_height as f64 * scale).round() as u32).max(1);
+    let cropped = image.resize_to_fill(width, height, FilterType::Lanczos3);
+
+    let fallback_type = fallback_type(&cropped);
+    let mut variants = Vec::new();
+    for &(name, variant_width) in widths {
+        if variant_width >= width {
+            break;
+        }
+        let variant_height = (variant_width * height / width).max(1);
+        let resized = cropped.resize_exact(variant_width, variant_height, FilterType::Lanczos3);
+        variants.push((name, encode(&resized, "image/webp")?));
+        variants.push((name, encode(&resized, fallback_type)?));
+    }
+    variants.push(("full", encode(&cropped, "image/webp")?));
+
+    let blurhash = blurhash(&cropped)?;
+    Ok((
+        encode(&cropped, fallback_type)?,
+        ImageVariants { blurhash, variants },
+    ))
+}
+
+// Stores variants next to their upload, as `{user_id}/{media_id}/{name}.{ext}`. When
+// one fails, those already stored are deleted again.
+pub async fn store_variants(
+    store: &dyn MediaStore,
+    media_id: uuid::Uuid,
+    storage_key: &str,
+    variants: Vec<(&'static str, EncodedImage)>,
+) -> Result<Vec<NewMediaVariant>, DbError> {
+    let base = storage_key
+        .rsplit_once('.')
+        .map_or(storage_key, |(base, _)| base);
+    let mut stored: Vec<NewMediaVariant> = Vec::new();
+    for (name, image) in variants {
+        let key = format!("{}/{}.{}", base, name, image.extension);
+        let size_bytes = image.data.len() as i64;
+        if let Err(e) = store.put(&key, image.content_type, image.data.into()).await {
+            for variant in &stored {
+                let _ = store.delete(&variant.storage_key).await;
+            }
+            return Err(e);
+        }
+        stored.push(NewMediaVariant {
+            media_id,
+            name: name.to_string(),
+            url: store.url(&key),
+            storage_key: key,
+            content_type: image.content_type.to_string(),
+            width: image.width as i32,
+            height: image.height as i32,
+            size_bytes,
+        });
+    }
+    Ok(stored)
+}
+
+// The default avatar of users who have not uploaded one
+pub fn identicon_url(user_id: uuid::Uuid) -> String {
+    format!("{}/{}/identicon", IDENTICON_PATH, user_id)
+}
+
+// A PNG of mirrored cells on a light background, with the pattern and color taken
+// from a hash of `seed`, so each user keeps the same one
+pub fn identicon(seed: &[u8], size: u32) -> Result<Vec<u8>, DbError> {
+    let hash = Sha256::digest(seed);
+    // Kept to mid tones so it stands out from the background
+    let color = Rgb([64 + hash[0] / 2, 64 + hash[1] / 2, 64 + hash[2] / 2]);
+    let background = Rgb([240, 240, 240]);
+
+    // Half a cell of margin on each side
+    let cell = (size / (IDENTICON_GRID + 1)).max(1);
+    let margin = (size.saturating_sub(cell * IDENTICON_GRID)) / 2;
+    let filled = |column: u32, row: u32| {
+        // Columns mirror around the middle one
+        let column = column.min(IDENTICON_GRID - 1 - column);
+        hash[(3 + row * 3 + column) as usize] % 2 == 0
+    };
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        if x < margin || y < margin {
+            return background;
+        }
+        let (column, row) = ((x - margin) / cell, (y - margin) / cell);
+        if column < IDENTICON_GRID && row < IDENTICON_GRID && filled(column, row) {
+            color
+        } else {
+            background
+        }
+    });
+
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(image).write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+    Ok(data)
+}
+
+// Stores uploaded files under keys such as `{user_id}/{media_id}.jpg`
+pub trait MediaStore: Send + Sync {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        data: Bytes,
+    ) -> BoxFuture<'a, Result<(), DbError>>;
+
+    // Stores the file at `path` without reading it into memory at once, for large
+    // files such as data exports
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), DbError>>;
+
+    // None when nothing is stored under the key
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, DbError>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), DbError>>;
+
+    // The URL clients fetch the file from
+    fn url(&self, key: &str) -> String;
+}
+
+// Public URL for `key`: under `MEDIA_PUBLIC_URL` when set (e.g. a CDN or public
+// bucket), otherwise served by this API
+fn public_url(public_base: &Option<String>, key: &str) -> String {
+    match public_base {
+        Some(base) => format!("{}/{}", base.trim_end_matches('/'), key),
+        None => format!("{}/{}", MEDIA_FILES_PATH, key),
+    }
+}
+
+// Files on the local disk under `root`
+pub struct LocalMediaStore {
+    root: PathBuf,
+    public_base: Option<String>,
+}
+
+impl LocalMediaStore {
+    pub fn new(root: PathBuf, public_base: Option<String>) -> Self {
+        Self { root, public_base }
+    }
+
+    // Keys come from request paths when files are served, so anything that could
+    // leave the root is rejected
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let relative = Path::new(key);
+        relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+            .then(|| self.root.join(relative))
+    }
+}
+
+impl MediaStore for LocalMediaStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        _content_type: &'a str,
+        data: Bytes,
+    ) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let path = self.path(key).ok_or("Invalid media key")?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(path, data).await?;
+            Ok(())
+        })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        _content_type: &'a str,
+        source: &'a Path,
+    ) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let path = self.path(key).ok_or("Invalid media key")?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::copy(source, path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, DbError>> {
+        Box::pin(async move {
+            let Some(path) = self.path(key) else {
+                return Ok(None);
+            };
+            match tokio::fs::read(path).await {
+                Ok(data) => Ok(Some(Bytes::from(data))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let path = self.path(key).ok_or("Invalid media key")?;
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        public_url(&self.public_base, key)
+    }
+}
+
+// An S3 bucket, or any S3-compatible service such as MinIO
+pub struct S3MediaStore {
+    store: AmazonS3,
+    public_base: Option<String>,
+}
+
+impl S3MediaStore {
+    // Reads the bucket from `MEDIA_S3_BUCKET`, an optional `MEDIA_S3_ENDPOINT` for
+    // S3-compatible services, and credentials and region from the usual `AWS_*`
+    // variables
+    pub fn from_env(public_base: Option<String>) -> Result<Self, DbError> {
+        let bucket = env::var("MEDIA_S3_BUCKET").map_err(|_| "MEDIA_S3_BUCKET must be set")?;
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+        if let Ok(endpoint) = env::var("MEDIA_S3_ENDPOINT") {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint)
+                .with_virtual_hosted_style_request(false);
+        }
+        Ok(Self {
+            store: builder.build()?,
+            public_base,
+        })
+    }
+}
+
+impl MediaStore for S3MediaStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        data: Bytes,
+    ) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let attributes = object_store::Attributes::from_iter([(
+                object_store::Attribute::ContentType,
+                content_type.to_string(),
+            )]);
+            self.store
+                .put_opts(
+                    &ObjectPath::from(key),
+                    PutPayload::from_bytes(data),
+                    attributes.into(),
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    // Uploaded in parts, a few at a time
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        source: &'a Path,
+    ) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let options = PutMultipartOptions {
+                attributes: object_store::Attributes::from_iter([(
+                    object_store::Attribute::ContentType,
+                    content_type.to_string(),
+                )]),
+                ..Default::default()
+            };
+            let upload = self
+                .store
+                .put_multipart_opts(&ObjectPath::from(key), options)
+                .await?;
+            let mut writer = WriteMultipart::new(upload);
+            let mut file = tokio::fs::File::open(source).await?;
+            let mut buffer = vec![0; UPLOAD_CHUNK_BYTES];
+            loop {
+                let read = match file.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(e) => {
+                        let _ = writer.abort().await;
+                        return Err(e.into());
+                    }
+                };
+                writer.wait_for_capacity(MAX_CONCURRENT_PARTS).await?;
+                writer.write(&buffer[..read]);
+            }
+            writer.finish().await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Bytes>, DbError>> {
+        Box::pin(async move {
+            let path = ObjectPath::parse(key)?;
+            match self.store.get(&path).await {
+                Ok(result) => Ok(Some(result.bytes().await?)),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            match self.store.delete(&ObjectPath::from(key)).await {
+                Err(e) if !matches!(e, object_store::Error::NotFound { .. }) => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        public_url(&self.public_base, key)
+    }
+}
+
+// The store selected by `MEDIA_STORE`: `local` (the default, under `MEDIA_ROOT`)
+// or `s3`
+pub fn store_from_env() -> Result<Box<dyn MediaStore>, DbError> {
+    let public_base = env::var("MEDIA_PUBLIC_URL").ok();
+    match env::var("MEDIA_STORE").as_deref() {
+        Ok("s3") => Ok(Box::new(S3MediaStore::from_env(public_base)?)),
+        Ok("local") | Err(_) => {
+            let root = env::var("MEDIA_ROOT").unwrap_or_else(|_| DEFAULT_MEDIA_ROOT.to_string());
+            Ok(Box::new(LocalMediaStore::new(root.into(), public_base)))
+        }
+        Ok(other) => Err(format!("Unknown MEDIA_STORE: {}", other).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gif() -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Gif)
+            .unwrap();
+        data
+    }
+
+    // Inserts extension blocks right after the global color table
+    fn with_extensions(data: &[u8], extensions: &[&[u8]]) -> Vec<u8> {
+        let flags = data[10];
+        let start = 13
+            + if flags & 0x80 != 0 {
+                3 << ((flags & 0x07) + 1)
+            } else {
+                0
+            };
+        let mut extended = data[..start].to_vec();
+        for extension in extensions {
+            extended.extend_from_slice(extension);
+        }
+        extended.extend_from_slice(&data[start..]);
+        extended
+    }
+
+    const COMMENT: &[u8] = b"\x21\xFE\x05hello\x00";
+    const XMP: &[u8] = b"\x21\xFF\x0BXMP DataXMP\x04<x/>\x00";
+    const LOOP: &[u8] = b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00";
+
+    #[test]
+    fn gif_comments_and_xmp_are_stripped() {
+        let original = gif();
+        let tagged = with_extensions(&original, &[COMMENT, XMP]);
+        assert_eq!(strip_gif_extensions(&tagged).unwrap(), original);
+    }
+
+    #[test]
+    fn gif_loop_count_is_kept() {
+        let original = with_extensions(&gif(), &[LOOP]);
+        let tagged = with_extensions(&gif(), &[COMMENT, LOOP, XMP]);
+        assert_eq!(strip_gif_extensions(&tagged).unwrap(), original);
+    }
+
+    #[test]
+    fn truncated_gif_is_rejected() {
+        let original = gif();
+        assert!(strip_gif_extensions(&original[..original.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn images_over_the_pixel_limit_are_rejected() {
+        assert!(check_pixel_count(10_000, 4_000).is_ok());
+        assert!(check_pixel_count(10_000, 4_001).is_err());
+    }
+}
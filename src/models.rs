@@ -1,7 +1,17 @@
 use chrono::{DateTime, Utc};
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Varchar;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
 use uuid::Uuid;
+use validator::Validate;
 
 #[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
 #[diesel(table_name = crate::schema::users)]
@@ -16,15 +26,230 @@ pub struct User {
     pub image_url: Option<String>,
     pub followers_count: i32,
     pub following_count: i32,
+    pub is_private: bool,
+    pub is_admin: bool,
+    pub banner_url: Option<String>,
+    pub bio: Option<String>,
+    pub location: Option<String>,
+    pub website: Option<String>,
+    pub pronouns: Option<String>,
+    // Custom `ProfileField`s, in display order
+    pub profile_fields: serde_json::Value,
+    // Set once the account has been erased
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Insertable, Deserialize)]
+// Most custom fields a profile can have
+pub const MAX_PROFILE_FIELDS: u64 = 4;
+
+#[derive(Serialize, Deserialize, Validate, Debug, Clone)]
+pub struct ProfileField {
+    #[validate(length(min = 1, max = 50, message = "Field names must be 1 to 50 characters"))]
+    pub name: String,
+    #[validate(length(max = 200, message = "Field values can be at most 200 characters"))]
+    pub value: String,
+}
+
+// Profile changes; fields left out are kept and empty text clears them.
+// `profile_fields` replaces the whole list.
+#[derive(Deserialize, Validate)]
+pub struct UpdateProfileRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 characters"))]
+    pub name: Option<String>,
+    #[validate(length(max = 500, message = "Bio can be at most 500 characters"))]
+    pub bio: Option<String>,
+    #[validate(length(max = 100, message = "Location can be at most 100 characters"))]
+    pub location: Option<String>,
+    #[validate(custom(function = "validate_website"))]
+    pub website: Option<String>,
+    #[validate(length(max = 40, message = "Pronouns can be at most 40 characters"))]
+    pub pronouns: Option<String>,
+    #[validate(
+        length(max = MAX_PROFILE_FIELDS, message = "Too many profile fields"),
+        nested
+    )]
+    pub profile_fields: Option<Vec<ProfileField>>,
+}
+
+impl UpdateProfileRequest {
+    // Surrounding whitespace is dropped before the lengths are checked, so a name of
+    // only spaces counts as empty
+    pub fn trimmed(self) -> Self {
+        let trim = |text: Option<String>| text.map(|text| text.trim().to_string());
+        Self {
+            name: trim(self.name),
+            bio: trim(self.bio),
+            location: trim(self.location),
+            website: trim(self.website),
+            pronouns: trim(self.pronouns),
+            profile_fields: self.profile_fields.map(|fields| {
+                fields
+                    .into_iter()
+                    .map(|field| ProfileField {
+                        name: field.name.trim().to_string(),
+                        value: field.value.trim().to_string(),
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+// An http(s) URL of up to 200 characters, or empty to clear it
+fn validate_website(website: &str) -> Result<(), validator::ValidationError> {
+    let valid = website.is_empty()
+        || (website.len() <= 200
+            && (website.starts_with("https://") || website.starts_with("http://"))
+            && <str as validator::ValidateUrl>::validate_url(website));
+    match valid {
+        true => Ok(()),
+        false => Err(validator::ValidationError::new("website")
+            .with_message("Website must be an http or https URL".into())),
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct EmailChangeRequest {
+    #[validate(email(message = "Invalid email format"))]
+    pub email: String,
+    // The current password, so a stolen session cannot take over the account
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailRequest {
+    pub token: String,
+}
+
+// Asks for a link to set the password of an account created without one
+#[derive(Deserialize, Validate)]
+pub struct PasswordSetupRequest {
+    #[validate(email(message = "Invalid email format"))]
+    pub email: String,
+}
+
+// Asks for a link to delete an account created without a password
+#[derive(Deserialize, Validate)]
+pub struct DeletionConfirmationRequest {
+    #[validate(email(message = "Invalid email format"))]
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmDeletionRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct ConfirmPasswordSetupRequest {
+    pub token: String,
+    #[validate(length(min = 6, message = "Password must be at least 6 characters"))]
+    pub password: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct UsernameChangeRequest {
+    #[validate(custom(function = "validate_username"))]
+    pub username: String,
+}
+
+pub fn validate_username(username: &str) -> Result<(), validator::ValidationError> {
+    match crate::accounts::is_valid_username(username) {
+        true => Ok(()),
+        false => Err(validator::ValidationError::new("username").with_message(
+            format!(
+                "Usernames must be {} to {} letters, digits or underscores",
+                crate::accounts::MIN_USERNAME_LENGTH,
+                crate::accounts::MAX_USERNAME_LENGTH
+            )
+            .into(),
+        )),
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::email_change_requests)]
+pub struct NewEmailChange {
+    pub user_id: Uuid,
+    pub new_email: String,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::password_setup_requests)]
+pub struct NewPasswordSetup {
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::account_deletion_confirmations)]
+pub struct NewDeletionConfirmation {
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Validate)]
 #[diesel(table_name = crate::schema::users)]
 pub struct NewUser {
+    #[validate(length(min = 1, message = "Name is required"))]
     pub name: String,
+    #[validate(email(message = "Invalid email format"))]
     pub email: String,
+    #[validate(custom(function = "validate_username"))]
     pub username: String,
-    pub image_url: Option<String>,
+    #[serde(default)]
+    pub is_private: bool,
+}
+
+#[derive(
+    AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum PostVisibility {
+    #[default]
+    Public,
+    Followers,
+    Mentioned,
+    Private,
+}
+
+impl PostVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PostVisibility::Public => "public",
+            PostVisibility::Followers => "followers",
+            PostVisibility::Mentioned => "mentioned",
+            PostVisibility::Private => "private",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for PostVisibility {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for PostVisibility {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"public" => Ok(PostVisibility::Public),
+            b"followers" => Ok(PostVisibility::Followers),
+            b"mentioned" => Ok(PostVisibility::Mentioned),
+            b"private" => Ok(PostVisibility::Private),
+            other => Err(format!(
+                "Unknown post visibility: {}",
+                String::from_utf8_lossy(other)
+            )
+            .into()),
+        }
+    }
 }
 
 #[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
@@ -36,17 +261,43 @@ pub struct Post {
     pub updated_at: DateTime<Utc>,
     pub user_id: Uuid,
     pub content: String,
-    pub images: Option<Vec<String>>,
     pub likes_count: i32,
     pub shares_count: i32,
+    pub reply_to_post_id: Option<Uuid>,
+    pub quoted_post_id: Option<Uuid>,
+    pub replies_count: i32,
+    pub quotes_count: i32,
+    pub visibility: PostVisibility,
+    pub entities: serde_json::Value,
+    pub content_html: Option<String>,
+    pub language: String,
+    // Set when the author's account was deleted and the post kept, emptied, for
+    // the replies and quotes of others
+    pub deleted_at: Option<DateTime<Utc>>,
+    // Set while the author has the post pinned to their profile
+    pub pinned_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Insertable, Deserialize)]
 #[diesel(table_name = crate::schema::posts)]
 pub struct NewPost {
+    // Set to the authenticated caller by `Database::create_post`/`create_comment`,
+    // never taken from the request body
+    #[serde(skip_deserializing)]
     pub user_id: Uuid,
     pub content: String,
-    pub images: Option<Vec<String>>,
+    // Uploaded media to attach, in display order; must belong to the author
+    #[diesel(skip_insertion)]
+    #[serde(default)]
+    pub media_ids: Vec<Uuid>,
+    pub reply_to_post_id: Option<Uuid>,
+    pub quoted_post_id: Option<Uuid>,
+    #[serde(default)]
+    pub visibility: PostVisibility,
+    pub language: Option<String>,
+    // Only set for imported posts, which keep their original time
+    #[serde(skip)]
+    pub created_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
@@ -59,16 +310,25 @@ pub struct Comment {
     pub post_id: Uuid,
     pub user_id: Uuid,
     pub content: String,
-    pub images: Option<Vec<String>>,
+    pub entities: serde_json::Value,
+    pub content_html: Option<String>,
+    pub language: String,
 }
 
 #[derive(Insertable, Deserialize)]
 #[diesel(table_name = crate::schema::comments)]
 pub struct NewComment {
     pub post_id: Uuid,
+    // Set to the authenticated caller by `Database::create_post`/`create_comment`,
+    // never taken from the request body
+    #[serde(skip_deserializing)]
     pub user_id: Uuid,
     pub content: String,
-    pub images: Option<Vec<String>>,
+    // Uploaded media to attach, in display order; must belong to the author
+    #[diesel(skip_insertion)]
+    #[serde(default)]
+    pub media_ids: Vec<Uuid>,
+    pub language: Option<String>,
 }
 
 #[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
@@ -108,6 +368,1142 @@ pub struct NewFollow {
     pub following_id: Uuid,
 }
 
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::follow_requests)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FollowRequest {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub requester_id: Uuid,
+    pub target_id: Uuid,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::follow_requests)]
+pub struct NewFollowRequest {
+    pub requester_id: Uuid,
+    pub target_id: Uuid,
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::blocks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Block {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub blocker_id: Uuid,
+    pub blocked_id: Uuid,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::blocks)]
+pub struct NewBlock {
+    pub blocker_id: Uuid,
+    pub blocked_id: Uuid,
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::mutes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Mute {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub muter_id: Uuid,
+    pub muted_id: Uuid,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::mutes)]
+pub struct NewMute {
+    pub muter_id: Uuid,
+    pub muted_id: Uuid,
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::hashtags)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Hashtag {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub name: String,
+    pub usage_count: i32,
+}
+
+#[derive(Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::mentions)]
+pub struct NewMention {
+    pub post_id: Uuid,
+    pub comment_id: Option<Uuid>,
+    pub user_id: Uuid,
+    pub start_offset: i32,
+    pub length: i32,
+}
+
+#[derive(
+    AsExpression,
+    FromSqlRow,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Like,
+    Share,
+    Follow,
+    FollowRequest,
+    Comment,
+    Mention,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 6] = [
+        NotificationKind::Like,
+        NotificationKind::Share,
+        NotificationKind::Follow,
+        NotificationKind::FollowRequest,
+        NotificationKind::Comment,
+        NotificationKind::Mention,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Like => "like",
+            NotificationKind::Share => "share",
+            NotificationKind::Follow => "follow",
+            NotificationKind::FollowRequest => "follow_request",
+            NotificationKind::Comment => "comment",
+            NotificationKind::Mention => "mention",
+        }
+    }
+
+    // What the actors did, as in "alice and 12 others liked your post"
+    pub fn action(&self) -> &'static str {
+        match self {
+            NotificationKind::Like => "liked your post",
+            NotificationKind::Share => "shared your post",
+            NotificationKind::Follow => "followed you",
+            NotificationKind::FollowRequest => "requested to follow you",
+            NotificationKind::Comment => "commented on your post",
+            NotificationKind::Mention => "mentioned you",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for NotificationKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for NotificationKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let kind = std::str::from_utf8(bytes.as_bytes())?;
+        NotificationKind::ALL
+            .into_iter()
+            .find(|k| k.as_str() == kind)
+            .ok_or_else(|| format!("Unknown notification kind: {}", kind).into())
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::notifications)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Notification {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub actor_id: Uuid,
+    pub kind: NotificationKind,
+    pub post_id: Option<Uuid>,
+    pub comment_id: Option<Uuid>,
+    pub group_key: String,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::notifications)]
+pub struct NewNotification {
+    pub user_id: Uuid,
+    pub actor_id: Uuid,
+    pub kind: NotificationKind,
+    pub post_id: Option<Uuid>,
+    pub comment_id: Option<Uuid>,
+    pub group_key: String,
+}
+
+#[derive(Insertable, Queryable, Selectable, Debug)]
+#[diesel(table_name = crate::schema::notification_preferences)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NotificationPreference {
+    pub user_id: Uuid,
+    pub kind: NotificationKind,
+    pub enabled: bool,
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamEventKind {
+    PostCreated,
+    PostCounters,
+    UserCounters,
+    Notification,
+}
+
+impl StreamEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamEventKind::PostCreated => "post_created",
+            StreamEventKind::PostCounters => "post_counters",
+            StreamEventKind::UserCounters => "user_counters",
+            StreamEventKind::Notification => "notification",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for StreamEventKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for StreamEventKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"post_created" => Ok(StreamEventKind::PostCreated),
+            b"post_counters" => Ok(StreamEventKind::PostCounters),
+            b"user_counters" => Ok(StreamEventKind::UserCounters),
+            b"notification" => Ok(StreamEventKind::Notification),
+            other => Err(format!(
+                "Unknown stream event kind: {}",
+                String::from_utf8_lossy(other)
+            )
+            .into()),
+        }
+    }
+}
+
+// Rows are written by database triggers, never by the application
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::stream_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StreamEvent {
+    pub id: i64,
+    pub kind: StreamEventKind,
+    pub user_id: Uuid,
+    pub post_id: Option<Uuid>,
+    pub payload: serde_json::Value,
+}
+
+// An event as delivered to one streaming client
+#[derive(Serialize, Debug, Clone)]
+pub struct StreamMessage {
+    pub id: i64,
+    pub event: StreamEventKind,
+    pub data: serde_json::Value,
+}
+
+#[derive(
+    AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash,
+)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    PostCreated,
+    PostLiked,
+    PostShared,
+    CommentCreated,
+    UserFollowed,
+}
+
+impl EventType {
+    pub const ALL: [EventType; 5] = [
+        EventType::PostCreated,
+        EventType::PostLiked,
+        EventType::PostShared,
+        EventType::CommentCreated,
+        EventType::UserFollowed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::PostCreated => "post_created",
+            EventType::PostLiked => "post_liked",
+            EventType::PostShared => "post_shared",
+            EventType::CommentCreated => "comment_created",
+            EventType::UserFollowed => "user_followed",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for EventType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for EventType {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let event = std::str::from_utf8(bytes.as_bytes())?;
+        EventType::ALL
+            .into_iter()
+            .find(|e| e.as_str() == event)
+            .ok_or_else(|| format!("Unknown event type: {}", event).into())
+    }
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Succeeded => "succeeded",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for DeliveryStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for DeliveryStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"pending" => Ok(DeliveryStatus::Pending),
+            b"succeeded" => Ok(DeliveryStatus::Succeeded),
+            b"failed" => Ok(DeliveryStatus::Failed),
+            other => Err(format!(
+                "Unknown delivery status: {}",
+                String::from_utf8_lossy(other)
+            )
+            .into()),
+        }
+    }
+}
+
+// The signing secret is only returned when the webhook is created
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Webhook {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub user_id: Option<Uuid>,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub event_types: Vec<EventType>,
+    pub is_active: bool,
+    pub consecutive_failures: i32,
+    pub disabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::webhooks)]
+pub struct NewWebhook {
+    pub user_id: Option<Uuid>,
+    pub url: String,
+    pub secret: String,
+    pub event_types: Vec<EventType>,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct WebhookRequest {
+    #[validate(custom(function = "validate_webhook_url"))]
+    pub url: String,
+    #[validate(length(min = 1, message = "At least one event type is required"))]
+    pub event_types: Vec<EventType>,
+}
+
+fn validate_webhook_url(url: &str) -> Result<(), validator::ValidationError> {
+    crate::webhooks::check_url(url)
+        .map(|_| ())
+        .map_err(|message| validator::ValidationError::new("url").with_message(message.into()))
+}
+
+// Setting `is_active` back to true re-enables a webhook disabled after failures
+#[derive(Deserialize, Validate)]
+pub struct UpdateWebhookRequest {
+    #[validate(custom(function = "validate_webhook_url"))]
+    pub url: Option<String>,
+    #[validate(length(min = 1, message = "At least one event type is required"))]
+    pub event_types: Option<Vec<EventType>>,
+    pub is_active: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub webhook_id: Uuid,
+    pub event_type: EventType,
+    pub payload: serde_json::Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub event_id: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub webhook_id: Uuid,
+    pub event_type: EventType,
+    pub payload: serde_json::Value,
+    pub event_id: Option<i64>,
+}
+
+// Outcome of one attempt to deliver a webhook
+pub struct WebhookAttempt {
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    // When to try again after a failure; None gives up on the delivery
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl WebhookAttempt {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+// Something that happened, recorded in the outbox in the same transaction as the change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum DomainEvent {
+    PostCreated {
+        post: Post,
+    },
+    PostLiked {
+        interaction: Interaction,
+        post_author_id: Uuid,
+    },
+    PostShared {
+        interaction: Interaction,
+        post_author_id: Uuid,
+    },
+    CommentCreated {
+        comment: Comment,
+        post_author_id: Uuid,
+    },
+    UserFollowed {
+        follow: Follow,
+    },
+}
+
+impl DomainEvent {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            DomainEvent::PostCreated { .. } => EventType::PostCreated,
+            DomainEvent::PostLiked { .. } => EventType::PostLiked,
+            DomainEvent::PostShared { .. } => EventType::PostShared,
+            DomainEvent::CommentCreated { .. } => EventType::CommentCreated,
+            DomainEvent::UserFollowed { .. } => EventType::UserFollowed,
+        }
+    }
+
+    // The post or user the event belongs to; events with the same key are related
+    pub fn key(&self) -> Uuid {
+        match self {
+            DomainEvent::PostCreated { post } => post.id,
+            DomainEvent::PostLiked { interaction, .. }
+            | DomainEvent::PostShared { interaction, .. } => interaction.post_id,
+            DomainEvent::CommentCreated { comment, .. } => comment.post_id,
+            DomainEvent::UserFollowed { follow } => follow.following_id,
+        }
+    }
+
+    // Users the event concerns; their own webhooks receive it
+    pub fn involved_users(&self) -> Vec<Uuid> {
+        match self {
+            DomainEvent::PostCreated { post } => vec![post.user_id],
+            DomainEvent::PostLiked {
+                interaction,
+                post_author_id,
+            }
+            | DomainEvent::PostShared {
+                interaction,
+                post_author_id,
+            } => vec![*post_author_id, interaction.user_id],
+            DomainEvent::CommentCreated {
+                comment,
+                post_author_id,
+            } => vec![*post_author_id, comment.user_id],
+            DomainEvent::UserFollowed { follow } => vec![follow.follower_id, follow.following_id],
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::outbox_events)]
+pub struct NewOutboxEvent {
+    pub event_type: EventType,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::outbox_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+}
+
+// An outbox event as handed to sinks: `{"id", "created_at", "event", "data"}` in JSON
+#[derive(Serialize, Debug, Clone)]
+pub struct PublishedEvent {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    // Ran out of attempts; kept for inspection until retried
+    Dead,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Dead => "dead",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for JobStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for JobStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"queued" => Ok(JobStatus::Queued),
+            b"running" => Ok(JobStatus::Running),
+            b"completed" => Ok(JobStatus::Completed),
+            b"dead" => Ok(JobStatus::Dead),
+            other => Err(format!("Unknown job status: {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BackgroundJob {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub dedupe_key: Option<String>,
+    pub last_error: Option<String>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// Built from a typed job with `jobs::new_job`
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::jobs)]
+pub struct NewBackgroundJob {
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    // A job is not enqueued while another with the same key is still queued
+    pub dedupe_key: Option<String>,
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaStatus {
+    // Uploaded but not yet stripped and resized; its files are not served yet
+    Processing,
+    Ready,
+    // The file could not be decoded as an image
+    Failed,
+    // Unused and being collected; the row goes once its files are deleted
+    Deleting,
+}
+
+impl MediaStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaStatus::Processing => "processing",
+            MediaStatus::Ready => "ready",
+            MediaStatus::Failed => "failed",
+            MediaStatus::Deleting => "deleting",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for MediaStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for MediaStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"processing" => Ok(MediaStatus::Processing),
+            b"ready" => Ok(MediaStatus::Ready),
+            b"failed" => Ok(MediaStatus::Failed),
+            b"deleting" => Ok(MediaStatus::Deleting),
+            other => {
+                Err(format!("Unknown media status: {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::media)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Media {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub user_id: Uuid,
+    #[serde(skip)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub url: String,
+    pub status: MediaStatus,
+    // Known once processed
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>,
+    pub alt_text: Option<String>,
+    pub caption: Option<String>,
+    // From -1 to 1 on each axis, with 0,0 at the center
+    pub focal_x: f32,
+    pub focal_y: f32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::media)]
+pub struct NewMedia {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub storage_key: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub url: String,
+    pub width: i32,
+    pub height: i32,
+    pub alt_text: Option<String>,
+    pub caption: Option<String>,
+    pub focal_x: f32,
+    pub focal_y: f32,
+    pub status: MediaStatus,
+    pub blurhash: Option<String>,
+}
+
+// A new avatar or banner with the sizes generated for it
+#[derive(Serialize, Debug)]
+pub struct UpdatedProfileImage {
+    pub user: User,
+    pub media: MediaView,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileImageKind {
+    // Square, in `users.image_url`
+    Avatar,
+    // 3:1, in `users.banner_url`
+    Banner,
+}
+
+// Bytes used by a user's uploads against their quota; no quota is unlimited
+#[derive(Serialize, Debug)]
+pub struct StorageUsage {
+    pub used_bytes: i64,
+    pub quota_bytes: Option<i64>,
+}
+
+impl StorageUsage {
+    pub fn allows(&self, size_bytes: i64) -> bool {
+        self.quota_bytes
+            .is_none_or(|quota| self.used_bytes + size_bytes <= quota)
+    }
+}
+
+// A null quota returns the user to their role's default
+#[derive(Deserialize, Validate)]
+pub struct StorageQuotaRequest {
+    #[validate(range(min = 0, message = "Quotas cannot be negative"))]
+    pub quota_bytes: Option<i64>,
+}
+
+// Descriptions given with an upload or edited later. Empty text clears it.
+#[derive(Deserialize, Validate, Default)]
+pub struct UpdateMediaRequest {
+    #[validate(length(max = 1500, message = "Alt text can be at most 1500 characters"))]
+    pub alt_text: Option<String>,
+    #[validate(length(max = 500, message = "Captions can be at most 500 characters"))]
+    pub caption: Option<String>,
+    #[validate(range(min = -1.0, max = 1.0, message = "Focal points range from -1 to 1"))]
+    pub focal_x: Option<f32>,
+    #[validate(range(min = -1.0, max = 1.0, message = "Focal points range from -1 to 1"))]
+    pub focal_y: Option<f32>,
+}
+
+// A resized copy of an upload, e.g. `small` as `image/webp`
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::media_variants)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MediaVariant {
+    #[serde(skip)]
+    pub media_id: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size_bytes: i64,
+    pub url: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::media_variants)]
+pub struct NewMediaVariant {
+    pub media_id: Uuid,
+    pub name: String,
+    pub storage_key: String,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub size_bytes: i64,
+    pub url: String,
+}
+
+// What processing an upload produced, recorded once the files are stored
+pub struct ProcessedMedia {
+    pub size_bytes: i64,
+    pub width: i32,
+    pub height: i32,
+    pub blurhash: String,
+    pub variants: Vec<NewMediaVariant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaView {
+    #[serde(flatten)]
+    pub media: Media,
+    pub variants: Vec<MediaVariant>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::media_attachments)]
+pub struct NewMediaAttachment {
+    pub post_id: Uuid,
+    pub comment_id: Option<Uuid>,
+    pub media_id: Uuid,
+    pub position: i32,
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    Failed,
+}
+
+impl ExportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportStatus::Pending => "pending",
+            ExportStatus::Ready => "ready",
+            ExportStatus::Failed => "failed",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for ExportStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for ExportStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"pending" => Ok(ExportStatus::Pending),
+            b"ready" => Ok(ExportStatus::Ready),
+            b"failed" => Ok(ExportStatus::Failed),
+            other => {
+                Err(format!("Unknown export status: {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::data_exports)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DataExport {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub status: ExportStatus,
+    // Exports are only served through the API, to their owner
+    #[serde(skip)]
+    pub storage_key: Option<String>,
+    // Known once ready
+    pub size_bytes: Option<i64>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Everything a data export holds about its user, written to `data.json` in the
+// archive next to their uploads
+#[derive(Serialize)]
+pub struct UserData {
+    pub exported_at: DateTime<Utc>,
+    pub profile: User,
+    pub posts: Vec<Post>,
+    pub comments: Vec<Comment>,
+    pub interactions: Vec<Interaction>,
+    pub following: Vec<FollowedUser>,
+    pub followers: Vec<FollowedUser>,
+    pub media: Vec<ExportedMedia>,
+    pub bookmarks: Vec<Bookmark>,
+    pub bookmark_collections: Vec<BookmarkCollection>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct FollowedUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub followed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct ExportedMedia {
+    #[serde(flatten)]
+    pub media: Media,
+    // Path of the file in the archive
+    pub file: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    // The current password, so a stolen session cannot delete the account
+    pub password: String,
+}
+
+// A pending account deletion, which the user can cancel until `deletes_at`
+#[derive(Serialize)]
+pub struct AccountDeletion {
+    pub requested_at: DateTime<Utc>,
+    pub deletes_at: DateTime<Utc>,
+}
+
+// What an import reads: a Twitter/X archive's `tweets.js`, a Mastodon archive or its
+// `outbox.json`, or a CSV of accounts to follow such as Mastodon's
+// `following_accounts.csv`
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportKind {
+    Twitter,
+    Mastodon,
+    Follows,
+}
+
+impl ImportKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportKind::Twitter => "twitter",
+            ImportKind::Mastodon => "mastodon",
+            ImportKind::Follows => "follows",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for ImportKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for ImportKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"twitter" => Ok(ImportKind::Twitter),
+            b"mastodon" => Ok(ImportKind::Mastodon),
+            b"follows" => Ok(ImportKind::Follows),
+            other => Err(format!("Unknown import kind: {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Pending,
+    Running,
+    Completed,
+    // The file could not be read at all; see `error`
+    Failed,
+}
+
+impl ImportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportStatus::Pending => "pending",
+            ImportStatus::Running => "running",
+            ImportStatus::Completed => "completed",
+            ImportStatus::Failed => "failed",
+        }
+    }
+}
+
+impl ToSql<Varchar, Pg> for ImportStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for ImportStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"pending" => Ok(ImportStatus::Pending),
+            b"running" => Ok(ImportStatus::Running),
+            b"completed" => Ok(ImportStatus::Completed),
+            b"failed" => Ok(ImportStatus::Failed),
+            other => {
+                Err(format!("Unknown import status: {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::imports)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Import {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub kind: ImportKind,
+    pub status: ImportStatus,
+    #[serde(skip)]
+    pub storage_key: Option<String>,
+    // Known once the file has been read
+    pub total_items: Option<i32>,
+    pub processed_items: i32,
+    pub imported_items: i32,
+    // Already imported, or nothing to import such as a boost
+    pub skipped_items: i32,
+    pub failed_items: i32,
+    pub failures: serde_json::Value,
+    pub error: Option<String>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::imports)]
+pub struct NewImport {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub kind: ImportKind,
+    pub storage_key: String,
+}
+
+// What became of one item of an import
+pub enum ImportOutcome {
+    Imported,
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::bookmark_collections)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BookmarkCollection {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub name: String,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct BookmarkCollectionRequest {
+    #[validate(length(min = 1, max = 100, message = "Names are 1 to 100 characters"))]
+    pub name: String,
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = crate::schema::bookmarks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Bookmark {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub post_id: Uuid,
+    pub collection_id: Option<Uuid>,
+}
+
+// Bookmarking a post that is already bookmarked moves it to `collection_id`, or out of
+// its collection when that is missing. Unknown fields are refused, so a misspelt
+// field is not taken for a missing one.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BookmarkRequest {
+    pub collection_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct BookmarkView {
+    #[serde(flatten)]
+    pub bookmark: Bookmark,
+    pub post: PostView,
+}
+
+// Where a page of bookmarks starts: just after the last bookmark of the previous
+// page. Clients get it as `{created_at in microseconds}_{id}`.
+#[derive(Clone, Copy, Debug)]
+pub struct BookmarkCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl fmt::Display for BookmarkCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}_{}",
+            self.created_at.timestamp_micros(),
+            self.id.simple()
+        )
+    }
+}
+
+impl FromStr for BookmarkCursor {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (micros, id) = value.split_once('_').ok_or(())?;
+        let micros = micros.parse().map_err(|_| ())?;
+        Ok(BookmarkCursor {
+            created_at: DateTime::from_timestamp_micros(micros).ok_or(())?,
+            id: id.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+// `next_cursor` fetches the following page, and is missing on the last one
+#[derive(Serialize)]
+pub struct BookmarksPage {
+    pub bookmarks: Vec<BookmarkView>,
+    pub next_cursor: Option<String>,
+}
+
+// Full-text search parameters. `q` uses web search syntax: quoted phrases,
+// `or`, and `-excluded` terms.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SearchFilters {
+    pub q: String,
+    pub lang: Option<String>,
+    pub author: Option<Uuid>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub hashtag: Option<String>,
+}
+
+// User search parameters. With `prefix` set, only usernames and names starting
+// with `q` match, for mention typeahead.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserSearchQuery {
+    pub q: String,
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserSearchResult {
+    #[serde(flatten)]
+    pub user: User,
+    pub followed_by_viewer: bool,
+    pub score: f64,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateContent {
+    pub content: String,
+}
+
+// Following a private account creates a request instead of a follow
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum FollowOutcome {
+    Followed(Follow),
+    Requested(FollowRequest),
+}
+
 // Response models with relationships
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserWithRelations {
@@ -140,3 +1536,116 @@ pub struct InteractionWithUser {
     pub interaction: Interaction,
     pub user: User,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostView {
+    #[serde(flatten)]
+    pub post: Post,
+    pub quoted_post: Option<Post>,
+    pub media: Vec<MediaView>,
+    pub bookmarked_by_viewer: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommentView {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub media: Vec<MediaView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PostSearchResult {
+    #[serde(flatten)]
+    pub post: PostView,
+    pub rank: f32,
+    pub highlight: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommentSearchResult {
+    #[serde(flatten)]
+    pub comment: CommentView,
+    pub rank: f32,
+    pub highlight: String,
+}
+
+// Notifications sharing a group key, newest first. `actors` holds the most recent
+// few distinct actors; `actors_count` counts all of them.
+#[derive(Serialize, Debug)]
+pub struct NotificationGroup {
+    pub group_key: String,
+    pub kind: NotificationKind,
+    pub post_id: Option<Uuid>,
+    pub comment_id: Option<Uuid>,
+    pub actors: Vec<User>,
+    pub actors_count: i64,
+    pub unread_count: i64,
+    pub latest_at: DateTime<Utc>,
+    pub summary: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UnreadCount {
+    pub unread_count: i64,
+}
+
+// Per-type switches; types missing from the map keep their current setting
+pub type NotificationPreferences = BTreeMap<NotificationKind, bool>;
+
+#[derive(Serialize, Debug)]
+pub struct NotificationsPage {
+    pub unread_count: i64,
+    pub groups: Vec<NotificationGroup>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostThread {
+    pub ancestors: Vec<PostView>,
+    pub post: PostView,
+    pub descendants: Vec<PostView>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bookmark_cursor_round_trips() {
+        let cursor = BookmarkCursor {
+            created_at: DateTime::from_timestamp_micros(1_760_000_000_123_456).unwrap(),
+            id: Uuid::new_v4(),
+        };
+        let parsed: BookmarkCursor = cursor.to_string().parse().unwrap();
+        assert_eq!(parsed.created_at, cursor.created_at);
+        assert_eq!(parsed.id, cursor.id);
+        assert_eq!(
+            cursor.to_string(),
+            format!("1760000000123456_{}", cursor.id.simple())
+        );
+    }
+
+    #[test]
+    fn bookmark_cursor_rejects_garbage() {
+        let id = Uuid::new_v4().simple();
+        for value in [
+            "".to_string(),
+            "123".to_string(),
+            format!("abc_{}", id),
+            "123_not-a-uuid".to_string(),
+            format!("{}_{}", i64::MAX, id),
+        ] {
+            assert!(value.parse::<BookmarkCursor>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn bookmark_request_refuses_unknown_fields() {
+        let id = Uuid::new_v4();
+        let request: BookmarkRequest =
+            serde_json::from_str(&format!("{{\"collection_id\": \"{}\"}}", id)).unwrap();
+        assert_eq!(request.collection_id, Some(id));
+        let request: BookmarkRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(request.collection_id, None);
+        assert!(serde_json::from_str::<BookmarkRequest>("{\"colection_id\": null}").is_err());
+    }
+}
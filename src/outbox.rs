@@ -0,0 +1,342 @@
+use crate::database::{Database, DbError};
+use crate::models::{DomainEvent, PublishedEvent};
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use std::env;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// A sink that has not accepted an event by then has failed, e.g. while a broker
+// connection is down and publishes are waiting for it to come back
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CLAIM_BATCH: i64 = 100;
+
+// Claimed events are published again if the relay has not finished them by then
+const CLAIM_LEASE_SECS: i64 = 60;
+
+// Events are retried until every sink accepts them, backing off up to this delay
+const BASE_RETRY_DELAY_SECS: i64 = 5;
+const MAX_RETRY_DELAY_SECS: i64 = 5 * 60;
+
+// Events still failing after this many attempts (about an hour and a half) are moved
+// to the dead letters
+const MAX_ATTEMPTS: i32 = 25;
+
+const DEFAULT_SUBJECT_PREFIX: &str = "posts.events";
+
+// Receives every published event. Delivery is at least once: when any sink fails the
+// event is retried for all of them, so sinks should be idempotent on `event.id`.
+// In-process subscribers implement this directly; external transports go through
+// `BrokerSink`.
+pub trait EventSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn publish<'a>(&'a self, event: &'a PublishedEvent) -> BoxFuture<'a, Result<(), DbError>>;
+}
+
+// Queues webhook deliveries for each event
+pub struct WebhookSink {
+    db: Database,
+}
+
+impl EventSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn publish<'a>(&'a self, event: &'a PublishedEvent) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(self.db.enqueue_webhook_deliveries(event))
+    }
+}
+
+// A message for a NATS subject or Kafka topic. `key` groups related messages, e.g.
+// as a Kafka partition key; `id` lets consumers drop duplicates.
+#[derive(Debug, Clone)]
+pub struct BrokerMessage {
+    pub subject: String,
+    pub key: String,
+    pub id: String,
+    pub payload: Vec<u8>,
+}
+
+pub trait MessageBroker: Send + Sync {
+    fn publish(&self, message: BrokerMessage) -> BoxFuture<'_, Result<(), DbError>>;
+}
+
+pub struct NatsBroker {
+    client: async_nats::Client,
+}
+
+impl NatsBroker {
+    // Keeps trying in the background if the server is not reachable yet
+    pub async fn connect(url: &str) -> Result<Self, DbError> {
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(url)
+            .await?;
+        Ok(Self { client })
+    }
+}
+
+impl MessageBroker for NatsBroker {
+    fn publish(&self, message: BrokerMessage) -> BoxFuture<'_, Result<(), DbError>> {
+        Box::pin(async move {
+            let mut headers = async_nats::HeaderMap::new();
+            // JetStream drops messages it has already seen with the same id
+            headers.insert("Nats-Msg-Id", message.id.as_str());
+            headers.insert("Event-Key", message.key.as_str());
+            self.client
+                .publish_with_headers(message.subject, headers, message.payload.into())
+                .await?;
+            self.client.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+// Keeps published messages in memory, standing in for a real broker in tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct InMemoryBroker {
+    messages: Arc<Mutex<Vec<BrokerMessage>>>,
+}
+
+#[cfg(test)]
+impl InMemoryBroker {
+    pub fn messages(&self) -> Vec<BrokerMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl MessageBroker for InMemoryBroker {
+    fn publish(&self, message: BrokerMessage) -> BoxFuture<'_, Result<(), DbError>> {
+        self.messages.lock().unwrap().push(message);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Publishes each event as JSON to `{prefix}.{event type}`, keyed by the post or user
+// it belongs to
+pub struct BrokerSink {
+    broker: Box<dyn MessageBroker>,
+    subject_prefix: String,
+}
+
+impl EventSink for BrokerSink {
+    fn name(&self) -> &'static str {
+        "broker"
+    }
+
+    fn publish<'a>(&'a self, event: &'a PublishedEvent) -> BoxFuture<'a, Result<(), DbError>> {
+        Box::pin(async move {
+            let message = BrokerMessage {
+                subject: format!(
+                    "{}.{}",
+                    self.subject_prefix,
+                    event.event.event_type().as_str()
+                ),
+                key: event.event.key().to_string(),
+                id: event.id.to_string(),
+                payload: serde_json::to_vec(event)?,
+            };
+            self.broker.publish(message).await
+        })
+    }
+}
+
+// The sinks configured for this instance. Webhooks are always on; a broker is added
+// when `EVENT_BROKER_URL` is set to a `nats://` URL.
+pub async fn sinks(db: Database) -> Result<Vec<Box<dyn EventSink>>, DbError> {
+    let mut sinks: Vec<Box<dyn EventSink>> = vec![Box::new(WebhookSink { db })];
+
+    if let Ok(url) = env::var("EVENT_BROKER_URL") {
+        sinks.push(Box::new(BrokerSink {
+            broker: Box::new(NatsBroker::connect(&url).await?),
+            subject_prefix: env::var("EVENT_SUBJECT_PREFIX")
+                .unwrap_or_else(|_| DEFAULT_SUBJECT_PREFIX.to_string()),
+        }));
+    }
+    Ok(sinks)
+}
+
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    let secs = BASE_RETRY_DELAY_SECS
+        .saturating_mul(2i64.pow(exponent))
+        .min(MAX_RETRY_DELAY_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+// Publishes outbox events to every sink. Events are claimed with SKIP LOCKED, so
+// several relays can run at once; each publishes its batch in order.
+pub struct OutboxRelay {
+    db: Database,
+    sinks: Vec<Box<dyn EventSink>>,
+}
+
+impl OutboxRelay {
+    pub fn new(db: Database, sinks: Vec<Box<dyn EventSink>>) -> Self {
+        Self { db, sinks }
+    }
+
+    async fn publish(&self, event: &PublishedEvent) -> Result<(), String> {
+        for sink in &self.sinks {
+            match tokio::time::timeout(SINK_TIMEOUT, sink.publish(event)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(format!("{} sink failed: {}", sink.name(), e)),
+                Err(_) => return Err(format!("{} sink timed out", sink.name())),
+            }
+        }
+        Ok(())
+    }
+
+    // Publishes one claimed batch, returning how many events it held
+    async fn relay(&self) -> usize {
+        let events = match self
+            .db
+            .claim_outbox_events(
+                CLAIM_BATCH,
+                chrono::Duration::seconds(CLAIM_LEASE_SECS),
+                MAX_ATTEMPTS,
+            )
+            .await
+        {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Error claiming outbox events: {}", e);
+                return 0;
+            }
+        };
+
+        let mut failed: Option<(i64, chrono::DateTime<Utc>)> = None;
+        for event in &events {
+            let recorded = match failed {
+                // Once an event fails the rest of the batch is held back with it, so
+                // the sinks see them in order when it is retried
+                Some((failed_id, retry_at)) => {
+                    let error = format!("Held back after event {} failed", failed_id);
+                    self.db
+                        .hold_back_outbox_event(event.id, error, retry_at)
+                        .await
+                }
+                None => match serde_json::from_value::<DomainEvent>(event.payload.clone()) {
+                    // Retrying cannot fix a payload that does not parse
+                    Err(e) => {
+                        let error = format!("Invalid event payload: {}", e);
+                        self.db.dead_letter_outbox_event(event.id, error).await
+                    }
+                    Ok(domain_event) => {
+                        let published = PublishedEvent {
+                            id: event.id,
+                            created_at: event.created_at,
+                            event: domain_event,
+                        };
+                        match self.publish(&published).await {
+                            Ok(()) => self.db.mark_outbox_event_published(event.id).await,
+                            // The rest of the batch goes ahead without it
+                            Err(error) if event.attempts >= MAX_ATTEMPTS => {
+                                self.db.dead_letter_outbox_event(event.id, error).await
+                            }
+                            Err(error) => {
+                                let retry_at = Utc::now() + retry_delay(event.attempts);
+                                failed = Some((event.id, retry_at));
+                                self.db.retry_outbox_event(event.id, error, retry_at).await
+                            }
+                        }
+                    }
+                },
+            };
+            if let Err(e) = recorded {
+                eprintln!("Error recording outbox event {}: {}", event.id, e);
+            }
+        }
+        events.len()
+    }
+
+    // Polls the outbox until the process exits
+    pub async fn run(self) {
+        loop {
+            // A full batch suggests more are waiting, so go again straight away
+            if self.relay().await < CLAIM_BATCH as usize {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Follow;
+    use uuid::Uuid;
+
+    fn followed_event() -> PublishedEvent {
+        PublishedEvent {
+            id: 42,
+            created_at: Utc::now(),
+            event: DomainEvent::UserFollowed {
+                follow: Follow {
+                    id: Uuid::from_u128(1),
+                    created_at: Utc::now(),
+                    follower_id: Uuid::from_u128(2),
+                    following_id: Uuid::from_u128(3),
+                },
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn broker_sink_publishes_to_the_event_type_subject() {
+        let broker = InMemoryBroker::default();
+        let sink = BrokerSink {
+            broker: Box::new(broker.clone()),
+            subject_prefix: DEFAULT_SUBJECT_PREFIX.to_string(),
+        };
+        sink.publish(&followed_event()).await.unwrap();
+
+        let messages = broker.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].subject, "posts.events.user_followed");
+        assert_eq!(messages[0].key, Uuid::from_u128(3).to_string());
+        assert_eq!(messages[0].id, "42");
+
+        let payload: serde_json::Value = serde_json::from_slice(&messages[0].payload).unwrap();
+        assert_eq!(payload["id"], 42);
+        assert_eq!(payload["event"], "user_followed");
+        assert_eq!(
+            payload["data"]["follow"]["follower_id"],
+            Uuid::from_u128(2).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn published_events_read_back_as_domain_events() {
+        let broker = InMemoryBroker::default();
+        let sink = BrokerSink {
+            broker: Box::new(broker.clone()),
+            subject_prefix: "custom".to_string(),
+        };
+        sink.publish(&followed_event()).await.unwrap();
+
+        let message = &broker.messages()[0];
+        assert_eq!(message.subject, "custom.user_followed");
+        let payload: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+        let event: DomainEvent = serde_json::from_value(payload).unwrap();
+        assert_eq!(event.key(), Uuid::from_u128(3));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(1).num_seconds(), BASE_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay(2).num_seconds(), BASE_RETRY_DELAY_SECS * 2);
+        assert_eq!(
+            retry_delay(MAX_ATTEMPTS).num_seconds(),
+            MAX_RETRY_DELAY_SECS
+        );
+    }
+}
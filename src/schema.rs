@@ -9,9 +9,23 @@ diesel::table! {
         image_url -> Nullable<Text>,
         followers_count -> Int4,
         following_count -> Int4,
+        password_hash -> Nullable<Varchar>,
+        is_private -> Bool,
+        is_admin -> Bool,
+        storage_quota_bytes -> Nullable<Int8>,
+        banner_url -> Nullable<Text>,
+        bio -> Nullable<Text>,
+        location -> Nullable<Varchar>,
+        website -> Nullable<Varchar>,
+        pronouns -> Nullable<Varchar>,
+        profile_fields -> Jsonb,
+        deletion_requested_at -> Nullable<Timestamptz>,
+        deleted_at -> Nullable<Timestamptz>,
     }
 }
 
+// `posts.search_vector` and `comments.search_vector` (tsvector) are maintained by a
+// trigger and only referenced from raw SQL in `Database::search_posts`/`search_comments`.
 diesel::table! {
     posts (id) {
         id -> Uuid,
@@ -22,6 +36,16 @@ diesel::table! {
         images -> Nullable<Array<Text>>,
         likes_count -> Int4,
         shares_count -> Int4,
+        reply_to_post_id -> Nullable<Uuid>,
+        quoted_post_id -> Nullable<Uuid>,
+        replies_count -> Int4,
+        quotes_count -> Int4,
+        visibility -> Varchar,
+        entities -> Jsonb,
+        content_html -> Nullable<Text>,
+        language -> Varchar,
+        deleted_at -> Nullable<Timestamptz>,
+        pinned_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -34,6 +58,9 @@ diesel::table! {
         user_id -> Uuid,
         content -> Text,
         images -> Nullable<Array<Text>>,
+        entities -> Jsonb,
+        content_html -> Nullable<Text>,
+        language -> Varchar,
     }
 }
 
@@ -57,6 +84,317 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    follow_requests (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        requester_id -> Uuid,
+        target_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    blocks (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        blocker_id -> Uuid,
+        blocked_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    mutes (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        muter_id -> Uuid,
+        muted_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    hashtags (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        name -> Varchar,
+        usage_count -> Int4,
+    }
+}
+
+diesel::table! {
+    post_hashtags (post_id, hashtag_id) {
+        post_id -> Uuid,
+        hashtag_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    comment_hashtags (comment_id, hashtag_id) {
+        comment_id -> Uuid,
+        hashtag_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    mentions (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        post_id -> Uuid,
+        comment_id -> Nullable<Uuid>,
+        user_id -> Uuid,
+        start_offset -> Int4,
+        length -> Int4,
+    }
+}
+
+diesel::table! {
+    notifications (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        actor_id -> Uuid,
+        kind -> Varchar,
+        post_id -> Nullable<Uuid>,
+        comment_id -> Nullable<Uuid>,
+        group_key -> Varchar,
+        read_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    notification_preferences (user_id, kind) {
+        user_id -> Uuid,
+        kind -> Varchar,
+        enabled -> Bool,
+    }
+}
+
+diesel::table! {
+    stream_events (id) {
+        id -> Int8,
+        created_at -> Timestamptz,
+        kind -> Varchar,
+        user_id -> Uuid,
+        post_id -> Nullable<Uuid>,
+        payload -> Jsonb,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        user_id -> Nullable<Uuid>,
+        url -> Varchar,
+        secret -> Varchar,
+        event_types -> Array<Text>,
+        is_active -> Bool,
+        consecutive_failures -> Int4,
+        disabled_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        webhook_id -> Uuid,
+        event_type -> Varchar,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_attempt_at -> Nullable<Timestamptz>,
+        response_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamptz>,
+        event_id -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    jobs (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        kind -> Varchar,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        max_attempts -> Int4,
+        run_at -> Timestamptz,
+        locked_until -> Nullable<Timestamptz>,
+        dedupe_key -> Nullable<Varchar>,
+        last_error -> Nullable<Text>,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    outbox_events (id) {
+        id -> Int8,
+        created_at -> Timestamptz,
+        event_type -> Varchar,
+        payload -> Jsonb,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        published_at -> Nullable<Timestamptz>,
+        dead_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    media (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        storage_key -> Varchar,
+        content_type -> Varchar,
+        size_bytes -> Int8,
+        url -> Varchar,
+        status -> Varchar,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        blurhash -> Nullable<Varchar>,
+        alt_text -> Nullable<Varchar>,
+        caption -> Nullable<Varchar>,
+        focal_x -> Float4,
+        focal_y -> Float4,
+    }
+}
+
+diesel::table! {
+    media_attachments (id) {
+        id -> Uuid,
+        post_id -> Uuid,
+        comment_id -> Nullable<Uuid>,
+        media_id -> Uuid,
+        position -> Int4,
+    }
+}
+
+diesel::table! {
+    media_variants (id) {
+        id -> Uuid,
+        media_id -> Uuid,
+        name -> Varchar,
+        storage_key -> Varchar,
+        content_type -> Varchar,
+        width -> Int4,
+        height -> Int4,
+        size_bytes -> Int8,
+        url -> Varchar,
+    }
+}
+
+diesel::table! {
+    email_change_requests (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        new_email -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    password_setup_requests (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        token_hash -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    account_deletion_confirmations (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        token_hash -> Varchar,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    username_history (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        username -> Varchar,
+    }
+}
+
+diesel::table! {
+    data_exports (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        status -> Varchar,
+        storage_key -> Nullable<Varchar>,
+        size_bytes -> Nullable<Int8>,
+        completed_at -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    imports (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        kind -> Varchar,
+        status -> Varchar,
+        storage_key -> Nullable<Varchar>,
+        total_items -> Nullable<Int4>,
+        processed_items -> Int4,
+        imported_items -> Int4,
+        skipped_items -> Int4,
+        failed_items -> Int4,
+        failures -> Jsonb,
+        error -> Nullable<Text>,
+        completed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    import_items (import_id, position) {
+        import_id -> Uuid,
+        position -> Int4,
+        item -> Jsonb,
+    }
+}
+
+diesel::table! {
+    imported_posts (user_id, source, source_id) {
+        user_id -> Uuid,
+        source -> Varchar,
+        source_id -> Varchar,
+        post_id -> Uuid,
+    }
+}
+
+diesel::table! {
+    bookmark_collections (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        name -> Varchar,
+    }
+}
+
+diesel::table! {
+    bookmarks (id) {
+        id -> Uuid,
+        created_at -> Timestamptz,
+        user_id -> Uuid,
+        post_id -> Uuid,
+        collection_id -> Nullable<Uuid>,
+    }
+}
+
 diesel::joinable!(posts -> users (user_id));
 diesel::joinable!(comments -> posts (post_id));
 diesel::joinable!(comments -> users (user_id));
@@ -64,5 +402,60 @@ diesel::joinable!(interactions -> posts (post_id));
 diesel::joinable!(interactions -> users (user_id));
 diesel::joinable!(follows -> users (follower_id));
 // diesel::joinable!(follows -> users (following_id));
+diesel::joinable!(post_hashtags -> posts (post_id));
+diesel::joinable!(post_hashtags -> hashtags (hashtag_id));
+diesel::joinable!(comment_hashtags -> comments (comment_id));
+diesel::joinable!(comment_hashtags -> hashtags (hashtag_id));
+diesel::joinable!(mentions -> posts (post_id));
+diesel::joinable!(mentions -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(media -> users (user_id));
+diesel::joinable!(media_attachments -> media (media_id));
+diesel::joinable!(media_attachments -> posts (post_id));
+diesel::joinable!(media_variants -> media (media_id));
+diesel::joinable!(email_change_requests -> users (user_id));
+diesel::joinable!(password_setup_requests -> users (user_id));
+diesel::joinable!(account_deletion_confirmations -> users (user_id));
+diesel::joinable!(username_history -> users (user_id));
+diesel::joinable!(data_exports -> users (user_id));
+diesel::joinable!(imports -> users (user_id));
+diesel::joinable!(import_items -> imports (import_id));
+diesel::joinable!(imported_posts -> posts (post_id));
+diesel::joinable!(bookmark_collections -> users (user_id));
+diesel::joinable!(bookmarks -> posts (post_id));
+diesel::joinable!(bookmarks -> bookmark_collections (collection_id));
 
-diesel::allow_tables_to_appear_in_same_query!(users, posts, comments, interactions, follows,);
+diesel::allow_tables_to_appear_in_same_query!(
+    users,
+    posts,
+    comments,
+    interactions,
+    follows,
+    follow_requests,
+    blocks,
+    mutes,
+    hashtags,
+    post_hashtags,
+    comment_hashtags,
+    mentions,
+    notifications,
+    notification_preferences,
+    stream_events,
+    webhooks,
+    webhook_deliveries,
+    jobs,
+    outbox_events,
+    media,
+    media_attachments,
+    media_variants,
+    email_change_requests,
+    password_setup_requests,
+    account_deletion_confirmations,
+    username_history,
+    data_exports,
+    imports,
+    import_items,
+    imported_posts,
+    bookmark_collections,
+    bookmarks,
+);
@@ -0,0 +1,273 @@
+use crate::database::{Database, DbError};
+use crate::models::StreamMessage;
+use actix_web::web;
+use actix_ws::{Message, MessageStream, Session};
+use futures_util::{Stream, StreamExt};
+use sqlx::postgres::PgListener;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// Channel the `stream_events` trigger notifies with each new event id
+const STREAM_CHANNEL: &str = "stream_events";
+
+// How often idle connections get a heartbeat. The hub also re-reads the event log
+// this often in case a notification was lost while the listener reconnected.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+// Events buffered per connection before a slow client is dropped; it can
+// reconnect and resume from its last event id
+const CLIENT_BUFFER: usize = 256;
+
+struct Subscriber {
+    id: u64,
+    sender: mpsc::Sender<StreamMessage>,
+}
+
+// Fans events from the database out to the clients connected to this instance
+#[derive(Clone)]
+pub struct StreamHub {
+    db: Database,
+    subscribers: Arc<Mutex<HashMap<Uuid, Vec<Subscriber>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+// One client's stream: the backlog it asked to resume from, then live events.
+// Unsubscribes when dropped.
+pub struct Subscription {
+    hub: StreamHub,
+    user_id: Uuid,
+    id: u64,
+    backlog: VecDeque<StreamMessage>,
+    // Live events already delivered from the backlog are skipped
+    resumed_up_to: i64,
+    receiver: mpsc::Receiver<StreamMessage>,
+}
+
+impl Subscription {
+    // None once the hub has dropped this client
+    pub async fn next(&mut self) -> Option<StreamMessage> {
+        if let Some(message) = self.backlog.pop_front() {
+            return Some(message);
+        }
+        loop {
+            let message = self.receiver.recv().await?;
+            if message.id > self.resumed_up_to {
+                return Some(message);
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.user_id, self.id);
+    }
+}
+
+impl StreamHub {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Registers a client, replaying what it missed after `last_event_id`
+    pub async fn subscribe(
+        &self,
+        user_id: Uuid,
+        last_event_id: Option<i64>,
+    ) -> Result<Subscription, DbError> {
+        let (sender, receiver) = mpsc::channel(CLIENT_BUFFER);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Subscribe before reading the backlog so nothing falls between the two
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_default()
+            .push(Subscriber { id, sender });
+
+        let mut subscription = Subscription {
+            hub: self.clone(),
+            user_id,
+            id,
+            backlog: VecDeque::new(),
+            resumed_up_to: 0,
+            receiver,
+        };
+        if let Some(last_event_id) = last_event_id {
+            let (backlog, resumed_up_to) =
+                self.db.get_stream_backlog(user_id, last_event_id).await?;
+            subscription.resumed_up_to = resumed_up_to;
+            subscription.backlog = backlog.into();
+        }
+        Ok(subscription)
+    }
+
+    fn unsubscribe(&self, user_id: Uuid, id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(connections) = subscribers.get_mut(&user_id) {
+            connections.retain(|s| s.id != id);
+            if connections.is_empty() {
+                subscribers.remove(&user_id);
+            }
+        }
+    }
+
+    fn publish(&self, user_id: Uuid, message: StreamMessage) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(connections) = subscribers.get_mut(&user_id) {
+            // Full or closed channels mean the client is gone or too slow to keep up
+            connections.retain(|s| s.sender.try_send(message.clone()).is_ok());
+            if connections.is_empty() {
+                subscribers.remove(&user_id);
+            }
+        }
+    }
+
+    fn connected_users(&self) -> Vec<Uuid> {
+        self.subscribers.lock().unwrap().keys().copied().collect()
+    }
+
+    // Delivers events logged after `last_id`, returning the new high-water mark
+    async fn dispatch(&self, last_id: i64) -> i64 {
+        let recipients = self.connected_users();
+        if recipients.is_empty() {
+            return self.db.latest_stream_event_id().await.unwrap_or(last_id);
+        }
+        match self.db.route_stream_events(last_id, recipients).await {
+            Ok((messages, last_id)) => {
+                for (user_id, message) in messages {
+                    self.publish(user_id, message);
+                }
+                last_id
+            }
+            Err(e) => {
+                eprintln!("Error routing stream events: {}", e);
+                last_id
+            }
+        }
+    }
+
+    // Listens for new events and fans them out until the process exits. Every
+    // instance runs its own listener, so clients can connect to any of them.
+    pub async fn run(self, database_url: String) {
+        let mut listener = loop {
+            match PgListener::connect(&database_url).await {
+                Ok(mut listener) => match listener.listen(STREAM_CHANNEL).await {
+                    Ok(()) => break listener,
+                    Err(e) => eprintln!("Error listening for stream events: {}", e),
+                },
+                Err(e) => eprintln!("Error connecting stream listener: {}", e),
+            }
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        };
+
+        let mut last_id = self.db.latest_stream_event_id().await.unwrap_or(0);
+        let mut catch_up = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                notification = listener.recv() => {
+                    // The listener reconnects by itself on the next call
+                    if let Err(e) = notification {
+                        eprintln!("Error receiving stream events: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                    last_id = self.dispatch(last_id).await;
+                }
+                _ = catch_up.tick() => {
+                    last_id = self.dispatch(last_id).await;
+                }
+            }
+        }
+    }
+}
+
+// Server-Sent Events framing for one message
+fn sse_frame(message: &StreamMessage) -> String {
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        message.id,
+        message.event.as_str(),
+        message.data
+    )
+}
+
+enum Outgoing {
+    Message(StreamMessage),
+    Heartbeat,
+}
+
+// A subscription as a Server-Sent Events body, with comment lines as heartbeats
+pub fn sse_stream(
+    mut subscription: Subscription,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    async_stream::stream! {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            let outgoing = tokio::select! {
+                message = subscription.next() => match message {
+                    Some(message) => Outgoing::Message(message),
+                    None => break,
+                },
+                _ = heartbeat.tick() => Outgoing::Heartbeat,
+            };
+            yield Ok(match outgoing {
+                Outgoing::Message(message) => web::Bytes::from(sse_frame(&message)),
+                Outgoing::Heartbeat => web::Bytes::from_static(b": heartbeat\n\n"),
+            });
+        }
+    }
+}
+
+// Sends a subscription over a WebSocket as JSON text frames, pinging the client
+// and closing the socket when it stops answering
+pub async fn run_websocket(
+    mut subscription: Subscription,
+    mut session: Session,
+    mut incoming: MessageStream,
+) {
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_pong = Instant::now();
+
+    loop {
+        tokio::select! {
+            message = subscription.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let Ok(text) = serde_json::to_string(&message) else {
+                    continue;
+                };
+                if session.text(text).await.is_err() {
+                    break;
+                }
+            }
+            frame = incoming.next() => match frame {
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Pong(_))) => last_pong = Instant::now(),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = heartbeat.tick() => {
+                if last_pong.elapsed() > HEARTBEAT_INTERVAL * 3 || session.ping(b"").await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = session.close(None).await;
+}
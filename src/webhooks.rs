@@ -0,0 +1,312 @@
+use crate::database::Database;
+use crate::models::{Webhook, WebhookAttempt, WebhookDelivery};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use sha2::Sha256;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+// Deliveries are given up on after this many attempts
+const MAX_ATTEMPTS: i32 = 8;
+
+// Retries back off exponentially from this delay, up to MAX_RETRY_DELAY
+const BASE_RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 6 * 60 * 60;
+
+// Webhooks are disabled after this many failed attempts in a row
+const DISABLE_AFTER_FAILURES: i32 = 25;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CLAIM_BATCH: i64 = 50;
+
+// Claimed deliveries are retried by another worker if no result is recorded
+// within this lease
+const CLAIM_LEASE_SECS: i64 = 60;
+
+// Whether `ip` is routable on the public internet. Loopback, private, link-local,
+// unique-local, shared, documentation and other reserved ranges are not.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_ipv4(mapped),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT)
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link-local fe80::/10 and the deprecated site-local fec0::/10
+        || (first & 0xffc0) == 0xfe80
+        || (first & 0xffc0) == 0xfec0
+        // Documentation 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // IPv4-compatible and NAT64 addresses, which embed IPv4 ones
+        || ip.segments()[..6].iter().all(|&s| s == 0)
+        || (first == 0x64 && ip.segments()[1] == 0xff9b))
+}
+
+// Webhooks must use https and must not name a local host or non-public address.
+// Hostnames are checked again when they are resolved for each delivery.
+pub fn check_url(url: &str) -> Result<Url, &'static str> {
+    let url = Url::parse(url).map_err(|_| "Invalid webhook URL")?;
+    if url.scheme() != "https" {
+        return Err("Webhook URLs must use https");
+    }
+    let host = url.host_str().unwrap_or_default();
+    let public = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => is_public_ip(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_lowercase();
+            !domain.is_empty() && domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    };
+    match public {
+        true => Ok(url),
+        false => Err("Webhook URLs must point at a public host"),
+    }
+}
+
+// Resolves webhook hosts for the client, refusing any host with a non-public
+// address. Checking at connection time means a host cannot pass a check and then
+// be re-pointed at an internal address before the request is made.
+struct PublicResolver;
+
+#[derive(Debug)]
+struct NonPublicAddressError;
+
+impl std::fmt::Display for NonPublicAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Host resolves to a non-public address")
+    }
+}
+
+impl std::error::Error for NonPublicAddressError {}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+                return Err(Box::new(NonPublicAddressError) as _);
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// A new random signing secret, hex encoded
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// `sha256=` followed by the hex HMAC-SHA256 of "{timestamp}.{body}"
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    let secs = BASE_RETRY_DELAY_SECS
+        .saturating_mul(2i64.pow(exponent))
+        .min(MAX_RETRY_DELAY_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+// Delivers queued webhook events. Deliveries are claimed with SKIP LOCKED, so
+// any number of instances can run a dispatcher against the same database.
+pub struct WebhookDispatcher {
+    db: Database,
+    client: reqwest::Client,
+}
+
+impl WebhookDispatcher {
+    pub fn new(db: Database) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(Policy::none())
+            .https_only(true)
+            .dns_resolver(PublicResolver)
+            .build()
+            .expect("Failed to build webhook client");
+        Self { db, client }
+    }
+
+    async fn deliver(&self, delivery: &WebhookDelivery, webhook: &Webhook) -> WebhookAttempt {
+        // IP literals never reach the resolver, so the URL is checked here too
+        let url = match check_url(&webhook.url) {
+            Ok(url) => url,
+            Err(message) => {
+                return WebhookAttempt {
+                    response_status: None,
+                    error: Some(message.to_string()),
+                    retry_at: None,
+                };
+            }
+        };
+
+        let body = delivery.payload.to_string();
+        let timestamp = Utc::now().timestamp();
+        let result = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Event", delivery.event_type.as_str())
+            .header("X-Webhook-Delivery", delivery.id.to_string())
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header(
+                "X-Webhook-Signature",
+                sign(&webhook.secret, timestamp, &body),
+            )
+            .body(body)
+            .send()
+            .await;
+
+        let (response_status, error) = match result {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16() as i32), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16() as i32),
+                Some(format!("Endpoint responded with {}", response.status())),
+            ),
+            // Errors are kept coarse so the log does not reveal how internal hosts
+            // respond
+            Err(e) if e.is_timeout() => (None, Some("Request timed out".to_string())),
+            Err(e) if e.is_connect() => (None, Some("Could not connect".to_string())),
+            Err(_) => (None, Some("Request failed".to_string())),
+        };
+        // `attempts` was already incremented when the delivery was claimed
+        let retry_at = (error.is_some() && delivery.attempts < MAX_ATTEMPTS)
+            .then(|| Utc::now() + retry_delay(delivery.attempts));
+        WebhookAttempt {
+            response_status,
+            error,
+            retry_at,
+        }
+    }
+
+    // Delivers one claimed batch, returning how many deliveries it held
+    async fn dispatch(&self) -> usize {
+        let claimed = match self
+            .db
+            .claim_webhook_deliveries(CLAIM_BATCH, chrono::Duration::seconds(CLAIM_LEASE_SECS))
+            .await
+        {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                eprintln!("Error claiming webhook deliveries: {}", e);
+                return 0;
+            }
+        };
+
+        let attempts = claimed.iter().map(|(delivery, webhook)| async move {
+            let attempt = self.deliver(delivery, webhook).await;
+            if let Err(e) = self
+                .db
+                .record_webhook_attempt(delivery.id, webhook.id, attempt, DISABLE_AFTER_FAILURES)
+                .await
+            {
+                eprintln!("Error recording webhook delivery {}: {}", delivery.id, e);
+            }
+        });
+        futures_util::future::join_all(attempts).await;
+        claimed.len()
+    }
+
+    // Polls the delivery queue until the process exits
+    pub async fn run(self) {
+        loop {
+            // A full batch suggests more are waiting, so go again straight away
+            if self.dispatch().await < CLAIM_BATCH as usize {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_url_requires_https() {
+        assert!(check_url("https://example.com/hook").is_ok());
+        assert!(check_url("http://example.com/hook").is_err());
+        assert!(check_url("ftp://example.com/hook").is_err());
+        assert!(check_url("not a url").is_err());
+    }
+
+    #[test]
+    fn check_url_rejects_local_hosts() {
+        for url in [
+            "https://localhost/hook",
+            "https://api.localhost./hook",
+            "https://127.0.0.1/hook",
+            "https://10.1.2.3/hook",
+            "https://172.16.0.1/hook",
+            "https://192.168.1.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/hook",
+            "https://0.0.0.0/hook",
+            "https://[::1]/hook",
+            "https://[fd00::1]/hook",
+            "https://[fe80::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+            "https://[::ffff:10.0.0.1]/hook",
+        ] {
+            assert!(check_url(url).is_err(), "{} should be rejected", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolver_refuses_hosts_with_local_addresses() {
+        let name: Name = "localhost".parse().unwrap();
+        let error = PublicResolver.resolve(name).await.err().unwrap();
+        assert!(error.is::<NonPublicAddressError>());
+    }
+
+    #[test]
+    fn check_url_accepts_public_addresses() {
+        assert!(check_url("https://93.184.215.14/hook").is_ok());
+        assert!(check_url("https://[2606:4700::1111]/hook").is_ok());
+    }
+}